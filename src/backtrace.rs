@@ -0,0 +1,131 @@
+// Kernel backtraces
+// Walks the frame pointer chain from where the kernel is, so that a panic
+// shows how it got there and not only where. The kernel is built with
+// frame pointers (see .cargo/config). Every frame keeps its return address
+// at fp - 8 and the caller's fp at fp - 16. No symbol table is compiled
+// in, so addresses in the kernel are shown as an offset into the text
+// section, which addr2line or the linker map turn into a function.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu::fp_read;
+
+extern "C" {
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+    static MEMORY_START: usize;
+    static MEMORY_END: usize;
+}
+
+// Deepest chain that is followed, in case it loops or runs off into garbage
+pub const MAX_FRAMES: usize = 32;
+
+// Whether the panic handler prints a backtrace, "backtrace" on the
+// command line turns it on
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Follow the chain from the frame at fp and hand f each return address,
+// innermost first. read loads the word at an address, or None if it
+// can't be read. The walk stops at a null or misaligned fp, at a return
+// address of 0, when the next frame isn't further up the stack, or after
+// MAX_FRAMES. Returns the number of frames walked.
+pub fn walk(fp: usize, read: impl Fn(usize) -> Option<usize>, mut f: impl FnMut(usize)) -> usize {
+    let mut fp = fp;
+    let mut frames = 0;
+    while frames < MAX_FRAMES && fp != 0 && fp & 7 == 0 {
+        let (Some(ra), Some(next)) = (read(fp.wrapping_sub(8)), read(fp.wrapping_sub(16))) else {
+            break;
+        };
+        if ra == 0 {
+            break;
+        }
+        f(ra);
+        frames += 1;
+        // Callers' frames are above ours, anything else isn't a frame
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    frames
+}
+
+// Read a word of RAM, the only place a kernel stack can be
+fn read_ram(addr: usize) -> Option<usize> {
+    let in_ram = unsafe { addr >= MEMORY_START && addr.checked_add(8).is_some_and(|end| end <= MEMORY_END) };
+    if !in_ram {
+        return None;
+    }
+    Some(unsafe { (addr as *const usize).read_volatile() })
+}
+
+// Print the return addresses of the frames above the caller
+pub fn print_backtrace() {
+    println!("Backtrace:");
+    let (text_start, text_end) = unsafe { (TEXT_START, TEXT_END) };
+    let frames = walk(fp_read(), read_ram, |ra| {
+        if (text_start..text_end).contains(&ra) {
+            println!("  0x{:016x} text+0x{:x}", ra, ra - text_start);
+        } else {
+            println!("  0x{:016x}", ra);
+        }
+    });
+    if frames == 0 {
+        println!("  <no frames>");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    // A stack of three frames at 0x3000, 0x2000 and 0x1000, each with its
+    // return address at fp - 8 and the caller's fp at fp - 16
+    fn read(addr: usize) -> Option<usize> {
+        match addr {
+            0xff8 => Some(0xaaa),
+            0xff0 => Some(0x2000),
+            0x1ff8 => Some(0xbbb),
+            0x1ff0 => Some(0x3000),
+            // The outermost frame has no return address
+            0x2ff8 => Some(0),
+            0x2ff0 => Some(0),
+            _ => None
+        }
+    }
+
+    #[test_case]
+    fn walk_follows_the_frame_chain() {
+        let mut ras = Vec::new();
+        assert_eq!(walk(0x1000, read, |ra| ras.push(ra)), 2);
+        assert_eq!(ras, [0xaaa, 0xbbb]);
+    }
+
+    #[test_case]
+    fn walk_stops_at_bad_frames() {
+        assert_eq!(walk(0, read, |_| {}), 0);
+        assert_eq!(walk(0x1004, read, |_| {}), 0);
+        // Unreadable
+        assert_eq!(walk(0x5000, read, |_| {}), 0);
+        // A frame that points back down the stack ends the walk after it
+        let looped = |addr| match addr {
+            0xff8 => Some(0xaaa),
+            0xff0 => Some(0x1000),
+            _ => None
+        };
+        assert_eq!(walk(0x1000, looped, |_| {}), 1);
+        // A chain that never ends is cut off at MAX_FRAMES
+        let endless = |addr: usize| if addr & 8 != 0 { Some(0xccc) } else { Some(addr + 0x1010) };
+        assert_eq!(walk(0x1000, endless, |_| {}), MAX_FRAMES);
+    }
+}
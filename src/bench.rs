@@ -0,0 +1,128 @@
+// Allocator benchmarks
+// Times the page allocator and kmalloc with mtime so that changes to them
+// can be compared by a number. Everything a round allocates is freed in
+// the same round, so a run leaves both allocators as it found them.
+
+use crate::{cpu::{mtime_read, TIMER_FREQ_HZ}, kmem, page};
+
+// Rounds run when the bench boot argument doesn't give a number
+pub const DEFAULT_ITERATIONS: usize = 1000;
+// kmalloc sizes timed, in bytes
+pub const KMALLOC_SIZES: [usize; 4] = [16, 64, 256, 1024];
+// Allocations held at once in a round. Holding a few means the allocator
+// has to get past taken memory, not just hand back what was freed last.
+const BATCH: usize = 8;
+// One page allocator result and one per kmalloc size
+pub const RESULTS: usize = 1 + KMALLOC_SIZES.len();
+
+#[derive(Clone, Copy, Debug)]
+pub struct BenchResult {
+    pub name:  &'static str,
+    // Bytes per allocation, pages are PAGE_SIZE
+    pub size:  usize,
+    // Allocations plus frees
+    pub ops:   usize,
+    // mtime ticks they took
+    pub ticks: u64
+}
+
+impl BenchResult {
+    const fn empty() -> Self {
+        BenchResult { name: "", size: 0, ops: 0, ticks: 0 }
+    }
+
+    pub fn ops_per_sec(&self) -> u64 {
+        // A round faster than one tick still took some time
+        self.ops as u64 * TIMER_FREQ_HZ / self.ticks.max(1)
+    }
+
+    pub fn avg_ns(&self) -> u64 {
+        if self.ops == 0 {
+            return 0;
+        }
+        self.ticks * 1_000_000_000 / TIMER_FREQ_HZ / self.ops as u64
+    }
+}
+
+#[derive(Debug)]
+pub enum BenchError {
+    // An allocation failed partway, what it had was freed again
+    OutOfMemory,
+    // The allocators didn't end up where they started
+    Leak { pages: isize, bytes: isize }
+}
+
+// Time iterations rounds of BATCH allocations and frees with the page
+// allocator and with kmalloc at each of KMALLOC_SIZES. The results live
+// on the stack so the run itself doesn't use the heap it measures.
+pub fn run_alloc_bench(iterations: usize) -> Result<[BenchResult; RESULTS], BenchError> {
+    let free_pages = page::free_page_count();
+    let used = kmem::used();
+    let mut results = [BenchResult::empty(); RESULTS];
+    results[0] = time_rounds("page", page::PAGE_SIZE, iterations, || page::alloc(1), |p| {
+        page::dealloc(p);
+    })?;
+    for (i, &size) in KMALLOC_SIZES.iter().enumerate() {
+        results[i + 1] = time_rounds("kmalloc", size, iterations, || kmem::kmalloc(size), kmem::kfree)?;
+    }
+    let pages = page::free_page_count() as isize - free_pages as isize;
+    let bytes = kmem::used() as isize - used as isize;
+    if pages != 0 || bytes != 0 {
+        return Err(BenchError::Leak { pages, bytes });
+    }
+    Ok(results)
+}
+
+fn time_rounds(name: &'static str,
+               size: usize,
+               iterations: usize,
+               mut alloc: impl FnMut() -> *mut u8,
+               mut free: impl FnMut(*mut u8))
+               -> Result<BenchResult, BenchError>
+{
+    let mut held = [core::ptr::null_mut(); BATCH];
+    let start = mtime_read();
+    for _ in 0..iterations {
+        for i in 0..BATCH {
+            held[i] = alloc();
+            if held[i].is_null() {
+                held[..i].iter().for_each(|&p| free(p));
+                return Err(BenchError::OutOfMemory);
+            }
+        }
+        held.iter().for_each(|&p| free(p));
+    }
+    let ticks = mtime_read() - start;
+    Ok(BenchResult { name, size, ops: 2 * BATCH * iterations, ticks })
+}
+
+// Run the benchmark and print a line per result
+pub fn print_alloc_bench(iterations: usize) {
+    println!("Allocator benchmark, {} rounds of {}", iterations, BATCH);
+    match run_alloc_bench(iterations) {
+        Ok(results) => {
+            for r in results.iter() {
+                println!("{:>8} {:>5} bytes: {:>10} ops/s, {:>6} ns avg", r.name, r.size, r.ops_per_sec(), r.avg_ns());
+            }
+        },
+        Err(e) => println!("Allocator benchmark failed: {:?}", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn a_run_leaves_the_allocators_as_it_found_them() {
+        let (free_pages, used) = (page::free_page_count(), kmem::used());
+        let results = run_alloc_bench(10).expect("benchmark failed");
+        assert_eq!(page::free_page_count(), free_pages);
+        assert_eq!(kmem::used(), used);
+        for r in results.iter() {
+            assert_eq!(r.ops, 2 * BATCH * 10);
+            assert!(r.ops_per_sec() > 0);
+        }
+        assert_eq!(results.map(|r| r.size)[1..], KMALLOC_SIZES);
+    }
+}
@@ -0,0 +1,553 @@
+// Boot sequence
+// Bringing the kernel up is split into phases that run in a fixed order.
+// Each one returns an error instead of panicking so the driver can say
+// which phase failed before halting. kinit() runs the machine mode
+// phases, then kmain() runs the rest in supervisor mode.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{backtrace, bootargs, cpu, fdt, kmem, log, mmio, page, plic, rng, scheduler, process::{self, ProcError}, uart::{self, UartError}};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+	// The UART, so the later phases can print
+	EarlyConsole,
+	// The page allocator
+	PhysMem,
+	// The kernel's byte allocator on top of the page allocator
+	KernelHeap,
+	// The kernel page table and the trap frame
+	Paging,
+	// The PLIC
+	Interrupts,
+	// The process list and the init process
+	Processes,
+}
+
+#[derive(Debug)]
+pub enum BootError {
+	Console(UartError),
+	OutOfMemory,
+	Kmem(kmem::KmemError),
+	PageTable(page::PtError),
+	// Number of kernel mappings that are both writable and executable
+	NotWxClean(usize),
+	// Number of pairs of kernel regions that share pages but not permissions
+	OverlappingMappings(usize),
+	// The page below the kernel stack at .0 ended up mapped
+	StackGuardMapped(usize),
+	// The init section isn't on free pages the page allocator manages
+	InitNotClaimed,
+	Process(ProcError),
+	// process::init() left no process to start
+	NoInitProcess,
+	// The device tree says RAM ends at .0, before the kernel's heap
+	// ends at .1
+	RamTooSmall(usize, usize),
+}
+
+pub struct Step {
+	pub phase: Phase,
+	pub run:   fn() -> Result<(), BootError>,
+}
+
+// Phases run by kinit() in machine mode
+pub const MACHINE_STEPS: [Step; 4] = [
+	Step { phase: Phase::EarlyConsole, run: early_console },
+	Step { phase: Phase::PhysMem, run: phys_mem },
+	Step { phase: Phase::KernelHeap, run: kernel_heap },
+	Step { phase: Phase::Paging, run: paging },
+];
+
+// Phases run by kmain() once we're in supervisor mode
+pub const SUPERVISOR_STEPS: [Step; 2] = [
+	Step { phase: Phase::Interrupts, run: interrupts },
+	Step { phase: Phase::Processes, run: processes },
+];
+
+/// Run steps in order, stopping at the first one that fails.
+/// Returns the phase that failed along with its error.
+pub fn run(steps: &[Step]) -> Result<(), (Phase, BootError)> {
+	for step in steps {
+		println!("boot: {:?}", step.phase);
+		(step.run)().map_err(|e| (step.phase, e))?;
+	}
+	Ok(())
+}
+
+/// Run steps and halt if one fails, there is nothing else to fall back on.
+pub fn run_or_halt(steps: &[Step]) {
+	if let Err((phase, e)) = run(steps) {
+		panic!("Boot failed in phase {:?}: {:?}", phase, e);
+	}
+}
+
+/// Give the pages holding the machine mode boot phases back to the page
+/// allocator. Nothing in the init section may run after this, which is
+/// why only the phases in MACHINE_STEPS live there.
+/// Returns the number of pages freed.
+pub fn reclaim_init() -> usize {
+	// The init section was never put in the kernel's page table, so
+	// there is nothing to unmap.
+	let pages = unsafe { page::dealloc_range(INIT_START, INIT_END) };
+	println!("Reclaimed {} KiB of init memory", pages * page::PAGE_SIZE / 1024);
+	pages
+}
+
+// The following symbols come from asm/mem.S. We can use
+// the symbols directly, but the address of the symbols
+// themselves are their values, which can cause issues.
+
+extern "C" {
+	static TEXT_START: usize;
+	static TEXT_END: usize;
+	static DATA_START: usize;
+	static DATA_END: usize;
+	static RODATA_START: usize;
+	static RODATA_END: usize;
+	static BSS_START: usize;
+	static BSS_END: usize;
+	static KERNEL_STACK_GUARD: usize;
+	static KERNEL_STACK_START: usize;
+	static KERNEL_STACK_END: usize;
+	static HEAP_START: usize;
+	static HEAP_SIZE: usize;
+	static MEMORY_END: usize;
+	static INIT_START: usize;
+	static INIT_END: usize;
+}
+
+// Address of the device tree blob boot.S was handed, 0 if there isn't one
+static DTB: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_dtb(addr: usize) {
+	DTB.store(addr, Ordering::Relaxed);
+}
+
+pub fn dtb() -> usize {
+	DTB.load(Ordering::Relaxed)
+}
+
+// Top of the boot hart's kernel stack, where kmain starts. The trap
+// stacks are above it, see cpu::TRAP_STACK_SIZE.
+pub fn kernel_stack_end() -> usize {
+	unsafe { KERNEL_STACK_END - cpu::MAX_HARTS * cpu::TRAP_STACK_SIZE }
+}
+
+// The page below the boot hart's kernel stack. It is never mapped, so a
+// stack overflow in the kernel faults here.
+pub fn kernel_stack_guard() -> usize {
+	unsafe { KERNEL_STACK_GUARD }
+}
+
+// Refuse to boot if any kernel page ends up both writable and executable
+const ENFORCE_WX: bool = true;
+
+/// Identity map range
+/// Takes a contiguous allocation of memory and maps it using PAGE_SIZE
+/// This assumes that start <= end
+pub fn id_map_range(root: &mut page::Table,
+                    start: usize,
+                    end: usize,
+                    bits: i64)
+{
+	let mut memaddr = start & !(page::PAGE_SIZE - 1);
+	let num_kb_pages =
+		(page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
+
+	for _ in 0..num_kb_pages {
+		let paddr = page::PhysAddr(memaddr);
+		page::map(root, paddr.to_identity_virt(), paddr, bits, 0);
+		memaddr += 1 << 12;
+	}
+}
+
+#[link_section = ".init.text"]
+fn early_console() -> Result<(), BootError> {
+	// If the UART never goes idle there is no console to report it on,
+	// so carry on booting with whatever state it is in.
+	let mut console = uart::Uart::new(mmio::UART_BASE);
+	let _ = console.init();
+	load_cmdline();
+	apply_bootargs();
+	if bootargs::get_bool("uart_selftest") != Some(false) {
+		match console.selftest() {
+			Ok(uart::SelfTest::Passed) => println!("UART loopback self-test passed"),
+			Ok(uart::SelfTest::Skipped) => {},
+			Err(e) => return Err(BootError::Console(e)),
+		}
+	}
+	Ok(())
+}
+
+// Use /chosen/bootargs from the device tree as the kernel command line.
+// The string stays in the blob, which phys_mem keeps away from the page
+// allocator and paging maps for the kernel to read later on.
+#[link_section = ".init.text"]
+fn load_cmdline() {
+	let tree = match unsafe { fdt::Fdt::from_ptr(dtb() as *const u8) } {
+		Ok(tree) => tree,
+		Err(_) => return,
+	};
+	match tree.bootargs() {
+		Ok(Some(cmdline)) => bootargs::set_cmdline(cmdline),
+		Ok(None) => {},
+		Err(e) => println!("Bad device tree, no command line: {:?}", e),
+	}
+}
+
+// The bytes the device tree blob takes up, None if there isn't one
+#[link_section = ".init.text"]
+fn dtb_range() -> Option<(usize, usize)> {
+	let tree = unsafe { fdt::Fdt::from_ptr(dtb() as *const u8) }.ok()?;
+	Some((dtb(), dtb() + tree.total_size()))
+}
+
+// Take the options we know about from the kernel command line.
+// Unknown keys and bad values are reported and otherwise ignored.
+#[link_section = ".init.text"]
+fn apply_bootargs() {
+	if !bootargs::cmdline().is_empty() {
+		println!("Command line: {}", bootargs::cmdline());
+	}
+	if let Some(name) = bootargs::get("loglevel") {
+		match log::LogLevel::parse(name) {
+			Some(level) => log::set_level(level),
+			None => println!("Unknown loglevel {}", name),
+		}
+	}
+	if let Some(ms) = bootargs::get("quantum_ms") {
+		match ms.parse() {
+			Ok(ms) => scheduler::set_quantum_ms(ms),
+			Err(_) => println!("Bad quantum_ms {}", ms),
+		}
+	}
+	if let Some(seed) = bootargs::get("seed") {
+		match seed.parse() {
+			Ok(seed) => rng::seed(seed),
+			Err(_) => println!("Bad seed {}", seed),
+		}
+	}
+	if let Some(on) = bootargs::get_bool("logical_time") {
+		scheduler::set_logical_time(on);
+	}
+	if let Some(on) = bootargs::get_bool("canary") {
+		process::set_canary_checks(on);
+	}
+	if let Some(on) = bootargs::get_bool("backtrace") {
+		backtrace::set_enabled(on);
+	}
+	if let Some(limit) = bootargs::get("fault_limit") {
+		match limit.parse() {
+			Ok(limit) => process::set_fault_limit(limit),
+			Err(_) => println!("Bad fault_limit {}", limit),
+		}
+	}
+}
+
+#[link_section = ".init.text"]
+fn phys_mem() -> Result<(), BootError> {
+	// The blob usually sits near the top of RAM, inside what the page
+	// allocator hands out, so read it before anything can be allocated.
+	check_device_tree()?;
+	page::init();
+	// The command line points into the blob, so it has to outlive boot.
+	// A blob outside of the pages the allocator manages is safe already.
+	if let Some((start, end)) = dtb_range() {
+		page::reserve(start, end);
+	}
+	// Keep the allocator away from the code we're running until we're done
+	// with it, see reclaim_init()
+	if unsafe { page::claim_range(INIT_START, INIT_END) } == 0 {
+		return Err(BootError::InitNotClaimed);
+	}
+	page::print_memory_map();
+	Ok(())
+}
+
+// Report what the device tree says about RAM and the devices we drive.
+// Their addresses are still fixed for now, this only makes sure the
+// linker script doesn't promise more RAM than the machine has.
+#[link_section = ".init.text"]
+fn check_device_tree() -> Result<(), BootError> {
+	let tree = match unsafe { fdt::Fdt::from_ptr(dtb() as *const u8) } {
+		Ok(tree) => tree,
+		Err(e) => {
+			println!("No device tree ({:?}), using the linker's memory map", e);
+			return Ok(());
+		},
+	};
+	println!("Device tree at 0x{:x}, {} bytes", dtb(), tree.total_size());
+	for (name, compat) in [("UART", "ns16550a"), ("PLIC", "riscv,plic0"), ("CLINT", "riscv,clint0")] {
+		if let Ok(Some(dev)) = tree.find_compatible(compat) {
+			println!("{:>6}: 0x{:x} ({} bytes)", name, dev.base, dev.size);
+		}
+	}
+	match tree.memory() {
+		Ok(Some(ram)) => {
+			let ram_end = (ram.base + ram.size) as usize;
+			println!("{:>6}: 0x{:x} -> 0x{:x}", "RAM", ram.base, ram_end);
+			let heap_end = unsafe { MEMORY_END };
+			if ram_end < heap_end {
+				return Err(BootError::RamTooSmall(ram_end, heap_end));
+			}
+		},
+		Ok(None) => println!("Device tree has no /memory node"),
+		Err(e) => println!("Bad device tree: {:?}", e),
+	}
+	Ok(())
+}
+
+#[link_section = ".init.text"]
+fn kernel_heap() -> Result<(), BootError> {
+	let pages = kmem::init().map_err(BootError::Kmem)?;
+	println!("Kernel heap: {} pages", pages);
+	Ok(())
+}
+
+// Build the kernel's page table and trap frame and load satp. We're still
+// in machine mode, so nothing is translated until boot.S drops us into
+// supervisor mode.
+#[link_section = ".init.text"]
+fn paging() -> Result<(), BootError> {
+
+	// Map heap allocations
+	let root_ptr = kmem::get_page_table();
+	let root_u = root_ptr as usize;
+	let mut root = match unsafe { root_ptr.as_mut() } {
+		Some(root) => root,
+		None => return Err(BootError::OutOfMemory),
+	};
+	let kheap_head = kmem::get_head() as usize;
+	let total_pages = kmem::get_num_allocations();
+	let kheap_end =
+		kheap_head.saturating_add(total_pages.saturating_mul(page::PAGE_SIZE));
+	println!();
+	println!();
+	unsafe {
+		println!("TEXT:   0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
+		println!("RODATA: 0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
+		println!("DATA:   0x{:x} -> 0x{:x}", DATA_START, DATA_END);
+		println!("BSS:    0x{:x} -> 0x{:x}", BSS_START, BSS_END);
+		println!(
+		         "GUARD:  0x{:x} -> 0x{:x}",
+		         KERNEL_STACK_GUARD, KERNEL_STACK_START
+		);
+		println!(
+		         "STACK:  0x{:x} -> 0x{:x}",
+		         KERNEL_STACK_START, KERNEL_STACK_END
+		);
+		println!(
+		         "HEAP:   0x{:x} -> 0x{:x}",
+		         kheap_head,
+		         kheap_end
+		);
+	}
+	// Every range the kernel identity maps. They are checked against each
+	// other before anything is mapped, since where two of them share a page
+	// whichever is mapped last silently decides its permissions.
+	let mut regions = Vec::new();
+	regions.push(KernelRegion::new("heap", kheap_head, kheap_end, page::EntryBits::ReadWrite));
+	// Using statics is inherently unsafe.
+	unsafe {
+		// Heap descriptors
+		let num_pages = HEAP_SIZE / page::PAGE_SIZE;
+		regions.push(KernelRegion::new("page descriptors", HEAP_START, HEAP_START + num_pages, page::EntryBits::ReadWrite));
+		regions.push(KernelRegion::new("text", TEXT_START, TEXT_END, page::EntryBits::ReadExecute));
+		// The linker script starts rodata on a fresh page so that it
+		// doesn't share one with the end of the text section. It is
+		// only data, so it doesn't need to be executable.
+		regions.push(KernelRegion::new("rodata", RODATA_START, RODATA_END, page::EntryBits::Read));
+		regions.push(KernelRegion::new("data", DATA_START, DATA_END, page::EntryBits::ReadWrite));
+		regions.push(KernelRegion::new("bss", BSS_START, BSS_END, page::EntryBits::ReadWrite));
+		regions.push(KernelRegion::new("kernel stack", KERNEL_STACK_START, KERNEL_STACK_END, page::EntryBits::ReadWrite));
+	}
+	// The device tree, for the command line
+	if let Some((start, end)) = dtb_range() {
+		regions.push(KernelRegion::new("device tree", start, end, page::EntryBits::Read));
+	}
+	// Devices: the test finisher, CLINT, PLIC and UART
+	for region in mmio::MMIO_REGIONS.iter() {
+		regions.push(KernelRegion::new(region.name, region.base, region.base + region.size, page::EntryBits::ReadWrite));
+	}
+	let clashes = overlapping_regions(&regions);
+	for &(a, b) in clashes.iter() {
+		println!("Kernel mappings {} and {} overlap with different permissions", regions[a].name, regions[b].name);
+	}
+	if !clashes.is_empty() {
+		return Err(BootError::OverlappingMappings(clashes.len()));
+	}
+	for region in regions.iter() {
+		id_map_range(root, region.start, region.end, region.bits);
+	}
+	// Nothing may cover the stack guard, or a kernel stack overflow would
+	// quietly run into whatever is mapped there
+	let guard = kernel_stack_guard();
+	if page::virt_to_phys(root, page::VirtAddr(guard)).is_some() {
+		return Err(BootError::StackGuardMapped(guard));
+	}
+
+	// The SATP value is written at the end of this phase, so paging is on
+	// by the time run_or_halt() moves on to the next one. kinit enters
+	// supervisor mode later, with cpu::enter_supervisor(). root_u is the
+	// root page table's address. When stored into the SATP register, this
+	// is divided by 4 KiB (right shift by 12 bits).
+	// We enable the MMU by setting mode 8. Bits 63, 62, 61, 60 determine
+	// the mode.
+	// 0 = Bare (no translation)
+	// 8 = Sv39
+	// 9 = Sv48
+	// build_satp has these parameters: mode, asid, page table address.
+	let satp_value = cpu::build_satp(cpu::SatpMode::Sv39, 0, root_u);
+	unsafe {
+		// We have to store the kernel's table. The tables will be moved
+		// back and forth between the kernel's table and user
+		// applicatons' tables. Note that we're writing the physical address
+		// of the trap frame.
+		cpu::mscratch_write(
+		                    (&mut cpu::KERNEL_TRAP_FRAME[0]
+		                     as *mut cpu::TrapFrame)
+		                    as usize,
+		);
+		cpu::sscratch_write(cpu::mscratch_read());
+		cpu::KERNEL_TRAP_FRAME[0].satp = satp_value;
+		// The trap frame itself is stored in the mscratch register.
+		id_map_range(
+		             &mut root,
+		             cpu::mscratch_read(),
+		             cpu::mscratch_read()
+		             + core::mem::size_of::<cpu::TrapFrame>(),
+		             page::EntryBits::ReadWrite.val()
+		);
+		page::print_page_allocations();
+	}
+	// Self-test: make sure everything we just mapped is a sane tree
+	// before handing it to the MMU.
+	page::validate_table(root).map_err(BootError::PageTable)?;
+	if ENFORCE_WX {
+		if let Err(bad) = page::enforce_wx(root) {
+			for vaddr in bad.iter() {
+				println!("W+X kernel mapping at 0x{:x}", vaddr);
+			}
+			return Err(BootError::NotWxClean(bad.len()));
+		}
+	}
+	// Set the satp and sfence.vma for MMU. This is the first time paging
+	// is on, so the fence is for every ASID, not just the kernel's 0.
+	println!("Setting 0x{:x}", satp_value);
+	println!("Scratch reg = 0x{:x}", cpu::mscratch_read());
+	cpu::satp_write_fence(satp_value);
+	Ok(())
+}
+
+// A range of the kernel's identity map and the permissions it gets
+struct KernelRegion {
+	name:  &'static str,
+	start: usize,
+	end:   usize,
+	bits:  i64,
+}
+
+impl KernelRegion {
+	fn new(name: &'static str, start: usize, end: usize, bits: page::EntryBits) -> Self {
+		KernelRegion { name, start, end, bits: bits.val() }
+	}
+
+	// The pages id_map_range() maps for this region
+	fn pages(&self) -> (usize, usize) {
+		(self.start & !(page::PAGE_SIZE - 1), page::align_val(self.end, 12))
+	}
+}
+
+// Every pair of regions that share a page but want different permissions,
+// as indices into regions. Regions that agree on their permissions may
+// share pages, like the end of the bss and the start of the stack.
+#[link_section = ".init.text"]
+fn overlapping_regions(regions: &[KernelRegion]) -> Vec<(usize, usize)> {
+	let mut clashes = Vec::new();
+	for (i, a) in regions.iter().enumerate() {
+		let (a_start, a_end) = a.pages();
+		for (j, b) in regions.iter().enumerate().skip(i + 1) {
+			let (b_start, b_end) = b.pages();
+			if a_start < b_end && b_start < a_end && a.bits != b.bits {
+				clashes.push((i, j));
+			}
+		}
+	}
+	clashes
+}
+
+fn interrupts() -> Result<(), BootError> {
+	// Lower threshold to 0 to allow all interrupts
+	plic::set_threshold(0);
+	// UART is interrupt id 10. Set it's priority as well
+	plic::enable(10);
+	plic::set_priority(10, 1);
+	println!("UART interrupts have been enabled...");
+	Ok(())
+}
+
+fn processes() -> Result<(), BootError> {
+	// Initialize the init process used by the kernel
+	let ret = process::init().map_err(BootError::Process)?;
+	// Starting it would jump to address 0
+	if ret == 0 {
+		return Err(BootError::NoInitProcess);
+	}
+	println!("Init process created at address 0x{:08x}", ret);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Number of times a stub step has run
+	static RAN: AtomicUsize = AtomicUsize::new(0);
+
+	fn ok_step() -> Result<(), BootError> {
+		RAN.fetch_add(1, Ordering::Relaxed);
+		Ok(())
+	}
+
+	fn failing_step() -> Result<(), BootError> {
+		Err(BootError::OutOfMemory)
+	}
+
+	#[test_case]
+	fn a_failing_phase_stops_the_rest() {
+		let steps = [
+			Step { phase: Phase::EarlyConsole, run: ok_step },
+			Step { phase: Phase::PhysMem, run: failing_step },
+			Step { phase: Phase::KernelHeap, run: ok_step },
+		];
+		RAN.store(0, Ordering::Relaxed);
+		assert!(matches!(run(&steps), Err((Phase::PhysMem, BootError::OutOfMemory))));
+		assert_eq!(RAN.load(Ordering::Relaxed), 1);
+		assert!(run(&[Step { phase: Phase::Paging, run: ok_step }]).is_ok());
+		assert_eq!(RAN.load(Ordering::Relaxed), 2);
+	}
+	#[test_case]
+	fn regions_sharing_a_page_must_agree() {
+		let regions = [
+			// rodata starting on the page text ends on
+			KernelRegion::new("text", 0x8000_0000, 0x8000_1800, page::EntryBits::ReadExecute),
+			KernelRegion::new("rodata", 0x8000_1800, 0x8000_2000, page::EntryBits::Read),
+			// These two share a page, but want the same permissions
+			KernelRegion::new("data", 0x8000_2000, 0x8000_2800, page::EntryBits::ReadWrite),
+			KernelRegion::new("bss", 0x8000_2800, 0x8000_4000, page::EntryBits::ReadWrite),
+		];
+		assert_eq!(overlapping_regions(&regions), [(0, 1)]);
+	}
+	#[test_case]
+	fn the_stack_guard_is_left_unmapped() {
+		// Paging has run by the time tests do
+		let root = unsafe { &*kmem::get_page_table() };
+		let guard = kernel_stack_guard();
+		assert!(page::virt_to_phys(root, page::VirtAddr(guard)).is_none());
+		assert!(page::virt_to_phys(root, page::VirtAddr(guard + page::PAGE_SIZE - 8)).is_none());
+		// The stack itself starts right above it
+		assert!(page::virt_to_phys(root, page::VirtAddr(guard + page::PAGE_SIZE)).is_some());
+	}
+}
@@ -0,0 +1,82 @@
+// Kernel command line
+// Boot options are space separated key=value pairs, like
+// "loglevel=debug quantum_ms=5". A key on its own is a flag and has an
+// empty value. Parsing works directly on the string, nothing is copied
+// or allocated, so it can run before the kernel heap exists.
+
+use crate::lock::Mutex;
+
+// Used when nothing else hands us a command line
+pub const DEFAULT_CMDLINE: &str = "";
+
+static CMDLINE: Mutex<&str> = Mutex::new(DEFAULT_CMDLINE);
+
+// Replace the command line the getters look at
+pub fn set_cmdline(cmdline: &'static str) {
+    *CMDLINE.lock() = cmdline;
+}
+
+pub fn cmdline() -> &'static str {
+    *CMDLINE.lock()
+}
+
+// Find the value of key in cmdline. If a key is given more than once
+// the last one wins, so options added at the end override earlier ones.
+pub fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline.split_ascii_whitespace()
+           .rev()
+           .map(|arg| arg.split_once('=').unwrap_or((arg, "")))
+           .find(|&(k, _)| k == key)
+           .map(|(_, v)| v)
+}
+
+pub fn get(key: &str) -> Option<&'static str> {
+    find(cmdline(), key)
+}
+
+// A numeric option, None if it is missing or isn't a number
+pub fn get_u64(key: &str) -> Option<u64> {
+    get(key)?.parse().ok()
+}
+
+// A yes/no option. A bare flag counts as yes.
+pub fn get_bool(key: &str) -> Option<bool> {
+    match get(key)? {
+        "" | "1" | "on" | "yes" | "true" => Some(true),
+        "0" | "off" | "no" | "false" => Some(false),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn find_takes_the_last_value() {
+        let cmdline = "loglevel=info quantum_ms=5  canary loglevel=debug";
+        assert_eq!(find(cmdline, "loglevel"), Some("debug"));
+        assert_eq!(find(cmdline, "quantum_ms"), Some("5"));
+        // A bare flag has an empty value
+        assert_eq!(find(cmdline, "canary"), Some(""));
+        assert_eq!(find(cmdline, "missing"), None);
+        // Keys match whole, not by prefix
+        assert_eq!(find(cmdline, "log"), None);
+        assert_eq!(find("", "loglevel"), None);
+    }
+
+    #[test_case]
+    fn get_reads_the_command_line() {
+        let old = cmdline();
+        set_cmdline("canary trace=on backtrace=0 quantum_ms=7 fault_limit=lots logical_time=maybe");
+        assert_eq!(get_bool("canary"), Some(true));
+        assert_eq!(get_bool("trace"), Some(true));
+        assert_eq!(get_bool("backtrace"), Some(false));
+        assert_eq!(get_bool("logical_time"), None);
+        assert_eq!(get_bool("missing"), None);
+        assert_eq!(get_u64("quantum_ms"), Some(7));
+        assert_eq!(get_u64("fault_limit"), None);
+        set_cmdline(old);
+        assert_eq!(cmdline(), old);
+    }
+}
@@ -0,0 +1,78 @@
+// Core local interruptor
+// The CLINT holds a software interrupt bit (MSIP) and a timer compare
+// register (MTIMECMP) for each hart, and the mtime counter they share.
+// Every CLINT access goes through here so that its layout, the SiFive
+// one QEMU's virt machine uses, is written down in one place.
+use crate::mmio::{Mmio, CLINT_BASE};
+
+const CLINT: Mmio = Mmio::new(CLINT_BASE);
+// Register offsets from the CLINT base address
+// A 32 bit MSIP register per hart, only bit 0 is used
+const CLINT_MSIP: usize = 0x0;
+// A 64 bit MTIMECMP register per hart
+const CLINT_MTIMECMP: usize = 0x4000;
+// The 64 bit mtime counter
+const CLINT_MTIME: usize = 0xbff8;
+
+pub const fn msip_offset(hart: usize) -> usize {
+    CLINT_MSIP + hart * 4
+}
+
+pub const fn mtimecmp_offset(hart: usize) -> usize {
+    CLINT_MTIMECMP + hart * 8
+}
+
+pub const MTIME_OFFSET: usize = CLINT_MTIME;
+
+// The layout from the SiFive CLINT documentation
+const _: () = assert!(CLINT_BASE + msip_offset(0) == 0x0200_0000);
+const _: () = assert!(CLINT_BASE + msip_offset(2) == 0x0200_0008);
+const _: () = assert!(CLINT_BASE + mtimecmp_offset(0) == 0x0200_4000);
+const _: () = assert!(CLINT_BASE + mtimecmp_offset(2) == 0x0200_4010);
+const _: () = assert!(CLINT_BASE + MTIME_OFFSET == 0x0200_bff8);
+
+// Raise the machine software interrupt of hart
+pub fn set_msip(hart: usize) {
+    CLINT.write32(msip_offset(hart), 1);
+}
+
+// Take the machine software interrupt of hart down again
+pub fn clear_msip(hart: usize) {
+    CLINT.write32(msip_offset(hart), 0);
+}
+
+// Read mtime, which counts up at cpu::TIMER_FREQ_HZ
+pub fn read_mtime() -> u64 {
+    CLINT.read64(MTIME_OFFSET)
+}
+
+// Make hart take a machine timer interrupt once mtime reaches val
+pub fn set_mtimecmp(hart: usize, val: u64) {
+    CLINT.write64(mtimecmp_offset(hart), val);
+}
+
+pub fn read_mtimecmp(hart: usize) -> u64 {
+    CLINT.read64(mtimecmp_offset(hart))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{mhartid_read, mip_read, MIP_MSIP, MIP_MTIP};
+
+    #[test_case]
+    fn registers_reach_this_harts_interrupts() {
+        // Interrupts are off while tests run, so these only show in mip
+        let hart = mhartid_read();
+        set_msip(hart);
+        assert_ne!(mip_read() & MIP_MSIP, 0);
+        clear_msip(hart);
+        assert_eq!(mip_read() & MIP_MSIP, 0);
+        let old = read_mtimecmp(hart);
+        set_mtimecmp(hart, read_mtime());
+        assert_ne!(mip_read() & MIP_MTIP, 0);
+        set_mtimecmp(hart, u64::MAX);
+        assert_eq!(mip_read() & MIP_MTIP, 0);
+        set_mtimecmp(hart, old);
+    }
+}
@@ -0,0 +1,312 @@
+// Console
+// print! and println! write to whichever console is active. It starts out
+// as the UART, and set() swaps in something else, like a framebuffer or a
+// log file, while the kernel runs.
+
+use core::fmt::Write;
+
+use crate::{cpu::spin_hint, lock::Mutex, mmio::UART_BASE, uart::Uart};
+
+// ANSI escape sequences start with ESC [
+const ESC: u8 = 0x1b;
+// Longest line read_line() keeps, including what history recalls
+pub const LINE_MAX: usize = 128;
+// Number of lines read_line() remembers for the arrow keys
+pub const HISTORY_LEN: usize = 8;
+
+pub trait Console: Write {
+    // Write one byte
+    fn putc(&mut self, c: u8);
+
+    // A byte of input if one has arrived. Consoles that only print have
+    // none.
+    fn getc(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn clear_screen(&mut self) {
+        // Clear everything and put the cursor in the top left corner
+        let _ = self.write_str("\x1b[2J\x1b[H");
+    }
+
+    // Rows and columns count from 1, like the terminal does
+    fn move_cursor(&mut self, row: usize, col: usize) {
+        let _ = write!(self, "\x1b[{};{}H", row, col);
+    }
+
+    // Clear the line the cursor is on and go back to its start
+    fn clear_line(&mut self) {
+        let _ = self.write_str("\r\x1b[2K");
+    }
+}
+
+impl Console for Uart {
+    fn putc(&mut self, c: u8) {
+        self.put(c);
+    }
+
+    fn getc(&mut self) -> Option<u8> {
+        self.get()
+    }
+}
+
+// The console set() swapped in, None while it is the UART
+static ACTIVE: Mutex<Option<&'static mut (dyn Console + Send)>> = Mutex::new(None);
+
+// Run f with the console print! writes to. The UART needs no heap and
+// keeps no state of its own, so printing works from the first line of
+// kinit. If the active console is in use, by another hart or by a print
+// that panicked halfway through, f gets the UART rather than waiting.
+pub fn with_active<R>(f: impl FnOnce(&mut dyn Console) -> R) -> R {
+    match ACTIVE.try_lock() {
+        Some(mut active) => match active.as_deref_mut() {
+            Some(console) => f(console),
+            None => f(&mut Uart::new(UART_BASE))
+        },
+        None => f(&mut Uart::new(UART_BASE))
+    }
+}
+
+// Send everything printed from now on to console. Returns the console that
+// was active before, None if it was the UART.
+pub fn set(console: &'static mut (dyn Console + Send)) -> Option<&'static mut (dyn Console + Send)> {
+    ACTIVE.lock().replace(console)
+}
+
+// Go back to printing on the UART
+pub fn reset() -> Option<&'static mut (dyn Console + Send)> {
+    ACTIVE.lock().take()
+}
+
+// Lines read_line() has returned, newest last, in a fixed ring so keeping
+// history never allocates
+struct History {
+    lines: [[u8; LINE_MAX]; HISTORY_LEN],
+    lens:  [usize; HISTORY_LEN],
+    // Slot the next line goes in
+    next:  usize,
+    count: usize
+}
+
+impl History {
+    const fn new() -> Self {
+        History { lines: [[0; LINE_MAX]; HISTORY_LEN], lens: [0; HISTORY_LEN], next: 0, count: 0 }
+    }
+
+    fn push(&mut self, line: &[u8]) {
+        if line.is_empty() {
+            return;
+        }
+        let len = line.len().min(LINE_MAX);
+        self.lines[self.next][..len].copy_from_slice(&line[..len]);
+        self.lens[self.next] = len;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = (self.count + 1).min(HISTORY_LEN);
+    }
+
+    // The line back entries before the newest, 1 being the newest itself
+    fn get(&self, back: usize) -> Option<&[u8]> {
+        if back == 0 || back > self.count {
+            return None;
+        }
+        let slot = (self.next + HISTORY_LEN - back) % HISTORY_LEN;
+        Some(&self.lines[slot][..self.lens[slot]])
+    }
+}
+
+static HISTORY: Mutex<History> = Mutex::new(History::new());
+
+// Where LineEditor is in an escape sequence
+#[derive(Clone, Copy, PartialEq)]
+enum EscState {
+    None,
+    // Seen ESC
+    Esc,
+    // Seen ESC [
+    Csi
+}
+
+// Turns console input one byte at a time into a line, echoing it back.
+// Backspace deletes, and the up and down arrows (ESC [ A and ESC [ B)
+// step through the history.
+pub struct LineEditor<'a> {
+    buf:     &'a mut [u8],
+    len:     usize,
+    esc:     EscState,
+    // How far back in the history the line came from, 0 for a new line
+    back:    usize
+}
+
+impl<'a> LineEditor<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        LineEditor { buf, len: 0, esc: EscState::None, back: 0 }
+    }
+
+    // The line so far
+    pub fn line(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    // Take one byte of input, echoing to out. Returns the length of the
+    // line once Enter is pressed.
+    pub fn feed(&mut self, c: u8, out: &mut dyn Console) -> Option<usize> {
+        match (self.esc, c) {
+            (EscState::None, ESC) => self.esc = EscState::Esc,
+            (EscState::Esc, b'[') => self.esc = EscState::Csi,
+            (EscState::Csi, b'A') => {
+                self.esc = EscState::None;
+                self.recall(self.back + 1, out);
+            },
+            (EscState::Csi, b'B') => {
+                self.esc = EscState::None;
+                self.recall(self.back.saturating_sub(1), out);
+            },
+            // Any other sequence is ignored
+            (EscState::Esc, _) | (EscState::Csi, _) => self.esc = EscState::None,
+            (EscState::None, b'\r') | (EscState::None, b'\n') => {
+                let _ = out.write_str("\r\n");
+                HISTORY.lock().push(&self.buf[..self.len]);
+                self.back = 0;
+                return Some(self.len);
+            },
+            // Backspace or delete
+            (EscState::None, 8) | (EscState::None, 127) => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    let _ = out.write_str("\x08 \x08");
+                }
+            },
+            (EscState::None, c) => {
+                if self.len < self.buf.len() {
+                    self.buf[self.len] = c;
+                    self.len += 1;
+                    out.putc(c);
+                }
+            }
+        }
+        None
+    }
+
+    // Replace the line with the history entry back steps ago, or an empty
+    // line for 0. Nothing happens if there is no such entry.
+    fn recall(&mut self, back: usize, out: &mut dyn Console) {
+        let history = HISTORY.lock();
+        let line = match back {
+            0 => &[][..],
+            _ => match history.get(back) {
+                Some(line) => line,
+                None => return
+            }
+        };
+        // Wipe what's on screen of the current line
+        for _ in 0..self.len {
+            let _ = out.write_str("\x08 \x08");
+        }
+        self.len = line.len().min(self.buf.len());
+        self.buf[..self.len].copy_from_slice(&line[..self.len]);
+        for &c in self.line() {
+            out.putc(c);
+        }
+        self.back = back;
+    }
+}
+
+// Read a line from the active console into buf and return its length.
+// This polls, so it is for the kernel's own use before there are
+// processes to switch to while waiting.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut editor = LineEditor::new(buf);
+    loop {
+        let done = with_active(|console| match console.getc() {
+            Some(c) => editor.feed(c, console),
+            None => {
+                spin_hint();
+                None
+            }
+        });
+        if let Some(len) = done {
+            return len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::*;
+
+    // Keeps what is written to it, and has no input
+    struct Sink(Vec<u8>);
+
+    impl Write for Sink {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0.extend_from_slice(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    impl Console for Sink {
+        fn putc(&mut self, c: u8) {
+            self.0.push(c);
+        }
+    }
+
+    fn feed_all(editor: &mut LineEditor, input: &[u8], out: &mut Sink) -> Option<usize> {
+        input.iter().fold(None, |_, &c| editor.feed(c, out))
+    }
+
+    #[test_case]
+    fn arrow_keys_recall_earlier_lines() {
+        let mut out = Sink(Vec::new());
+        let mut buf = [0; LINE_MAX];
+        for line in [&b"first\r"[..], b"second\r"] {
+            let mut editor = LineEditor::new(&mut buf);
+            assert_eq!(feed_all(&mut editor, line, &mut out), Some(line.len() - 1));
+        }
+        let mut editor = LineEditor::new(&mut buf);
+        assert_eq!(feed_all(&mut editor, b"typed", &mut out), None);
+        assert_eq!(feed_all(&mut editor, b"\x1b[A", &mut out), None);
+        assert_eq!(editor.line(), b"second");
+        feed_all(&mut editor, b"\x1b[A", &mut out);
+        assert_eq!(editor.line(), b"first");
+        feed_all(&mut editor, b"\x1b[B", &mut out);
+        assert_eq!(editor.line(), b"second");
+        // Down past the newest gives an empty line
+        feed_all(&mut editor, b"\x1b[B", &mut out);
+        assert_eq!(editor.line(), b"");
+        // Other sequences are swallowed
+        feed_all(&mut editor, b"\x1b[Cx\x7f", &mut out);
+        assert_eq!(editor.line(), b"");
+        feed_all(&mut editor, b"\x1b[A", &mut out);
+        assert_eq!(feed_all(&mut editor, b"\r", &mut out), Some(6));
+        assert_eq!(editor.line(), b"second");
+        assert!(out.0.ends_with(b"second\r\n"));
+    }
+
+    #[test_case]
+    fn history_keeps_the_newest_lines() {
+        let mut history = History::new();
+        history.push(b"");
+        assert_eq!(history.get(1), None);
+        for i in 0..HISTORY_LEN + 2 {
+            history.push(&[b'a' + i as u8]);
+        }
+        assert_eq!(history.get(0), None);
+        assert_eq!(history.get(1), Some(&[b'a' + HISTORY_LEN as u8 + 1][..]));
+        assert_eq!(history.get(HISTORY_LEN), Some(&b"c"[..]));
+        assert_eq!(history.get(HISTORY_LEN + 1), None);
+    }
+    #[test_case]
+    fn println_goes_to_the_console_set() {
+        let sink = Box::into_raw(Box::new(Sink(Vec::new())));
+        let before = set(unsafe { &mut *sink });
+        println!("routed {}", 7);
+        assert!(reset().is_some());
+        if let Some(console) = before {
+            set(console);
+        }
+        let sink = unsafe { Box::from_raw(sink) };
+        assert_eq!(sink.0, b"routed 7\r\n");
+    }
+}
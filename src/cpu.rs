@@ -1,9 +1,12 @@
 // CPU helper functions
 // and kernel trap frame
 
-use core::{arch::asm, ptr::null_mut};
+use core::{arch::asm, mem::{offset_of, size_of}, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+
+use crate::{clint, lock::Mutex};
 
 #[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum SatpMode {
     Off = 0,
     Sv39 = 8,
@@ -18,10 +21,39 @@ pub struct TrapFrame {
     pub regs: [usize; 32], // 32 general purpose registers of 8 bytes each = 0 - 255
     pub fregs: [usize; 32], // 32 floating point registers of 8 bytes each = 255 - 511
     pub satp: usize,        // SATP Register 512 - 519
-    pub trap_stack: *mut u8, // Trap stack to handle 520
-    pub hartid: usize      // The current hart id 528
+    pub hartid: usize      // The current hart id 520
+}
+
+// trap.S reaches into the trap frame at these offsets, which have to match
+// the struct above. main.rs hands them to trap.S, and reordering the
+// struct's fields fails the build here instead of having the assembly
+// read the wrong field.
+pub const FRAME_REGS: usize = 0;
+pub const FRAME_FREGS: usize = 256;
+pub const FRAME_SATP: usize = 512;
+pub const FRAME_HARTID: usize = 520;
+
+// Whether a struct with TrapFrame's fields has them at the FRAME_ offsets
+macro_rules! frame_layout_matches {
+    ($frame:ty) => {
+        offset_of!($frame, regs) == FRAME_REGS
+            && offset_of!($frame, fregs) == FRAME_FREGS
+            && offset_of!($frame, satp) == FRAME_SATP
+            && offset_of!($frame, hartid) == FRAME_HARTID
+            && size_of::<$frame>() == FRAME_HARTID + 8
+    };
 }
 
+const _: () = assert!(frame_layout_matches!(TrapFrame), "TrapFrame doesn't match the FRAME_ offsets");
+
+// ABI names of the general purpose registers, indexed by register number
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
 impl TrapFrame {
     // Zero out the trap frame
     pub const fn zero() -> Self {
@@ -29,16 +61,150 @@ impl TrapFrame {
             regs: [0; 32],
             fregs: [0; 32],
             satp: 0,
-            trap_stack: null_mut(),
             hartid: 0
         }
     }
+
+    // Print the general purpose registers, four per line
+    pub fn dump(&self) {
+        for row in 0..8 {
+            for col in 0..4 {
+                let i = row * 4 + col;
+                print!("{:>4} = 0x{:016x}  ", REG_NAMES[i], self.regs[i]);
+            }
+            println!();
+        }
+    }
+}
+
+// The registers run_saved in trap.S keeps for resume_saved, which are
+// the ones a call has to preserve: ra, sp and s0-s11, in that order
+#[repr(C)]
+pub struct SavedRegs {
+    pub ra: usize,
+    pub sp: usize,
+    pub s:  [usize; 12]
+}
+
+const _: () = assert!(offset_of!(SavedRegs, sp) == 8);
+const _: () = assert!(offset_of!(SavedRegs, s) == 16);
+const _: () = assert!(size_of::<SavedRegs>() == 112);
+
+impl SavedRegs {
+    pub const fn zero() -> Self {
+        SavedRegs { ra: 0, sp: 0, s: [0; 12] }
+    }
 }
 
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::zero(); 8];
+// Most harts the kernel keeps per-hart state for
+pub const MAX_HARTS: usize = 8;
+// Bytes of the kernel stack each hart's traps run on. The top
+// MAX_HARTS * TRAP_STACK_SIZE of the stack are the trap stacks, hart n's
+// ending n * TRAP_STACK_SIZE below the top, see m_trap_vector. kinit and
+// kmain run below them, so a trap never lands on their frames.
+pub const TRAP_STACK_SIZE: usize = 0x8000;
+
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+
+// Rust versions of the register save/restore done by m_trap_vector and
+// switch_to_user in trap.S. The assembly stays the hot path, these exist so
+// the frame layout (regs[i] at offset i * 8) can be checked from Rust.
+// Like trap.S, only the general purpose registers are handled. The FPU is
+// left off (mstatus.FS = 0), so touching fregs would trap.
+
+// Store x1 - x31 into frame.regs
+// t6 holds the frame address, so regs[31] ends up as the frame address
+// rather than the caller's t6 (trap.S recovers it from mscratch instead).
+pub fn save_frame(frame: &mut TrapFrame) {
+    unsafe {
+        asm!(
+            ".irp i, 1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+            "sd x\\i, ((\\i)*8)(t6)",
+            ".endr",
+            in("t6") frame as *mut TrapFrame
+        );
+    }
+}
+
+// Load registers back from frame.regs
+// sp, gp, tp, s0 (fp) and s1 belong to the compiler while Rust code is
+// running, so unlike switch_to_user these are not restored. Everything else
+// is loaded, with t6 last since it holds the frame address.
+pub fn restore_frame(frame: &TrapFrame) {
+    unsafe {
+        asm!(
+            ".irp i, 1,5,6,7,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30",
+            "ld x\\i, ((\\i)*8)(t6)",
+            ".endr",
+            "ld x31, (31*8)(t6)",
+            inout("t6") frame as *const TrapFrame => _,
+            out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+            out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+            clobber_abi("C")
+        );
+    }
+}
 
+// The root table address goes into satp as a 44-bit PPN, so it has to be
+// page aligned.
 pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
-    (mode as usize) << 60 | (asid & 0xffff) << 44 | (addr >> 12) & 0xff_ffff_ffff
+    debug_assert!(addr & 0xfff == 0, "satp root table is not page aligned");
+    (mode as usize) << 60 | (asid & 0xffff) << 44 | (addr >> 12) & 0xfff_ffff_ffff
+}
+
+// Split a satp value into its mode, ASID and root table PPN.
+// A write of a mode the hart doesn't support leaves satp unchanged, so
+// satp only ever holds a mode we asked for.
+pub const fn decode_satp(val: usize) -> (SatpMode, u16, usize) {
+    let mode = match val >> 60 {
+        8 => SatpMode::Sv39,
+        9 => SatpMode::Sv48,
+        _ => SatpMode::Off
+    };
+    (mode, ((val >> 44) & 0xffff) as u16, val & 0xfff_ffff_ffff)
+}
+
+pub const fn satp_mode(val: usize) -> SatpMode {
+    decode_satp(val).0
+}
+
+pub const fn satp_asid(val: usize) -> u16 {
+    decode_satp(val).1
+}
+
+// The PPN of the root table, shift it left by 12 for its address
+pub const fn satp_ppn(val: usize) -> usize {
+    decode_satp(val).2
+}
+
+// Print the address space that is active right now
+pub fn print_satp() {
+    let satp = satp_read();
+    let (mode, asid, ppn) = decode_satp(satp);
+    println!("satp = 0x{:016x}: mode {:?}, ASID {}, root table 0x{:x} (PPN 0x{:x})",
+             satp, mode, asid, ppn << 12, ppn);
+}
+
+// The hart we're running on, from machine or supervisor mode. mhartid
+// can only be read in machine mode, so this goes through the kernel trap
+// frame that sscratch points at, which records it. Until that is set up
+// only the boot hart runs, and that is hart 0.
+pub fn hart_id() -> usize {
+    let frame = sscratch_read() as *const TrapFrame;
+    if frame.is_null() {
+        0
+    } else {
+        unsafe { (*frame).hartid }
+    }
+}
+
+// The frame pointer (s0) of the caller. Inlined so that it is the
+// caller's frame and not one of its own.
+#[inline(always)]
+pub fn fp_read() -> usize {
+    let fp;
+    unsafe { asm!("mv {}, s0", out(reg) fp); }
+    fp
 }
 
 pub fn mhartid_read() -> usize {
@@ -63,6 +229,151 @@ pub fn mstatus_read() -> usize {
 	}
 }
 
+pub fn mepc_write(val: usize) {
+	unsafe {
+		asm!("csrw	mepc, {}", in(reg) val);
+	}
+}
+
+pub fn mepc_read() -> usize {
+	unsafe {
+		let mepc;
+		asm!("csrr	{}, mepc", out(reg) mepc);
+		mepc
+	}
+}
+
+pub fn mie_write(val: usize) {
+	unsafe {
+		asm!("csrw	mie, {}", in(reg) val);
+	}
+}
+
+pub fn mie_read() -> usize {
+	unsafe {
+		let mie;
+		asm!("csrr	{}, mie", out(reg) mie);
+		mie
+	}
+}
+
+// mip bit 7, set while a machine timer interrupt is pending
+pub const MIP_MTIP: usize = 1 << 7;
+// mip bits 3 and 11, machine software and external interrupts pending
+pub const MIP_MSIP: usize = 1 << 3;
+pub const MIP_MEIP: usize = 1 << 11;
+
+pub fn mip_read() -> usize {
+	unsafe {
+		let mip;
+		asm!("csrr	{}, mip", out(reg) mip);
+		mip
+	}
+}
+
+pub fn mtvec_write(val: usize) {
+	unsafe {
+		asm!("csrw	mtvec, {}", in(reg) val);
+	}
+}
+
+// mstatus.MPP, the mode mret returns to
+pub const MSTATUS_MPP_MASK: usize = 0b11 << 11;
+pub const MSTATUS_MPP_SUPERVISOR: usize = 0b01 << 11;
+// mstatus.MIE, whether machine mode takes interrupts
+pub const MSTATUS_MIE: usize = 1 << 3;
+// mstatus.MPIE and SPIE become MIE and SIE on mret
+pub const MSTATUS_MPIE: usize = 1 << 7;
+pub const MSTATUS_SPIE: usize = 1 << 5;
+// Interrupts the kernel takes once it is in supervisor mode: supervisor
+// software (SSIE), timer (STIE) and external (SEIE), and machine timer
+// (MTIE) and external (MEIE). The machine timer is what starts the first
+// process once kmain is done.
+pub const MIE_KERNEL: usize = (1 << 1) | (1 << 5) | (1 << 7) | (1 << 9) | (1 << 11);
+
+extern "C" {
+	fn m_trap_vector();
+}
+
+// Where a supervisor entry point goes if it ever returns. Interrupts still
+// come in, so a hart parked here is woken by the timer.
+pub extern "C" fn park() -> ! {
+	loop {
+		wait_for_interrupt();
+	}
+}
+
+// mtime ticks every hart has spent with nothing to do
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+// When each hart went idle, 0 while it is busy
+static IDLE_SINCE: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+
+// wfi, counting the time until the hart wakes as idle. wfi returns for
+// any interrupt, or for nothing at all, so callers loop around it.
+pub fn wait_for_interrupt() {
+	// hart_id() works in supervisor mode, where mhartid can't be read
+	let hart = hart_id() % MAX_HARTS;
+	IDLE_SINCE[hart].store(mtime_read().max(1), Ordering::Relaxed);
+	unsafe { asm!("wfi") };
+	idle_end(hart);
+}
+
+// Stop counting hart as idle. The trap handler calls this too, since an
+// interrupt taken in wfi may switch to a process and never come back to
+// the wfi to count the time there.
+pub fn idle_end(hart: usize) {
+	let since = IDLE_SINCE[hart % MAX_HARTS].swap(0, Ordering::Relaxed);
+	if since != 0 {
+		add_idle(since, mtime_read());
+	}
+}
+
+// Count before..after, two mtime readings, as idle
+pub fn add_idle(before: u64, after: u64) {
+	IDLE_TICKS.fetch_add(after.saturating_sub(before), Ordering::Relaxed);
+}
+
+// mtime ticks spent idle since boot, on all harts together
+pub fn idle_time() -> u64 {
+	IDLE_TICKS.load(Ordering::Relaxed)
+}
+
+// Percentage of uptime spent busy. mtime counts from 0 at reset, so it is
+// the uptime. With several harts this is the average over them.
+pub fn utilization(harts: u64) -> u64 {
+	let uptime = mtime_read().max(1) * harts.max(1);
+	100 - (idle_time().min(uptime) * 100 / uptime)
+}
+
+// Leave machine mode and start running entry in supervisor mode on stack.
+// Machine mode traps still go to m_trap_vector, and mscratch has to point
+// at this hart's trap frame already. satp takes effect once we're in
+// supervisor mode, so it should be set before this too.
+pub fn enter_supervisor(entry: usize, stack: usize) -> ! {
+	// Let supervisor and user mode reach all of physical memory, the
+	// page tables decide what they can actually touch
+	unsafe {
+		asm!("csrw	pmpcfg0, {}", "csrw	pmpaddr0, {}", in(reg) 0x1f, in(reg) usize::MAX);
+	}
+	// mret drops to MPP with MIE = MPIE, so interrupts come on as we leave
+	mstatus_write(MSTATUS_MPP_SUPERVISOR | MSTATUS_MPIE | MSTATUS_SPIE);
+	mtvec_write(m_trap_vector as *const () as usize);
+	mepc_write(entry);
+	mie_write(MIE_KERNEL);
+	debug_assert_eq!(mstatus_read() & MSTATUS_MPP_MASK, MSTATUS_MPP_SUPERVISOR);
+	debug_assert_eq!(mepc_read(), entry);
+	debug_assert_eq!(mie_read() & MIE_KERNEL, MIE_KERNEL);
+	debug_assert!(mscratch_read() != 0, "mscratch has no trap frame");
+	unsafe {
+		asm!("mv	sp, {stack}",
+		     "mv	ra, {ret}",
+		     "mret",
+		     stack = in(reg) stack,
+		     ret = in(reg) park as *const () as usize,
+		     options(noreturn));
+	}
+}
+
 pub fn stvec_write(val: usize) {
 	unsafe {
 		asm!("csrw	stvec, {}", in(reg) val);
@@ -141,6 +452,28 @@ pub fn satp_write(val: usize) {
 	}
 }
 
+// Make the instruction fetches of this hart see what it has stored, for
+// code that is copied or written at run time
+pub fn fence_i() {
+	unsafe { asm!("fence.i", options(nostack)) };
+}
+
+// Switch to the page table in val and drop every translation the TLB
+// holds. The write and the fence are one asm block so nothing, the
+// compiler's own loads and stores included, can land between them and
+// go through a stale translation. rs2 is zero rather than a register
+// holding the ASID: sfence.vma with a register operand only fences that
+// one ASID and leaves global mappings alone, while x0 fences them all.
+// Whatever the firmware left in the TLB, under any ASID, is gone after.
+pub fn satp_write_fence(val: usize) {
+	unsafe {
+		asm!("csrw satp, {0}",
+		     "sfence.vma zero, zero",
+		     in(reg) val,
+		     options(nostack));
+	}
+}
+
 pub fn satp_read() -> usize {
 	unsafe {
 		let satp;
@@ -159,4 +492,461 @@ pub fn satp_fence_asid(asid: usize) {
 	unsafe {
 		asm!("sfence.vma zero, {}", in(reg) asid);
 	}
-}
\ No newline at end of file
+}
+
+// Translations another hart has to drop from its TLB. A hart that
+// changes a page table other harts may be using queues (vaddr, asid) in
+// each of their mailboxes and raises their software interrupt, and they
+// fence from the interrupt handler. Every shootdown queued gets the next
+// generation number of the mailbox, and once the hart has fenced it
+// stores the generation it got to in SHOOTDOWNS_DONE.
+pub const SHOOTDOWN_SLOTS: usize = 8;
+
+pub struct Shootdowns {
+    pending:  [(usize, usize); SHOOTDOWN_SLOTS],
+    len:      usize,
+    // More were queued than fit, so the whole TLB has to go
+    overflow: bool,
+    // Generation of the last shootdown queued
+    queued:   usize
+}
+
+impl Shootdowns {
+    const fn new() -> Self {
+        Shootdowns { pending: [(0, 0); SHOOTDOWN_SLOTS], len: 0, overflow: false, queued: 0 }
+    }
+
+    // Queue a shootdown and return its generation
+    fn push(&mut self, vaddr: usize, asid: usize) -> usize {
+        if self.len == SHOOTDOWN_SLOTS {
+            self.overflow = true;
+        } else {
+            self.pending[self.len] = (vaddr, asid);
+            self.len += 1;
+        }
+        self.queued = self.queued.wrapping_add(1);
+        self.queued
+    }
+
+    // Fence everything queued and empty the mailbox
+    fn flush(&mut self) -> usize {
+        let fenced = self.len;
+        if self.overflow {
+            unsafe { asm!("sfence.vma zero, zero") };
+        } else {
+            for &(vaddr, asid) in self.pending[..self.len].iter() {
+                satp_fence(vaddr, asid);
+            }
+        }
+        self.len = 0;
+        self.overflow = false;
+        fenced
+    }
+
+    // Number of shootdowns waiting, and whether some didn't fit
+    pub fn pending(&self) -> (usize, bool) {
+        (self.len, self.overflow)
+    }
+}
+
+static SHOOTDOWNS: [Mutex<Shootdowns>; MAX_HARTS] = [const { Mutex::new(Shootdowns::new()) }; MAX_HARTS];
+// The generation each hart has fenced up to
+static SHOOTDOWNS_DONE: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// Bit n is set while hart n is running the kernel. Only the boot hart is
+// started so far.
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn set_hart_online(hart: usize, online: bool) {
+    if online {
+        ONLINE_HARTS.fetch_or(1 << hart, Ordering::AcqRel);
+    } else {
+        ONLINE_HARTS.fetch_and(!(1 << hart), Ordering::AcqRel);
+    }
+}
+
+// Drop the translation of vaddr in asid from every hart's TLB. Use this
+// instead of satp_fence() after unmapping a page or changing its
+// permissions in a table that may be live. This hart fences right away,
+// the others do it when they take the software interrupt. It only
+// returns once every other online hart has fenced, so the page can be
+// freed straight after without another hart still reaching it through a
+// stale translation.
+pub fn tlb_shootdown(vaddr: usize, asid: usize) {
+    satp_fence(vaddr, asid);
+    let me = hart_id();
+    let online = ONLINE_HARTS.load(Ordering::Acquire);
+    let mut waiting = [None; MAX_HARTS];
+    for hart in (0..MAX_HARTS).filter(|&h| h != me && online & (1 << h) != 0) {
+        waiting[hart] = Some(queue_shootdown(hart, vaddr, asid));
+        clint::set_msip(hart);
+    }
+    for (hart, generation) in waiting.iter().enumerate() {
+        let Some(generation) = *generation else {
+            continue;
+        };
+        while !shootdown_done(hart, generation) && ONLINE_HARTS.load(Ordering::Acquire) & (1 << hart) != 0 {
+            // The other hart may be in here too, waiting on us with
+            // interrupts off
+            if pending_shootdowns(me) != (0, false) {
+                service_shootdowns(me);
+            }
+            spin_hint();
+        }
+    }
+}
+
+// Put a shootdown in hart's mailbox without interrupting it. Returns its
+// generation, see shootdown_done().
+pub fn queue_shootdown(hart: usize, vaddr: usize, asid: usize) -> usize {
+    SHOOTDOWNS[hart].lock().push(vaddr, asid)
+}
+
+// Whether hart has fenced the shootdown queue_shootdown() gave generation
+pub fn shootdown_done(hart: usize, generation: usize) -> bool {
+    // Generations wrap, so compare by distance
+    (SHOOTDOWNS_DONE[hart].load(Ordering::Acquire).wrapping_sub(generation) as isize) >= 0
+}
+
+// What is waiting in hart's mailbox, see Shootdowns::pending()
+pub fn pending_shootdowns(hart: usize) -> (usize, bool) {
+    SHOOTDOWNS[hart].lock().pending()
+}
+
+// Called by hart from its software interrupt handler. Clears the
+// interrupt and fences what was queued. Returns how many were queued.
+pub fn service_shootdowns(hart: usize) -> usize {
+    clint::clear_msip(hart);
+    let mut mailbox = SHOOTDOWNS[hart].lock();
+    let fenced = mailbox.flush();
+    SHOOTDOWNS_DONE[hart].store(mailbox.queued, Ordering::Release);
+    fenced
+}
+
+// Tell the hart we're busy-waiting, so it can save power or let another
+// hardware thread run. This is Zihintpause's pause, which is encoded as a
+// FENCE that orders nothing, so harts without the extension treat it as a
+// no-op and it is safe to use everywhere.
+#[inline(always)]
+pub fn spin_hint() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!(".4byte 0x0100000f", options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    core::hint::spin_loop();
+}
+
+// Frequency of the CLINT's mtime counter on QEMU's virt machine
+pub const TIMER_FREQ_HZ: u64 = 10_000_000;
+
+// Read the CLINT's mtime register, which counts up at TIMER_FREQ_HZ
+pub fn mtime_read() -> u64 {
+    clint::read_mtime()
+}
+
+// Number of mtime ticks in ms milliseconds, u64::MAX if that many don't
+// fit. Whole seconds are converted first so that nothing overflows on
+// the way to a result that does fit.
+pub const fn ms_to_ticks(ms: u64) -> u64 {
+    (ms / 1000).saturating_mul(TIMER_FREQ_HZ).saturating_add(ms % 1000 * TIMER_FREQ_HZ / 1000)
+}
+
+// Set hart's timer interrupt to fire ms milliseconds from now and return
+// the mtime value it will fire at. Each hart has its own mtimecmp, so this
+// is called with the hart the timer is for, normally the calling one.
+pub fn schedule_timer_in(hart: usize, ms: u64) -> u64 {
+    let deadline = mtime_read().saturating_add(ms_to_ticks(ms));
+    clint::set_mtimecmp(hart, deadline);
+    deadline
+}
+
+// A raw instruction word split into its base (32-bit) encoding fields.
+// Which fields mean something depends on the format of the opcode, they
+// are all extracted so a crash report can show whatever applies.
+#[derive(Clone, Copy)]
+pub struct Instruction(pub u32);
+
+impl Instruction {
+    // Compressed instructions are 16 bits and never have 0b11 in the
+    // lowest two bits. Only the low half of the word is meaningful then.
+    pub fn is_compressed(&self) -> bool {
+        self.0 & 0b11 != 0b11
+    }
+
+    pub fn opcode(&self) -> u32 {
+        self.0 & 0x7f
+    }
+
+    pub fn rd(&self) -> u32 {
+        (self.0 >> 7) & 0x1f
+    }
+
+    pub fn funct3(&self) -> u32 {
+        (self.0 >> 12) & 0x7
+    }
+
+    pub fn rs1(&self) -> u32 {
+        (self.0 >> 15) & 0x1f
+    }
+
+    pub fn rs2(&self) -> u32 {
+        (self.0 >> 20) & 0x1f
+    }
+
+    pub fn funct7(&self) -> u32 {
+        self.0 >> 25
+    }
+
+    // Bytes the instruction takes up, what to add to the pc to skip it
+    pub fn size(&self) -> usize {
+        if self.is_compressed() { 2 } else { 4 }
+    }
+
+    // The integer load or store this is, if it is one. Floating point
+    // loads and stores aren't included.
+    pub fn mem_access(&self) -> Option<MemAccess> {
+        let load = |width, signed, reg| Some(MemAccess { store: false, width, signed, reg: reg as usize });
+        let store = |width, reg| Some(MemAccess { store: true, width, signed: false, reg: reg as usize });
+        if self.is_compressed() {
+            let half = self.0 & 0xffff;
+            // The 3-bit register fields of the compressed formats name x8-x15
+            let reg_low = ((half >> 2) & 0x7) + 8;
+            return match (half & 0b11, half >> 13) {
+                // c.lw, c.ld, c.sw, c.sd
+                (0b00, 0b010) => load(4, true, reg_low),
+                (0b00, 0b011) => load(8, false, reg_low),
+                (0b00, 0b110) => store(4, reg_low),
+                (0b00, 0b111) => store(8, reg_low),
+                // c.lwsp, c.ldsp, c.swsp, c.sdsp
+                (0b10, 0b010) => load(4, true, (half >> 7) & 0x1f),
+                (0b10, 0b011) => load(8, false, (half >> 7) & 0x1f),
+                (0b10, 0b110) => store(4, (half >> 2) & 0x1f),
+                (0b10, 0b111) => store(8, (half >> 2) & 0x1f),
+                _ => None
+            };
+        }
+        match (self.opcode(), self.funct3()) {
+            // lb, lh, lw, ld, lbu, lhu, lwu
+            (0b000_0011, f3 @ 0..=6) => load(1 << (f3 & 0b11), f3 < 4 && f3 != 3, self.rd()),
+            // sb, sh, sw, sd
+            (0b010_0011, f3 @ 0..=3) => store(1 << f3, self.rs2()),
+            _ => None
+        }
+    }
+}
+
+// A load or store decoded by Instruction::mem_access
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemAccess {
+    pub store:  bool,
+    // Bytes accessed, 1, 2, 4 or 8
+    pub width:  usize,
+    // Loads only, whether the value is sign extended to 64 bits
+    pub signed: bool,
+    // The register loaded into (rd) or stored from (rs2)
+    pub reg:    usize
+}
+
+impl core::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if self.is_compressed() {
+            write!(f, "0x{:04x} (compressed, op={:#04b} funct3={:#05b})",
+                   self.0 & 0xffff, self.0 & 0b11, (self.0 >> 13) & 0x7)
+        } else {
+            write!(f, "0x{:08x} (opcode={:#09b} rd={} funct3={:#05b} rs1={} rs2={} funct7={:#09b})",
+                   self.0, self.opcode(), self.rd(), self.funct3(), self.rs1(), self.rs2(), self.funct7())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PAGE_SIZE;
+
+    // TrapFrame with regs and fregs the wrong way round
+    #[repr(C)]
+    struct SwappedFrame {
+        fregs:      [usize; 32],
+        regs:       [usize; 32],
+        satp:       usize,
+        hartid:     usize
+    }
+
+    #[test_case]
+    fn frame_layout_is_checked() {
+        assert!(frame_layout_matches!(TrapFrame));
+        assert!(!frame_layout_matches!(SwappedFrame));
+    }
+
+    // A value no register would hold by chance
+    const fn sentinel(reg: usize) -> usize {
+        0x5e47_0000_0000 | reg
+    }
+
+    #[test_case]
+    fn save_then_restore_keeps_registers() {
+        let mut frame = TrapFrame::zero();
+        let addr = &mut frame as *mut TrapFrame;
+        let (t0, t1, t2, a1, a2, a3, a4, a5, a6, a7, t3, t4, t5): (usize, usize, usize, usize, usize, usize,
+                                                                   usize, usize, usize, usize, usize, usize,
+                                                                   usize);
+        // Both are called straight from the assembly, so nothing the
+        // compiler does between them touches the registers. s2 keeps the
+        // frame address across save_frame, the s registers are the
+        // callee's to preserve.
+        unsafe {
+            asm!(
+                "call {save}",
+                "mv a0, s2",
+                "call {restore}",
+                save = sym save_frame,
+                restore = sym restore_frame,
+                inout("a0") addr => _,
+                in("s2") addr,
+                inout("t0") sentinel(5) => t0, inout("t1") sentinel(6) => t1, inout("t2") sentinel(7) => t2,
+                inout("a1") sentinel(11) => a1, inout("a2") sentinel(12) => a2, inout("a3") sentinel(13) => a3,
+                inout("a4") sentinel(14) => a4, inout("a5") sentinel(15) => a5, inout("a6") sentinel(16) => a6,
+                inout("a7") sentinel(17) => a7,
+                inout("t3") sentinel(28) => t3, inout("t4") sentinel(29) => t4, inout("t5") sentinel(30) => t5,
+                in("s3") sentinel(19), in("s4") sentinel(20), in("s5") sentinel(21), in("s6") sentinel(22),
+                in("s7") sentinel(23), in("s8") sentinel(24), in("s9") sentinel(25), in("s10") sentinel(26),
+                in("s11") sentinel(27),
+                clobber_abi("C")
+            );
+        }
+        // Saved where trap.S expects them
+        for reg in (5..=7).chain(11..=17).chain(19..=27).chain(28..=30) {
+            assert_eq!(frame.regs[reg], sentinel(reg), "x{} saved wrong", reg);
+        }
+        assert_eq!(frame.regs[10], addr as usize);
+        assert_eq!(frame.regs[18], addr as usize);
+        // And loaded back
+        assert_eq!([t0, t1, t2], [sentinel(5), sentinel(6), sentinel(7)]);
+        assert_eq!([a1, a2, a3, a4, a5, a6, a7], [sentinel(11), sentinel(12), sentinel(13), sentinel(14),
+                                                  sentinel(15), sentinel(16), sentinel(17)]);
+        assert_eq!([t3, t4, t5], [sentinel(28), sentinel(29), sentinel(30)]);
+    }
+
+    #[test_case]
+    fn decode_satp_round_trips() {
+        let addr = 0x8020_3000;
+        assert_eq!(decode_satp(build_satp(SatpMode::Sv39, 7, addr)), (SatpMode::Sv39, 7, addr >> 12));
+        assert_eq!(decode_satp(build_satp(SatpMode::Sv48, 0xffff, addr)), (SatpMode::Sv48, 0xffff, addr >> 12));
+        assert_eq!(decode_satp(0), (SatpMode::Off, 0, 0));
+    }
+
+    #[test_case]
+    fn build_satp_keeps_all_44_ppn_bits() {
+        // Above the 40 bits the PPN mask used to keep
+        for addr in [1 << 55, (1 << 56) - PAGE_SIZE] {
+            let satp = build_satp(SatpMode::Sv39, 7, addr);
+            assert_eq!(satp_mode(satp), SatpMode::Sv39);
+            assert_eq!(satp_asid(satp), 7);
+            assert_eq!(satp_ppn(satp) << 12, addr);
+        }
+    }
+
+    #[test_case]
+    fn mem_access_decodes_loads_and_stores() {
+        // lw a0, 0(a1)
+        assert_eq!(Instruction(0x0005_a503).mem_access(), Some(MemAccess { store: false, width: 4, signed: true, reg: 10 }));
+        // lbu a0, 0(a1)
+        assert_eq!(Instruction(0x0005_c503).mem_access(), Some(MemAccess { store: false, width: 1, signed: false, reg: 10 }));
+        // ld a0, 0(a1)
+        assert_eq!(Instruction(0x0005_b503).mem_access(), Some(MemAccess { store: false, width: 8, signed: false, reg: 10 }));
+        // sd a0, 8(sp)
+        assert_eq!(Instruction(0x00a1_3423).mem_access(), Some(MemAccess { store: true, width: 8, signed: false, reg: 10 }));
+        // c.lw a0, 0(a1)
+        assert_eq!(Instruction(0x4188).mem_access(), Some(MemAccess { store: false, width: 4, signed: true, reg: 10 }));
+        // add a0, a0, a1
+        assert_eq!(Instruction(0x00b5_0533).mem_access(), None);
+    }
+
+    #[test_case]
+    fn add_idle_ignores_time_going_backwards() {
+        let before = idle_time();
+        add_idle(10, 25);
+        assert_eq!(idle_time(), before + 15);
+        add_idle(25, 10);
+        assert_eq!(idle_time(), before + 15);
+    }
+
+    #[test_case]
+    fn ms_to_ticks_saturates() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ms_to_ticks(1), TIMER_FREQ_HZ / 1000);
+        assert_eq!(ms_to_ticks(1500), TIMER_FREQ_HZ * 3 / 2);
+        assert_eq!(ms_to_ticks(u64::MAX), u64::MAX);
+        // The largest ms that still fits comes out exact
+        let ms = u64::MAX / TIMER_FREQ_HZ * 1000;
+        assert_eq!(ms_to_ticks(ms), ms / 1000 * TIMER_FREQ_HZ);
+    }
+
+    #[test_case]
+    fn timer_is_set_ms_from_now() {
+        let hart = mhartid_read();
+        let old = clint::read_mtimecmp(hart);
+        let before = mtime_read();
+        let deadline = schedule_timer_in(hart, 10);
+        let after = mtime_read();
+        // 10 MHz, so 10 ms is 100_000 ticks
+        assert!((before + 100_000..=after + 100_000).contains(&deadline));
+        assert_eq!(clint::read_mtimecmp(hart), deadline);
+        clint::set_mtimecmp(hart, old);
+    }
+
+    // The 32 bits of code at addr, which is only 2-byte aligned when
+    // compressed instructions come before it
+    fn code_word(addr: usize) -> u32 {
+        unsafe { (addr as *const u16).read_volatile() as u32
+                 | (((addr + 2) as *const u16).read_volatile() as u32) << 16 }
+    }
+
+    // Whether the code at f has a pause within its first 256 bytes.
+    // Instructions may be compressed, so look at every halfword. The
+    // functions checked are much shorter than that, only the function
+    // after one could make this pass when it shouldn't.
+    fn emits_pause(f: usize) -> bool {
+        (0..256).step_by(2).any(|off| code_word(f + off) == 0x0100_000f)
+    }
+
+    #[test_case]
+    fn spin_loops_pause() {
+        assert!(emits_pause(crate::lock::Mutex::<u8>::lock as *const () as usize));
+        assert!(emits_pause(crate::scheduler::wait_until as *const () as usize));
+        // Nothing spins in here
+        assert!(!emits_pause(ms_to_ticks as *const () as usize));
+    }
+
+    #[test_case]
+    fn satp_write_is_followed_by_the_fence() {
+        // csrw satp from any register, then sfence.vma zero, zero right
+        // after it with nothing in between
+        let f = satp_write_fence as *const () as usize;
+        assert!((0..256).step_by(2).any(|off| {
+            code_word(f + off) & 0xfff0_7fff == 0x1800_1073 && code_word(f + off + 4) == 0x1200_0073
+        }));
+    }
+
+    #[test_case]
+    fn shootdowns_are_done_once_serviced() {
+        // A hart that isn't running, so nothing services it but us
+        let hart = MAX_HARTS - 1;
+        let first = queue_shootdown(hart, 0x1000, 1);
+        let second = queue_shootdown(hart, 0x2000, 1);
+        assert_eq!(second, first.wrapping_add(1));
+        assert!(!shootdown_done(hart, first));
+        assert_eq!(service_shootdowns(hart), 2);
+        assert!(shootdown_done(hart, first) && shootdown_done(hart, second));
+        assert_eq!(pending_shootdowns(hart), (0, false));
+        // Generations compare across the wrap
+        let done = SHOOTDOWNS_DONE[hart].swap(usize::MAX, Ordering::Relaxed);
+        assert!(shootdown_done(hart, usize::MAX - 1));
+        assert!(!shootdown_done(hart, 0));
+        SHOOTDOWNS_DONE[hart].store(done, Ordering::Relaxed);
+        // No other hart is online, so there is nobody to wait for
+        tlb_shootdown(0x1000, 1);
+    }
+}
@@ -0,0 +1,160 @@
+// ELF loader support
+// Parses the parts of a 64-bit little-endian RISC-V ELF executable that are
+// needed to load it: the file header and the PT_LOAD program headers. The
+// segments are mapped into a process by process::add_process_elf.
+
+use crate::process::ProcError;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+// The 64-bit file header and program header sizes
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+// Segment permission bits in p_flags
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug)]
+pub enum ElfError {
+    // The file doesn't start with the ELF magic number
+    NotElf,
+    // It's an ELF file, but not a 64-bit little-endian one
+    BadClass,
+    // It's for another machine than RISC-V
+    NotRiscv,
+    // It's not an executable, such as an object file or a shared library
+    NotExecutable,
+    // A header or segment points past the end of the file
+    Truncated,
+    // A segment is bigger in the file than in memory, lies outside of
+    // where user programs are loaded, or shares a page with another one
+    // that has different permissions
+    BadSegment,
+    // There wasn't memory for the segments or the process
+    OutOfMemory,
+    // The process couldn't be added to the process list
+    Process(ProcError)
+}
+
+pub struct Elf<'a> {
+    data:      &'a [u8],
+    entry:     usize,
+    phoff:     usize,
+    phentsize: usize,
+    phnum:     usize
+}
+
+// A PT_LOAD segment: memsz bytes at vaddr, the first filesz of them copied
+// from offset in the file and the rest zeroed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub vaddr:  usize,
+    pub offset: usize,
+    pub filesz: usize,
+    pub memsz:  usize,
+    pub flags:  u32
+}
+
+impl Segment {
+    // The bytes of the file that go at the start of the segment
+    pub fn file_bytes<'a>(&self, elf: &Elf<'a>) -> &'a [u8] {
+        // parse() checked that these are in the file
+        &elf.data[self.offset..self.offset + self.filesz]
+    }
+}
+
+impl<'a> Elf<'a> {
+    // Check the header and every program header of data
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(if data.starts_with(&ELF_MAGIC) { ElfError::Truncated } else { ElfError::NotElf });
+        }
+        if data[..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(ElfError::BadClass);
+        }
+        if le16(data, 18) != Some(EM_RISCV) {
+            return Err(ElfError::NotRiscv);
+        }
+        if le16(data, 16) != Some(ET_EXEC) {
+            return Err(ElfError::NotExecutable);
+        }
+        let elf = Elf {
+            data,
+            entry:     le64(data, 24).ok_or(ElfError::Truncated)? as usize,
+            phoff:     le64(data, 32).ok_or(ElfError::Truncated)? as usize,
+            phentsize: le16(data, 54).ok_or(ElfError::Truncated)? as usize,
+            phnum:     le16(data, 56).ok_or(ElfError::Truncated)? as usize
+        };
+        if elf.phnum > 0 && elf.phentsize < PHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        for i in 0..elf.phnum {
+            if let Some(seg) = elf.program_header(i)? {
+                let end = seg.offset.checked_add(seg.filesz).ok_or(ElfError::Truncated)?;
+                if end > data.len() {
+                    return Err(ElfError::Truncated);
+                }
+                if seg.filesz > seg.memsz || seg.vaddr.checked_add(seg.memsz).is_none() {
+                    return Err(ElfError::BadSegment);
+                }
+            }
+        }
+        Ok(elf)
+    }
+
+    // Where the program starts
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    // The PT_LOAD segments, in the order of the program headers
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        // parse() already read every header once
+        (0..self.phnum).filter_map(|i| self.program_header(i).ok().flatten())
+    }
+
+    // Program header i if it is a PT_LOAD one
+    fn program_header(&self, i: usize) -> Result<Option<Segment>, ElfError> {
+        let off = i.checked_mul(self.phentsize)
+                   .and_then(|o| o.checked_add(self.phoff))
+                   .ok_or(ElfError::Truncated)?;
+        let ph = self.data.get(off..off.checked_add(PHDR_SIZE).ok_or(ElfError::Truncated)?)
+                          .ok_or(ElfError::Truncated)?;
+        if le32(ph, 0) != Some(PT_LOAD) {
+            return Ok(None);
+        }
+        Ok(Some(Segment {
+            flags:  le32(ph, 4).ok_or(ElfError::Truncated)?,
+            offset: le64(ph, 8).ok_or(ElfError::Truncated)? as usize,
+            vaddr:  le64(ph, 16).ok_or(ElfError::Truncated)? as usize,
+            filesz: le64(ph, 32).ok_or(ElfError::Truncated)? as usize,
+            memsz:  le64(ph, 40).ok_or(ElfError::Truncated)? as usize
+        }))
+    }
+}
+
+fn le16(buf: &[u8], off: usize) -> Option<u16> {
+    let bytes = buf.get(off..off.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn le32(buf: &[u8], off: usize) -> Option<u32> {
+    let bytes = buf.get(off..off.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn le64(buf: &[u8], off: usize) -> Option<u64> {
+    let bytes = buf.get(off..off.checked_add(8)?)?;
+    let mut le = [0u8; 8];
+    le.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(le))
+}
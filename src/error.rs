@@ -0,0 +1,75 @@
+// Kernel errors
+// The reasons something in the kernel can fail, shared between modules so
+// that a failure keeps its meaning as it is passed up, all the way to the
+// errno a syscall hands back to user space. Module errors with more
+// detail, like ProcError, convert into this with From.
+
+use crate::process::ProcError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelError {
+    // No pages or heap left for it
+    OutOfMemory,
+    // The address isn't mapped, or can't be used the way it was asked to
+    InvalidAddress,
+    // There is no process, file or descriptor by that name
+    NotFound,
+    // It exists, but the caller may not do that to it
+    Permission,
+    // It can't be done yet, try again later
+    WouldBlock,
+    // The process list is full
+    TooManyProcesses
+}
+
+// The Linux errno numbers, which is what C libraries expect
+pub const EPERM: isize = 1;
+pub const ENOENT: isize = 2;
+pub const EAGAIN: isize = 11;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+
+impl KernelError {
+    pub fn errno(self) -> isize {
+        match self {
+            KernelError::OutOfMemory => ENOMEM,
+            KernelError::InvalidAddress => EFAULT,
+            KernelError::NotFound => ENOENT,
+            KernelError::Permission => EPERM,
+            KernelError::WouldBlock | KernelError::TooManyProcesses => EAGAIN
+        }
+    }
+
+    // The value a syscall leaves in a0 for this error, -errno
+    pub fn to_a0(self) -> usize {
+        -self.errno() as usize
+    }
+}
+
+impl From<ProcError> for KernelError {
+    fn from(e: ProcError) -> Self {
+        match e {
+            ProcError::NoProcessList => KernelError::NotFound,
+            ProcError::TooManyProcesses => KernelError::TooManyProcesses
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{page::{dealloc, zalloc, Table}, vm::copy_from_user};
+
+    #[test_case]
+    fn bad_user_addresses_are_efault() {
+        // Nothing is mapped in a fresh table
+        let root = zalloc(1) as *mut Table;
+        assert!(!root.is_null());
+        let mut buf = [0u8; 8];
+        let err = copy_from_user(unsafe { &*root }, &mut buf, 0x2000_0000).unwrap_err();
+        assert_eq!(err, KernelError::InvalidAddress);
+        assert_eq!(err.errno(), EFAULT);
+        assert_eq!(err.to_a0() as isize, -14);
+        dealloc(root as *mut u8);
+    }
+}
@@ -0,0 +1,251 @@
+// File descriptors
+// Each process has a table of open files. A descriptor says what it
+// refers to and, for files, where the next read or write happens.
+// Descriptors 0, 1 and 2 start out on the console.
+
+use crate::{mmio::UART_BASE,
+            pipe::{PipeEnd, Waiter},
+            process,
+            ramfs::{self, FsError, Handle},
+            uart::{self, Uart}};
+
+// Most files a process can have open at once
+pub const MAX_FDS: usize = 16;
+
+#[derive(Debug)]
+pub enum FdError {
+    // The descriptor isn't open
+    BadFd,
+    // Every slot in the table is in use
+    TooManyOpen,
+    // The descriptor refers to something without a position, like the console
+    NotSeekable,
+    // The seek would move the offset before the start of the file
+    InvalidSeek,
+    // A user buffer isn't mapped with the permissions the call needs
+    Fault,
+    // Nothing can be read yet, or a pipe is full, the caller should wait
+    WouldBlock,
+    // A write to a pipe whose read ends are all closed
+    BrokenPipe,
+    // The filesystem refused the operation
+    Fs(FsError)
+}
+
+impl From<FsError> for FdError {
+    fn from(e: FsError) -> Self {
+        FdError::Fs(e)
+    }
+}
+
+// What a descriptor refers to
+#[derive(Clone)]
+pub enum FileKind {
+    Console,
+    File(Handle),
+    Pipe(PipeEnd)
+}
+
+// What a descriptor that returned WouldBlock is waiting for
+#[derive(Clone, Copy, Debug)]
+pub enum Blocker {
+    // Input from the UART, see uart::rx_key
+    Console,
+    // Data in a pipe or room in it, depending on the end
+    Pipe(Waiter)
+}
+
+// Where lseek measures the new offset from
+#[derive(Clone, Copy, Debug)]
+pub enum Whence {
+    // From the start of the file
+    Set,
+    // From the current offset
+    Cur,
+    // From the end of the file
+    End
+}
+
+impl Whence {
+    // The whence numbers used by the lseek syscall
+    pub fn from_usize(val: usize) -> Option<Self> {
+        match val {
+            0 => Some(Whence::Set),
+            1 => Some(Whence::Cur),
+            2 => Some(Whence::End),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FileDescriptor {
+    pub kind:   FileKind,
+    // Where the next read or write starts. Each descriptor has its own,
+    // even when two of them refer to the same file.
+    pub offset: usize
+}
+
+impl FileDescriptor {
+    pub fn console() -> Self {
+        FileDescriptor { kind: FileKind::Console, offset: 0 }
+    }
+
+    pub fn file(handle: Handle) -> Self {
+        FileDescriptor { kind: FileKind::File(handle), offset: 0 }
+    }
+
+    pub fn pipe(end: PipeEnd) -> Self {
+        FileDescriptor { kind: FileKind::Pipe(end), offset: 0 }
+    }
+
+    // What to wait on when this returns WouldBlock. Files never do.
+    pub fn blocker(&self) -> Option<Blocker> {
+        match &self.kind {
+            FileKind::Console => Some(Blocker::Console),
+            FileKind::File(_) => None,
+            FileKind::Pipe(end) => Some(Blocker::Pipe(end.waiter()))
+        }
+    }
+
+    // Read into buf from the offset. Returns 0 at the end of a file. The
+    // offset stays where it is, the caller moves it with advance() once
+    // the bytes have got where they were going. The console returns
+    // whatever the UART interrupt has queued so far, or WouldBlock if that
+    // is nothing, and a pipe works the same way, see PipeEnd::read. What
+    // those hand out is gone from them.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FdError> {
+        match &self.kind {
+            FileKind::Console => {
+                let mut count = 0;
+                while count < buf.len() {
+                    match uart::rx_pop() {
+                        Some(c) => buf[count] = c,
+                        None => break
+                    }
+                    count += 1;
+                }
+                if count == 0 && !buf.is_empty() {
+                    return Err(FdError::WouldBlock);
+                }
+                Ok(count)
+            },
+            FileKind::File(handle) => Ok(ramfs::read(handle, self.offset, buf)?),
+            FileKind::Pipe(end) => end.read(buf)
+        }
+    }
+
+    // Move the offset past count bytes that read() returned. Only files
+    // have an offset, for anything else this does nothing.
+    pub fn advance(&mut self, count: usize) {
+        if let FileKind::File(_) = self.kind {
+            self.offset += count;
+        }
+    }
+
+    // Write buf at the offset and move the offset past it. Writing past
+    // the end of a file extends it. A pipe takes what fits, see
+    // PipeEnd::write.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FdError> {
+        match &self.kind {
+            FileKind::Console => {
+                Uart::new(UART_BASE).write_bytes(buf);
+                process::capture_console(buf);
+                Ok(buf.len())
+            },
+            FileKind::File(handle) => {
+                let count = ramfs::write(handle, self.offset, buf)?;
+                self.offset += count;
+                Ok(count)
+            },
+            FileKind::Pipe(end) => end.write(buf)
+        }
+    }
+
+    // Move the offset and return where it ended up. Seeking past the end
+    // is allowed, a write there leaves a hole of zeroes.
+    pub fn seek(&mut self, offset: isize, whence: Whence) -> Result<usize, FdError> {
+        let handle = match &self.kind {
+            FileKind::Console | FileKind::Pipe(_) => return Err(FdError::NotSeekable),
+            FileKind::File(handle) => handle
+        };
+        let base = match whence {
+            Whence::Set => 0,
+            Whence::Cur => self.offset,
+            Whence::End => ramfs::size(handle)?
+        };
+        self.offset = base.checked_add_signed(offset).ok_or(FdError::InvalidSeek)?;
+        Ok(self.offset)
+    }
+}
+
+pub struct FdTable {
+    fds: [Option<FileDescriptor>; MAX_FDS]
+}
+
+impl FdTable {
+    // A table with stdin, stdout and stderr open on the console
+    pub fn new() -> Self {
+        let mut fds = [const { None }; MAX_FDS];
+        for fd in fds.iter_mut().take(3) {
+            *fd = Some(FileDescriptor::console());
+        }
+        FdTable { fds }
+    }
+
+    // Put desc in the lowest free slot and return its number
+    pub fn insert(&mut self, desc: FileDescriptor) -> Result<usize, FdError> {
+        let fd = self.fds.iter().position(|d| d.is_none()).ok_or(FdError::TooManyOpen)?;
+        self.fds[fd] = Some(desc);
+        Ok(fd)
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Result<&mut FileDescriptor, FdError> {
+        self.fds.get_mut(fd).and_then(|d| d.as_mut()).ok_or(FdError::BadFd)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), FdError> {
+        self.fds.get_mut(fd).and_then(|d| d.take()).map(|_| ()).ok_or(FdError::BadFd)
+    }
+
+    // Close every descriptor, for a process that is done
+    pub fn close_all(&mut self) {
+        self.fds.iter_mut().for_each(|d| *d = None);
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn read_after_seek_returns_the_rest_of_the_file() {
+        let handle = ramfs::create("/fd-seek-test").expect("create failed");
+        let mut desc = FileDescriptor::file(handle);
+        assert!(matches!(desc.write(b"0123456789"), Ok(10)));
+        assert_eq!(desc.offset, 10);
+        assert!(matches!(desc.seek(5, Whence::Set), Ok(5)));
+        let mut buf = [0; 10];
+        let count = desc.read(&mut buf).expect("read failed");
+        assert_eq!(&buf[..count], b"56789");
+        desc.advance(count);
+        assert!(matches!(desc.read(&mut buf), Ok(0)));
+        assert!(matches!(desc.seek(-3, Whence::End), Ok(7)));
+        assert!(matches!(desc.seek(-8, Whence::Cur), Err(FdError::InvalidSeek)));
+        // A failed seek leaves the offset alone
+        assert_eq!(desc.offset, 7);
+        assert!(ramfs::unlink("/fd-seek-test").is_ok());
+    }
+
+    #[test_case]
+    fn console_is_not_seekable() {
+        let mut desc = FileDescriptor::console();
+        assert!(matches!(desc.seek(0, Whence::Set), Err(FdError::NotSeekable)));
+    }
+}
@@ -0,0 +1,393 @@
+// Flattened device tree (FDT) parser
+// The firmware (QEMU itself when there is none) passes the address of a
+// device tree blob in a1. It describes the RAM and the devices on the
+// machine. Everything in the blob is big-endian. This only reads the
+// structure block, which is a stream of 32-bit tokens: nodes are opened
+// and closed, and properties sit inside the node they belong to.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+// The header is ten 32-bit fields
+const HEADER_SIZE: usize = 40;
+// Deepest node nesting we keep #address-cells/#size-cells for
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum FdtError {
+    // The pointer was null
+    Missing,
+    // The blob doesn't start with the FDT magic number
+    BadMagic,
+    // An offset or length in the blob points outside of it
+    Truncated,
+    // A token we don't know, the blob is corrupt
+    BadToken(u32)
+}
+
+pub struct Fdt<'a> {
+    blob:      &'a [u8],
+    structs:   &'a [u8],
+    strings:   &'a [u8]
+}
+
+// A base address and size from a reg property
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64
+}
+
+impl<'a> Fdt<'a> {
+    // Parse a blob that is already in memory as a slice
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Self, FdtError> {
+        if blob.len() < HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        if be32(blob, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total = be32(blob, 4).ok_or(FdtError::Truncated)? as usize;
+        let blob = blob.get(..total).ok_or(FdtError::Truncated)?;
+        let struct_off = be32(blob, 8).ok_or(FdtError::Truncated)? as usize;
+        let strings_off = be32(blob, 12).ok_or(FdtError::Truncated)? as usize;
+        let strings_size = be32(blob, 32).ok_or(FdtError::Truncated)? as usize;
+        let struct_size = be32(blob, 36).ok_or(FdtError::Truncated)? as usize;
+        let structs = blob.get(struct_off..struct_off.checked_add(struct_size).ok_or(FdtError::Truncated)?)
+                          .ok_or(FdtError::Truncated)?;
+        let strings = blob.get(strings_off..strings_off.checked_add(strings_size).ok_or(FdtError::Truncated)?)
+                          .ok_or(FdtError::Truncated)?;
+        Ok(Fdt { blob, structs, strings })
+    }
+
+    /// Parse the blob the boot hart was handed
+    /// # Safety
+    /// ptr must be null or point at a device tree that stays where it is
+    /// for as long as the returned Fdt is used.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Fdt<'static>, FdtError> {
+        if ptr.is_null() {
+            return Err(FdtError::Missing);
+        }
+        // Read the header first to find out how big the whole blob is
+        let header = core::slice::from_raw_parts(ptr, HEADER_SIZE);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total = be32(header, 4).ok_or(FdtError::Truncated)? as usize;
+        Fdt::from_bytes(core::slice::from_raw_parts(ptr, total))
+    }
+
+    // Size of the whole blob in bytes
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    // The first RAM region, from the reg of /memory or /memory@<address>
+    pub fn memory(&self) -> Result<Option<Region>, FdtError> {
+        self.find_reg(|depth, name, _| depth == 1 && (name == "memory" || name.starts_with("memory@")))
+    }
+
+    // The first region of the first node compatible with compat, such as
+    // "ns16550a" for the UART
+    pub fn find_compatible(&self, compat: &str) -> Result<Option<Region>, FdtError> {
+        self.find_reg(|_, _, compatible| {
+            compatible.split(|&c| c == 0).any(|c| c == compat.as_bytes())
+        })
+    }
+
+    // The kernel command line, the bootargs property of /chosen. QEMU sets
+    // it from -append.
+    pub fn bootargs(&self) -> Result<Option<&'a str>, FdtError> {
+        // depth counts the root as 1, so /chosen is at depth 2
+        let mut depth = 0;
+        let mut in_chosen = false;
+        let mut off = 0;
+        loop {
+            let token = be32(self.structs, off).ok_or(FdtError::Truncated)?;
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let rest = self.structs.get(off..).ok_or(FdtError::Truncated)?;
+                    let len = rest.iter().position(|&c| c == 0).ok_or(FdtError::Truncated)?;
+                    depth += 1;
+                    if depth == 2 {
+                        in_chosen = &rest[..len] == b"chosen";
+                    }
+                    off = align4(off + len + 1);
+                },
+                FDT_END_NODE => {
+                    if depth == 0 {
+                        return Err(FdtError::BadToken(token));
+                    }
+                    if in_chosen && depth == 2 {
+                        // /chosen is done and had no bootargs
+                        return Ok(None);
+                    }
+                    depth -= 1;
+                },
+                FDT_PROP => {
+                    let len = be32(self.structs, off).ok_or(FdtError::Truncated)? as usize;
+                    let name_off = be32(self.structs, off + 4).ok_or(FdtError::Truncated)? as usize;
+                    let value = self.structs.get(off + 8..off + 8 + len).ok_or(FdtError::Truncated)?;
+                    off = align4(off + 8 + len);
+                    if in_chosen && depth == 2 && self.string(name_off) == "bootargs" {
+                        // A string property, ending in a NUL
+                        let len = value.iter().position(|&c| c == 0).unwrap_or(value.len());
+                        return Ok(core::str::from_utf8(&value[..len]).ok());
+                    }
+                },
+                FDT_NOP => {},
+                FDT_END => return Ok(None),
+                _ => return Err(FdtError::BadToken(token))
+            }
+        }
+    }
+
+    // Walk every node and return the first entry of the reg property of
+    // the first node that matches. matches is given the node's depth (the
+    // root is 0), its name and its compatible property.
+    fn find_reg(&self, matches: impl Fn(usize, &str, &[u8]) -> bool) -> Result<Option<Region>, FdtError> {
+        // #address-cells and #size-cells apply to the children of the
+        // node they are in. depth counts the root as 1 here, so cells[d]
+        // holds the ones for the children of the node at depth d.
+        // The defaults from the spec are 2 and 1.
+        let mut cells = [(2u32, 1u32); MAX_DEPTH + 1];
+        let mut depth = 0;
+        let mut name = "";
+        let mut compatible: &[u8] = &[];
+        let mut reg: &[u8] = &[];
+        let mut off = 0;
+        loop {
+            let token = be32(self.structs, off).ok_or(FdtError::Truncated)?;
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    // The node we're in has all of its properties now,
+                    // look at it before going into its child
+                    if depth > 0 {
+                        if let Some(region) = self.check(depth, name, compatible, reg, &cells, &matches) {
+                            return Ok(Some(region));
+                        }
+                    }
+                    let rest = self.structs.get(off..).ok_or(FdtError::Truncated)?;
+                    let len = rest.iter().position(|&c| c == 0).ok_or(FdtError::Truncated)?;
+                    name = core::str::from_utf8(&rest[..len]).unwrap_or("");
+                    off = align4(off + len + 1);
+                    depth += 1;
+                    compatible = &[];
+                    reg = &[];
+                    if depth < MAX_DEPTH {
+                        // Children get the defaults unless this node says otherwise
+                        cells[depth] = (2, 1);
+                    }
+                },
+                FDT_END_NODE => {
+                    // Properties come before child nodes, so by the time a
+                    // node with no children ends we have all of its own.
+                    // A node with children was already looked at when its
+                    // first child began, above, and name is empty by now.
+                    if depth == 0 {
+                        return Err(FdtError::BadToken(token));
+                    }
+                    if let Some(region) = self.check(depth, name, compatible, reg, &cells, &matches) {
+                        return Ok(Some(region));
+                    }
+                    name = "";
+                    compatible = &[];
+                    reg = &[];
+                    depth -= 1;
+                },
+                FDT_PROP => {
+                    let len = be32(self.structs, off).ok_or(FdtError::Truncated)? as usize;
+                    let name_off = be32(self.structs, off + 4).ok_or(FdtError::Truncated)? as usize;
+                    let value = self.structs.get(off + 8..off + 8 + len).ok_or(FdtError::Truncated)?;
+                    off = align4(off + 8 + len);
+                    match self.string(name_off) {
+                        "compatible" => compatible = value,
+                        "reg" => reg = value,
+                        "#address-cells" if depth < MAX_DEPTH => cells[depth].0 = be32(value, 0).unwrap_or(2),
+                        "#size-cells" if depth < MAX_DEPTH => cells[depth].1 = be32(value, 0).unwrap_or(1),
+                        _ => {}
+                    }
+                },
+                FDT_NOP => {},
+                FDT_END => return Ok(None),
+                _ => return Err(FdtError::BadToken(token))
+            }
+        }
+    }
+
+    // Does the node that just ended match, and if so where is it
+    fn check(&self,
+             depth: usize,
+             name: &str,
+             compatible: &[u8],
+             reg: &[u8],
+             cells: &[(u32, u32); MAX_DEPTH + 1],
+             matches: &impl Fn(usize, &str, &[u8]) -> bool)
+             -> Option<Region> {
+        if name.is_empty() || reg.is_empty() || !matches(depth - 1, name, compatible) {
+            return None;
+        }
+        // reg is laid out with the cells of the parent
+        let (addr_cells, size_cells) = cells[(depth - 1).min(MAX_DEPTH)];
+        let base = read_cells(reg, 0, addr_cells)?;
+        let size = read_cells(reg, addr_cells as usize * 4, size_cells)?;
+        Some(Region { base, size })
+    }
+
+    // A property name from the strings block
+    fn string(&self, off: usize) -> &'a str {
+        let rest = match self.strings.get(off..) {
+            Some(rest) => rest,
+            None => return ""
+        };
+        let len = rest.iter().position(|&c| c == 0).unwrap_or(rest.len());
+        core::str::from_utf8(&rest[..len]).unwrap_or("")
+    }
+}
+
+fn be32(buf: &[u8], off: usize) -> Option<u32> {
+    let bytes = buf.get(off..off.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A number made of 1 or 2 big-endian cells
+fn read_cells(buf: &[u8], off: usize, cells: u32) -> Option<u64> {
+    match cells {
+        1 => be32(buf, off).map(u64::from),
+        2 => Some((u64::from(be32(buf, off)?) << 32) | u64::from(be32(buf, off + 4)?)),
+        _ => None
+    }
+}
+
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    // Writes a device tree blob token by token
+    struct Blob {
+        structs: Vec<u8>,
+        strings: Vec<u8>
+    }
+
+    impl Blob {
+        fn new() -> Self {
+            Blob { structs: Vec::new(), strings: Vec::new() }
+        }
+
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn pad(&mut self) {
+            self.structs.resize(align4(self.structs.len()), 0);
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP).token(value.len() as u32).token(name_off);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let struct_off = HEADER_SIZE;
+            let strings_off = struct_off + self.structs.len();
+            let total = strings_off + self.strings.len();
+            let header = [FDT_MAGIC, total as u32, struct_off as u32, strings_off as u32, 0, 17, 16, 0,
+                          self.strings.len() as u32, self.structs.len() as u32];
+            let mut blob: Vec<u8> = header.iter().flat_map(|h| h.to_be_bytes()).collect();
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    // Like QEMU's virt machine: 2 address and 2 size cells at the root,
+    // but the soc uses 1 and 1 so the UART's reg is laid out differently
+    // from the memory's
+    fn virt_blob(bootargs: Option<&str>) -> Vec<u8> {
+        let mut blob = Blob::new();
+        blob.begin("").cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+        blob.begin("memory@80000000")
+            .prop("device_type", b"memory\0")
+            .cells("reg", &[0, 0x8000_0000, 0, 0x0800_0000])
+            .end();
+        blob.begin("soc").cells("#address-cells", &[1]).cells("#size-cells", &[1]);
+        blob.begin("serial@10000000")
+            .prop("compatible", b"ns16550a\0")
+            .cells("reg", &[0x1000_0000, 0x100])
+            .end();
+        // A bootargs below /chosen isn't the command line
+        blob.begin("chosen").prop("bootargs", b"not this\0").end();
+        blob.end();
+        blob.begin("chosen");
+        if let Some(bootargs) = bootargs {
+            let mut value = Vec::from(bootargs.as_bytes());
+            value.push(0);
+            blob.prop("bootargs", &value);
+        }
+        blob.end();
+        blob.end().finish()
+    }
+
+    #[test_case]
+    fn reads_memory_uart_and_bootargs() {
+        let blob = virt_blob(Some("loglevel=debug canary"));
+        let fdt = Fdt::from_bytes(&blob).expect("canned blob doesn't parse");
+        assert_eq!(fdt.total_size(), blob.len());
+        assert_eq!(fdt.memory().ok().flatten(), Some(Region { base: 0x8000_0000, size: 0x0800_0000 }));
+        assert_eq!(fdt.find_compatible("ns16550a").ok().flatten(), Some(Region { base: 0x1000_0000, size: 0x100 }));
+        assert_eq!(fdt.find_compatible("virtio,mmio").ok().flatten(), None);
+        assert_eq!(fdt.bootargs().ok().flatten(), Some("loglevel=debug canary"));
+    }
+
+    #[test_case]
+    fn bootargs_is_none_without_one_in_chosen() {
+        let blob = virt_blob(None);
+        let fdt = Fdt::from_bytes(&blob).expect("canned blob doesn't parse");
+        assert_eq!(fdt.bootargs().ok().flatten(), None);
+    }
+
+    #[test_case]
+    fn rejects_bad_blobs() {
+        let mut blob = virt_blob(None);
+        assert!(matches!(Fdt::from_bytes(&blob[..HEADER_SIZE - 1]), Err(FdtError::Truncated)));
+        // totalsize past the end of what we have
+        assert!(matches!(Fdt::from_bytes(&blob[..blob.len() - 1]), Err(FdtError::Truncated)));
+        blob[0] = 0;
+        assert!(matches!(Fdt::from_bytes(&blob), Err(FdtError::BadMagic)));
+    }
+}
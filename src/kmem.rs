@@ -1,7 +1,13 @@
 // Byte level allocator
 
-use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
-use core::{mem::size_of, ptr::null_mut};
+use crate::{cpu::{hart_id, MAX_HARTS},
+            error::KernelError,
+            mem::{memcpy, memset},
+            page::{align_val, allocation_pages, dealloc, free_page_count, zalloc, Table, PAGE_SIZE}};
+use core::{cell::UnsafeCell,
+           mem::size_of,
+           ptr::null_mut,
+           sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
 
 #[repr(usize)]
 enum AllocListFlags {
@@ -17,6 +23,9 @@ impl AllocListFlags {
 struct AllocList {
 	pub flags_size: usize,
 }
+// kmalloc hands out the address right after the header, so the header
+// has to keep 8-byte alignment.
+const _: () = assert!(size_of::<AllocList>() & 7 == 0);
 impl AllocList {
 	pub fn is_taken(&self) -> bool {
 		self.flags_size & AllocListFlags::Taken.val() != 0
@@ -53,6 +62,10 @@ static mut KMEM_HEAD: *mut AllocList = null_mut();
 // Store number of allocated pages for the kernel
 static mut KMEM_ALLOC: usize = 0;
 static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+// Bytes in taken chunks right now, headers included
+static KMEM_USED: AtomicUsize = AtomicUsize::new(0);
+// The most KMEM_USED has ever been
+static KMEM_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
 
 // These functions are safe helpers around an unsafe
 // operation.
@@ -68,21 +81,81 @@ pub fn get_num_allocations() -> usize {
 	unsafe { KMEM_ALLOC }
 }
 
+/// Bytes of the kernel heap in use, including allocation headers
+pub fn used() -> usize {
+	KMEM_USED.load(Ordering::Relaxed)
+}
+
+/// The most bytes of the kernel heap that have ever been in use at once
+pub fn high_water() -> usize {
+	KMEM_HIGH_WATER.load(Ordering::Relaxed)
+}
+
+// The kernel heap takes this percentage of the free pages at init,
+// but no fewer than KMEM_MIN_PAGES and no more than KMEM_MAX_PAGES
+pub const KMEM_PERCENT: usize = 10;
+pub const KMEM_MIN_PAGES: usize = 16;
+pub const KMEM_MAX_PAGES: usize = 512;
+
+#[derive(Debug)]
+pub enum KmemError {
+	// The heap and the kernel page table need want pages, only free are left
+	NotEnoughPages { want: usize, free: usize },
+	// There were enough free pages, but not in one contiguous run
+	OutOfMemory,
+}
+
+/// How many pages the kernel heap reserves when free_pages are free
+pub const fn reservation(free_pages: usize) -> usize {
+	let pages = free_pages * KMEM_PERCENT / 100;
+	if pages < KMEM_MIN_PAGES {
+		KMEM_MIN_PAGES
+	}
+	else if pages > KMEM_MAX_PAGES {
+		KMEM_MAX_PAGES
+	}
+	else {
+		pages
+	}
+}
+
 /// Initialize kernel's memory
 /// This is not to be used to allocate memory
 /// for user processes. If that's the case, use
 /// alloc/dealloc from the page crate.
-pub fn init() {
+/// The heap size comes from reservation(), see get_num_allocations().
+/// Returns the number of pages reserved for the heap.
+pub fn init() -> Result<usize, KmemError> {
+	let free = free_page_count();
+	let pages = reservation(free);
+	// One more page for the kernel's root page table
+	if pages + 1 > free {
+		return Err(KmemError::NotEnoughPages { want: pages + 1, free });
+	}
 	unsafe {
 		// Allocate kernel pages (KMEM_ALLOC)
-		KMEM_ALLOC = 512;
-		let k_alloc = zalloc(KMEM_ALLOC);
-		assert!(!k_alloc.is_null());
+		let k_alloc = zalloc(pages);
+		if k_alloc.is_null() {
+			return Err(KmemError::OutOfMemory);
+		}
+		// The chunk walk runs from the head to head + pages * PAGE_SIZE,
+		// so the heap has to be one run. alloc() only hands out runs,
+		// this makes sure it stays that way.
+		assert_eq!(allocation_pages(k_alloc as usize), pages, "kernel heap isn't one contiguous run");
+		let table = zalloc(1);
+		if table.is_null() {
+			// Nothing points at the heap yet, so give its pages back
+			// rather than leaving them taken for good
+			dealloc(k_alloc);
+			return Err(KmemError::OutOfMemory);
+		}
+		KMEM_ALLOC = pages;
 		KMEM_HEAD = k_alloc as *mut AllocList;
 		(*KMEM_HEAD).set_free();
 		(*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
-		KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
+		KMEM_PAGE_TABLE = table as *mut Table;
 	}
+	Ok(pages)
 }
 
 /// Allocate sub-page level allocation based on bytes and zero the memory
@@ -91,10 +164,8 @@ pub fn kzmalloc(sz: usize) -> *mut u8 {
 	let ret = kmalloc(size);
 
 	if !ret.is_null() {
-		for i in 0..size {
-			unsafe {
-				(*ret.add(i)) = 0;
-			}
+		unsafe {
+			memset(ret, 0, size);
 		}
 	}
 	ret
@@ -104,13 +175,19 @@ pub fn kzmalloc(sz: usize) -> *mut u8 {
 pub fn kmalloc(sz: usize) -> *mut u8 {
 	unsafe {
         // Size with byte boundary + size of the AllocList to be allocated
-		let size = align_val(sz, 3) + size_of::<AllocList>();
+		// The total is aligned to 8 bytes again so that whatever
+		// remainder we split off below also starts on an 8-byte
+		// boundary, keeping every chunk (and its user pointer) aligned.
+		let size = align_val(align_val(sz, 3) + size_of::<AllocList>(), 3);
 		let mut head = KMEM_HEAD;
         // End of kernel memory allocation
 		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
 		           as *mut AllocList;
 
 		while head < tail {
+			if bad_chunk(head, tail, "kmalloc") {
+				break;
+			}
             // Check if head is free and size is less than the allocated memory for the kernel
 			if (*head).is_free() && size <= (*head).get_size() {
 				let chunk_size = (*head).get_size();
@@ -128,6 +205,12 @@ pub fn kmalloc(sz: usize) -> *mut u8 {
 					// If we get here, take the entire chunk
 					(*head).set_size(chunk_size);
 				}
+				let used = KMEM_USED.fetch_add((*head).get_size(), Ordering::Relaxed)
+				           + (*head).get_size();
+				KMEM_HIGH_WATER.fetch_max(used, Ordering::Relaxed);
+				// The head is 8-byte aligned and so is the header size,
+				// so the pointer after the header is too.
+				debug_assert!(head.add(1) as usize & 7 == 0);
 				return head.add(1) as *mut u8;
 			}
 			else {
@@ -142,6 +225,77 @@ pub fn kmalloc(sz: usize) -> *mut u8 {
 	null_mut()
 }
 
+/// Resize the allocation at ptr to sz bytes. What fits of the old
+/// contents is kept, and the new pointer is returned. A null ptr is a
+/// fresh kmalloc(). If there isn't room the old allocation is left as
+/// it was and the error is OutOfMemory, and a ptr that isn't a live
+/// allocation is InvalidAddress.
+pub fn krealloc(ptr: *mut u8, sz: usize) -> Result<*mut u8, KernelError> {
+	if ptr.is_null() {
+		let ret = kmalloc(sz);
+		return if ret.is_null() { Err(KernelError::OutOfMemory) } else { Ok(ret) };
+	}
+	let p = match live_chunk(ptr) {
+		Some(p) => p,
+		None => return Err(KernelError::InvalidAddress),
+	};
+	unsafe {
+		let have = (*p).get_size() - size_of::<AllocList>();
+		// Shrinking, or growing into the slack of the chunk
+		if sz <= have {
+			return Ok(ptr);
+		}
+		let ret = kmalloc(sz);
+		if ret.is_null() {
+			return Err(KernelError::OutOfMemory);
+		}
+		// The old contents start right after the header
+		memcpy(ret, p.add(1) as *const u8, have);
+		kfree(ptr);
+		Ok(ret)
+	}
+}
+
+/// The header of the taken chunk that kmalloc() returned ptr for. The
+/// word before ptr is only read once the chunk list says a header is
+/// there, so a pointer from outside the heap, or into the middle of a
+/// chunk, is None rather than whatever its neighbour holds.
+fn live_chunk(ptr: *mut u8) -> Option<*mut AllocList> {
+	unsafe {
+		let mut head = KMEM_HEAD;
+		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+		           as *mut AllocList;
+		if ptr <= head as *mut u8 || ptr >= tail as *mut u8 {
+			return None;
+		}
+		while head < tail && !bad_chunk(head, tail, "krealloc") {
+			if head.add(1) as *mut u8 == ptr {
+				return if (*head).is_taken() { Some(head) } else { None };
+			}
+			// The chunks are in address order, so we've gone past it
+			if head.add(1) as *mut u8 > ptr {
+				return None;
+			}
+			head = (head as *mut u8).add((*head).get_size())
+			       as *mut AllocList;
+		}
+	}
+	None
+}
+
+// A chunk with a size of 0 would have the walk stop moving, and one that
+// runs past tail points the next header outside of the heap. Either means
+// the heap is corrupt, so the walk has to stop. who is printed with the
+// diagnostic.
+unsafe fn bad_chunk(head: *mut AllocList, tail: *mut AllocList, who: &str) -> bool {
+	let size = (*head).get_size();
+	if size == 0 || size > tail as usize - head as usize {
+		println!("{}: corrupt kmem chunk at {:p} with size {}", who, head, size);
+		return true;
+	}
+	false
+}
+
 /// Free a sub-page level allocation
 pub fn kfree(ptr: *mut u8) {
 	unsafe {
@@ -149,6 +303,7 @@ pub fn kfree(ptr: *mut u8) {
 			let p = (ptr as *mut AllocList).offset(-1);
 			if (*p).is_taken() {
 				(*p).set_free();
+				KMEM_USED.fetch_sub((*p).get_size(), Ordering::Relaxed);
 			}
 			// After we free, see if we can combine adjacent free
 			// spots to see if we can reduce fragmentation.
@@ -207,6 +362,9 @@ pub fn print_table() {
 		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
 		           as *mut AllocList;
 		while head < tail {
+			if bad_chunk(head, tail, "print_table") {
+				break;
+			}
 			println!(
 			         "{:p}: Length = {:<10} Taken = {}",
 			         head,
@@ -219,6 +377,18 @@ pub fn print_table() {
 	}
 }
 
+/// Print how much of the kernel heap is used now and at its peak
+pub fn print_kmem_summary() {
+	let total = get_num_allocations() * PAGE_SIZE;
+	println!("KERNEL HEAP");
+	println!("Total    : {:>10} bytes", total);
+	println!("Used     : {:>10} bytes", used());
+	println!("Free     : {:>10} bytes", total - used());
+	println!("Peak used: {:>10} bytes", high_water());
+	println!("Fallback : {:>10} bytes", fallback_used());
+	println!("Leaked   : {:>10} frees", nested_frees());
+}
+
 // ///////////////////////////////////
 // / GLOBAL ALLOCATOR
 // ///////////////////////////////////
@@ -229,6 +399,64 @@ pub fn print_table() {
 // allocator.
 use core::alloc::{GlobalAlloc, Layout};
 
+// Set while a hart is inside the global allocator. An allocation that
+// starts while it is set, from a trap taken in the middle of kmalloc or
+// from something kmalloc itself calls, would walk a chunk list that is
+// half updated, so it is served from the fallback below instead.
+static IN_ALLOC: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+// Bump allocator for nested allocations. It is small and never reused,
+// nested allocations should be rare and short.
+const FALLBACK_SIZE: usize = 4096;
+#[repr(align(4096))]
+struct Fallback(UnsafeCell<[u8; FALLBACK_SIZE]>);
+// Every allocation reserves its own range of the buffer through
+// FALLBACK_NEXT before touching it, so no two harts share any bytes
+unsafe impl Sync for Fallback {}
+static FALLBACK: Fallback = Fallback(UnsafeCell::new([0; FALLBACK_SIZE]));
+// Offset of the next free byte in FALLBACK
+static FALLBACK_NEXT: AtomicUsize = AtomicUsize::new(0);
+// Frees that arrived while the hart was already inside the allocator.
+// They can't be done safely then, so the memory is leaked.
+static NESTED_FREES: AtomicUsize = AtomicUsize::new(0);
+
+fn fallback_alloc(layout: Layout) -> *mut u8 {
+	let base = FALLBACK.0.get() as *mut u8;
+	let mut next = FALLBACK_NEXT.load(Ordering::Relaxed);
+	loop {
+		let start = align_val(base as usize + next, layout.align().trailing_zeros() as usize)
+		            - base as usize;
+		let end = match start.checked_add(layout.size()) {
+			Some(end) if end <= FALLBACK_SIZE => end,
+			_ => return null_mut(),
+		};
+		match FALLBACK_NEXT.compare_exchange(next, end, Ordering::Relaxed, Ordering::Relaxed) {
+			Ok(_) => unsafe {
+				// The fallback is never reused, but the global allocator
+				// hands out zeroed memory like kzmalloc does
+				memset(base.add(start), 0, layout.size());
+				return base.add(start);
+			},
+			Err(seen) => next = seen,
+		}
+	}
+}
+
+fn in_fallback(ptr: *mut u8) -> bool {
+	let base = FALLBACK.0.get() as usize;
+	(base..base + FALLBACK_SIZE).contains(&(ptr as usize))
+}
+
+/// Bytes handed out by the nested allocation fallback
+pub fn fallback_used() -> usize {
+	FALLBACK_NEXT.load(Ordering::Relaxed)
+}
+
+/// Number of frees dropped because they happened inside an allocation
+pub fn nested_frees() -> usize {
+	NESTED_FREES.load(Ordering::Relaxed)
+}
+
 // The global allocator is a static constant to a global allocator
 // structure. We don't need any members because we're using this
 // structure just to implement alloc and dealloc.
@@ -236,16 +464,38 @@ struct OsGlobalAlloc;
 
 unsafe impl GlobalAlloc for OsGlobalAlloc {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		// Nothing can be read or written through a zero-size allocation,
+		// so it doesn't need memory, only a non-null pointer that is
+		// aligned. The alignment itself is the usual one, dealloc()
+		// knows from the layout not to free it.
+		if layout.size() == 0 {
+			return layout.align() as *mut u8;
+		}
+		let busy = &IN_ALLOC[hart_id() % MAX_HARTS];
+		if busy.swap(true, Ordering::Acquire) {
+			return fallback_alloc(layout);
+		}
 		// We align to the next page size so that when
 		// we divide by PAGE_SIZE, we get exactly the number
 		// of pages necessary.
-		kzmalloc(layout.size())
+		let ret = kzmalloc(layout.size());
+		busy.store(false, Ordering::Release);
+		ret
 	}
 
-	unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-		// We ignore layout since our allocator uses ptr_start -> last
-		// to determine the span of an allocation.
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if layout.size() == 0 || in_fallback(ptr) {
+			return;
+		}
+		let busy = &IN_ALLOC[hart_id() % MAX_HARTS];
+		if busy.swap(true, Ordering::Acquire) {
+			NESTED_FREES.fetch_add(1, Ordering::Relaxed);
+			return;
+		}
+		// Past the zero-size check we ignore layout since our allocator
+		// uses ptr_start -> last to determine the span of an allocation.
 		kfree(ptr);
+		busy.store(false, Ordering::Release);
 	}
 }
 
@@ -266,3 +516,171 @@ pub fn alloc_error(l: Layout) -> ! {
 	       l.align()
 	);
 }
+
+#[cfg(test)]
+mod tests {
+	use alloc::vec::Vec;
+
+	use super::*;
+	use crate::page;
+
+	#[test_case]
+	fn reservation_is_clamped() {
+		assert_eq!(reservation(0), KMEM_MIN_PAGES);
+		assert_eq!(reservation(1000), 100);
+		assert_eq!(reservation(usize::MAX / 100), KMEM_MAX_PAGES);
+	}
+
+	#[test_case]
+	fn init_fails_cleanly_on_a_small_heap() {
+		// Take all but 40 pages, in as few allocations as we can
+		let mut held = Vec::new();
+		let mut ask = free_page_count() - 40;
+		while free_page_count() > 40 {
+			ask = ask.min(free_page_count() - 40);
+			let page = page::alloc(ask);
+			if page.is_null() {
+				ask = (ask / 2).max(1);
+				continue;
+			}
+			held.push(page);
+		}
+		// First fit hands these out in address order, so freeing every
+		// other one leaves no two free pages next to each other
+		let singles: [*mut u8; 40] = core::array::from_fn(|_| page::alloc(1));
+		assert!(singles.iter().all(|p| !p.is_null()));
+		let (head, pages) = (get_head(), get_num_allocations());
+		for &page in singles[..20].iter().step_by(2) {
+			dealloc(page);
+		}
+		let small = init();
+		for &page in singles[20..].iter().step_by(2) {
+			dealloc(page);
+		}
+		// Enough pages this time, but no run long enough
+		let scattered = init();
+		for &page in singles.iter().skip(1).step_by(2).chain(held.iter()) {
+			dealloc(page);
+		}
+		assert!(matches!(small, Err(KmemError::NotEnoughPages { want: 17, free: 10 })));
+		assert!(matches!(scattered, Err(KmemError::OutOfMemory)));
+		// The heap in use was left alone
+		assert_eq!((get_head(), get_num_allocations()), (head, pages));
+	}
+
+	#[test_case]
+	fn krealloc_only_takes_live_allocations() {
+		let ptr = kmalloc(16);
+		assert!(!ptr.is_null());
+		unsafe {
+			assert_eq!(krealloc(ptr.add(8), 32), Err(KernelError::InvalidAddress));
+		}
+		let mut outside = 0u64;
+		assert_eq!(krealloc(&mut outside as *mut u64 as *mut u8, 32), Err(KernelError::InvalidAddress));
+		let grown = krealloc(ptr, 64).expect("no room to grow");
+		// Growing past the chunk moves it and frees the old one
+		assert_ne!(grown, ptr);
+		assert_eq!(krealloc(ptr, 32), Err(KernelError::InvalidAddress));
+		kfree(grown);
+		assert_eq!(krealloc(grown, 32), Err(KernelError::InvalidAddress));
+	}
+
+	#[test_case]
+	fn odd_sizes_stay_aligned_and_apart() {
+		let a = kmalloc(5);
+		let b = kmalloc(5);
+		assert!(!a.is_null() && !b.is_null());
+		assert_eq!(a as usize & 7, 0);
+		assert_eq!(b as usize & 7, 0);
+		let (a_end, b_end) = (a as usize + 5, b as usize + 5);
+		assert!(a_end <= b as usize || b_end <= a as usize);
+		unsafe {
+			a.write_bytes(0xaa, 5);
+			b.write_bytes(0xbb, 5);
+			assert_eq!(*a.add(4), 0xaa);
+			assert_eq!(*b, 0xbb);
+		}
+		kfree(a);
+		kfree(b);
+	}
+
+	#[test_case]
+	fn high_water_stays_at_the_peak() {
+		let before = used();
+		// Past any earlier peak, if there is room for it
+		let burst = (high_water() - before + 64).min(get_num_allocations() * PAGE_SIZE / 2);
+		let ptrs = [kmalloc(burst), kmalloc(64), kmalloc(64)];
+		assert!(ptrs.iter().all(|p| !p.is_null()));
+		let peak = used();
+		assert!(high_water() >= peak);
+		let high = high_water();
+		for p in ptrs {
+			kfree(p);
+		}
+		assert_eq!(used(), before);
+		assert_eq!(high_water(), high);
+		assert!(high > used());
+	}
+
+	#[test_case]
+	fn nested_allocations_use_the_fallback() {
+		let layout = Layout::from_size_align(16, 8).unwrap();
+		let busy = &IN_ALLOC[hart_id() % MAX_HARTS];
+		let (used_before, fallback_before, frees_before) = (used(), fallback_used(), nested_frees());
+		let outer = kmalloc(16);
+		assert!(!outer.is_null());
+		let used_before_nested = used();
+		// As if this hart were in the middle of kmalloc
+		busy.store(true, Ordering::Relaxed);
+		let nested = unsafe { GA.alloc(layout) };
+		unsafe { GA.dealloc(outer, layout) };
+		busy.store(false, Ordering::Relaxed);
+		assert!(in_fallback(nested));
+		assert!(fallback_used() >= fallback_before + 16);
+		assert_eq!(used(), used_before_nested);
+		// The free was dropped rather than done halfway through
+		assert_eq!(nested_frees(), frees_before + 1);
+		unsafe {
+			GA.dealloc(nested, layout);
+			GA.dealloc(outer, layout);
+		}
+		assert_eq!(used(), used_before);
+	}
+	#[test_case]
+	fn zero_size_allocations_take_no_memory() {
+		let before = used();
+		for align in [1, 8, PAGE_SIZE] {
+			let layout = Layout::from_size_align(0, align).unwrap();
+			let ptr = unsafe { GA.alloc(layout) };
+			assert!(!ptr.is_null());
+			assert_eq!(ptr as usize % align, 0);
+			assert_eq!(used(), before);
+			unsafe { GA.dealloc(ptr, layout) };
+			assert_eq!(used(), before);
+		}
+	}
+
+	#[test_case]
+	fn corrupt_chunks_stop_kmalloc() {
+		let page = zalloc(1);
+		assert!(!page.is_null());
+		let (zero, past) = unsafe {
+			// kmalloc walks a heap of one page for a moment, the real
+			// one is put back before anything else can allocate
+			let (head, pages) = (KMEM_HEAD, KMEM_ALLOC);
+			KMEM_HEAD = page as *mut AllocList;
+			KMEM_ALLOC = 1;
+			// zalloc left a free chunk of size 0
+			let zero = kmalloc(16);
+			// One that claims to run past the end of the heap
+			(*KMEM_HEAD).set_size(2 * PAGE_SIZE);
+			let past = kmalloc(16);
+			KMEM_HEAD = head;
+			KMEM_ALLOC = pages;
+			(zero, past)
+		};
+		assert!(zero.is_null());
+		assert!(past.is_null());
+		dealloc(page);
+	}
+}
@@ -0,0 +1,73 @@
+// Spinlocks
+// Interrupts are not masked while a lock is held, so never take a lock in
+// a trap handler that the interrupted code might already be holding.
+
+use core::{cell::UnsafeCell,
+           ops::{Deref, DerefMut},
+           sync::atomic::{AtomicBool, Ordering}};
+
+use crate::cpu::spin_hint;
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    data:   UnsafeCell<T>
+}
+
+// The lock hands out one reference to the data at a time, so sharing the
+// Mutex between harts is fine as long as the data itself can be sent.
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            data:   UnsafeCell::new(data)
+        }
+    }
+
+    // Spin until we own the lock. It is released when the guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        while self.locked
+                  .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                  .is_err()
+        {
+            // Wait with plain loads so we don't keep taking the cache line
+            while self.locked.load(Ordering::Relaxed) {
+                spin_hint();
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    // Take the lock only if nobody holds it
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,176 @@
+// Kernel logging and debug traces
+// Traces are switched on and off at runtime, they are off by default
+// so that they don't slow down the common paths.
+
+use core::{fmt::{self, Write},
+           sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering}};
+
+use crate::console::{self, Console};
+
+// How chatty the kernel is, each level includes the ones before it
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug
+}
+
+impl LogLevel {
+    // The names used for loglevel= on the kernel command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None
+        }
+    }
+}
+
+// A LogLevel as u8
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        _ => LogLevel::Debug
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// Should a message at this level be printed
+pub fn enabled(level: LogLevel) -> bool {
+    level <= self::level()
+}
+
+// Record every ProcessState transition, see process::dump_state_history()
+static TRACE_PROCESS_STATE: AtomicBool = AtomicBool::new(false);
+
+pub fn trace_process_state() -> bool {
+    TRACE_PROCESS_STATE.load(Ordering::Relaxed)
+}
+
+pub fn set_trace_process_state(on: bool) {
+    TRACE_PROCESS_STATE.store(on, Ordering::Relaxed);
+}
+
+// The last RING_SIZE bytes print! wrote, so that what the kernel said
+// before it hung can still be read, with dump_ring() or from a debugger
+// through the LOG_RING and LOG_RING_HEAD symbols. It is in .bss, which
+// boot.S clears, so it doesn't outlive a reset.
+pub const RING_SIZE: usize = 4096;
+
+#[no_mangle]
+static LOG_RING: [AtomicU8; RING_SIZE] = [const { AtomicU8::new(0) }; RING_SIZE];
+// Bytes ever written to the ring, the next one goes at this modulo
+// RING_SIZE
+#[no_mangle]
+static LOG_RING_HEAD: AtomicUsize = AtomicUsize::new(0);
+
+// Append bytes to the ring. Each writer reserves its own range before
+// copying, so this takes no lock and a trap handler can log while the
+// code it interrupted is in the middle of a message.
+pub fn ring_write(bytes: &[u8]) {
+    let start = LOG_RING_HEAD.fetch_add(bytes.len(), Ordering::Relaxed);
+    // Only the end of something longer than the ring would survive
+    let skip = bytes.len().saturating_sub(RING_SIZE);
+    for (i, &b) in bytes.iter().enumerate().skip(skip) {
+        LOG_RING[start.wrapping_add(i) % RING_SIZE].store(b, Ordering::Relaxed);
+    }
+}
+
+// Print what is in the ring, oldest first. Once the ring has wrapped the
+// oldest line is only partly there, so it starts at the next full one.
+// This goes straight to the console so that it doesn't log itself.
+pub fn dump_ring() {
+    console::with_active(dump_ring_to);
+}
+
+fn dump_ring_to(out: &mut dyn Console) {
+    let head = LOG_RING_HEAD.load(Ordering::Relaxed);
+    let mut at = head.saturating_sub(RING_SIZE);
+    if at > 0 {
+        while at < head && LOG_RING[at % RING_SIZE].load(Ordering::Relaxed) != b'\n' {
+            at += 1;
+        }
+        at += 1;
+    }
+    while at < head {
+        out.putc(LOG_RING[at % RING_SIZE].load(Ordering::Relaxed));
+        at += 1;
+    }
+}
+
+// What print! writes through: everything goes to the ring and then to
+// the console
+pub struct Tee<'a>(pub &'a mut dyn Console);
+
+impl Write for Tee<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        ring_write(s.as_bytes());
+        self.0.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec::Vec};
+
+    use super::*;
+
+    // Keeps what is written to it
+    struct Sink(Vec<u8>);
+
+    impl Write for Sink {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.extend_from_slice(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    impl Console for Sink {
+        fn putc(&mut self, c: u8) {
+            self.0.push(c);
+        }
+    }
+
+    fn dumped() -> Vec<u8> {
+        let mut out = Sink(Vec::new());
+        dump_ring_to(&mut out);
+        out.0
+    }
+
+    #[test_case]
+    fn ring_keeps_the_newest_lines() {
+        // 600 lines of 9 bytes, which is more than the ring holds
+        for i in 0..600 {
+            ring_write(format!("line {:03}\n", i).as_bytes());
+        }
+        // The ring ends up starting 1304 bytes in, partway through line
+        // 144, which is dropped
+        let want: Vec<u8> = (145..600).flat_map(|i| format!("line {:03}\n", i).into_bytes()).collect();
+        assert_eq!(dumped(), want);
+    }
+
+    #[test_case]
+    fn ring_keeps_the_end_of_a_long_message() {
+        let mut long = [0; RING_SIZE + 100];
+        for (i, b) in long.iter_mut().enumerate() {
+            *b = b'0' + (i % 10) as u8;
+        }
+        long[50] = b'\n';
+        long[150] = b'\n';
+        long[RING_SIZE + 99] = b'\n';
+        ring_write(&long);
+        // Only the last RING_SIZE bytes fit, and the dump starts after
+        // the first newline in them
+        assert_eq!(dumped(), &long[151..]);
+    }
+}
@@ -1,12 +1,20 @@
 #![no_std]
 #![no_main]
 #![feature(allocator_api,
-           alloc_error_handler)]
+           alloc_error_handler,
+           custom_test_frameworks)]
+#![test_runner(crate::test::run_tests)]
+#![reexport_test_harness_main = "test_main"]
 
-use core::arch::{asm, global_asm};
+use core::{arch::global_asm, sync::atomic::{AtomicU8, AtomicUsize, Ordering}};
 
-global_asm!(include_str!("asm/boot.S"));
-global_asm!(include_str!("asm/trap.S"));
+global_asm!(include_str!("asm/boot.S"),
+            MAX_HARTS = const cpu::MAX_HARTS,
+            TRAP_STACK_SIZE = const cpu::TRAP_STACK_SIZE);
+global_asm!(include_str!("asm/trap.S"),
+            TRAP_STACK_SIZE = const cpu::TRAP_STACK_SIZE,
+            FRAME_REGS = const cpu::FRAME_REGS,
+            FRAME_FREGS = const cpu::FRAME_FREGS);
 global_asm!(include_str!("asm/mem.S"));
 
 #[macro_use]
@@ -20,8 +28,10 @@ use alloc::{boxed::Box, string::String};
 macro_rules! print
 {
 	($($args:tt)+) => ({
-			use core::fmt::Write;
-			let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+			crate::console::with_active(|console| {
+				let _ = core::fmt::Write::write_fmt(&mut crate::log::Tee(console),
+				                                   format_args!($($args)+));
+			});
 			});
 }
 #[macro_export]
@@ -46,6 +56,12 @@ extern "C" fn eh_personality() {}
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+	// Count panics so that a panic raised while handling another one is
+	// visible instead of looking like the first.
+	let count = PANIC_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+	if count > 1 {
+		print!("(panic #{}) ", count);
+	}
 	print!("Aborting: ");
 	if let Some(p) = info.location() {
 		println!(
@@ -58,14 +74,23 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 	else {
 		println!("no information available.");
 	}
+	if count == 1 && backtrace::enabled() {
+		backtrace::print_backtrace();
+	}
+	// A panic in a test is that test failing
+	#[cfg(test)]
+	test_finisher(FINISHER_FAIL);
+	// A nested panic means we already failed somewhere in here, so just
+	// spin rather than risk panicking again.
+	if count == 1 {
+		finish(&mmio::Mmio::new(TEST_FINISHER), panic_action());
+	}
 	abort();
 }
 #[no_mangle]
 extern "C" fn abort() -> ! {
 	loop {
-		unsafe {
-			asm!("wfi");
-		}
+		cpu::wait_for_interrupt();
 	}
 }
 
@@ -73,223 +98,78 @@ extern "C" fn abort() -> ! {
 // / CONSTANTS
 // ///////////////////////////////////
 
-// The following symbols come from asm/mem.S. We can use
-// the symbols directly, but the address of the symbols
-// themselves are their values, which can cause issues.
+// QEMU's virt machine has a "test" device that can power off or reset
+// the whole machine. Writing FINISHER_PASS shuts QEMU down and
+// FINISHER_RESET reboots it. FINISHER_FAIL shuts it down with exit
+// status 1, which is how `cargo test` sees a failed test.
+const TEST_FINISHER: usize = 0x0010_0000;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+#[cfg(test)]
+const FINISHER_FAIL: u32 = (1 << 16) | 0x3333;
 
-extern "C" {
-	static TEXT_START: usize;
-	static TEXT_END: usize;
-	static DATA_START: usize;
-	static DATA_END: usize;
-	static RODATA_START: usize;
-	static RODATA_END: usize;
-	static BSS_START: usize;
-	static BSS_END: usize;
-	static KERNEL_STACK_START: usize;
-	static KERNEL_STACK_END: usize;
-	static HEAP_START: usize;
-	static HEAP_SIZE: usize;
+/// What the panic handler does after it has printed the panic message.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PanicAction {
+	// Park the hart forever in abort(), the default
+	Spin,
+	// Power off QEMU through the test finisher
+	Shutdown,
+	// Reset QEMU through the test finisher
+	Reboot,
 }
-/// Identity map range
-/// Takes a contiguous allocation of memory and maps it using PAGE_SIZE
-/// This assumes that start <= end
-pub fn id_map_range(root: &mut page::Table,
-                    start: usize,
-                    end: usize,
-                    bits: i64)
-{
-	let mut memaddr = start & !(page::PAGE_SIZE - 1);
-	let num_kb_pages =
-		(page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
 
-	for _ in 0..num_kb_pages {
-		page::map(root, memaddr, memaddr, bits, 0);
-		memaddr += 1 << 12;
+// A PanicAction as u8
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Spin as u8);
+// Number of times the panic handler has been entered
+static PANIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn panic_action() -> PanicAction {
+	match PANIC_ACTION.load(Ordering::Relaxed) {
+		1 => PanicAction::Shutdown,
+		2 => PanicAction::Reboot,
+		_ => PanicAction::Spin,
 	}
 }
 
-// Get the switch_to_user function from trap.S
-extern "C" {
-	fn switch_to_user(frame: usize, mepc: usize, satp: usize) -> !;
+pub fn set_panic_action(action: PanicAction) {
+	PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+fn test_finisher(code: u32) {
+	mmio::Mmio::new(TEST_FINISHER).write32(0, code);
+}
+
+// Tell finisher what the panic action asks for, if it asks for anything
+fn finish(finisher: &mmio::Mmio, action: PanicAction) {
+	match action {
+		PanicAction::Spin => {},
+		PanicAction::Shutdown => finisher.write32(0, FINISHER_PASS),
+		PanicAction::Reboot => finisher.write32(0, FINISHER_RESET),
+	}
 }
 
 // ///////////////////////////////////
 // / ENTRY POINT
 // ///////////////////////////////////
 #[no_mangle]
-extern "C" fn kinit() {
+extern "C" fn kinit(dtb: usize) -> ! {
 	// We created kinit, which runs in machine mode(3)
 	// The job of kinit() is to get us into supervisor mode
 	// as soon as possible.
 	// Interrupts are disabled for the duration of kinit()
-	uart::Uart::new(0x1000_0000).init();
-	page::init();
-	kmem::init();
-
-	// Map heap allocations
-	let root_ptr = kmem::get_page_table();
-	let root_u = root_ptr as usize;
-	let mut root = unsafe { root_ptr.as_mut().unwrap() };
-	let kheap_head = kmem::get_head() as usize;
-	let total_pages = kmem::get_num_allocations();
-	println!();
-	println!();
-	unsafe {
-		println!("TEXT:   0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
-		println!("RODATA: 0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
-		println!("DATA:   0x{:x} -> 0x{:x}", DATA_START, DATA_END);
-		println!("BSS:    0x{:x} -> 0x{:x}", BSS_START, BSS_END);
-		println!(
-		         "STACK:  0x{:x} -> 0x{:x}",
-		         KERNEL_STACK_START, KERNEL_STACK_END
-		);
-		println!(
-		         "HEAP:   0x{:x} -> 0x{:x}",
-		         kheap_head,
-		         kheap_head + total_pages * page::PAGE_SIZE
-		);
-	}
-	id_map_range(
-	             &mut root,
-	             kheap_head,
-	             kheap_head + total_pages * page::PAGE_SIZE,
-	             page::EntryBits::ReadWrite.val(),
-	);
-	// Using statics is inherently unsafe.
-	unsafe {
-		// Map heap descriptors
-		let num_pages = HEAP_SIZE / page::PAGE_SIZE;
-		id_map_range(
-		             &mut root,
-		             HEAP_START,
-		             HEAP_START + num_pages,
-		             page::EntryBits::ReadWrite.val(),
-		);
-		// Map executable section
-		id_map_range(
-		             &mut root,
-		             TEXT_START,
-		             TEXT_END,
-		             page::EntryBits::ReadExecute.val(),
-		);
-		// Map rodata section
-		// We put the ROdata section into the text section, so they can
-		// potentially overlap however, we only care that it's read
-		// only.
-		id_map_range(
-		             &mut root,
-		             RODATA_START,
-		             RODATA_END,
-		             page::EntryBits::ReadExecute.val(),
-		);
-		// Map data section
-		id_map_range(
-		             &mut root,
-		             DATA_START,
-		             DATA_END,
-		             page::EntryBits::ReadWrite.val(),
-		);
-		// Map bss section
-		id_map_range(
-		             &mut root,
-		             BSS_START,
-		             BSS_END,
-		             page::EntryBits::ReadWrite.val(),
-		);
-		// Map kernel stack
-		id_map_range(
-		             &mut root,
-		             KERNEL_STACK_START,
-		             KERNEL_STACK_END,
-		             page::EntryBits::ReadWrite.val(),
-		);
-	}
-
-	// UART
-	id_map_range(
-	             &mut root,
-	             0x1000_0000,
-	             0x1000_0100,
-	             page::EntryBits::ReadWrite.val(),
-	);
-
-	// CLINT
-	//  -> MSIP
-	id_map_range(
-	             &mut root,
-	             0x0200_0000,
-	             0x0200_ffff,
-	             page::EntryBits::ReadWrite.val(),
-	);
-	// PLIC
-	id_map_range(
-	             &mut root,
-	             0x0c00_0000,
-	             0x0c00_2001,
-	             page::EntryBits::ReadWrite.val(),
-	);
-	id_map_range(
-	             &mut root,
-	             0x0c20_0000,
-	             0x0c20_8001,
-	             page::EntryBits::ReadWrite.val(),
-	);
-	// When we return from here, we'll go back to boot.S and switch into
-	// supervisor mode We will return the SATP register to be written when
-	// we return. root_u is the root page table's address. When stored into
-	// the SATP register, this is divided by 4 KiB (right shift by 12 bits).
-	// We enable the MMU by setting mode 8. Bits 63, 62, 61, 60 determine
-	// the mode.
-	// 0 = Bare (no translation)
-	// 8 = Sv39
-	// 9 = Sv48
-	// build_satp has these parameters: mode, asid, page table address.
-	let satp_value = cpu::build_satp(cpu::SatpMode::Sv39, 0, root_u);
-	unsafe {
-		// We have to store the kernel's table. The tables will be moved
-		// back and forth between the kernel's table and user
-		// applicatons' tables. Note that we're writing the physical address
-		// of the trap frame.
-		cpu::mscratch_write(
-		                    (&mut cpu::KERNEL_TRAP_FRAME[0]
-		                     as *mut cpu::TrapFrame)
-		                    as usize,
-		);
-		cpu::sscratch_write(cpu::mscratch_read());
-		cpu::KERNEL_TRAP_FRAME[0].satp = satp_value;
-		// Move the stack pointer to the very bottom. The stack is
-		// actually in a non-mapped page. The stack is decrement-before
-		// push and increment after pop. Therefore, the stack will be
-		// allocated (decremented) before it is stored.
-		cpu::KERNEL_TRAP_FRAME[0].trap_stack =
-			page::zalloc(1).add(page::PAGE_SIZE);
-		id_map_range(
-		             &mut root,
-		             cpu::KERNEL_TRAP_FRAME[0].trap_stack
-		                                      .sub(page::PAGE_SIZE)
-		             as usize,
-		             cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize,
-		             page::EntryBits::ReadWrite.val()
-		);
-		// The trap frame itself is stored in the mscratch register.
-		id_map_range(
-		             &mut root,
-		             cpu::mscratch_read(),
-		             cpu::mscratch_read()
-		             + core::mem::size_of::<cpu::TrapFrame>(),
-		             page::EntryBits::ReadWrite.val()
-		);
-		page::print_page_allocations();
-		let p = cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize - 1;
-		let m = page::virt_to_phys(&root, p).unwrap_or(0);
-		println!("Walk 0x{:x} = 0x{:x}", p, m);
+	boot::set_dtb(dtb);
+	boot::run_or_halt(&boot::MACHINE_STEPS);
+	// Tests run here, still in machine mode, so that they can switch to
+	// processes with run_to_exit. They never come back.
+	#[cfg(test)]
+	{
+		boot::run_or_halt(&boot::SUPERVISOR_STEPS);
+		test_main();
 	}
-	// Set the satp and sfence.vma for MMU 
-	println!("Setting 0x{:x}", satp_value);
-	println!("Scratch reg = 0x{:x}", cpu::mscratch_read());
-	cpu::satp_write(satp_value);
-	cpu::satp_fence_asid(0);
+	// kmain starts on a fresh kernel stack, nothing of kinit's is needed
+	cpu::enter_supervisor(kmain as *const () as usize, boot::kernel_stack_end());
 }
 
 #[no_mangle]
@@ -312,17 +192,17 @@ extern "C" fn kinit_hart(hartid: usize) {
 }
 
 #[no_mangle]
-extern "C" fn kmain() {
+extern "C" fn kmain() -> ! {
 	// kmain() starts in supervisor mode. So, we should have the trap
 	// vector setup and the MMU turned on when we get here.
 
 	// We initialized my_uart in machine mode under kinit for debugging
 	// prints, but this just grabs a pointer to it.
-	let mut _my_uart = uart::Uart::new(0x1000_0000);
+	let mut _my_uart = uart::Uart::new(mmio::UART_BASE);
 
-	// Initialize the init process used by the kernel
-	let ret = process::init();
-	println!("Init process created at address 0x{:08x}", ret);
+	// Interrupts and the init process, see boot.rs
+	boot::run_or_halt(&boot::SUPERVISOR_STEPS);
+	boot::reclaim_init();
 
 	// Create a new scope so that we can test the global allocator and
 	// deallocator
@@ -345,42 +225,84 @@ extern "C" fn kmain() {
 	// If we get here, the Box, vec, and String should all be freed since
 	// they go out of scope. This calls their "Drop" trait.
 
-	println!("Setting up interrupts and PLIC...");
-	// Lower threshold to 0 to allow all interrupts
-	plic::set_threshold(0);
-	// UART is interrupt id 10. Set it's priority as well
-	plic::enable(10);
-	plic::set_priority(10, 1);
-	println!("UART interrupts have been enabled...");
+	// "bench" on the command line, or "bench=<rounds>", times the
+	// allocators while nothing else is using them
+	if let Some(rounds) = bootargs::get("bench") {
+		bench::print_alloc_bench(rounds.parse().unwrap_or(bench::DEFAULT_ITERATIONS));
+	}
 
 	println!("Getting ready for first process.");
-	println!("Issuing the first context-switch timer.");
-	unsafe {
-		let mtimecmp = 0x0200_4000 as *mut u64;
-		let mtime = 0x0200_bff8 as *const u64;
-		// The frequency given by QEMU is 10_000_000 Hz, so this sets
-		// the next interrupt to fire one second from now.
-		mtimecmp.write_volatile(mtime.read_volatile() + 1_000_000);
-	}
-	let (frame, mepc, satp) = scheduler::schedule();
-	unsafe {
-		switch_to_user(frame, mepc, satp);
+	if scheduler::runnable_count() == 0 {
+		panic!("No process to start, the process list is empty");
 	}
-	
-	// switch_to_user will not return, so we should never get here
-	println!("WE DIDN'T SCHEDULE?! THIS ISN'T RIGHT!");
+	// kmain runs on hart 0, the only one boot.S lets past kinit
+	println!("Issuing the first context-switch timer.");
+	// kmain runs in supervisor mode and can't switch to a process itself,
+	// switch_to_user writes machine mode registers. The timer interrupt
+	// traps into machine mode, schedules the first process and switches
+	// to it, so we only have to wait for it here.
+	cpu::schedule_timer_in(0, scheduler::quantum_ms());
+	cpu::park();
 }
 
 // ///////////////////////////////////
 // / RUST MODULES
 // ///////////////////////////////////
 
+pub mod backtrace;
+pub mod bench;
+pub mod boot;
+pub mod bootargs;
+pub mod clint;
+pub mod console;
 pub mod cpu;
+pub mod elf;
+pub mod error;
+pub mod fd;
+pub mod fdt;
 pub mod kmem;
+pub mod lock;
+pub mod log;
+pub mod mem;
+pub mod mmio;
 pub mod page;
+pub mod pipe;
 pub mod trap;
 pub mod uart;
 pub mod plic;
 pub mod process;
+pub mod ramfs;
+pub mod rng;
 pub mod syscall;
-pub mod scheduler;
\ No newline at end of file
+pub mod scheduler;
+pub mod slab;
+pub mod sync;
+#[cfg(test)]
+pub mod test;
+pub mod timer;
+pub mod vm;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test_case]
+	fn reboot_action_writes_the_reset_code() {
+		let mut finisher = [0u32; 1];
+		let mmio = mmio::Mmio::new(finisher.as_mut_ptr() as usize);
+		finish(&mmio, PanicAction::Spin);
+		assert_eq!(finisher[0], 0);
+		finish(&mmio, PanicAction::Reboot);
+		assert_eq!(finisher[0], FINISHER_RESET);
+		finish(&mmio, PanicAction::Shutdown);
+		assert_eq!(finisher[0], FINISHER_PASS);
+
+		// The handler goes by whatever was last set
+		let old = panic_action();
+		set_panic_action(PanicAction::Reboot);
+		mmio.write32(0, 0);
+		finish(&mmio, panic_action());
+		assert_eq!(finisher[0], FINISHER_RESET);
+		set_panic_action(old);
+	}
+}
@@ -0,0 +1,102 @@
+// Memory mapped IO registers
+// Devices like the UART and PLIC are controlled by reading and writing
+// to fixed physical addresses. Mmio wraps a device's base address so that
+// registers can be accessed by their offset, keeping the volatile pointer
+// casts in one place.
+
+#[derive(Clone, Copy)]
+pub struct Mmio {
+    base: usize
+}
+
+impl Mmio {
+    pub const fn new(base: usize) -> Self {
+        Mmio {
+            base
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    // Read a byte wide register at base + offset
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { ((self.base + offset) as *const u8).read_volatile() }
+    }
+
+    // Write a byte wide register at base + offset
+    pub fn write8(&self, offset: usize, val: u8) {
+        unsafe { ((self.base + offset) as *mut u8).write_volatile(val) }
+    }
+
+    // Read a 32 bit register at base + offset
+    // The offset is in bytes, not in words, so register maps can be
+    // written exactly as they appear in the device's specification.
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    // Write a 32 bit register at base + offset
+    pub fn write32(&self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+
+    // Read a 64 bit register at base + offset
+    pub fn read64(&self, offset: usize) -> u64 {
+        unsafe { ((self.base + offset) as *const u64).read_volatile() }
+    }
+
+    // Write a 64 bit register at base + offset
+    pub fn write64(&self, offset: usize, val: u64) {
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(val) }
+    }
+}
+
+// Where QEMU's virt machine puts the devices we drive
+pub const UART_BASE: usize = 0x1000_0000;
+pub const CLINT_BASE: usize = 0x0200_0000;
+pub const PLIC_BASE: usize = 0x0c00_0000;
+
+// A range of device registers the kernel maps
+pub struct MmioRegion {
+    pub name: &'static str,
+    pub base: usize,
+    pub size: usize
+}
+
+// Every device range the kernel maps. The kernel page table and the
+// memory map printout are both built from this, so they always agree.
+pub const MMIO_REGIONS: [MmioRegion; 5] = [
+    // QEMU test finisher, so a panic in supervisor mode can still shut
+    // down or reset the machine
+    MmioRegion { name: "MMIO-TEST", base: crate::TEST_FINISHER, size: 0x1000 },
+    // CLINT, MSIP and the timer registers
+    MmioRegion { name: "MMIO-CLINT", base: CLINT_BASE, size: 0x1_0000 },
+    // PLIC priorities, pending bits and hart 0's enables
+    MmioRegion { name: "MMIO-PLIC", base: PLIC_BASE, size: 0x2001 },
+    // PLIC threshold and claim/complete registers for hart 0's contexts
+    MmioRegion { name: "MMIO-PLIC", base: PLIC_BASE + 0x20_0000, size: 0x8001 },
+    MmioRegion { name: "MMIO-UART", base: UART_BASE, size: 0x100 },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn registers_are_at_byte_offsets() {
+        // u64s, so the array is aligned for every access size
+        let mut backing = [0u64; 2];
+        let regs = Mmio::new(backing.as_mut_ptr() as usize);
+        regs.write32(4, 0x1122_3344);
+        assert_eq!(backing[0], 0x1122_3344_0000_0000);
+        assert_eq!(regs.read8(4), 0x44);
+        assert_eq!(regs.read8(7), 0x11);
+        regs.write8(0, 0xaa);
+        assert_eq!(regs.read32(0), 0xaa);
+        regs.write64(8, 0x0102_0304_0506_0708);
+        assert_eq!(regs.read32(12), 0x0102_0304);
+        assert_eq!(regs.read64(8), backing[1]);
+    }
+}
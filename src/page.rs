@@ -1,6 +1,9 @@
 // Page allocator
 
-use core::{mem::size_of, ptr::null_mut};
+use alloc::{vec, vec::Vec};
+use core::{fmt::{self, Write}, mem::size_of, ptr::null_mut, sync::atomic::{AtomicUsize, Ordering}};
+
+use crate::error::KernelError;
 
 // ////////////////////////////////
 // // Allocation routines
@@ -8,12 +11,29 @@ use core::{mem::size_of, ptr::null_mut};
 extern "C" {
 	static HEAP_START: usize;
 	static HEAP_SIZE: usize;
+	static TEXT_START: usize;
+	static RODATA_END: usize;
+	static MEMORY_START: usize;
+	static MEMORY_END: usize;
 }
 
 // We will use ALLOC_START to mark the start of the actual
 // memory we can dish out.
 static mut ALLOC_START: usize = 0;
+// Number of pages currently handed out by alloc() and not yet returned
+// by dealloc(), so the free count doesn't need a scan of every Page.
+static ALLOCATED_PAGES: AtomicUsize = AtomicUsize::new(0);
+// How many of those pages are intermediate page tables made by map().
+// Root tables belong to whoever created them and aren't counted here.
+static PAGE_TABLE_PAGES: AtomicUsize = AtomicUsize::new(0);
+// The most pages ALLOCATED_PAGES has ever been
+static PAGES_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+// Pages set aside by reserve(). They aren't free, but they aren't
+// counted as allocated either.
+static RESERVED_PAGES: AtomicUsize = AtomicUsize::new(0);
 const PAGE_ORDER: usize = 12;
+// Sv39 physical addresses are 56 bits wide
+const PHYS_ADDR_BITS: usize = 56;
 pub const PAGE_SIZE: usize = 1 << 12;
 
 /// Align (set to a multiple of some power of two)
@@ -25,11 +45,20 @@ pub const fn align_val(val: usize, order: usize) -> usize {
 	(val + o) & !o
 }
 
+/// Number of bytes covered by a number of pages.
+/// This is computed in a u128 so that byte totals for a very large heap
+/// can't wrap around, which is what we want when printing them.
+pub const fn pages_to_bytes(pages: usize) -> u128 {
+	pages as u128 * PAGE_SIZE as u128
+}
+
 #[repr(u8)]
 pub enum PageBits {
 	Empty = 0,
 	Taken = 1 << 0,
 	Last = 1 << 1,
+	// Taken by reserve(), never handed out or freed
+	Reserved = 1 << 2,
 }
 
 impl PageBits {
@@ -75,6 +104,11 @@ impl Page {
 		!self.is_taken()
 	}
 
+	// Reserved pages are also taken, so alloc() passes over them
+	pub fn is_reserved(&self) -> bool {
+		self.flags & PageBits::Reserved.val() != 0
+	}
+
 	// Clear the Page structure and all associated allocations.
 	pub fn clear(&mut self) {
 		self.flags = PageBits::Empty.val();
@@ -92,9 +126,47 @@ impl Page {
 	}
 }
 
+/// Ways the heap the linker script gives us can be unusable, see
+/// heap_layout()
+#[derive(Debug, PartialEq)]
+pub enum HeapError {
+	// HEAP_START isn't on a page boundary
+	Unaligned { start: usize },
+	// The heap can't hold its Page structures plus one page to hand out
+	TooSmall { size: usize, needed: usize },
+}
+
+/// Check a heap at start..start + size and return where its first
+/// allocatable page is, which is ALLOC_START for the real heap. The Page
+/// structures take up the start of the heap, one for every page in it.
+pub fn heap_layout(start: usize, size: usize) -> Result<usize, HeapError> {
+	if start & (PAGE_SIZE - 1) != 0 {
+		return Err(HeapError::Unaligned { start });
+	}
+	let num_pages = size / PAGE_SIZE;
+	// ALLOC_START = (HEAP_START + num_pages * size_of::<Page>() + PAGE_SIZE - 1)
+	// & !(PAGE_SIZE - 1);
+	let alloc_start = align_val(start + num_pages * size_of::<Page>(), PAGE_ORDER);
+	let needed = alloc_start - start + PAGE_SIZE;
+	if size < needed {
+		return Err(HeapError::TooSmall { size, needed });
+	}
+	Ok(alloc_start)
+}
+
 /// Initialize the allocation system.
+/// Panics if HEAP_START and HEAP_SIZE from the linker script don't make a
+/// usable heap, the allocator would hand out memory that isn't there.
 pub fn init() {
 	unsafe {
+		// Determine where the actual useful memory starts. This will be
+		// after all Page structures. We also must align the ALLOC_START
+		// to a page-boundary (PAGE_SIZE = 4096).
+		let alloc_start = match heap_layout(HEAP_START, HEAP_SIZE) {
+			Ok(addr) => addr,
+			Err(e) => panic!("Bad heap in the linker script, HEAP_START 0x{:x} HEAP_SIZE 0x{:x}: {:?}",
+			                 HEAP_START, HEAP_SIZE, e),
+		};
 		// let desc_per_page = PAGE_SIZE / size_of::<Page>();
 		let num_pages = HEAP_SIZE / PAGE_SIZE;
 		// let num_desc_pages = num_pages / desc_per_page;
@@ -104,42 +176,106 @@ pub fn init() {
 		for i in 0..num_pages {
 			(*ptr.add(i)).clear();
 		}
-		// Determine where the actual useful memory starts. This will be
-		// after all Page structures. We also must align the ALLOC_START
-		// to a page-boundary (PAGE_SIZE = 4096). ALLOC_START =
-		// (HEAP_START + num_pages * size_of::<Page>() + PAGE_SIZE - 1)
-		// & !(PAGE_SIZE - 1);
-		ALLOC_START = align_val(
-		                        HEAP_START
-		                        + num_pages * size_of::<Page>(),
-		                        PAGE_ORDER,
-		);
+		ALLOCATED_PAGES.store(0, Ordering::Relaxed);
+		PAGE_TABLE_PAGES.store(0, Ordering::Relaxed);
+		PAGES_HIGH_WATER.store(0, Ordering::Relaxed);
+		RESERVED_PAGES.store(0, Ordering::Relaxed);
+		ALLOC_START = alloc_start;
 	}
 }
 
+/// Number of pages alloc() manages, from ALLOC_START to the end of the
+/// heap. The Page structures take up the start of the heap, so there are
+/// a few more of them than this and the ones past it are never used.
+pub fn page_count() -> usize {
+	unsafe { (HEAP_SIZE - (ALLOC_START - HEAP_START)) / PAGE_SIZE }
+}
+
+/// Whether paddr is in a page alloc() manages
+pub fn in_range(paddr: usize) -> bool {
+	unsafe { paddr >= ALLOC_START && paddr - ALLOC_START < page_count() * PAGE_SIZE }
+}
+
+/// Index of the page paddr is in. paddr must be in_range().
+pub fn index_of(paddr: usize) -> usize {
+	debug_assert!(in_range(paddr), "0x{:x} isn't a page the allocator manages", paddr);
+	unsafe { (paddr - ALLOC_START) / PAGE_SIZE }
+}
+
+/// Address of the start of page idx
+pub fn addr_of_index(idx: usize) -> usize {
+	unsafe { ALLOC_START + idx * PAGE_SIZE }
+}
+
+/// The Page structure that describes page idx. There is one Page per
+/// page, in an array at HEAP_START.
+pub fn meta_of_index(idx: usize) -> *mut Page {
+	unsafe { (HEAP_START as *mut Page).add(idx) }
+}
+
 /// Allocate a page or multiple pages
 /// pages: the number of PAGE_SIZE pages to allocate
 pub fn alloc(pages: usize) -> *mut u8 {
 	// We have to find a contiguous allocation of pages
 	assert!(pages > 0);
+	match find_run(pages, None) {
+		Some(i) => take_run(i, pages),
+		// If we get here, that means that no contiguous allocation was
+		// found.
+		None => null_mut(),
+	}
+}
+
+// Number of cache colors alloc_colored() sorts pages into
+static PAGE_COLORS: AtomicUsize = AtomicUsize::new(8);
+
+pub fn page_colors() -> usize {
+	PAGE_COLORS.load(Ordering::Relaxed)
+}
+
+/// Set how many cache colors there are. A page's color is its index,
+/// see index_of(), modulo this. 0 is taken as 1, every page the same
+/// color.
+pub fn set_page_colors(colors: usize) {
+	PAGE_COLORS.store(colors.max(1), Ordering::Relaxed);
+}
+
+/// Like alloc(), but prefer a run whose first page has the given color,
+/// so that things used together don't fight over the same cache sets.
+/// Any run will do if there is none of that color.
+pub fn alloc_colored(pages: usize, color: usize) -> *mut u8 {
+	assert!(pages > 0);
+	let color = color % page_colors();
+	match find_run(pages, Some(color)).or_else(|| find_run(pages, None)) {
+		Some(i) => take_run(i, pages),
+		None => null_mut(),
+	}
+}
+
+// Find pages free pages in a row, starting on a page of color if one is
+// given. Returns the index of the first page.
+fn find_run(pages: usize, color: Option<usize>) -> Option<usize> {
 	unsafe {
-		// We create a Page structure for each page on the heap. We
-		// actually might have more since HEAP_SIZE moves and so does
-		// the size of our structure, but we'll only waste a few bytes.
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
-		let ptr = HEAP_START as *mut Page;
-		for i in 0..num_pages - pages {
+		// Asking for more pages than there are at all can never be
+		// satisfied, and would underflow the search bound below.
+		let last = page_count().checked_sub(pages)?;
+		for i in 0..=last {
+			if let Some(color) = color {
+				if i % page_colors() != color {
+					continue;
+				}
+			}
 			let mut found = false;
 			// Check to see if this Page is free. If so, we have our
 			// first candidate memory address.
-			if (*ptr.add(i)).is_free() {
+			if (*meta_of_index(i)).is_free() {
 				found = true;
 				for j in i..i + pages {
 					// Now check to see if we have a
 					// contiguous allocation for all of the
 					// request pages. If not, we should
 					// check somewhere else.
-					if (*ptr.add(j)).is_taken() {
+					if (*meta_of_index(j)).is_taken() {
 						found = false;
 						break;
 					}
@@ -150,27 +286,33 @@ pub fn alloc(pages: usize) -> *mut u8 {
 			// will be false, otherwise it will be true, which means
 			// we've found valid memory we can allocate.
 			if found {
-				for k in i..i + pages - 1 {
-					(*ptr.add(k)).set_flag(PageBits::Taken);
-				}
-				// The marker for the last page is
-				// PageBits::Last This lets us know when we've
-				// hit the end of this particular allocation.
-				(*ptr.add(i+pages-1)).set_flag(PageBits::Taken);
-				(*ptr.add(i+pages-1)).set_flag(PageBits::Last);
-				// The Page structures themselves aren't the
-				// useful memory. Instead, there is 1 Page
-				// structure per 4096 bytes starting at
-				// ALLOC_START.
-				return (ALLOC_START + PAGE_SIZE * i)
-				       as *mut u8;
+				return Some(i);
 			}
 		}
 	}
+	None
+}
 
-	// If we get here, that means that no contiguous allocation was
-	// found.
-	null_mut()
+// Mark the pages free pages starting at index i taken and return the
+// address of the first
+fn take_run(i: usize, pages: usize) -> *mut u8 {
+	unsafe {
+		for k in i..i + pages - 1 {
+			(*meta_of_index(k)).set_flag(PageBits::Taken);
+		}
+		// The marker for the last page is
+		// PageBits::Last This lets us know when we've
+		// hit the end of this particular allocation.
+		(*meta_of_index(i+pages-1)).set_flag(PageBits::Taken);
+		(*meta_of_index(i+pages-1)).set_flag(PageBits::Last);
+		let allocated = ALLOCATED_PAGES.fetch_add(pages, Ordering::Relaxed) + pages;
+		PAGES_HIGH_WATER.fetch_max(allocated, Ordering::Relaxed);
+		// The Page structures themselves aren't the
+		// useful memory. Instead, there is 1 Page
+		// structure per 4096 bytes starting at
+		// ALLOC_START.
+		addr_of_index(i) as *mut u8
+	}
 }
 
 /// Allocate and zero a page or multiple pages
@@ -202,20 +344,25 @@ pub fn zalloc(pages: usize) -> *mut u8 {
 /// Deallocate a page by its pointer
 /// The way we've structured this, it will automatically coalesce
 /// contiguous pages.
-pub fn dealloc(ptr: *mut u8) {
+/// Returns the number of pages that were freed.
+pub fn dealloc(ptr: *mut u8) -> usize {
 	// Make sure we don't try to free a null pointer.
 	assert!(!ptr.is_null());
+	// Make sure that the address is one of ours before looking up the
+	// Page structure that describes it
+	assert!(in_range(ptr as usize), "dealloc of {:p}, which isn't a heap page", ptr);
 	unsafe {
-		let addr =
-			HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
-		// Make sure that the address makes sense. The address we
-		// calculate here is the page structure, not the HEAP address!
-		assert!(addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE);
-		let mut p = addr as *mut Page;
+		let mut p = meta_of_index(index_of(ptr as usize));
+		if (*p).is_reserved() {
+			println!("dealloc: refusing to free reserved page {:p}", ptr);
+			return 0;
+		}
+		let mut freed = 1;
 		// Keep clearing pages until we hit the last page.
 		while (*p).is_taken() && !(*p).is_last() {
 			(*p).clear();
 			p = p.add(1);
+			freed += 1;
 		}
 		// If the following assertion fails, it is most likely
 		// caused by a double-free.
@@ -227,6 +374,188 @@ pub fn dealloc(ptr: *mut u8) {
 		// If we get here, we've taken care of all previous pages and
 		// we are on the last page.
 		(*p).clear();
+		ALLOCATED_PAGES.fetch_sub(freed, Ordering::Relaxed);
+		freed
+	}
+}
+
+/// Mark the pages covering start..end as one allocation, for memory that
+/// is already in use when the allocator starts, like the kernel's init
+/// code. They can be handed back later with dealloc_range().
+/// Returns the number of pages claimed, 0 if the range isn't all free
+/// pages that the allocator manages.
+pub fn claim_range(start: usize, end: usize) -> usize {
+	let first = start & !(PAGE_SIZE - 1);
+	let pages = (align_val(end, PAGE_ORDER) - first) / PAGE_SIZE;
+	unsafe {
+		if pages == 0 || !in_range(first) {
+			return 0;
+		}
+		let i = index_of(first);
+		if i + pages > page_count() {
+			return 0;
+		}
+		if (i..i + pages).any(|j| (*meta_of_index(j)).is_taken()) {
+			return 0;
+		}
+		for j in i..i + pages {
+			(*meta_of_index(j)).set_flag(PageBits::Taken);
+		}
+		(*meta_of_index(i + pages - 1)).set_flag(PageBits::Last);
+		let allocated = ALLOCATED_PAGES.fetch_add(pages, Ordering::Relaxed) + pages;
+		PAGES_HIGH_WATER.fetch_max(allocated, Ordering::Relaxed);
+	}
+	pages
+}
+
+/// Take the pages covering start..end away from the allocator for good,
+/// for memory something else owns, like a framebuffer, a DMA buffer or a
+/// range the bootloader said to stay out of. Call this during init,
+/// before the pages could have been handed out.
+/// alloc() never returns reserved pages and dealloc() refuses them.
+/// Returns the number of pages reserved, 0 if the range isn't all free
+/// pages that the allocator manages.
+pub fn reserve(start: usize, end: usize) -> usize {
+	let first = start & !(PAGE_SIZE - 1);
+	let pages = (align_val(end, PAGE_ORDER) - first) / PAGE_SIZE;
+	unsafe {
+		if pages == 0 || !in_range(first) {
+			return 0;
+		}
+		let i = index_of(first);
+		if i + pages > page_count() {
+			return 0;
+		}
+		if (i..i + pages).any(|j| (*meta_of_index(j)).is_taken()) {
+			return 0;
+		}
+		for j in i..i + pages {
+			(*meta_of_index(j)).set_flag(PageBits::Taken);
+			(*meta_of_index(j)).set_flag(PageBits::Reserved);
+		}
+		(*meta_of_index(i + pages - 1)).set_flag(PageBits::Last);
+		RESERVED_PAGES.fetch_add(pages, Ordering::Relaxed);
+	}
+	pages
+}
+
+/// Number of pages reserve() has taken
+pub fn reserved_page_count() -> usize {
+	RESERVED_PAGES.load(Ordering::Relaxed)
+}
+
+/// Free every allocation in start..end. The range should cover whole
+/// allocations, one that begins before start is freed from start on and
+/// its first part stays allocated.
+/// Returns the number of pages freed.
+pub fn dealloc_range(start: usize, end: usize) -> usize {
+	let mut freed = 0;
+	let mut addr = start & !(PAGE_SIZE - 1);
+	// What is left of an allocation that begins before start needs a
+	// Last page of its own, or freeing it later runs into our free pages
+	if is_allocated(addr) && !is_reserved(addr) && index_of(addr) > 0 {
+		unsafe {
+			let before = meta_of_index(index_of(addr) - 1);
+			if (*before).is_taken() && !(*before).is_last() && !(*before).is_reserved() {
+				(*before).set_flag(PageBits::Last);
+			}
+		}
+	}
+	while addr < end {
+		// dealloc() frees nothing of a reserved range
+		if is_allocated(addr) && !is_reserved(addr) {
+			let pages = dealloc(addr as *mut u8);
+			freed += pages;
+			addr += pages * PAGE_SIZE;
+		}
+		else {
+			addr += PAGE_SIZE;
+		}
+	}
+	freed
+}
+
+/// Number of pages that are currently allocated
+pub fn allocated_page_count() -> usize {
+	ALLOCATED_PAGES.load(Ordering::Relaxed)
+}
+
+/// The most pages that have ever been allocated at once
+pub fn high_water() -> usize {
+	PAGES_HIGH_WATER.load(Ordering::Relaxed)
+}
+
+/// Number of allocated pages that hold intermediate page tables
+pub fn page_table_page_count() -> usize {
+	PAGE_TABLE_PAGES.load(Ordering::Relaxed)
+}
+
+/// Number of pages that are still free to allocate
+pub fn free_page_count() -> usize {
+	page_count() - allocated_page_count() - reserved_page_count()
+}
+
+/// The page allocator's counts, which the meminfo syscall hands to user
+/// space. It is repr(C) with only u64 fields, so it is part of the
+/// syscall ABI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemInfo {
+	pub total_pages:      u64,
+	pub free_pages:       u64,
+	pub allocated_pages:  u64,
+	// Of the allocated pages, the ones holding page tables
+	pub page_table_pages: u64,
+	pub reserved_pages:   u64,
+	pub high_water:       u64,
+	// Bytes of the kernel heap in use, see kmem::used()
+	pub kmem_used:        u64,
+}
+
+/// The counts as they are right now
+pub fn meminfo() -> MemInfo {
+	MemInfo { total_pages:      page_count() as u64,
+	          free_pages:       free_page_count() as u64,
+	          allocated_pages:  allocated_page_count() as u64,
+	          page_table_pages: page_table_page_count() as u64,
+	          reserved_pages:   reserved_page_count() as u64,
+	          high_water:       high_water() as u64,
+	          kmem_used:        crate::kmem::used() as u64, }
+}
+
+/// A range of the physical address space and what it is used for
+pub struct MemRegion {
+	pub kind: &'static str,
+	pub base: usize,
+	pub size: usize,
+}
+
+/// Every range of physical memory the kernel knows about, sorted by
+/// address: the kernel image, the Page structures, the pages alloc()
+/// hands out and the devices from mmio::MMIO_REGIONS.
+pub fn memory_map() -> Vec<MemRegion> {
+	let mut map = Vec::new();
+	unsafe {
+		map.push(MemRegion { kind: "Image", base: MEMORY_START, size: HEAP_START - MEMORY_START });
+		map.push(MemRegion { kind: "PageMeta", base: HEAP_START, size: ALLOC_START - HEAP_START });
+		map.push(MemRegion { kind: "Heap", base: ALLOC_START, size: MEMORY_END - ALLOC_START });
+	}
+	for region in crate::mmio::MMIO_REGIONS.iter() {
+		map.push(MemRegion { kind: region.name, base: region.base, size: region.size });
+	}
+	map.sort_unstable_by_key(|r| r.base);
+	map
+}
+
+/// Print the physical memory map, see memory_map()
+pub fn print_memory_map() {
+	println!("PHYSICAL MEMORY MAP");
+	for region in memory_map() {
+		println!("0x{:016x} -> 0x{:016x} {:>10} bytes  {}",
+		         region.base,
+		         region.base + region.size,
+		         region.size,
+		         region.kind);
 	}
 }
 
@@ -234,58 +563,72 @@ pub fn dealloc(ptr: *mut u8) {
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
 	unsafe {
-		let num_pages = (HEAP_SIZE - (ALLOC_START - HEAP_START)) / PAGE_SIZE;
-		let mut beg = HEAP_START as *const Page;
-		let end = beg.add(num_pages);
-		let alloc_beg = ALLOC_START;
-		let alloc_end = ALLOC_START + num_pages * PAGE_SIZE;
+		let num_pages = page_count();
+		let alloc_beg = addr_of_index(0);
+		let alloc_end = addr_of_index(num_pages);
 		println!();
 		println!(
 		         "PAGE ALLOCATION TABLE\nMETA: {:p} -> {:p}\nPHYS: \
 		          0x{:x} -> 0x{:x}",
-		         beg, end, alloc_beg, alloc_end
+		         meta_of_index(0), meta_of_index(num_pages), alloc_beg, alloc_end
 		);
 		println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
 		let mut num = 0;
-		while beg < end {
-			if (*beg).is_taken() {
-				let start = beg as usize;
-				let memaddr = ALLOC_START
-				              + (start - HEAP_START)
-				                * PAGE_SIZE;
-				print!("0x{:x} => ", memaddr);
-				loop {
-					num += 1;
-					if (*beg).is_last() {
-						let end = beg as usize;
-						let memaddr = ALLOC_START
-						              + (end
-						                 - HEAP_START)
-						                * PAGE_SIZE
-						              + PAGE_SIZE - 1;
+		let mut reserved = 0;
+		let mut i = 0;
+		while i < num_pages {
+			if (*meta_of_index(i)).is_taken() {
+				let start = i;
+				let is_reserved = (*meta_of_index(i)).is_reserved();
+				print!("0x{:x} => ", addr_of_index(start));
+				// A run missing its Last marker stops at the end of the
+				// table rather than reading past it
+				while i < num_pages {
+					if is_reserved {
+						reserved += 1;
+					}
+					else {
+						num += 1;
+					}
+					if (*meta_of_index(i)).is_last() || i + 1 == num_pages {
 						print!(
 						       "0x{:x}: {:>3} page(s)",
-						       memaddr,
-						       (end - start + 1)
+						       addr_of_index(i + 1) - 1,
+						       (i - start + 1)
 						);
-						println!(".");
+						println!("{}", if is_reserved { ", reserved." } else { "." });
 						break;
 					}
-					beg = beg.add(1);
+					i += 1;
 				}
 			}
-			beg = beg.add(1);
+			i += 1;
 		}
 		println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
 		println!(
 		         "Allocated: {:>6} pages ({:>10} bytes).",
 		         num,
-		         num * PAGE_SIZE
+		         pages_to_bytes(num)
+		);
+		println!(
+		         "Reserved : {:>6} pages ({:>10} bytes).",
+		         reserved,
+		         pages_to_bytes(reserved)
 		);
 		println!(
 		         "Free     : {:>6} pages ({:>10} bytes).",
-		         num_pages - num,
-		         (num_pages - num) * PAGE_SIZE
+		         num_pages - num - reserved,
+		         pages_to_bytes(num_pages - num - reserved)
+		);
+		println!(
+		         "Tables   : {:>6} pages ({:>10} bytes) of the allocated.",
+		         page_table_page_count(),
+		         pages_to_bytes(page_table_page_count())
+		);
+		println!(
+		         "Peak     : {:>6} pages ({:>10} bytes).",
+		         high_water(),
+		         pages_to_bytes(high_water())
 		);
 		println!();
 	}
@@ -295,6 +638,102 @@ pub fn print_page_allocations() {
 // // MMU Routines
 // ////////////////////////////////
 
+// Physical and virtual addresses are both plain numbers, so they get
+// their own types to keep one from being passed where the other belongs.
+// Converting between them is always explicit.
+
+/// A physical address
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PhysAddr(pub usize);
+
+/// A virtual address
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct VirtAddr(pub usize);
+
+impl PhysAddr {
+	/// The physical page number (the address without the page offset)
+	pub fn page(self) -> usize {
+		self.0 >> PAGE_ORDER
+	}
+
+	/// The offset into the page
+	pub fn offset(self) -> usize {
+		self.0 & (PAGE_SIZE - 1)
+	}
+
+	/// The Sv39 PPN fields. PPN[0] and PPN[1] are 9 bits, but PPN[2]
+	/// is 26 bits.
+	pub fn ppn(self) -> [usize; 3] {
+		[
+		 // PPN[0] = paddr[20:12]
+		 (self.0 >> 12) & 0x1ff,
+		 // PPN[1] = paddr[29:21]
+		 (self.0 >> 21) & 0x1ff,
+		 // PPN[2] = paddr[55:30]
+		 (self.0 >> 30) & 0x3ff_ffff,
+		]
+	}
+
+	/// The virtual address that maps to this one under an identity
+	/// mapping, which is how the kernel maps itself.
+	pub fn to_identity_virt(self) -> VirtAddr {
+		VirtAddr(self.0)
+	}
+}
+
+impl VirtAddr {
+	/// The virtual page number (the address without the page offset)
+	pub fn page(self) -> usize {
+		self.0 >> PAGE_ORDER
+	}
+
+	/// The offset into the page
+	pub fn offset(self) -> usize {
+		self.0 & (PAGE_SIZE - 1)
+	}
+
+	/// The Sv39 VPN fields, each 9 bits.
+	pub fn vpn(self) -> [usize; 3] {
+		[
+		 // VPN[0] = vaddr[20:12]
+		 (self.0 >> 12) & 0x1ff,
+		 // VPN[1] = vaddr[29:21]
+		 (self.0 >> 21) & 0x1ff,
+		 // VPN[2] = vaddr[38:30]
+		 (self.0 >> 30) & 0x1ff,
+		]
+	}
+
+	/// The physical address this maps to under an identity mapping.
+	pub fn to_identity_phys(self) -> PhysAddr {
+		PhysAddr(self.0)
+	}
+}
+
+impl From<usize> for PhysAddr {
+	fn from(addr: usize) -> Self {
+		PhysAddr(addr)
+	}
+}
+
+impl From<PhysAddr> for usize {
+	fn from(addr: PhysAddr) -> Self {
+		addr.0
+	}
+}
+
+impl From<usize> for VirtAddr {
+	fn from(addr: usize) -> Self {
+		VirtAddr(addr)
+	}
+}
+
+impl From<VirtAddr> for usize {
+	fn from(addr: VirtAddr) -> Self {
+		addr.0
+	}
+}
+
 // Represent (repr) our entry bits as
 // unsigned 64-bit integers.
 #[repr(i64)]
@@ -330,6 +769,24 @@ impl EntryBits {
 	}
 }
 
+// The two bits of an entry (RSW, bits 8 and 9) that the hardware ignores
+// and leaves for the kernel. Setting or clearing them never changes what
+// the MMU does with the entry.
+#[repr(i64)]
+#[derive(Copy, Clone)]
+pub enum SwBits {
+	// The page is shared copy-on-write, a write fault should copy it
+	Cow = 1 << 8,
+	// Nothing is behind the page yet, a fault should allocate it
+	Lazy = 1 << 9,
+}
+
+impl SwBits {
+	pub fn val(self) -> i64 {
+		self as i64
+	}
+}
+
 // A single entry. We're using an i64 so that
 // this will sign-extend rather than zero-extend
 // since RISC-V requires that the reserved sections
@@ -367,6 +824,20 @@ impl Entry {
 	pub fn get_entry(&self) -> i64 {
 		self.entry
 	}
+
+	// The software bits only touch bits 8 and 9, the hardware flags and
+	// the PPN stay as they are.
+	pub fn set_sw(&mut self, bit: SwBits) {
+		self.entry |= bit.val();
+	}
+
+	pub fn clear_sw(&mut self, bit: SwBits) {
+		self.entry &= !bit.val();
+	}
+
+	pub fn has_sw(&self, bit: SwBits) -> bool {
+		self.entry & bit.val() != 0
+	}
 }
 
 // Table represents a single table, which contains 512 (2^9), 64-bit entries.
@@ -391,42 +862,100 @@ impl Table {
 ///       The bits MUST include one or more of the following:
 ///          Read, Write, Execute
 ///       The valid bit automatically gets added.
+/// This panics if a page table can't be allocated, see try_map().
 pub fn map(root: &mut Table,
-           vaddr: usize,
-           paddr: usize,
+           vaddr: VirtAddr,
+           paddr: PhysAddr,
            bits: i64,
            level: usize)
+{
+	if let Err(e) = try_map(root, vaddr, paddr, bits, level) {
+		panic!("Unable to map 0x{:x} -> 0x{:x}: {:?}", vaddr.0, paddr.0, e);
+	}
+}
+
+/// Same as map(), but returns an error instead of panicking.
+/// OutOfMemory: there was no free page for an intermediate page table.
+/// Any tables allocated before the failure are freed again, so the tree
+/// is left as it was.
+/// InvalidAddress: the physical address doesn't fit in Sv39's 56-bit
+/// physical address space, the mapping would cover the null page, or
+/// vaddr is already mapped with a page of another size. A leaf at the
+/// same level is simply replaced, but a mapping never splits a bigger
+/// page or swallows a table of smaller ones, unmap those first.
+/// The first page of every address space is left unmapped so that a
+/// null dereference faults, so mapping vaddr 0 is refused in debug
+/// builds. Use try_map_null() when that is really what's wanted.
+pub fn try_map(root: &mut Table,
+               vaddr: VirtAddr,
+               paddr: PhysAddr,
+               bits: i64,
+               level: usize)
+               -> Result<(), KernelError>
+{
+	map_checked(root, vaddr, paddr, bits, level, false)
+}
+
+/// Same as try_map(), but allowed to map the null page. Nothing in the
+/// kernel does this, it's for a caller that has to put something at
+/// address 0 on purpose.
+pub fn try_map_null(root: &mut Table,
+                    vaddr: VirtAddr,
+                    paddr: PhysAddr,
+                    bits: i64,
+                    level: usize)
+                    -> Result<(), KernelError>
+{
+	map_checked(root, vaddr, paddr, bits, level, true)
+}
+
+fn map_checked(root: &mut Table,
+               vaddr: VirtAddr,
+               paddr: PhysAddr,
+               bits: i64,
+               level: usize,
+               allow_null: bool)
+               -> Result<(), KernelError>
 {
 	// Make sure that Read, Write, or Execute have been provided
 	// otherwise, we'll leak memory and always create a page fault.
 	assert!(bits & 0xe != 0);
+	// A user-writable mapping of the kernel's code or constants would let
+	// a process rewrite the kernel. This is always a bug in address space
+	// setup, so catch it here rather than when something odd happens.
+	if cfg!(debug_assertions) && exposes_kernel_text(paddr, bits) {
+		panic!(
+		       "User-writable mapping of kernel text: 0x{:x} -> 0x{:x}",
+		       vaddr.0, paddr.0
+		);
+	}
+	// A leaf at level covers 2^(12 + 9 * level) bytes, so vaddr only has
+	// to be somewhere in the first of those to take in the null page.
+	if cfg!(debug_assertions) && !allow_null && vaddr.0 >> (12 + 9 * level) == 0 {
+		return Err(KernelError::InvalidAddress);
+	}
+	// ppn() would silently drop the bits above 55, mapping some other
+	// physical page instead.
+	if paddr.0 >> PHYS_ADDR_BITS != 0 {
+		return Err(KernelError::InvalidAddress);
+	}
 	// Extract out each VPN from the virtual address
 	// On the virtual address, each VPN is exactly 9 bits,
 	// which is why we use the mask 0x1ff = 0b1_1111_1111 (9 bits)
-	let vpn = [
-	           // VPN[0] = vaddr[20:12]
-	           (vaddr >> 12) & 0x1ff,
-	           // VPN[1] = vaddr[29:21]
-	           (vaddr >> 21) & 0x1ff,
-	           // VPN[2] = vaddr[38:30]
-	           (vaddr >> 30) & 0x1ff,
-	];
+	let vpn = vaddr.vpn();
 
 	// Just like the virtual address, extract the physical address
 	// numbers (PPN). However, PPN[2] is different in that it stores
 	// 26 bits instead of 9. Therefore, we use,
 	// 0x3ff_ffff = 0b11_1111_1111_1111_1111_1111_1111 (26 bits).
-	let ppn = [
-	           // PPN[0] = paddr[20:12]
-	           (paddr >> 12) & 0x1ff,
-	           // PPN[1] = paddr[29:21]
-	           (paddr >> 21) & 0x1ff,
-	           // PPN[2] = paddr[55:30]
-	           (paddr >> 30) & 0x3ff_ffff,
-	];
+	let ppn = paddr.ppn();
 	// We will use this as a floating reference so that we can set
 	// individual entries as we walk the table.
 	let mut v = &mut root.entries[vpn[2]];
+	// Entries we pointed at newly allocated tables, so they can be undone.
+	// There are at most two tables below the root.
+	let mut made: [*mut Entry; 2] = [null_mut(); 2];
+	let mut num_made = 0;
 	// Now, we're going to traverse the page table and set the bits
 	// properly. We expect the root to be valid, however we're required to
 	// create anything beyond the root.
@@ -435,9 +964,29 @@ pub fn map(root: &mut Table,
 	// VPN[2] The .. operator is inclusive on start but exclusive on end.
 	// So, (0..2) will iterate 0 and 1.
 	for i in (level..2).rev() {
+		// A bigger page already covers vaddr. Walking into it would take
+		// the physical page it maps for a table. Any table we get to
+		// after making one is empty, so nothing has been made yet.
+		if v.is_valid() && v.is_leaf() {
+			return Err(KernelError::InvalidAddress);
+		}
 		if !v.is_valid() {
 			// Allocate a page
 			let page = zalloc(1);
+			if page.is_null() {
+				// Unwind in reverse, the last table made is the
+				// one furthest from the root.
+				for &made_entry in made[..num_made].iter().rev() {
+					unsafe {
+						let table = ((*made_entry).get_entry() & !0x3ff) << 2;
+						(*made_entry).set_entry(0);
+						dealloc(table as *mut u8);
+						PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
+					}
+				}
+				return Err(KernelError::OutOfMemory);
+			}
+			PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
 			// The page is already aligned by 4,096, so store it
 			// directly The page is stored in the entry shifted
 			// right by 2 places.
@@ -445,6 +994,8 @@ pub fn map(root: &mut Table,
 			            (page as i64 >> 2)
 			            | EntryBits::Valid.val(),
 			);
+			made[num_made] = v as *mut Entry;
+			num_made += 1;
 		}
 		let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
 		v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
@@ -461,9 +1012,108 @@ pub fn map(root: &mut Table,
 				EntryBits::Dirty.val() |  // Some machines require this to =1
 				EntryBits::Access.val()   // Just like dirty, some machines require this
 				;
+	// Bits 63:54 are reserved. Only bits outside of 0x3ff could get there.
+	debug_assert!(
+	              (entry as u64) >> 54 == 0,
+	              "Leaf entry 0x{:x} sets reserved bits, bits were 0x{:x}",
+	              entry,
+	              bits
+	);
+	// A table of smaller pages already hangs off this entry. Writing the
+	// leaf over it would lose track of those tables and their pages.
+	if v.is_valid() && v.is_branch() {
+		return Err(KernelError::InvalidAddress);
+	}
 	// Set the entry. V should be set to the correct pointer by the loop
 	// above.
 	v.set_entry(entry);
+	Ok(())
+}
+
+/// Whether mapping paddr with bits would let user code write the
+/// kernel's text or read-only data.
+fn exposes_kernel_text(paddr: PhysAddr, bits: i64) -> bool {
+	let user_write = EntryBits::User.val() | EntryBits::Write.val();
+	bits & user_write == user_write
+	   && unsafe { (TEXT_START..RODATA_END).contains(&paddr.0) }
+}
+
+/// Print the whole tree under root, one valid entry per line, indented by
+/// level. Branches show the table they point at, leaves the virtual
+/// address they map, the physical address and their permissions.
+pub fn print_table(root: &Table) {
+	let _ = crate::console::with_active(|out| write_table(out, root));
+}
+
+/// Same as print_table(), but writes to out
+pub fn write_table(out: &mut dyn Write, root: &Table) -> fmt::Result {
+	writeln!(out, "Page table at 0x{:x}:\r", root as *const Table as usize)?;
+	write_level(out, root, 2, 0)
+}
+
+// Write the entries of table, which sits at level. vpn holds the VPN
+// indices of the levels above, already shifted into place.
+fn write_level(out: &mut dyn Write, table: &Table, level: usize, vpn: usize) -> fmt::Result {
+	let indent = (2 - level) * 2;
+	for (i, entry) in table.entries.iter().enumerate() {
+		if entry.is_invalid() {
+			continue;
+		}
+		let vaddr = sign_extend(vpn | i << (12 + level * 9));
+		let addr = ((entry.get_entry() & !0x3ff) << 2) as usize;
+		if entry.is_leaf() {
+			writeln!(out,
+			         "{:indent$}[{}] 0x{:x} -> 0x{:x} {}\r",
+			         "",
+			         i,
+			         vaddr,
+			         addr,
+			         PermString(entry.get_entry()),
+			         indent = indent)?;
+		}
+		else if level == 0 {
+			// There is no level below 0 for a branch to point at
+			writeln!(out, "{:indent$}[{}] bad branch -> 0x{:x}\r", "", i, addr, indent = indent)?;
+		}
+		else {
+			writeln!(out, "{:indent$}[{}] table 0x{:x}\r", "", i, addr, indent = indent)?;
+			let next = unsafe { &*(addr as *const Table) };
+			write_level(out, next, level - 1, vpn | i << (12 + level * 9))?;
+		}
+	}
+	Ok(())
+}
+
+// Sv39 addresses copy bit 38 into all of the bits above it
+fn sign_extend(vaddr: usize) -> usize {
+	if vaddr & (1 << 38) != 0 {
+		vaddr | !((1 << 39) - 1)
+	}
+	else {
+		vaddr
+	}
+}
+
+// The permission bits of an entry as "rwxugad", with a '-' for each one
+// that is clear
+struct PermString(i64);
+
+impl fmt::Display for PermString {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let bits = [
+			(EntryBits::Read, 'r'),
+			(EntryBits::Write, 'w'),
+			(EntryBits::Execute, 'x'),
+			(EntryBits::User, 'u'),
+			(EntryBits::Global, 'g'),
+			(EntryBits::Access, 'a'),
+			(EntryBits::Dirty, 'd'),
+		];
+		for (bit, c) in bits {
+			f.write_char(if self.0 & bit.val() != 0 { c } else { '-' })?;
+		}
+		Ok(())
+	}
 }
 
 /// Unmaps and frees all memory associated with a table.
@@ -473,12 +1123,19 @@ pub fn map(root: &mut Table,
 /// The reason we don't free the root is because it is
 /// usually embedded into the Process structure.
 pub fn unmap(root: &mut Table) {
+	// Every table we've come across. A corrupted entry pointing back at
+	// one of them would otherwise have us free the same page twice.
+	let mut seen = vec![root as *mut Table as usize];
 	// Start with level 2
 	for lv2 in 0..Table::len() {
 		let ref entry_lv2 = root.entries[lv2];
 		if entry_lv2.is_valid() && entry_lv2.is_branch() {
 			// This is a valid entry, so drill down and free.
 			let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
+			if !claim_table(memaddr_lv1 as usize, &mut seen) {
+				println!("unmap: skipping bad level 1 table 0x{:x} in entry {}", memaddr_lv1, lv2);
+				continue;
+			}
 			let table_lv1 = unsafe {
 				// Make table_lv1 a mutable reference instead of
 				// a pointer.
@@ -490,31 +1147,69 @@ pub fn unmap(root: &mut Table) {
 				{
 					let memaddr_lv0 = (entry_lv1.get_entry()
 					                   & !0x3ff) << 2;
+					if !claim_table(memaddr_lv0 as usize, &mut seen) {
+						println!("unmap: skipping bad level 0 table 0x{:x} in entry {}", memaddr_lv0, lv1);
+						continue;
+					}
 					// The next level is level 0, which
 					// cannot have branches, therefore,
 					// we free here.
 					dealloc(memaddr_lv0 as *mut u8);
+					PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
 				}
 			}
 			dealloc(memaddr_lv1 as *mut u8);
+			PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
 		}
 	}
 }
 
+// A branch may only point at a table that the page allocator handed out
+// and that no other entry we've walked points at. Remember it if so.
+fn claim_table(table: usize, seen: &mut Vec<usize>) -> bool {
+	if seen.contains(&table) || !is_allocated(table) {
+		return false;
+	}
+	seen.push(table);
+	true
+}
+
+/// Unmap a single 4 KiB page mapped by map() at level 0.
+/// The page tables along the way are left in place, and the page the
+/// entry pointed to is NOT freed, that's up to the caller.
+/// Returns the physical address the page was mapped to, or None if there
+/// was no 4 KiB leaf at vaddr.
+pub fn unmap_page(root: &mut Table, vaddr: VirtAddr) -> Option<PhysAddr> {
+	let vpn = vaddr.vpn();
+	let mut v = &mut root.entries[vpn[2]];
+	for i in (0..2).rev() {
+		if v.is_invalid() || v.is_leaf() {
+			return None;
+		}
+		let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+		v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+	}
+	if v.is_invalid() || v.is_branch() {
+		return None;
+	}
+	let paddr = ((v.get_entry() & !0x3ff) << 2) as usize;
+	v.set_entry(0);
+	Some(PhysAddr(paddr))
+}
+
 /// Walk the page table to convert a virtual address to a
 /// physical address.
 /// If a page fault would occur, this returns None
 /// Otherwise, it returns Some with the physical address.
-pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
+pub fn virt_to_phys(root: &Table, vaddr: VirtAddr) -> Option<PhysAddr> {
+	lookup(root, vaddr).map(|(paddr, _)| paddr)
+}
+
+/// Same as virt_to_phys(), but also returns the bits of the leaf entry
+/// so the caller can check what the mapping allows.
+pub fn lookup(root: &Table, vaddr: VirtAddr) -> Option<(PhysAddr, i64)> {
 	// Walk the page table pointed to by root
-	let vpn = [
-	           // VPN[0] = vaddr[20:12]
-	           (vaddr >> 12) & 0x1ff,
-	           // VPN[1] = vaddr[29:21]
-	           (vaddr >> 21) & 0x1ff,
-	           // VPN[2] = vaddr[38:30]
-	           (vaddr >> 30) & 0x1ff,
-	];
+	let vpn = vaddr.vpn();
 
 	let mut v = &root.entries[vpn[2]];
 	for i in (0..=2).rev() {
@@ -529,17 +1224,19 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
 			// bits and they start at bit #12. So, our formula
 			// 12 + i * 9
 			let off_mask = (1 << (12 + i * 9)) - 1;
-			let vaddr_pgoff = vaddr & off_mask;
+			let vaddr_pgoff = vaddr.0 & off_mask;
 			let addr = ((v.get_entry() << 2) as usize) & !off_mask;
-			return Some(addr | vaddr_pgoff);
+			return Some((PhysAddr(addr | vaddr_pgoff), v.get_entry() & 0x3ff));
 		}
 		// Set v to the next entry which is pointed to by this
 		// entry. However, the address was shifted right by 2 places
 		// when stored in the page table entry, so we shift it left
 		// to get it back into place.
+		// A branch at level 0 is malformed, there is no level below.
+		if i == 0 {
+			break;
+		}
 		let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
-		// We do i - 1 here, however we should get None or Some() above
-		// before we do 0 - 1 = -1.
 		v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
 	}
 
@@ -547,3 +1244,627 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
 	// found a leaf.
 	None
 }
+
+
+/// Things that can be wrong with a page table entry.
+/// Each carries the virtual address the bad entry translates.
+#[derive(Debug)]
+pub enum PtError {
+	// A valid entry at level 0 without any of R, W, or X. There is no
+	// level below 0 for it to point to.
+	BranchAtLeaf { vaddr: usize },
+	// A branch pointing at a page the page allocator hasn't handed out
+	UnallocatedTable { vaddr: usize, table: usize },
+	// A megapage or gigapage leaf whose lower PPN fields aren't zero
+	MisalignedSuperpage { vaddr: usize, level: usize },
+	// Bits 63:54 are reserved and must be zero
+	ReservedBits { vaddr: usize },
+	// W without R is a reserved encoding
+	WriteWithoutRead { vaddr: usize },
+	// A branch pointing at a table that is already part of the tree,
+	// either one of its own parents or a table shared with another entry
+	Cycle { vaddr: usize, table: usize },
+}
+
+/// Returns true if paddr is inside a page the page allocator has handed out.
+pub fn is_allocated(paddr: usize) -> bool {
+	in_range(paddr) && unsafe { (*meta_of_index(index_of(paddr))).is_taken() }
+}
+
+/// Number of pages in the allocation that starts at paddr, counted up to
+/// and including the page marked Last. 0 if paddr isn't a taken page.
+pub fn allocation_pages(paddr: usize) -> usize {
+	if !is_allocated(paddr) {
+		return 0;
+	}
+	let first = index_of(paddr);
+	let mut i = first;
+	unsafe {
+		while i + 1 < page_count() && !(*meta_of_index(i)).is_last() {
+			i += 1;
+		}
+	}
+	i - first + 1
+}
+
+/// View the pages at ptr as a byte slice, so a driver can use slice
+/// operations on a buffer from alloc() or zalloc(). Debug builds check
+/// that ptr is page aligned and that every page is allocated.
+///
+/// # Safety
+/// The pages must stay allocated while the slice is in use, and nothing
+/// else may read or write them meanwhile
+pub unsafe fn as_slice_mut(ptr: *mut u8, pages: usize) -> &'static mut [u8] {
+	debug_check_allocated(ptr as usize, pages);
+	core::slice::from_raw_parts_mut(ptr, pages * PAGE_SIZE)
+}
+
+/// The read only version of as_slice_mut()
+///
+/// # Safety
+/// The pages must stay allocated while the slice is in use, and nothing
+/// may write them meanwhile
+pub unsafe fn as_slice(ptr: *const u8, pages: usize) -> &'static [u8] {
+	debug_check_allocated(ptr as usize, pages);
+	core::slice::from_raw_parts(ptr, pages * PAGE_SIZE)
+}
+
+fn debug_check_allocated(paddr: usize, pages: usize) {
+	debug_assert!(paddr & (PAGE_SIZE - 1) == 0, "0x{:x} isn't page aligned", paddr);
+	debug_assert!((0..pages).all(|i| is_allocated(paddr + i * PAGE_SIZE)),
+	              "0x{:x} + {} pages isn't all allocated", paddr, pages);
+}
+
+/// Whether the page at paddr was set aside by reserve()
+pub fn is_reserved(paddr: usize) -> bool {
+	in_range(paddr) && unsafe { (*meta_of_index(index_of(paddr))).is_reserved() }
+}
+
+/// Walk every table reachable from root and check that each valid entry
+/// makes sense. The first bad entry found is returned.
+pub fn validate_table(root: &Table) -> Result<(), PtError> {
+	let mut seen = vec![root as *const Table as usize];
+	validate_level(root, 2, 0, &mut seen)
+}
+
+fn validate_level(table: &Table,
+                  level: usize,
+                  vaddr_base: usize,
+                  seen: &mut Vec<usize>)
+                  -> Result<(), PtError>
+{
+	// Sv39 has three levels, 2 down to 0
+	debug_assert!(level < 3);
+	for (i, entry) in table.entries.iter().enumerate() {
+		if entry.is_invalid() {
+			continue;
+		}
+		let vaddr = vaddr_base | (i << (12 + level * 9));
+		let bits = entry.get_entry();
+		if (bits as u64) >> 54 != 0 {
+			return Err(PtError::ReservedBits { vaddr });
+		}
+		let paddr = ((bits & !0x3ff) << 2) as usize;
+		if entry.is_leaf() {
+			if bits & EntryBits::Write.val() != 0
+			   && bits & EntryBits::Read.val() == 0
+			{
+				return Err(PtError::WriteWithoutRead { vaddr });
+			}
+			// A leaf at level 1 or 2 maps a 2 MiB or 1 GiB page, so the
+			// physical address has to be aligned to that size.
+			if paddr & ((1 << (12 + level * 9)) - 1) != 0 {
+				return Err(PtError::MisalignedSuperpage { vaddr, level });
+			}
+		}
+		else if level == 0 {
+			return Err(PtError::BranchAtLeaf { vaddr });
+		}
+		else if !is_allocated(paddr) {
+			return Err(PtError::UnallocatedTable { vaddr, table: paddr });
+		}
+		else if seen.contains(&paddr) {
+			return Err(PtError::Cycle { vaddr, table: paddr });
+		}
+		else {
+			seen.push(paddr);
+			let next = unsafe { (paddr as *const Table).as_ref().unwrap() };
+			validate_level(next, level - 1, vaddr, seen)?;
+		}
+	}
+	Ok(())
+}
+
+/// Walk every leaf reachable from root and collect the virtual address of
+/// each one that is both writable and executable. Nothing should ever be
+/// mapped like that in the kernel, code that can be rewritten can be
+/// hijacked.
+pub fn enforce_wx(root: &Table) -> Result<(), Vec<usize>> {
+	let mut bad = Vec::new();
+	collect_wx(root, 2, 0, &mut bad);
+	if bad.is_empty() {
+		Ok(())
+	}
+	else {
+		Err(bad)
+	}
+}
+
+fn collect_wx(table: &Table, level: usize, vaddr_base: usize, bad: &mut Vec<usize>) {
+	let wx = EntryBits::Write.val() | EntryBits::Execute.val();
+	for (i, entry) in table.entries.iter().enumerate() {
+		if entry.is_invalid() {
+			continue;
+		}
+		let vaddr = vaddr_base | (i << (12 + level * 9));
+		if entry.is_leaf() {
+			if entry.get_entry() & wx == wx {
+				bad.push(vaddr);
+			}
+		}
+		else if level > 0 {
+			let next = ((entry.get_entry() & !0x3ff) << 2) as *const Table;
+			collect_wx(unsafe { next.as_ref().unwrap() }, level - 1, vaddr, bad);
+		}
+	}
+}
+
+/// Call f for every leaf reachable from root, in address order, with its
+/// virtual address, the physical address it maps to, its bits (the low
+/// ten bits of the entry) and the level it is at, 0 for a 4 KiB page.
+/// Virtual addresses with bit 38 set are sign extended like the MMU does.
+pub fn for_each_mapping(root: &Table, mut f: impl FnMut(VirtAddr, PhysAddr, i64, usize)) {
+	walk_leaves(root, 2, 0, &mut f);
+}
+
+fn walk_leaves(table: &Table, level: usize, vaddr_base: usize, f: &mut impl FnMut(VirtAddr, PhysAddr, i64, usize)) {
+	for (i, entry) in table.entries.iter().enumerate() {
+		if entry.is_invalid() {
+			continue;
+		}
+		let vaddr = vaddr_base | (i << (12 + level * 9));
+		let paddr = ((entry.get_entry() & !0x3ff) << 2) as usize;
+		if entry.is_leaf() {
+			// Sv39 addresses are 39 bits, the rest copies bit 38
+			let vaddr = ((vaddr << 25) as isize >> 25) as usize;
+			f(VirtAddr(vaddr), PhysAddr(paddr), entry.get_entry() & 0x3ff, level);
+		}
+		else if level > 0 {
+			walk_leaves(unsafe { (paddr as *const Table).as_ref().unwrap() }, level - 1, vaddr, f);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use alloc::{format, string::String};
+
+	use super::*;
+
+	#[test_case]
+	fn heap_layout_puts_pages_after_their_descriptors() {
+		let start = 0x8100_0000;
+		let size = 64 * PAGE_SIZE;
+		let alloc_start = align_val(start + 64 * size_of::<Page>(), PAGE_ORDER);
+		assert_eq!(heap_layout(start, size), Ok(alloc_start));
+		assert_eq!(heap_layout(start + 8, size), Err(HeapError::Unaligned { start: start + 8 }));
+		// The descriptors plus one page to hand out
+		let needed = alloc_start - start + PAGE_SIZE;
+		assert_eq!(heap_layout(start, PAGE_SIZE), Err(HeapError::TooSmall { size: PAGE_SIZE, needed: PAGE_SIZE * 2 }));
+		assert_eq!(heap_layout(start, needed), Ok(alloc_start));
+	}
+
+	#[test_case]
+	fn byte_totals_dont_wrap() {
+		assert_eq!(pages_to_bytes(3), 3 * PAGE_SIZE as u128);
+		// usize::MAX pages is more bytes than a usize can hold
+		let huge = pages_to_bytes(usize::MAX);
+		assert!(huge > usize::MAX as u128);
+		assert_eq!(huge / PAGE_SIZE as u128, usize::MAX as u128);
+	}
+
+	#[test_case]
+	fn dealloc_returns_the_pages_it_freed() {
+		let (allocated, free) = (allocated_page_count(), free_page_count());
+		let pages = alloc(3);
+		assert!(!pages.is_null());
+		assert_eq!(allocated_page_count(), allocated + 3);
+		assert_eq!(free_page_count(), free - 3);
+		assert_eq!(dealloc(pages), 3);
+		assert_eq!(allocated_page_count(), allocated);
+		assert_eq!(free_page_count(), free);
+	}
+
+	#[test_case]
+	fn high_water_stays_at_the_peak() {
+		let pages = alloc(4);
+		assert!(!pages.is_null());
+		let high = high_water();
+		assert!(high >= allocated_page_count());
+		dealloc(pages);
+		assert_eq!(high_water(), high);
+		assert!(high >= allocated_page_count() + 4);
+	}
+
+	#[test_case]
+	fn dealloc_range_frees_what_it_covers() {
+		let free = free_page_count();
+		let pages = alloc(5) as usize;
+		assert!(pages != 0);
+		assert_eq!(dealloc_range(pages, pages + 5 * PAGE_SIZE), 5);
+		assert_eq!(free_page_count(), free);
+		// Only the end of an allocation
+		let pages = alloc(4) as usize;
+		assert!(pages != 0);
+		assert_eq!(dealloc_range(pages + 2 * PAGE_SIZE, pages + 4 * PAGE_SIZE), 2);
+		assert_eq!(free_page_count(), free - 2);
+		assert_eq!(dealloc(pages as *mut u8), 2);
+		assert_eq!(free_page_count(), free);
+	}
+
+	#[test_case]
+	fn memory_map_has_the_heap() {
+		let map = memory_map();
+		let heap = map.iter().find(|r| r.kind == "Heap").expect("no heap in the memory map");
+		assert_eq!(heap.base, addr_of_index(0));
+		assert_eq!(heap.size, page_count() * PAGE_SIZE);
+		// Sorted, and nothing overlaps
+		assert!(map.windows(2).all(|w| w[0].base + w[0].size <= w[1].base));
+	}
+
+	#[test_case]
+	fn index_of_undoes_addr_of_index() {
+		let first = addr_of_index(0);
+		assert!(in_range(first));
+		assert_eq!(first & (PAGE_SIZE - 1), 0);
+		assert_eq!(index_of(first), 0);
+		assert_eq!(addr_of_index(5), first + 5 * PAGE_SIZE);
+		// Anywhere in the page is the same page
+		assert_eq!(index_of(addr_of_index(5) + 123), 5);
+		let last = page_count() - 1;
+		assert_eq!(index_of(addr_of_index(last)), last);
+		assert!(!in_range(addr_of_index(last + 1)));
+	}
+
+	#[test_case]
+	fn address_fields_are_split_the_sv39_way() {
+		let vaddr = VirtAddr((0x1a5 << 30) | (0x0f3 << 21) | (0x12c << 12) | 0x678);
+		assert_eq!(vaddr.vpn(), [0x12c, 0x0f3, 0x1a5]);
+		assert_eq!(vaddr.offset(), 0x678);
+		assert_eq!(vaddr.page(), vaddr.0 >> 12);
+		// Bits 63:39 are copies of bit 38, and not part of any VPN
+		assert_eq!(VirtAddr(0xffff_ffc0_0000_0000).vpn(), [0, 0, 0x100]);
+		// PPN[2] is 26 bits wide, up to bit 55
+		let paddr = PhysAddr((0x3ff_ffff << 30) | (0x1ff << 21) | (0x001 << 12) | 0xfff);
+		assert_eq!(paddr.ppn(), [0x001, 0x1ff, 0x3ff_ffff]);
+		assert_eq!(paddr.offset(), 0xfff);
+		assert_eq!(PhysAddr(1 << 56).ppn(), [0, 0, 0]);
+		assert_eq!(PhysAddr(0x8020_3000).ppn(), [0x003, 0x001, 0x002]);
+		assert_eq!(usize::from(PhysAddr::from(0x8020_3000)), 0x8020_3000);
+		assert_eq!(PhysAddr(0x8020_3000).to_identity_virt(), VirtAddr(0x8020_3000));
+	}
+
+	#[test_case]
+	fn software_bits_leave_the_rest_of_the_entry_alone() {
+		// A user RWX leaf, accessed and dirty, for physical page 0x8020_3
+		let hw = (0x8020_3 << 10) | EntryBits::UserReadWriteExecute.val()
+		         | EntryBits::Access.val() | EntryBits::Dirty.val() | EntryBits::Valid.val();
+		let mut entry = Entry { entry: hw };
+		entry.set_sw(SwBits::Cow);
+		assert!(entry.has_sw(SwBits::Cow));
+		assert!(!entry.has_sw(SwBits::Lazy));
+		assert_eq!(entry.get_entry(), hw | 1 << 8);
+		assert!(entry.is_valid() && entry.is_leaf());
+		entry.set_sw(SwBits::Lazy);
+		entry.clear_sw(SwBits::Cow);
+		assert_eq!(entry.get_entry(), hw | 1 << 9);
+		entry.clear_sw(SwBits::Lazy);
+		assert_eq!(entry.get_entry(), hw);
+	}
+
+	#[test_case]
+	fn slices_cover_the_whole_allocation() {
+		let ptr = zalloc(3);
+		assert!(!ptr.is_null());
+		let slice = unsafe { as_slice_mut(ptr, 3) };
+		assert_eq!(slice.len(), 3 * PAGE_SIZE);
+		slice[0] = 0x12;
+		slice[3 * PAGE_SIZE - 1] = 0x34;
+		slice[PAGE_SIZE..PAGE_SIZE + 4].copy_from_slice(&[1, 2, 3, 4]);
+		unsafe {
+			assert_eq!(ptr.read(), 0x12);
+			assert_eq!(ptr.add(3 * PAGE_SIZE - 1).read(), 0x34);
+			assert_eq!((ptr.add(PAGE_SIZE) as *const u32).read(), 0x0403_0201);
+			ptr.add(2).write(0x56);
+			let read_only = as_slice(ptr, 3);
+			assert_eq!(read_only.len(), 3 * PAGE_SIZE);
+			assert_eq!(read_only[2], 0x56);
+			assert_eq!(read_only.as_ptr(), ptr as *const u8);
+		}
+		dealloc(ptr);
+	}
+
+	#[test_case]
+	fn alloc_colored_prefers_the_color_asked_for() {
+		let colors = page_colors();
+		set_page_colors(4);
+		let page = alloc_colored(1, 2);
+		assert!(!page.is_null());
+		assert_eq!(index_of(page as usize) % 4, 2);
+		// Colors wrap, 6 is the same as 2
+		let run = alloc_colored(3, 6);
+		assert!(!run.is_null());
+		assert_eq!(index_of(run as usize) % 4, 2);
+		assert_eq!(allocation_pages(run as usize), 3);
+		dealloc(run);
+		dealloc(page);
+		set_page_colors(colors);
+	}
+
+	#[test_case]
+	fn alloc_colored_falls_back_when_the_color_is_used_up() {
+		let colors = page_colors();
+		// Every page its own color, so taking one page uses up its color
+		set_page_colors(page_count());
+		let taken = alloc(1);
+		assert!(!taken.is_null());
+		let color = index_of(taken as usize);
+		let other = alloc_colored(1, color);
+		assert!(!other.is_null());
+		assert_ne!(other, taken);
+		dealloc(other);
+		dealloc(taken);
+		// Free again, so that is the page it gets
+		let again = alloc_colored(1, color);
+		assert_eq!(again, taken);
+		dealloc(again);
+		set_page_colors(colors);
+	}
+
+	// Allocate until only left pages are free. Returns how many of held
+	// were filled in, each is one allocation to hand back to dealloc().
+	fn take_all_but(left: usize, held: &mut [*mut u8]) -> usize {
+		let mut count = 0;
+		let mut ask = free_page_count() - left;
+		while free_page_count() > left {
+			ask = ask.min(free_page_count() - left);
+			let page = alloc(ask);
+			if page.is_null() {
+				ask = (ask / 2).max(1);
+				continue;
+			}
+			assert!(count < held.len(), "heap too fragmented for the test");
+			held[count] = page;
+			count += 1;
+		}
+		count
+	}
+
+	#[test_case]
+	fn failed_try_map_leaves_the_tree_as_it_was() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let target = PhysAddr(root as *mut Table as usize);
+		assert!(try_map(root, VirtAddr(0x4000_0000), target, EntryBits::Read.val(), 0).is_ok());
+		let entries: [i64; 512] = core::array::from_fn(|i| root.entries[i].get_entry());
+		let tables = page_table_page_count();
+		// A different 1 GiB region needs two new tables, leave room for
+		// only the first
+		let mut held = [null_mut(); 256];
+		let count = take_all_but(1, &mut held);
+		let result = try_map(root, VirtAddr(0x8000_0000), target, EntryBits::Read.val(), 0);
+		let free = free_page_count();
+		for &page in &held[..count] {
+			dealloc(page);
+		}
+		assert_eq!(result, Err(KernelError::OutOfMemory));
+		// The table that was made got freed again
+		assert_eq!(free, 1);
+		assert_eq!(page_table_page_count(), tables);
+		assert!((0..512).all(|i| root.entries[i].get_entry() == entries[i]));
+		assert!(validate_table(root).is_ok());
+		unmap(root);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn pages_of_another_size_are_not_mapped_over() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let target = PhysAddr(root as *mut Table as usize);
+		let bits = EntryBits::Read.val();
+		assert!(try_map(root, VirtAddr(0x4020_3000), target, bits, 0).is_ok());
+		// A 2 MiB page would leave the table the 4 KiB one is in behind
+		assert_eq!(try_map(root, VirtAddr(0x4020_0000), PhysAddr(0x8020_0000), bits, 1),
+		           Err(KernelError::InvalidAddress));
+		assert_eq!(lookup(root, VirtAddr(0x4020_3000)).map(|(paddr, _)| paddr), Some(target));
+		assert!(lookup(root, VirtAddr(0x4020_4000)).is_none());
+		// And the other way around, a 4 KiB page can't go inside a 2 MiB one
+		assert!(try_map(root, VirtAddr(0x4040_0000), PhysAddr(0x8020_0000), bits, 1).is_ok());
+		assert_eq!(try_map(root, VirtAddr(0x4040_3000), target, bits, 0), Err(KernelError::InvalidAddress));
+		assert_eq!(virt_to_phys(root, VirtAddr(0x4040_3000)), Some(PhysAddr(0x8020_3000)));
+		// A page of the same size is simply replaced
+		assert!(try_map(root, VirtAddr(0x4020_3000), PhysAddr(0x8000_0000), bits, 0).is_ok());
+		assert_eq!(virt_to_phys(root, VirtAddr(0x4020_3000)), Some(PhysAddr(0x8000_0000)));
+		assert!(validate_table(root).is_ok());
+		unmap(root);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	// The table a branch entry points to
+	fn next_table(entry: &Entry) -> &mut Table {
+		unsafe { (((entry.get_entry() & !0x3ff) << 2) as *mut Table).as_mut().unwrap() }
+	}
+
+	#[test_case]
+	fn corrupted_entries_are_reported_with_their_vaddr() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let page = zalloc(1);
+		let vaddr = 0x4020_3000;
+		map(root, VirtAddr(vaddr), PhysAddr(page as usize), EntryBits::Read.val(), 0);
+		assert!(validate_table(root).is_ok());
+		// Level 2 index 1, level 1 index 1, level 0 index 3
+		let leaf = &mut next_table(&next_table(&root.entries[1]).entries[1]).entries[3];
+		let good = leaf.get_entry();
+		leaf.set_entry(good & !EntryBits::Read.val() | EntryBits::Write.val());
+		assert!(matches!(validate_table(root), Err(PtError::WriteWithoutRead { vaddr: 0x4020_3000 })));
+		// Valid without R, W or X points at a table, there are none below level 0
+		leaf.set_entry(good & !EntryBits::Read.val());
+		assert!(matches!(validate_table(root), Err(PtError::BranchAtLeaf { vaddr: 0x4020_3000 })));
+		leaf.set_entry(good);
+		// A branch to a page that isn't allocated (any more). Leaves
+		// aren't checked, so the one above still mapping it is fine.
+		dealloc(page);
+		root.entries[5].set_entry(((page as i64) >> 2) | EntryBits::Valid.val());
+		assert!(matches!(validate_table(root), Err(PtError::UnallocatedTable { vaddr: 0x1_4000_0000, .. })));
+		root.entries[5].set_entry(0);
+		unmap(root);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn writable_code_is_reported() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let page = PhysAddr(root as *mut Table as usize);
+		map(root, VirtAddr(0x1000_0000), page, EntryBits::ReadWrite.val(), 0);
+		map(root, VirtAddr(0x1000_1000), page, EntryBits::ReadExecute.val(), 0);
+		assert_eq!(enforce_wx(root), Ok(()));
+		map(root, VirtAddr(0x1000_2000), page, EntryBits::ReadWriteExecute.val(), 0);
+		map(root, VirtAddr(0x4000_0000), page, EntryBits::ReadWriteExecute.val(), 0);
+		assert_eq!(enforce_wx(root), Err(vec![0x1000_2000, 0x4000_0000]));
+		unmap(root);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn user_writable_kernel_text_is_caught() {
+		let text = PhysAddr(unsafe { TEXT_START });
+		let rodata_end = unsafe { RODATA_END };
+		assert!(exposes_kernel_text(text, EntryBits::UserReadWrite.val()));
+		assert!(exposes_kernel_text(PhysAddr(rodata_end - 8), EntryBits::UserReadWrite.val()));
+		// Read only, or not for user code, is fine
+		assert!(!exposes_kernel_text(text, EntryBits::UserReadExecute.val()));
+		assert!(!exposes_kernel_text(text, EntryBits::ReadWrite.val()));
+		assert!(!exposes_kernel_text(PhysAddr(rodata_end), EntryBits::UserReadWrite.val()));
+	}
+
+	#[test_case]
+	fn cyclic_tables_are_caught() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let shared = zalloc(1) as usize;
+		let branch = |table: usize| (table as i64 >> 2) | EntryBits::Valid.val();
+		let root_addr = root as *mut Table as usize;
+		root.entries[0].set_entry(branch(root_addr));
+		assert!(matches!(validate_table(root), Err(PtError::Cycle { vaddr: 0, table }) if table == root_addr));
+		root.entries[0].set_entry(0);
+		// Two entries sharing a table
+		root.entries[1].set_entry(branch(shared));
+		root.entries[2].set_entry(branch(shared));
+		assert!(matches!(validate_table(root), Err(PtError::Cycle { vaddr: 0x8000_0000, table }) if table == shared));
+		// unmap frees the shared table once and doesn't follow the entry
+		// back to root
+		root.entries[0].set_entry(branch(root_addr));
+		// unmap takes the shared table off the count of table pages, as
+		// if map had made it
+		PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+		let free = free_page_count();
+		unmap(root);
+		assert_eq!(free_page_count(), free + 1);
+		assert!(!is_allocated(shared));
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn table_pages_are_counted() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let target = PhysAddr(root as *mut Table as usize);
+		let tables = page_table_page_count();
+		// A level 1 and a level 0 table each
+		map(root, VirtAddr(0x4000_0000), target, EntryBits::Read.val(), 0);
+		map(root, VirtAddr(0x8000_0000), target, EntryBits::Read.val(), 0);
+		assert_eq!(page_table_page_count(), tables + 4);
+		// Another 2 MiB in the same 1 GiB only needs a level 0 table
+		map(root, VirtAddr(0x4020_0000), target, EntryBits::Read.val(), 0);
+		// The same 2 MiB needs none
+		map(root, VirtAddr(0x4020_1000), target, EntryBits::Read.val(), 0);
+		assert_eq!(page_table_page_count(), tables + 5);
+		assert_eq!(meminfo().page_table_pages, (tables + 5) as u64);
+		unmap(root);
+		assert_eq!(page_table_page_count(), tables);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn table_dump_shows_branches_and_leaves() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let target = root as *mut Table as usize;
+		map(root, VirtAddr(0x4020_3000), PhysAddr(target), EntryBits::UserReadWrite.val(), 0);
+		// Bit 38 set, which the dump copies up into the top bits
+		map(root, VirtAddr(0x40_0000_0000), PhysAddr(0x8000_0000), EntryBits::Read.val(), 2);
+		let mut out = String::new();
+		assert!(write_table(&mut out, root).is_ok());
+		let level1 = (root.entries[1].get_entry() & !0x3ff) << 2;
+		let level0 = unsafe { ((*(level1 as *const Table)).entries[1].get_entry() & !0x3ff) << 2 };
+		let lines: Vec<&str> = out.split("\r\n").collect();
+		assert_eq!(lines, [format!("Page table at 0x{target:x}:"),
+		                   format!("[1] table 0x{level1:x}"),
+		                   format!("  [1] table 0x{level0:x}"),
+		                   format!("    [3] 0x40203000 -> 0x{target:x} rw-u-ad"),
+		                   String::from("[256] 0xffffffc000000000 -> 0x80000000 r----ad"),
+		                   String::new()]);
+		unmap(root);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn paddrs_past_56_bits_are_refused() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let paddr = PhysAddr(root as *mut Table as usize | 1 << 57);
+		let tables = page_table_page_count();
+		assert_eq!(try_map(root, VirtAddr(0x4000_0000), paddr, EntryBits::Read.val(), 0),
+		           Err(KernelError::InvalidAddress));
+		assert_eq!(page_table_page_count(), tables);
+		assert!(lookup(root, VirtAddr(0x4000_0000)).is_none());
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn the_null_page_needs_the_override() {
+		let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+		let target = PhysAddr(root as *mut Table as usize);
+		let bits = EntryBits::Read.val();
+		assert_eq!(try_map(root, VirtAddr(0x800), target, bits, 0), Err(KernelError::InvalidAddress));
+		// A 2 MiB page starting anywhere in the first 2 MiB covers it too
+		assert_eq!(try_map(root, VirtAddr(0x1000), PhysAddr(0x8000_0000), bits, 1), Err(KernelError::InvalidAddress));
+		assert!(lookup(root, VirtAddr(0)).is_none());
+		assert!(try_map(root, VirtAddr(PAGE_SIZE), target, bits, 0).is_ok());
+		assert!(try_map_null(root, VirtAddr(0), target, bits, 0).is_ok());
+		assert!(lookup(root, VirtAddr(0x800)).is_some());
+		unmap(root);
+		dealloc(root as *mut Table as *mut u8);
+	}
+
+	#[test_case]
+	fn reserved_pages_are_never_handed_out() {
+		// alloc() is first fit, so the page it returns and frees again is
+		// the one the next alloc(1) would get
+		let page = alloc(1);
+		assert!(!page.is_null());
+		dealloc(page);
+		let reserved = reserved_page_count();
+		let free = free_page_count();
+		// There is no way to give a page back, so this one is gone
+		assert_eq!(reserve(page as usize, page as usize + PAGE_SIZE), 1);
+		assert_eq!(reserved_page_count(), reserved + 1);
+		assert_eq!(meminfo().reserved_pages, reserved as u64 + 1);
+		assert_eq!(free_page_count(), free - 1);
+		assert!(is_reserved(page as usize));
+		let other = alloc(1);
+		assert!(!other.is_null());
+		assert_ne!(other, page);
+		dealloc(other);
+		assert_eq!(dealloc(page), 0);
+		assert!(is_allocated(page as usize));
+		// Already taken, so reserving it again does nothing
+		assert_eq!(reserve(page as usize, page as usize + PAGE_SIZE), 0);
+		assert_eq!(reserved_page_count(), reserved + 1);
+	}
+}
@@ -0,0 +1,282 @@
+// Pipes
+// An anonymous pipe is a ring buffer in the kernel with a read end and a
+// write end, each of which can be open in any number of descriptors.
+// Reading an empty pipe waits for a writer and writing a full one waits
+// for a reader. Once every write end is closed, reading the empty pipe
+// returns 0 for the end of the file. Pipes live in a fixed table, so a
+// descriptor only has to remember the slot.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{fd::FdError, lock::Mutex, sync::WaitQueue};
+
+// Most pipes that can be open at once
+pub const MAX_PIPES: usize = 16;
+// Bytes a pipe holds before writers have to wait
+pub const PIPE_SIZE: usize = 4096;
+
+struct Ring {
+    data:    [u8; PIPE_SIZE],
+    // Where the oldest byte is, and how many there are
+    head:    usize,
+    len:     usize,
+    // Descriptors open on each end. A slot with neither is free.
+    readers: usize,
+    writers: usize
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring { data: [0; PIPE_SIZE], head: 0, len: 0, readers: 0, writers: 0 }
+    }
+
+    // Append as much of buf as fits, returns how much that was
+    fn push(&mut self, buf: &[u8]) -> usize {
+        let count = buf.len().min(PIPE_SIZE - self.len);
+        for (i, &b) in buf[..count].iter().enumerate() {
+            self.data[(self.head + self.len + i) % PIPE_SIZE] = b;
+        }
+        self.len += count;
+        count
+    }
+
+    // Take the oldest bytes into buf, returns how many there were
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for (i, b) in buf[..count].iter_mut().enumerate() {
+            *b = self.data[(self.head + i) % PIPE_SIZE];
+        }
+        self.head = (self.head + count) % PIPE_SIZE;
+        self.len -= count;
+        count
+    }
+}
+
+struct Pipe {
+    ring:     Mutex<Ring>,
+    // Readers waiting for data and writers waiting for room
+    readable: WaitQueue,
+    writable: WaitQueue
+}
+
+impl Pipe {
+    const fn new() -> Self {
+        Pipe { ring: Mutex::new(Ring::new()), readable: WaitQueue::new(), writable: WaitQueue::new() }
+    }
+}
+
+static PIPES: [Pipe; MAX_PIPES] = [const { Pipe::new() }; MAX_PIPES];
+
+// Wait queues to wake, see run_wakes. Bit 2 * slot is the readable queue
+// of a slot and the bit after it the writable one.
+static PENDING_WAKES: AtomicU32 = AtomicU32::new(0);
+const READABLE: usize = 0;
+const WRITABLE: usize = 1;
+const _: () = assert!(2 * MAX_PIPES <= 32, "PENDING_WAKES has a bit per queue");
+
+// Pipe ends are read, written and closed from inside walks of the process
+// list, where the list is taken and a WaitQueue can't unblock anybody. So
+// the queues are only marked here and woken once the list is back.
+fn wake_later(slot: usize, queue: usize) {
+    PENDING_WAKES.fetch_or(1 << (2 * slot + queue), Ordering::AcqRel);
+}
+
+// Wake everybody on the queues marked since the last call. Called on the
+// way out of a trap and before scheduling, when the process list isn't
+// taken. Every waiter is woken since each one tries its syscall again.
+pub fn run_wakes() {
+    let mut pending = PENDING_WAKES.swap(0, Ordering::AcqRel);
+    while pending != 0 {
+        let bit = pending.trailing_zeros() as usize;
+        pending &= pending - 1;
+        let pipe = &PIPES[bit / 2];
+        if bit % 2 == READABLE {
+            pipe.readable.wake_all();
+        } else {
+            pipe.writable.wake_all();
+        }
+    }
+}
+
+// One open end of a pipe. Cloning it opens that end once more and
+// dropping it closes it, so the counts in the ring follow the descriptors.
+pub struct PipeEnd {
+    slot:  usize,
+    write: bool
+}
+
+// Make a pipe and return its read and write ends, or None if every slot
+// is in use
+pub fn create() -> Option<(PipeEnd, PipeEnd)> {
+    for (slot, pipe) in PIPES.iter().enumerate() {
+        let mut ring = pipe.ring.lock();
+        if ring.readers == 0 && ring.writers == 0 {
+            ring.head = 0;
+            ring.len = 0;
+            ring.readers = 1;
+            ring.writers = 1;
+            return Some((PipeEnd { slot, write: false }, PipeEnd { slot, write: true }));
+        }
+    }
+    None
+}
+
+impl PipeEnd {
+    // Read into buf, see FileDescriptor::read. An empty pipe returns
+    // WouldBlock while a write end is open and 0 once none is.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FdError> {
+        if self.write {
+            return Err(FdError::BadFd);
+        }
+        let mut ring = PIPES[self.slot].ring.lock();
+        if ring.len == 0 && !buf.is_empty() {
+            return if ring.writers == 0 { Ok(0) } else { Err(FdError::WouldBlock) };
+        }
+        let count = ring.pop(buf);
+        if count > 0 {
+            wake_later(self.slot, WRITABLE);
+        }
+        Ok(count)
+    }
+
+    // Write as much of buf as fits. A full pipe returns WouldBlock, and
+    // one nobody can read from any more BrokenPipe.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FdError> {
+        if !self.write {
+            return Err(FdError::BadFd);
+        }
+        let mut ring = PIPES[self.slot].ring.lock();
+        if ring.readers == 0 {
+            return Err(FdError::BrokenPipe);
+        }
+        let count = ring.push(buf);
+        if count == 0 && !buf.is_empty() {
+            return Err(FdError::WouldBlock);
+        }
+        if count > 0 {
+            wake_later(self.slot, READABLE);
+        }
+        Ok(count)
+    }
+
+    // What to wait on after this end returned WouldBlock
+    pub fn waiter(&self) -> Waiter {
+        Waiter { slot: self.slot, write: self.write }
+    }
+}
+
+impl Clone for PipeEnd {
+    fn clone(&self) -> Self {
+        let mut ring = PIPES[self.slot].ring.lock();
+        if self.write {
+            ring.writers += 1;
+        } else {
+            ring.readers += 1;
+        }
+        PipeEnd { slot: self.slot, write: self.write }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut ring = PIPES[self.slot].ring.lock();
+        // Whoever waits on the other end has to see that this one is gone
+        if self.write {
+            ring.writers -= 1;
+            if ring.writers == 0 {
+                wake_later(self.slot, READABLE);
+            }
+        } else {
+            ring.readers -= 1;
+            if ring.readers == 0 {
+                wake_later(self.slot, WRITABLE);
+            }
+        }
+    }
+}
+
+// A pipe end to wait on. This doesn't keep the end open, so it can be
+// held by code that may never return, like a process that blocks.
+#[derive(Clone, Copy, Debug)]
+pub struct Waiter {
+    slot:  usize,
+    write: bool
+}
+
+impl Waiter {
+    // Block the process that owns frame until the end has something to
+    // read or room to write, to resume at pc. Returns true, without
+    // waiting, if it already has, and false if the process can't wait.
+    pub fn wait(self, frame: usize, pc: usize) -> bool {
+        let pipe = &PIPES[self.slot];
+        if self.write {
+            pipe.writable.wait_while(frame, pc, || {
+                let ring = pipe.ring.lock();
+                ring.len == PIPE_SIZE && ring.readers > 0
+            })
+        } else {
+            pipe.readable.wait_while(frame, pc, || {
+                let ring = pipe.ring.lock();
+                ring.len == 0 && ring.writers > 0
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ring_keeps_bytes_in_order() {
+        let mut ring = Ring::new();
+        assert_eq!(ring.push(b"hello"), 5);
+        let mut buf = [0; 3];
+        assert_eq!(ring.pop(&mut buf), 3);
+        assert_eq!(&buf, b"hel");
+        let mut buf = [0; 8];
+        assert_eq!(ring.pop(&mut buf), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(ring.pop(&mut buf), 0);
+    }
+
+    #[test_case]
+    fn ring_stops_when_full_and_wraps() {
+        let mut ring = Ring::new();
+        let fill = [7; PIPE_SIZE];
+        assert_eq!(ring.push(&fill[..PIPE_SIZE - 2]), PIPE_SIZE - 2);
+        // Only two bytes of room left
+        assert_eq!(ring.push(b"abcd"), 2);
+        let mut out = [0; PIPE_SIZE];
+        assert_eq!(ring.pop(&mut out[..PIPE_SIZE - 2]), PIPE_SIZE - 2);
+        // These go round the end of data
+        assert_eq!(ring.push(b"efgh"), 4);
+        assert_eq!(ring.pop(&mut out), 6);
+        assert_eq!(&out[..6], b"abefgh");
+    }
+    #[test_case]
+    fn closing_every_write_end_is_eof() {
+        let (read, write) = create().expect("no free pipe");
+        assert!(matches!(write.write(b"hello"), Ok(5)));
+        let mut buf = [0; 8];
+        assert!(matches!(read.read(&mut buf), Ok(5)));
+        assert_eq!(&buf[..5], b"hello");
+        assert!(matches!(read.read(&mut buf), Err(FdError::WouldBlock)));
+        // Still one write end open after this, as after a fork
+        let other = write.clone();
+        drop(write);
+        assert!(matches!(read.read(&mut buf), Err(FdError::WouldBlock)));
+        drop(other);
+        assert!(matches!(read.read(&mut buf), Ok(0)));
+        run_wakes();
+    }
+
+    #[test_case]
+    fn writing_with_no_reader_is_a_broken_pipe() {
+        let (read, write) = create().expect("no free pipe");
+        drop(read);
+        assert!(matches!(write.write(b"x"), Err(FdError::BrokenPipe)));
+        drop(write);
+        run_wakes();
+    }
+}
@@ -1,20 +1,34 @@
 // Platform level interrupt controller
 // PLIC is MMIO, so we read and write to specific memory locations to address registers
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::mmio::{Mmio, PLIC_BASE};
+
+const PLIC: Mmio = Mmio::new(PLIC_BASE);
+// Register offsets from the PLIC base address
+const PLIC_PRIORITY: usize = 0x0;
+const PLIC_INT_ENABLE: usize = 0x2000;
+const PLIC_THRESHOLD: usize = 0x20_0000;
+const PLIC_CLAIM: usize = 0x20_0004;
+
+// Most interrupts claimed in one external interrupt trap, so a device that
+// keeps interrupting can't keep the hart in the trap handler forever
+pub const MAX_CLAIMS_PER_TRAP: usize = 16;
+// Claiming the same id this many times in a row in one trap means the
+// device is re-asserting as fast as we complete it
+pub const STORM_THRESHOLD: usize = 8;
+// Interrupt ids we keep storm state for. QEMU's virt machine uses 1..=53.
+const MAX_ID: usize = 64;
+// Priority each storming id had before it was masked, 0 if it isn't masked
+static MASKED: [AtomicU8; MAX_ID] = [const { AtomicU8::new(0) }; MAX_ID];
 
 // Enable an interrupt id
 pub fn enable(id: u32) {
-    let enables = PLIC_INT_ENABLE as *mut u32;
     // The PLIC_INT_ENABLE is a 32 bit register, with each bit specifying the index of an interrupt
     // To enable interrupts from that particular source, we set the bit id in the PLIC_INT_ENABLE
     let bit_id = 1 << id;
-    unsafe {
-        // Get value of PLIC_INT_ENABLE and add the interrupt to enable
-        enables.write_volatile(enables.read_volatile() | bit_id);
-    }
+    // Get value of PLIC_INT_ENABLE and add the interrupt to enable
+    PLIC.write32(PLIC_INT_ENABLE, PLIC.read32(PLIC_INT_ENABLE) | bit_id);
 }
 
 // Set interrupt priority for id
@@ -22,13 +36,15 @@ pub fn enable(id: u32) {
 // Therefore get the the last 3 bits as the actual priority
 pub fn set_priority(id: u32, prio: u8) {
     let cutoff_prio = prio as u32 & 7;
-    let priority = PLIC_PRIORITY as *mut u32;
-    unsafe {
-        // Location of each interrupt's priority is given by:
-        // PLIC_PRIORITY + id * 4
-        // The add will move by 4 bytes, since we use a 32 bit pointer
-        priority.add(id as usize).write_volatile(cutoff_prio);
-    }
+    // Location of each interrupt's priority is given by:
+    // PLIC_PRIORITY + id * 4
+    // Each priority register is 32 bits wide, so we move by 4 bytes per id
+    PLIC.write32(PLIC_PRIORITY + id as usize * 4, cutoff_prio);
+}
+
+// Current priority of interrupt id
+pub fn priority(id: u32) -> u8 {
+    (PLIC.read32(PLIC_PRIORITY + id as usize * 4) & 7) as u8
 }
 
 // Set global threshold for all interrupts
@@ -36,20 +52,13 @@ pub fn set_priority(id: u32, prio: u8) {
 // Threshold can be in the range 0..7
 pub fn set_threshold(threshold: u8) {
     let cutoff_threshold = threshold as u32 & 7;
-    let threshold_ptr = PLIC_THRESHOLD as *mut u32;
-    unsafe {
-        threshold_ptr.write_volatile(cutoff_threshold);
-    }
+    PLIC.write32(PLIC_THRESHOLD, cutoff_threshold);
 }
 
 // Get next available interrupt through the claim register
 // The PLIC gives the id of the next interrupting device sorted by priority
 pub fn next() -> Option<u32> {
-    let claim_ptr = PLIC_CLAIM as *const u32;
-    let claim_id;
-    unsafe {
-        claim_id = claim_ptr.read_volatile();
-    }
+    let claim_id = PLIC.read32(PLIC_CLAIM);
 
     if claim_id == 0 {
         None
@@ -60,8 +69,94 @@ pub fn next() -> Option<u32> {
 
 // Complete the interrupt
 pub fn complete(id: u32) {
-    let complete_ptr = PLIC_CLAIM as *mut u32;
-    unsafe {
-        complete_ptr.write_volatile(id);
+    PLIC.write32(PLIC_CLAIM, id);
+}
+
+// Claim, handle and complete pending interrupts until there are none left
+// or MAX_CLAIMS_PER_TRAP have been handled. An id that comes back
+// STORM_THRESHOLD times in a row is masked by dropping its priority to 0
+// until unmask_storms() is called.
+// Returns the number of interrupts handled.
+pub fn drain(handle: impl FnMut(u32)) -> usize {
+    drain_on(&PLIC, handle)
+}
+
+// drain() on the PLIC at plic
+fn drain_on(plic: &Mmio, mut handle: impl FnMut(u32)) -> usize {
+    let mut handled = 0;
+    let mut last = 0;
+    let mut repeats = 0;
+    while handled < MAX_CLAIMS_PER_TRAP {
+        let id = match plic.read32(PLIC_CLAIM) {
+            0 => break,
+            id => id,
+        };
+        handle(id);
+        plic.write32(PLIC_CLAIM, id);
+        handled += 1;
+        if id == last {
+            repeats += 1;
+        } else {
+            last = id;
+            repeats = 1;
+        }
+        if repeats >= STORM_THRESHOLD {
+            mask_storm(plic, id);
+            break;
+        }
+    }
+    handled
+}
+
+fn mask_storm(plic: &Mmio, id: u32) {
+    let prio = (plic.read32(PLIC_PRIORITY + id as usize * 4) & 7) as u8;
+    println!("Interrupt storm from id {}, masking it", id);
+    if let Some(saved) = MASKED.get(id as usize) {
+        if prio != 0 {
+            saved.store(prio, Ordering::Relaxed);
+        }
+    }
+    plic.write32(PLIC_PRIORITY + id as usize * 4, 0);
+}
+
+// Give every id masked by drain() its priority back. Called from the
+// timer, so a storming device is quiet for at most one timer period.
+pub fn unmask_storms() {
+    for (id, saved) in MASKED.iter().enumerate() {
+        let prio = saved.swap(0, Ordering::Relaxed);
+        if prio != 0 {
+            set_priority(id as u32, prio);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{dealloc, zalloc, PAGE_SIZE};
+
+    #[test_case]
+    fn a_reasserting_device_is_masked() {
+        // Plain memory up to and including the claim register. Completing
+        // an id writes it back to the claim register, so it is claimed
+        // again, like a device that re-asserts right away.
+        let mem = zalloc((PLIC_CLAIM + 4).div_ceil(PAGE_SIZE));
+        assert!(!mem.is_null());
+        let plic = Mmio::new(mem as usize);
+        let id = 10;
+        plic.write32(PLIC_PRIORITY + id as usize * 4, 3);
+        assert_eq!(drain_on(&plic, |_| panic!("nothing is pending")), 0);
+        plic.write32(PLIC_CLAIM, id);
+        let mut seen = 0;
+        assert_eq!(drain_on(&plic, |claimed| {
+            assert_eq!(claimed, id);
+            seen += 1;
+        }), STORM_THRESHOLD);
+        assert_eq!(seen, STORM_THRESHOLD);
+        assert_eq!(plic.read32(PLIC_PRIORITY + id as usize * 4), 0);
+        // Forget the priority so unmask_storms() doesn't put it on the
+        // real PLIC
+        assert_eq!(MASKED[id as usize].swap(0, Ordering::Relaxed), 3);
+        dealloc(mem);
     }
-}
\ No newline at end of file
+}
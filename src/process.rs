@@ -1,16 +1,66 @@
 // Create and store processes
 
-use alloc::collections::vec_deque::VecDeque;
+use alloc::{collections::vec_deque::VecDeque, string::String, vec::Vec};
+use core::{mem::size_of, ptr::null_mut, sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering}};
 
-use crate::{cpu::{build_satp, mscratch_write, satp_fence_asid, satp_write, SatpMode, TrapFrame},
-            page::{alloc, dealloc, map, unmap, zalloc, EntryBits, Table, PAGE_SIZE}};
+use crate::{clint,
+            cpu::{self, mhartid_read, ms_to_ticks, mtime_read, tlb_shootdown, SavedRegs, TrapFrame, MAX_HARTS},
+            elf::{Elf, ElfError, Segment, PF_R, PF_W, PF_X},
+            error::KernelError,
+            fd::{Blocker, FdError, FdTable, FileDescriptor, Whence},
+            lock::Mutex,
+            log,
+            mem::memcpy,
+            pipe,
+            ramfs::{self, FsError},
+            rng,
+            page::{self, align_val, alloc, allocation_pages, dealloc, for_each_mapping, lookup, map, unmap, unmap_page, virt_to_phys, zalloc, EntryBits, MemInfo, PhysAddr, Table, VirtAddr, PAGE_SIZE},
+            scheduler::{self, SchedDecision},
+            slab::Cache,
+            syscall::{check_preempt, syscall0, Progress},
+            timer::{self, TimerId},
+            vm::{copy_from_user, copy_to_user}};
 
-// Stack pages needed for each process
-const STACK_PAGES: usize = 2;
-// Stack virtual address that is seen by the user
-const STACK_ADDR: usize = 0x1_0000_0000;
+// Most pages a process stack can grow to
+pub const MAX_STACK_PAGES: usize = 16;
+// Bytes at the bottom of the stack taken by the canary, see
+// Process::canary_intact
+const CANARY_SIZE: usize = size_of::<u64>();
+// Lowest stack virtual address that is seen by the user. Only the top
+// page of the stack is mapped at first, the stack grows down towards
+// here one page at a time as the process stores below what is mapped.
+pub const STACK_ADDR: usize = 0x1_0000_0000;
+// The stack pointer starts here, at the top of the stack region
+pub const STACK_TOP: usize = STACK_ADDR + MAX_STACK_PAGES * PAGE_SIZE;
 // All processes will have a defined starting point in virtual memory seen by the user.
-const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
+pub const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
+// Arguments passed to a process are mapped at this virtual address.
+// The first page starts with argv, an array of argc pointers followed by a
+// null pointer, and then the null-terminated strings they point to. If
+// they don't fit in one page, the following pages are mapped after it.
+// The process starts with argc in a0 and argv in a1.
+pub const ARGS_ADDR: usize = 0x3000_0000;
+// The user heap starts here and grows up with sbrk. This is above the
+// identity mapped code in RAM and below the stack.
+pub const HEAP_ADDR: usize = 0xc000_0000;
+// ELF segments have to lie in PAGE_SIZE..ELF_LOAD_END, clear of the null
+// page and of everything the kernel maps for the process from ARGS_ADDR up
+pub const ELF_LOAD_END: usize = ARGS_ADDR;
+// Every process has the vDSO page mapped read and execute here. It is one
+// page the kernel shares with all of them: the syscall trampoline at its
+// start and the time at VDSO_TIME.
+pub const VDSO_ADDR: usize = 0x4000_0000;
+// Offset in the vDSO page of the time as a u64, in mtime ticks. The timer
+// interrupt updates it, so it lags the real time by up to a quantum.
+pub const VDSO_TIME: usize = 0x800;
+// Most pages a process can have mapped at once, counting its code, stack,
+// heap and arguments. It keeps one process from using up all of memory.
+pub const MAX_MAPPED_PAGES: usize = 4096;
+// A mem_limit_pages that adds no limit of its own, see set_mem_limit
+pub const MEM_UNLIMITED: usize = usize::MAX;
+// Most processes the process list will hold at once. The list reserves
+// room for all of them up front so adding a process never reallocates.
+pub const MAX_PROCESSES: usize = 64;
 
 // Here, we store a process list. It uses the global allocator
 // that we made before and its job is to store all processes.
@@ -22,13 +72,65 @@ const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
 // a VecDeque at compile time, so we are somewhat forced to
 // do this.
 pub static mut PROCESS_LIST: Option<VecDeque<Process>> = None;
+// Trap frames are much smaller than a page, so they are carved out of
+// shared pages by a slab cache instead of taking a page each.
+static TRAP_FRAMES: Mutex<Cache<TrapFrame>> = Mutex::new(Cache::new());
 // We can search through the process list to get a new PID, but
-// it's probably easier and faster just to increase the pid:
-static mut NEXT_PID: u16 = 1;
+// it's probably easier and faster just to increase the pid. It's atomic
+// so that two harts creating processes at once never get the same one.
+static NEXT_PID: AtomicU16 = AtomicU16::new(1);
+// Processes blocked in futex_wait, as (physical address, pid) in the
+// order they started waiting. Keying on the physical address means two
+// processes that map the same page at different addresses still meet.
+static FUTEX_WAITERS: Mutex<Vec<(usize, u16)>> = Mutex::new(Vec::new());
+// (key, count) for wake_key calls that found the process list taken
+static PENDING_FUTEX_WAKES: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+// The physical page behind the vDSO, 0 until the first process is made
+static VDSO_PAGE: AtomicUsize = AtomicUsize::new(0);
+// Whether traps look at stack canaries, see stack_smashed
+static CANARY_CHECKS: AtomicBool = AtomicBool::new(false);
+
+// The trap frame each hart last switched to. Traps on the hart save into
+// it until the hart switches again, so it may not be freed before then,
+// see reap.
+static HART_FRAMES: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// The process run_to_exit() runs, and the registers to go back to when
+// it is done
+struct Alone {
+    pid:  u16,
+    regs: SavedRegs
+}
+
+impl Alone {
+    fn regs_ptr(&mut self) -> *mut SavedRegs {
+        &mut self.regs
+    }
+}
+
+static ALONE: Mutex<Option<Alone>> = Mutex::new(None);
+// What the process run_to_exit() last ran wrote to the console
+static ALONE_OUTPUT: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+// What exit_status reads for a process that was killed instead of exiting
+pub const KILLED: usize = usize::MAX;
+
+// Page faults in a row on the same instruction and address after which a
+// process is killed, unless "fault_limit=<n>" says otherwise
+pub const DEFAULT_FAULT_LIMIT: usize = 16;
+static FAULT_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_FAULT_LIMIT);
+
+// The init process, the first one made. It adopts the children of
+// processes that end.
+pub const INIT_PID: u16 = 1;
 
 // Gets make_syscall function symbol from trap.S file
 extern "C" {
 	fn make_syscall(a: usize) -> usize;
+	fn make_syscall_end();
+	fn run_saved(frame: usize, mepc: usize, satp: usize, regs: *mut SavedRegs) -> usize;
+	fn resume_saved(regs: *const SavedRegs, ret: usize) -> !;
 }
 
 // We will eventually move this function out of here, but its
@@ -40,73 +142,1137 @@ fn init_process() {
     loop {
         i += 1;
         if i > 70_000_000 {
-            unsafe { 
-                make_syscall(i);
-            }
+            // The test syscall
+            syscall0(1);
             i = 0;
         }
     }
 }
 
+#[derive(Debug)]
+pub enum ProcError {
+    // The process list hasn't been created yet or is in use
+    NoProcessList,
+    // The process list already holds MAX_PROCESSES processes
+    TooManyProcesses
+}
+
+// Push a process onto the list unless it is already full. On failure the
+// process is handed back so that it is dropped and its memory freed.
+fn push_process(p: Process) -> Result<u16, ProcError> {
+    let pid = p.pid;
+    unsafe {
+        // PROCESS_LIST is wrapped in an Option<> enumeration, which
+        // means that the Option owns the Deque. We can only borrow from
+        // it or move ownership to us. In this case, we choose the
+        // latter, where we move ownership to us, add a process, and
+        // then move ownership back to the PROCESS_LIST.
+        // This allows mutual exclusion as anyone else trying to grab
+        // the process list will get None rather than the Deque.
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            // .take() will replace PROCESS_LIST with None and give
+            // us the only copy of the Deque.
+            let result = if pl.len() < MAX_PROCESSES {
+                pl.push_back(p);
+                Ok(pid)
+            }
+            else {
+                Err(ProcError::TooManyProcesses)
+            };
+            // Now, we no longer need the owned Deque, so we hand it
+            // back by replacing the PROCESS_LIST's None with the
+            // Some(pl).
+            PROCESS_LIST.replace(pl);
+            return result;
+        }
+    }
+    Err(ProcError::NoProcessList)
+}
+
+// Check for room before building a process, creating one allocates its
+// stack, page tables and trap frame only to throw them away again.
+fn process_list_full() -> bool {
+    unsafe {
+        match PROCESS_LIST.as_ref() {
+            Some(pl) => pl.len() >= MAX_PROCESSES,
+            None => false
+        }
+    }
+}
+
 // Add a process given a function address and then
 // push it onto the LinkedList. Uses Process::new_default
 // to create a new stack, etc.
-pub fn add_process_default(pr: fn()) {
-	unsafe {
-		// PROCESS_LIST is wrapped in an Option<> enumeration, which
-		// means that the Option owns the Deque. We can only borrow from
-		// it or move ownership to us. In this case, we choose the
-		// latter, where we move ownership to us, add a process, and
-		// then move ownership back to the PROCESS_LIST.
-		// This allows mutual exclusion as anyone else trying to grab
-		// the process list will get None rather than the Deque.
-		if let Some(mut pl) = PROCESS_LIST.take() {
-			// .take() will replace PROCESS_LIST with None and give
-			// us the only copy of the Deque.
-			let p = Process::new_default(pr);
-			pl.push_back(p);
-			// Now, we no longer need the owned Deque, so we hand it
-			// back by replacing the PROCESS_LIST's None with the
-			// Some(pl).
-			PROCESS_LIST.replace(pl);
-		}
-	}
+// Returns the new process' pid.
+pub fn add_process_default(pr: fn()) -> Result<u16, ProcError> {
+    if process_list_full() {
+        return Err(ProcError::TooManyProcesses);
+    }
+    push_process(Process::new_default(pr))
+}
+
+// Add a process that runs the ELF executable in data. Its segments are
+// copied into fresh pages, so data doesn't have to stay around.
+// Returns the new process' pid.
+pub fn add_process_elf(data: &[u8]) -> Result<u16, ElfError> {
+    let elf = Elf::parse(data)?;
+    if process_list_full() {
+        return Err(ElfError::Process(ProcError::TooManyProcesses));
+    }
+    let mut p = Process::new_bare();
+    // Dropping p on an error frees whatever was loaded so far
+    p.load_elf(&elf)?;
+    p.program_counter = elf.entry();
+    push_process(p).map_err(ElfError::Process)
+}
+
+// Add a process that receives arguments. The entry function is called
+// with argc and argv as described at ARGS_ADDR.
+// Returns the new process' pid.
+pub fn add_process_with_args(entry: extern "C" fn(usize, usize), args: &[&str]) -> Result<u16, ProcError> {
+    if process_list_full() {
+        return Err(ProcError::TooManyProcesses);
+    }
+    let mut p = Process::new_at(entry as usize);
+    p.set_args(args);
+    push_process(p)
+}
+
+// Hand out the next pid. The pid is also the process' ASID, so once the
+// counter wraps around it skips 0 (the kernel's ASID) and any pid that a
+// live process still has.
+pub fn alloc_pid() -> u16 {
+    loop {
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        if pid != 0 && !pid_in_use(pid) {
+            return pid;
+        }
+    }
+}
+
+fn pid_in_use(pid: u16) -> bool {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let used = pl.iter().any(|p| p.pid == pid);
+            PROCESS_LIST.replace(pl);
+            return used;
+        }
+    }
+    false
+}
+
+// The vDSO page, allocated and filled in the first time it is asked for.
+// It is never freed, every process shares it.
+fn vdso_page() -> usize {
+    let page = VDSO_PAGE.load(Ordering::Acquire);
+    if page != 0 {
+        return page;
+    }
+    let new = zalloc(1);
+    assert!(!new.is_null(), "Out of memory for the vDSO");
+    unsafe {
+        let start = make_syscall as *const () as usize;
+        let len = make_syscall_end as *const () as usize - start;
+        memcpy(new, start as *const u8, len);
+    }
+    // The copy went through the data cache. No hart has fetched from the
+    // fresh page yet, but this one may from here on.
+    cpu::fence_i();
+    // Two harts making their first process at once both get here, keep
+    // whichever page was stored first
+    match VDSO_PAGE.compare_exchange(0, new as usize, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => new as usize,
+        Err(page) => {
+            dealloc(new);
+            page
+        }
+    }
+}
+
+
+// Store now as the time processes read from the vDSO
+pub fn set_vdso_time(now: u64) {
+    let page = VDSO_PAGE.load(Ordering::Acquire);
+    if page != 0 {
+        unsafe { ((page + VDSO_TIME) as *mut u64).write_volatile(now); }
+    }
 }
 
 // This should only be called once, and its job is to create
 // the init process. Right now, this process is in the kernel,
 // but later, it should call the shell.
-pub fn init() -> usize {
+// kmain calls this in supervisor mode, where mscratch can't be written,
+// so nothing is switched to the process here. The first context-switch
+// timer does that from machine mode through switch_to_user.
+pub fn init() -> Result<usize, ProcError> {
 	unsafe {
-        // Initialize Process list with a deque(double ended queue with a capacity of 5 processes)
-		PROCESS_LIST = Some(VecDeque::with_capacity(15));
+        // Initialize Process list with a deque(double ended queue) that has
+        // room for every process we will ever allow
+		PROCESS_LIST = Some(VecDeque::with_capacity(MAX_PROCESSES));
         // Add the initial kernel process to the list and give it a process structure
-		add_process_default(init_process);
+		add_process_default(init_process)?;
         // We transfer ownership of the PROCESS_LIST to ourselves then give it back using replace
         // This ensures that any other process using the PROCESS_LIST does not interfere with it
-		let pl = PROCESS_LIST.take().unwrap();
-		let p = pl.front().unwrap().frame;
+		let pl = PROCESS_LIST.take().ok_or(ProcError::NoProcessList)?;
         // Get the program_counter address to jump to that function
-        let func_vaddr = pl.front().unwrap().program_counter;
-        // Take the trap frame of the process and write it to the mscratch
-		let frame = p as *const TrapFrame as usize;
-		mscratch_write(frame);
-        // Fill the satp register with the root page table of the init process
-		satp_write(build_satp(
-			SatpMode::Sv39,
-			1,
-			pl.front().unwrap().root as usize,
-		));
-		// Synchronize PID 1. We use ASID as the PID.
-		satp_fence_asid(1);
+        let func_vaddr = pl.front().map_or(0, |p| p.program_counter);
 		// Put the process list back in the global.
 		PROCESS_LIST.replace(pl);
 		// Return the first instruction's address to execute from the program_counter variable
-		func_vaddr
+		Ok(func_vaddr)
 	}
 }
 
+// Remember where the process that owns frame was interrupted, so that it
+// carries on from there the next time it is scheduled. Returns false if
+// frame isn't a process', such as when the kernel itself was interrupted.
+pub fn save_pc(frame: usize, pc: usize) -> bool {
+    with_frame_process(frame, |p| {
+        p.program_counter = pc;
+        // It got somewhere since its last fault, see fault_storm
+        if pc != p.last_fault.0 {
+            p.fault_repeats = 0;
+        }
+    }).is_some()
+}
+
+pub fn set_fault_limit(limit: usize) {
+    FAULT_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+// Count a page fault of the process that owns frame, by the instruction
+// at epc on the address tval. A fault the kernel deals with runs the
+// instruction again, so the same one faulting on the same address over
+// and over means whatever the kernel does isn't helping. Returns the pid
+// once that has happened more than the limit in a row, for the caller to
+// kill it. Another fault, or the timer finding the process at another
+// pc, starts the count over.
+pub fn fault_storm(frame: usize, epc: usize, tval: usize) -> Option<u16> {
+    let limit = FAULT_LIMIT.load(Ordering::Relaxed);
+    with_frame_process(frame, |p| {
+        if p.last_fault == (epc, tval) {
+            p.fault_repeats += 1;
+        } else {
+            p.last_fault = (epc, tval);
+            p.fault_repeats = 1;
+        }
+        (p.fault_repeats > limit).then_some(p.pid)
+    }).flatten()
+}
+
+// Charge the process that owns frame for the timer tick that interrupted
+// it. Returns false if frame isn't a process'.
+pub fn charge_tick(frame: usize) -> bool {
+    with_frame_process(frame, |p| p.cpu_ticks += 1).is_some()
+}
+
+// Whether the stack canary of process pid is still in place, see
+// Process::canary_intact. False if there is no such process.
+pub fn check_canary(pid: u16) -> bool {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let intact = pl.iter().find(|p| p.pid == pid).is_some_and(|p| p.canary_intact());
+            PROCESS_LIST.replace(pl);
+            return intact;
+        }
+    }
+    false
+}
+
+// Have the timer and store faults check the canary of the process they
+// interrupt. It's off unless "canary" is on the command line.
+pub fn set_canary_checks(on: bool) {
+    CANARY_CHECKS.store(on, Ordering::Relaxed);
+}
+
+// If canary checks are on and the process that owns frame has
+// overwritten its stack canary, report it and return its pid. The caller
+// kills it, since its stack can't be trusted any more.
+pub fn stack_smashed(frame: usize) -> Option<u16> {
+    if !CANARY_CHECKS.load(Ordering::Relaxed) {
+        return None;
+    }
+    let pid = with_frame_process(frame, |p| (!p.canary_intact()).then_some(p.pid)).flatten()?;
+    println!("Stack smashing detected in PID {}", pid);
+    Some(pid)
+}
+
+// What proc_stat() tells user space about a process. The layout is part
+// of the syscall ABI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcStat {
+    pub pid:       u64,
+    // 0 Running, 1 Sleeping, 2 Waiting, 3 Dead, or PROC_GONE
+    pub state:     u64,
+    // The scheduler has no priorities yet, so this is always 0
+    pub priority:  u64,
+    pub cpu_ticks: u64,
+    // Pages mapped into the process' address space
+    pub rss:       u64
+}
+
+// The state of a ProcStat for an index past the last process, such as
+// when processes exited while the caller was going through them
+pub const PROC_GONE: u64 = u64::MAX;
+
+impl ProcStat {
+    fn of(p: &Process) -> Self {
+        ProcStat {
+            pid:       p.pid as u64,
+            state:     p.state as u64,
+            priority:  0,
+            cpu_ticks: p.cpu_ticks,
+            rss:       p.mapped_pages as u64
+        }
+    }
+}
+
+// Copy the ProcStat of process number index, counting in pid order, to
+// the user address buf of the process that owns frame. The process list
+// is rotated by the scheduler, pids give an order that holds between
+// calls. Returns the number of processes. Past the last process a
+// ProcStat with state PROC_GONE is copied and None returned, as it is if
+// frame isn't a process' or buf isn't writable.
+pub fn proc_stat(frame: usize, index: usize, buf: usize) -> Option<usize> {
+    let (stat, count, root) = unsafe {
+        let pl = PROCESS_LIST.take()?;
+        let stat = pl.iter()
+                     .find(|p| pl.iter().filter(|q| q.pid < p.pid).count() == index)
+                     .map(ProcStat::of);
+        let root = pl.iter().find(|p| p.frame as usize == frame).map(|p| p.root);
+        let count = pl.len();
+        PROCESS_LIST.replace(pl);
+        (stat, count, root?)
+    };
+    let out = stat.unwrap_or(ProcStat { state: PROC_GONE, ..ProcStat::default() });
+    let bytes = unsafe { core::slice::from_raw_parts(&out as *const ProcStat as *const u8, size_of::<ProcStat>()) };
+    copy_to_user(unsafe { &*root }, buf, bytes).ok()?;
+    stat.map(|_| count)
+}
+
+// Copy a page::MemInfo to the user address buf of the process that owns
+// frame. Returns None if frame isn't a process' or buf isn't writable.
+pub fn meminfo(frame: usize, buf: usize) -> Option<()> {
+    let info = page::meminfo();
+    let bytes = unsafe { core::slice::from_raw_parts(&info as *const MemInfo as *const u8, size_of::<MemInfo>()) };
+    with_frame_process(frame, |p| copy_to_user(unsafe { &*p.root }, buf, bytes).ok())?
+}
+
+// Pin the process with the given pid to a hart, or let it run on any hart
+// by passing None. Returns false if there is no such process.
+pub fn set_affinity(pid: u16, hart: Option<usize>) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut found = false;
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+                p.set_affinity(hart);
+                found = true;
+            }
+            PROCESS_LIST.replace(pl);
+            return found;
+        }
+    }
+    false
+}
+
+// Make parent the parent of process pid, which it passes on to init when
+// it ends, see adopt_orphans. Returns false if there is no such process.
+pub fn set_parent(pid: u16, parent: u16) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut found = false;
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+                p.parent = parent;
+                found = true;
+            }
+            PROCESS_LIST.replace(pl);
+            return found;
+        }
+    }
+    false
+}
+
+// Set the thread pointer (tp, x4) the process with the given pid runs
+// with. Its trap frame is saved and restored whole on every trap and
+// switch, and the kernel never uses tp itself, so the value stays until
+// the process changes it. Returns false if there is no such process.
+pub fn set_thread_pointer(pid: u16, val: usize) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut found = false;
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+                (*p.frame).regs[4] = val;
+                found = true;
+            }
+            PROCESS_LIST.replace(pl);
+            return found;
+        }
+    }
+    false
+}
+
+// Find the process that owns a trap frame. The trap vector hands us the
+// frame from mscratch, so this is how a trap finds out who it came from.
+pub fn pid_of_frame(frame: usize) -> Option<u16> {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let pid = pl.iter().find(|p| p.frame as usize == frame).map(|p| p.pid);
+            PROCESS_LIST.replace(pl);
+            return pid;
+        }
+    }
+    None
+}
+
+// The trap frame and page table of process pid, for tests that run
+// syscalls against a process without switching to it
+#[cfg(test)]
+pub fn frame_of(pid: u16) -> Option<(*mut TrapFrame, *mut Table)> {
+    unsafe {
+        let pl = PROCESS_LIST.take()?;
+        let found = pl.iter().find(|p| p.pid == pid).map(|p| (p.frame, p.root));
+        PROCESS_LIST.replace(pl);
+        found
+    }
+}
+
+// The state of process pid, for tests
+#[cfg(test)]
+pub fn state_of(pid: u16) -> Option<ProcessState> {
+    unsafe {
+        let pl = PROCESS_LIST.take()?;
+        let state = pl.iter().find(|p| p.pid == pid).map(|p| p.state);
+        PROCESS_LIST.replace(pl);
+        state
+    }
+}
+
+// An entry point that yields forever, for tests that need a process
+// that is there but never has to do anything
+#[cfg(test)]
+pub extern "C" fn spin_entry(_argc: usize, _argv: usize) {
+    loop {
+        syscall0(5);
+    }
+}
+
+// End process pid on its own request, with status for whoever waits on
+// it, see run_to_exit
+pub fn exit(pid: u16, status: usize) -> Result<(), KernelError> {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+                p.exit_status = status;
+            }
+            PROCESS_LIST.replace(pl);
+        }
+    }
+    kill(pid)
+}
+
+// Run process pid on this hart until it exits, with nothing else
+// scheduled and the context-switch timer off, and return its exit
+// status, or KILLED if something else ended it. This is for tests that
+// want a program's syscalls to happen in one fixed order. It switches to
+// the process itself, so it has to be called in machine mode.
+pub fn run_to_exit(pid: u16) -> Result<usize, KernelError> {
+    if running_alone().is_some() {
+        return Err(KernelError::WouldBlock);
+    }
+    let next = unsafe {
+        let pl = PROCESS_LIST.take().ok_or(KernelError::NotFound)?;
+        let next = pl.iter().find(|p| p.pid == pid && p.state == ProcessState::Running).map(SchedDecision::from);
+        PROCESS_LIST.replace(pl);
+        next.ok_or(KernelError::NotFound)?
+    };
+    // Everything switching to the process changes, to put back after
+    let hart = mhartid_read();
+    let (mstatus, mie, mscratch, satp) = (cpu::mstatus_read(), cpu::mie_read(), cpu::mscratch_read(), cpu::satp_read());
+    let mtimecmp = clint::read_mtimecmp(hart);
+    clint::set_mtimecmp(hart, u64::MAX);
+    // run_saved and resume_saved get at the registers without the lock,
+    // nothing else touches them while ALONE is set
+    ALONE_OUTPUT.lock().clear();
+    let regs = ALONE.lock().insert(Alone { pid, regs: SavedRegs::zero() }).regs_ptr();
+    switched_to(hart, next.frame_addr);
+    let status = unsafe { run_saved(next.frame_addr, next.pc, next.satp, regs) };
+    switched_to(hart, mscratch);
+    *ALONE.lock() = None;
+    clint::set_mtimecmp(hart, mtimecmp);
+    cpu::mscratch_write(mscratch);
+    cpu::mie_write(mie);
+    cpu::satp_write_fence(satp);
+    cpu::mstatus_write(mstatus);
+    Ok(status)
+}
+
+// The process run_to_exit() is running, if it is
+pub fn running_alone() -> Option<u16> {
+    ALONE.lock().as_ref().map(|a| a.pid)
+}
+
+// Keep a copy of console output while run_to_exit() runs a process. It
+// has the hart to itself, so the output can only be that process'.
+pub fn capture_console(buf: &[u8]) {
+    if running_alone().is_some() {
+        ALONE_OUTPUT.lock().extend_from_slice(buf);
+    }
+}
+
+// What the process the last run_to_exit() ran wrote to the console
+pub fn take_alone_output() -> Vec<u8> {
+    core::mem::take(&mut *ALONE_OUTPUT.lock())
+}
+
+// If the process run_to_exit() runs is done, go back to run_to_exit()
+// with its exit status. Called by run_next, which every way for a process
+// to end goes through. Returns if there is nothing to go back to.
+pub fn resume_if_alone_done() {
+    let Some(pid) = running_alone() else {
+        return;
+    };
+    let status = unsafe {
+        match PROCESS_LIST.take() {
+            Some(pl) => {
+                let status = match pl.iter().find(|p| p.pid == pid) {
+                    Some(p) if p.state == ProcessState::Dead => Some(p.exit_status),
+                    Some(_) => None,
+                    None => Some(KILLED)
+                };
+                PROCESS_LIST.replace(pl);
+                status
+            },
+            None => None
+        }
+    };
+    if let Some(status) = status {
+        let regs = ALONE.lock().as_mut().map(Alone::regs_ptr);
+        if let Some(regs) = regs {
+            unsafe { resume_saved(regs, status); }
+        }
+    }
+}
+
+// Mark a process as Dead so the scheduler no longer picks it. Killing a
+// process that is already dead does nothing.
+// Fails with NotFound if there is no such process.
+pub fn kill(pid: u16) -> Result<(), KernelError> {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut found = Err(KernelError::NotFound);
+            let mut ended = false;
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+                if p.state == ProcessState::Sleeping {
+                    timer::cancel(TimerId::Wake(pid));
+                }
+                if p.state != ProcessState::Dead {
+                    // Every live state may become Dead
+                    let _ = p.transition(ProcessState::Dead);
+                    // Close its pipe ends so the other ends see it go
+                    p.data.fds.close_all();
+                    ended = true;
+                }
+                found = Ok(());
+            }
+            if ended {
+                adopt_orphans(&mut pl, pid);
+            }
+            PROCESS_LIST.replace(pl);
+            return found;
+        }
+    }
+    Err(KernelError::NotFound)
+}
+
+// Hand the children of pid, which just ended, to init. Children that are
+// Dead already were waiting for pid to collect them, and init doesn't
+// wait for anything, so they are marked to be reaped. Their memory isn't
+// freed here, one may have died on another hart that is still on its
+// trap frame, see reap. When init itself ends its children have no
+// parent left. Returns how many were marked.
+fn adopt_orphans(pl: &mut VecDeque<Process>, pid: u16) -> usize {
+    let mut marked = 0;
+    for p in pl.iter_mut().filter(|p| p.parent == pid && p.state == ProcessState::Dead) {
+        p.reap_pending = true;
+        marked += 1;
+    }
+    let heir = if pid == INIT_PID { 0 } else { INIT_PID };
+    pl.iter_mut().filter(|p| p.parent == pid).for_each(|p| p.parent = heir);
+    marked
+}
+
+// Record that hart is about to switch to the process with the trap frame
+// at frame. Called right before every switch to a process.
+pub fn switched_to(hart: usize, frame: usize) {
+    HART_FRAMES[hart % MAX_HARTS].store(frame, Ordering::Release);
+}
+
+// Free the processes adopt_orphans marked whose trap frame no hart is on
+// anymore. The scheduler calls this with the list taken, so nothing can
+// switch to them meanwhile. Returns how many were freed.
+pub fn reap(pl: &mut VecDeque<Process>) -> usize {
+    let before = pl.len();
+    pl.retain(|p| {
+        !p.reap_pending || HART_FRAMES.iter().any(|f| f.load(Ordering::Acquire) == p.frame as usize)
+    });
+    before - pl.len()
+}
+
+// Read a saved register of a stopped process for a debugger.
+// regnum 0..32 are x0-x31 and 32..64 are f0-f31. Returns None if there
+// is no such process or register, or if the process is Running, since
+// its registers only sit still in the trap frame while it isn't.
+pub fn read_reg(pid: u16, regnum: usize) -> Option<usize> {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let val = pl.iter()
+                        .find(|p| p.pid == pid && p.state != ProcessState::Running)
+                        .and_then(|p| {
+                            let frame = &*p.frame;
+                            match regnum {
+                                0..32 => Some(frame.regs[regnum]),
+                                32..64 => Some(frame.fregs[regnum - 32]),
+                                _ => None
+                            }
+                        });
+            PROCESS_LIST.replace(pl);
+            return val;
+        }
+    }
+    None
+}
+
+// Change a saved register of a stopped process, numbered as in read_reg.
+// x0 is always zero, so writing it fails like writing a bad register.
+// The new value is loaded when the process is next scheduled.
+pub fn write_reg(pid: u16, regnum: usize, val: usize) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut written = false;
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid && p.state != ProcessState::Running) {
+                let frame = &mut *p.frame;
+                match regnum {
+                    1..32 => {
+                        frame.regs[regnum] = val;
+                        written = true;
+                    },
+                    32..64 => {
+                        frame.fregs[regnum - 32] = val;
+                        written = true;
+                    },
+                    _ => {}
+                }
+            }
+            PROCESS_LIST.replace(pl);
+            return written;
+        }
+    }
+    false
+}
+
+// Why a stack couldn't grow
+#[derive(Debug, PartialEq)]
+pub enum GrowError {
+    // The frame doesn't belong to a process
+    NoProcess,
+    // The address isn't in the unmapped part of the stack region
+    NotStack,
+    OutOfMemory,
+    // It would take the process over its page limit, see set_mem_limit
+    OverLimit
+}
+
+// Grow the stack of the process that owns frame down to cover vaddr.
+// Called on a store page fault.
+pub fn grow_stack(frame: usize, vaddr: usize) -> Result<(), GrowError> {
+    with_frame_process(frame, |p| p.grow_stack(vaddr)).unwrap_or(Err(GrowError::NoProcess))
+}
+
+// Limit process pid to pages mapped pages, like RLIMIT_AS in pages.
+// MEM_UNLIMITED leaves only MAX_MAPPED_PAGES, which is the default. A
+// limit below what the process has now doesn't take anything away, it
+// only keeps it from growing. Heap growth past the limit fails and stack
+// growth gets the process killed. Returns false if there is no such
+// process.
+pub fn set_mem_limit(pid: u16, pages: usize) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut found = false;
+            if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+                p.mem_limit_pages = pages;
+                found = true;
+            }
+            PROCESS_LIST.replace(pl);
+            return found;
+        }
+    }
+    false
+}
+
+// Number of pages process pid has mapped, None if there is no such process
+pub fn rss(pid: u16) -> Option<usize> {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let pages = pl.iter().find(|p| p.pid == pid).map(|p| p.mapped_pages);
+            PROCESS_LIST.replace(pl);
+            return pages;
+        }
+    }
+    None
+}
+
+// One user page of a snapshot. Pages the process owns are copied, since
+// they change or are freed along with it. The rest, the kernel code a
+// process from new_at() runs and the vDSO, are the same for everyone and
+// outlive any process, so only where they are is kept.
+enum SnapshotData {
+    Copied(*mut u8),
+    Shared(usize)
+}
+
+struct SnapshotPage {
+    vaddr: usize,
+    bits:  i64,
+    data:  SnapshotData
+}
+
+// A process' registers and memory at one point in time, see checkpoint.
+// It holds the page copies until it is dropped.
+pub struct ProcessSnapshot {
+    pid:             u16,
+    regs:            [usize; 32],
+    fregs:           [usize; 32],
+    program_counter: usize,
+    restart_done:    usize,
+    stack_bottom:    usize,
+    heap_top:        usize,
+    // The ELF segments and the argument pages, which all belong to the
+    // process that is restored
+    segments:        Vec<(usize, usize)>,
+    canary:          u64,
+    mem_limit_pages: usize,
+    affinity:        Option<usize>,
+    pages:           Vec<SnapshotPage>
+}
+
+impl ProcessSnapshot {
+    // The process it was taken of
+    pub fn pid(&self) -> u16 {
+        self.pid
+    }
+
+    // Pages it holds a copy of
+    pub fn copied_pages(&self) -> usize {
+        self.pages.iter().filter(|pg| matches!(pg.data, SnapshotData::Copied(_))).count()
+    }
+}
+
+impl Drop for ProcessSnapshot {
+    fn drop(&mut self) {
+        for pg in self.pages.iter() {
+            if let SnapshotData::Copied(copy) = pg.data {
+                dealloc(copy);
+            }
+        }
+    }
+}
+
+// Take a snapshot of process pid: its registers, where it carries on, and
+// a copy of every page it has mapped. Its open files and working
+// directory aren't part of it. Fails with NotFound if there is no such
+// process and OutOfMemory if the copies don't fit, in which case nothing
+// is held on to.
+pub fn checkpoint(pid: u16) -> Result<ProcessSnapshot, KernelError> {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let snap = match pl.iter().find(|p| p.pid == pid) {
+                Some(p) => p.snapshot(),
+                None => Err(KernelError::NotFound)
+            };
+            PROCESS_LIST.replace(pl);
+            return snap;
+        }
+    }
+    Err(KernelError::NotFound)
+}
+
+// Make a new process out of snap, with memory and registers as they were
+// when it was taken, and return its pid. The snapshot can be restored any
+// number of times, every process gets copies of its own. The new process
+// starts runnable with only the console open, and has no parent.
+pub fn restore(snap: &ProcessSnapshot) -> Result<u16, KernelError> {
+    if process_list_full() {
+        return Err(KernelError::TooManyProcesses);
+    }
+    let mut p = Process::new_bare();
+    // The stack comes from the snapshot, not the page new_bare() mapped
+    p.free_user_pages(p.stack_bottom, STACK_TOP);
+    // Set the ranges first, so that dropping p on failure frees whatever
+    // was mapped into them
+    p.stack_bottom = snap.stack_bottom;
+    p.heap_top = snap.heap_top;
+    p.segments = snap.segments.clone();
+    p.canary = snap.canary;
+    p.mem_limit_pages = snap.mem_limit_pages;
+    p.affinity = snap.affinity;
+    p.program_counter = snap.program_counter;
+    p.restart_done = snap.restart_done;
+    unsafe {
+        (*p.frame).regs = snap.regs;
+        (*p.frame).fregs = snap.fregs;
+    }
+    for pg in snap.pages.iter() {
+        let paddr = match pg.data {
+            SnapshotData::Copied(copy) => {
+                let page = alloc(1);
+                if page.is_null() {
+                    return Err(KernelError::OutOfMemory);
+                }
+                unsafe { memcpy(page, copy, PAGE_SIZE); }
+                page as usize
+            },
+            SnapshotData::Shared(paddr) => {
+                // new_bare() has mapped the vDSO already
+                if lookup(unsafe { &*p.root }, VirtAddr(pg.vaddr)).is_some() {
+                    continue;
+                }
+                paddr
+            }
+        };
+        if !p.map_user(pg.vaddr, paddr, pg.bits) {
+            if let SnapshotData::Copied(_) = pg.data {
+                dealloc(paddr as *mut u8);
+            }
+            return Err(KernelError::OutOfMemory);
+        }
+    }
+    Ok(push_process(p)?)
+}
+
+// Run f on the process that owns frame, None if there isn't one
+fn with_frame_process<R>(frame: usize, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let ret = pl.iter_mut().find(|p| p.frame as usize == frame).map(f);
+            PROCESS_LIST.replace(pl);
+            return ret;
+        }
+    }
+    None
+}
+
+// User buffers are moved through the kernel this many bytes at a time
+const FD_CHUNK: usize = PAGE_SIZE;
+
+// Open a file in the RAM filesystem for the process that owns frame.
+// path is a user address holding len bytes, with create a missing file is
+// made empty. Returns the new descriptor.
+pub fn fd_open(frame: usize, path: usize, len: usize, create: bool) -> Result<usize, FdError> {
+    with_frame_process(frame, |p| {
+        if len > ramfs::NAME_MAX + 1 {
+            return Err(FdError::Fs(FsError::BadPath));
+        }
+        let mut buf = [0u8; ramfs::NAME_MAX + 1];
+        copy_from_user(unsafe { &*p.root }, &mut buf[..len], path).map_err(|_| FdError::Fault)?;
+        let path = core::str::from_utf8(&buf[..len]).map_err(|_| FdError::Fs(FsError::BadPath))?;
+        let name = p.data.resolve(path)?;
+        let handle = match ramfs::open(&name) {
+            Err(FsError::NotFound) if create => ramfs::create(&name)?,
+            other => other?
+        };
+        p.data.fds.insert(FileDescriptor::file(handle))
+    }).unwrap_or(Err(FdError::BadFd))
+}
+
+pub fn fd_close(frame: usize, fd: usize) -> Result<(), FdError> {
+    with_frame_process(frame, |p| p.data.fds.close(fd)).unwrap_or(Err(FdError::BadFd))
+}
+
+// Make a pipe for the process that owns frame and copy its read and write
+// descriptors, in that order, to the user address fds as two 32-bit ints
+// like C's int fds[2]. Running out of pipes is TooManyOpen too.
+pub fn fd_pipe(frame: usize, fds: usize) -> Result<(), FdError> {
+    with_frame_process(frame, |p| {
+        let (read, write) = pipe::create().ok_or(FdError::TooManyOpen)?;
+        let rfd = p.data.fds.insert(FileDescriptor::pipe(read))?;
+        let wfd = match p.data.fds.insert(FileDescriptor::pipe(write)) {
+            Ok(wfd) => wfd,
+            Err(e) => {
+                let _ = p.data.fds.close(rfd);
+                return Err(e);
+            }
+        };
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&(rfd as i32).to_ne_bytes());
+        out[4..].copy_from_slice(&(wfd as i32).to_ne_bytes());
+        if copy_to_user(unsafe { &*p.root }, fds, &out).is_err() {
+            let _ = p.data.fds.close(rfd);
+            let _ = p.data.fds.close(wfd);
+            return Err(FdError::Fault);
+        }
+        Ok(())
+    }).unwrap_or(Err(FdError::BadFd))
+}
+
+// What fd of the process that owns frame waits on after WouldBlock, see
+// FileDescriptor::blocker
+pub fn fd_blocker(frame: usize, fd: usize) -> Option<Blocker> {
+    with_frame_process(frame, |p| p.data.fds.get_mut(fd).ok()?.blocker()).flatten()
+}
+
+// Write count bytes from the user address buf to fd, see FileDescriptor::write
+// If the timer wants the hart back partway through, this stops after the
+// chunk it is on and returns Preempted with the bytes written by this
+// call. The caller moves buf and count past them and runs the syscall
+// again later, and Done finally counts every byte written by all of them.
+// A full pipe does the same with Blocked, and the caller waits for room.
+pub fn fd_write(frame: usize, fd: usize, buf: usize, count: usize) -> Result<Progress, FdError> {
+    with_frame_process(frame, |p| {
+        let root = unsafe { &*p.root };
+        let desc = p.data.fds.get_mut(fd);
+        let ret = desc.and_then(|desc| {
+            let mut chunk = Vec::new();
+            let mut done = 0;
+            while done < count {
+                let len = (count - done).min(FD_CHUNK);
+                chunk.resize(len, 0);
+                copy_from_user(root, &mut chunk, buf.checked_add(done).ok_or(FdError::Fault)?).map_err(|_| FdError::Fault)?;
+                done += match desc.write(&chunk) {
+                    Err(FdError::WouldBlock) => return Ok(Progress::Blocked(done)),
+                    ret => ret?
+                };
+                if done < count && check_preempt() {
+                    return Ok(Progress::Preempted(done));
+                }
+            }
+            Ok(Progress::Done(done))
+        });
+        // What earlier runs of a restarted write did
+        match ret {
+            Ok(Progress::Preempted(done)) | Ok(Progress::Blocked(done)) => p.restart_done += done,
+            Ok(Progress::Done(done)) => return Ok(Progress::Done(done + core::mem::take(&mut p.restart_done))),
+            Err(_) => p.restart_done = 0
+        }
+        ret
+    }).unwrap_or(Err(FdError::BadFd))
+}
+
+// Read up to count bytes from fd into the user address buf, see
+// FileDescriptor::read. Stops early at the end of the file. The offset
+// only moves past what made it to buf, so a read that faults can be made
+// again without losing anything.
+pub fn fd_read(frame: usize, fd: usize, buf: usize, count: usize) -> Result<usize, FdError> {
+    with_frame_process(frame, |p| {
+        let root = unsafe { &*p.root };
+        let desc = p.data.fds.get_mut(fd)?;
+        let mut chunk = Vec::new();
+        let mut done = 0;
+        while done < count {
+            chunk.resize((count - done).min(FD_CHUNK), 0);
+            let len = match desc.read(&mut chunk) {
+                // Hand back what we have rather than wait for more
+                Err(FdError::WouldBlock) if done > 0 => break,
+                ret => ret?
+            };
+            copy_to_user(root, buf.checked_add(done).ok_or(FdError::Fault)?, &chunk[..len]).map_err(|_| FdError::Fault)?;
+            desc.advance(len);
+            done += len;
+            if len < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }).unwrap_or(Err(FdError::BadFd))
+}
+
+// Move the offset of fd, see FileDescriptor::seek
+pub fn fd_seek(frame: usize, fd: usize, offset: isize, whence: Whence) -> Result<usize, FdError> {
+    with_frame_process(frame, |p| p.data.fds.get_mut(fd)?.seek(offset, whence)).unwrap_or(Err(FdError::BadFd))
+}
+
+// Put the process that owns frame to sleep for ms milliseconds, to carry
+// on at pc when it wakes. With ms = 0 it stays runnable and only gives up
+// the rest of its quantum. The caller has to schedule something else.
+// Returns false if frame isn't a process' or the process can't sleep.
+pub fn msleep(frame: usize, ms: u64, pc: usize) -> bool {
+    with_frame_process(frame, |p| {
+        if ms == 0 {
+            p.program_counter = pc;
+            unsafe { (*p.frame).regs[10] = 0; }
+            true
+        } else {
+            // Sleeping for longer than mtime can count is sleeping forever
+            let until = scheduler::now().saturating_add(ms_to_ticks(ms));
+            if timer::at(until, TimerId::Wake(p.pid)).is_err() {
+                return false;
+            }
+            if p.sleep(until as usize, pc).is_err() {
+                timer::cancel(TimerId::Wake(p.pid));
+                return false;
+            }
+            true
+        }
+    }).unwrap_or(false)
+}
+
+// Wake the process pid if it is asleep and its time is up at now. Called
+// when its wake up timer fires. Returns true if the process was woken.
+pub fn wake_due(pid: u16, now: usize) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let woken = pl.iter_mut().find(|p| p.pid == pid).is_some_and(|p| p.wake_if_due(now));
+            PROCESS_LIST.replace(pl);
+            return woken;
+        }
+    }
+    false
+}
+
+// What futex_wait did with the calling process
+#[derive(Debug, PartialEq)]
+pub enum FutexWait {
+    // The process is Waiting, the caller has to schedule something else
+    Blocked,
+    // The word no longer held the expected value, the process carries on
+    Changed
+}
+
+// The physical address a futex word at the user address uaddr is keyed
+// on. The word has to be 4 byte aligned and mapped in root.
+fn futex_key(root: &Table, uaddr: usize) -> Option<usize> {
+    if !uaddr.is_multiple_of(4) {
+        return None;
+    }
+    virt_to_phys(root, VirtAddr(uaddr)).map(usize::from)
+}
+
+// Block the process that owns frame until futex_wake is called on uaddr,
+// but only if the 32-bit word at uaddr still holds expected. It resumes at
+// pc with 0 in a0. Returns None if frame isn't a process' or uaddr isn't
+// an aligned, readable user address.
+pub fn futex_wait(frame: usize, uaddr: usize, expected: u32, pc: usize) -> Option<FutexWait> {
+    with_frame_process(frame, |p| {
+        let root = unsafe { &*p.root };
+        let key = futex_key(root, uaddr)?;
+        // The word is read again with the waiters locked, so a waker that
+        // changes it and then calls futex_wake either runs before this
+        // check and we see the new value, or after we are in the list.
+        let mut waiters = FUTEX_WAITERS.lock();
+        let mut word = [0u8; 4];
+        copy_from_user(root, &mut word, uaddr).ok()?;
+        if u32::from_ne_bytes(word) != expected {
+            return Some(FutexWait::Changed);
+        }
+        p.wait(&mut waiters, key, pc).ok()?;
+        unsafe { (*p.frame).regs[10] = 0; }
+        Some(FutexWait::Blocked)
+    }).flatten()
+}
+
+// Block the process that owns frame until wake_key is called on key. The
+// kernel waits on its own objects by using their address as the key. The
+// process resumes at pc with its registers as they are. Returns false if
+// frame isn't a process' or the process can't wait.
+pub fn wait_on(frame: usize, key: usize, pc: usize) -> bool {
+    with_frame_process(frame, |p| p.wait(&mut FUTEX_WAITERS.lock(), key, pc).is_ok()).unwrap_or(false)
+}
+
+// Make the process that owns frame Waiting, to resume at pc with its
+// registers as they are. Whoever blocks it has to remember the pid and
+// call unblock() later, see sync::WaitQueue. Returns the pid, or None if
+// frame isn't a process' or the process can't wait.
+pub fn block(frame: usize, pc: usize) -> Option<u16> {
+    with_frame_process(frame, |p| {
+        p.transition(ProcessState::Waiting).ok()?;
+        p.program_counter = pc;
+        Some(p.pid)
+    }).flatten()
+}
+
+// Make the Waiting process pid runnable again. Returns false if there is
+// no such process or it isn't Waiting, like when it was killed meanwhile.
+pub fn unblock(pid: u16) -> bool {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let woken = pl.iter_mut()
+                          .find(|p| p.pid == pid)
+                          .is_some_and(|p| p.state == ProcessState::Waiting && p.transition(ProcessState::Running).is_ok());
+            PROCESS_LIST.replace(pl);
+            return woken;
+        }
+    }
+    false
+}
+
+// Wake up to count processes waiting on uaddr in the address space of the
+// process that owns frame, oldest first. Returns how many were woken, or
+// None if frame isn't a process' or uaddr isn't mapped.
+pub fn futex_wake(frame: usize, uaddr: usize, count: usize) -> Option<usize> {
+    let key = with_frame_process(frame, |p| futex_key(unsafe { &*p.root }, uaddr)).flatten()?;
+    Some(wake_key(key, count))
+}
+
+// Wake up to count processes waiting on key, a physical address.
+// The process list is taken before FUTEX_WAITERS, the same order as
+// futex_wait. If another hart has the list the wake up is put off until
+// run_futex_wakes, and the waiters it will wake are counted instead.
+pub fn wake_key(key: usize, count: usize) -> usize {
+    let Some(mut pl) = (unsafe { PROCESS_LIST.take() }) else {
+        let queued = FUTEX_WAITERS.lock().iter().filter(|&&(k, _)| k == key).count();
+        PENDING_FUTEX_WAKES.lock().push((key, count));
+        return queued.min(count);
+    };
+    let mut waiters = FUTEX_WAITERS.lock();
+    let mut woken = 0;
+    let mut i = 0;
+    while i < waiters.len() && woken < count {
+        if waiters[i].0 != key {
+            i += 1;
+            continue;
+        }
+        let (_, pid) = waiters.remove(i);
+        // A process that was killed while it waited is dropped
+        // from the queue without using up one of the wake ups
+        if let Some(p) = pl.iter_mut().find(|p| p.pid == pid) {
+            if p.state == ProcessState::Waiting && p.transition(ProcessState::Running).is_ok() {
+                woken += 1;
+            }
+        }
+    }
+    drop(waiters);
+    unsafe { PROCESS_LIST.replace(pl); }
+    woken
+}
+
+// Do the wake ups wake_key had to put off. Called, like pipe::run_wakes,
+// where this hart doesn't have the process list taken.
+pub fn run_futex_wakes() {
+    let pending = core::mem::take(&mut *PENDING_FUTEX_WAKES.lock());
+    for (key, count) in pending {
+        wake_key(key, count);
+    }
+}
+
+// Move the heap top of the process that owns frame by incr bytes.
+// Returns the previous heap top, or None if the heap would shrink below
+// HEAP_ADDR or we ran out of memory, in which case nothing changes.
+pub fn sbrk(frame: usize, incr: isize) -> Option<usize> {
+    unsafe {
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            let ret = match pl.iter_mut().find(|p| p.frame as usize == frame) {
+                Some(p) => p.sbrk(incr),
+                None => None,
+            };
+            PROCESS_LIST.replace(pl);
+            return ret;
+        }
+    }
+    None
+}
+
 // A process can have four states, represent them using an enum
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ProcessState {
     Running,
     Sleeping,
@@ -114,19 +1280,121 @@ pub enum ProcessState {
     Dead
 }
 
+impl ProcessState {
+    // The legal state changes. A process only stops running by going to
+    // sleep, waiting or dying, it only runs again from Sleeping or
+    // Waiting, and nothing comes back from Dead.
+    pub fn can_become(self, to: ProcessState) -> bool {
+        use ProcessState::*;
+        matches!((self, to),
+                 (Running, Sleeping) | (Running, Waiting) | (Running, Dead) |
+                 (Sleeping, Running) | (Sleeping, Dead) |
+                 (Waiting, Running) | (Waiting, Dead))
+    }
+}
+
+// A state change Process::transition refused
+#[derive(Debug)]
+pub struct StateError {
+    pub pid:  u16,
+    pub from: ProcessState,
+    pub to:   ProcessState
+}
+
+// A single recorded state change of a process
+#[derive(Clone, Copy)]
+struct StateRecord {
+    pid:    u16,
+    from:   ProcessState,
+    to:     ProcessState,
+    tick:   u64
+}
+
+// State transitions are kept in a fixed-size ring so that tracing never
+// allocates. Once the ring is full, the oldest record is overwritten.
+const STATE_HISTORY_LEN: usize = 32;
+#[derive(Clone, Copy)]
+struct StateHistory {
+    records: [Option<StateRecord>; STATE_HISTORY_LEN],
+    // Index of the slot the next record is written to
+    next:    usize
+}
+
+impl StateHistory {
+    // The records from oldest to newest
+    fn iter(&self) -> impl Iterator<Item = &StateRecord> {
+        (0..STATE_HISTORY_LEN).filter_map(move |i| self.records[(self.next + i) % STATE_HISTORY_LEN].as_ref())
+    }
+}
+
+static STATE_HISTORY: Mutex<StateHistory> = Mutex::new(StateHistory { records: [None; STATE_HISTORY_LEN], next: 0 });
+
+fn record_state_transition(pid: u16, from: ProcessState, to: ProcessState) {
+    let mut history = STATE_HISTORY.lock();
+    let next = history.next;
+    history.records[next] = Some(StateRecord {
+        pid,
+        from,
+        to,
+        tick: mtime_read()
+    });
+    history.next = (next + 1) % STATE_HISTORY_LEN;
+}
+
+// Print the recorded state transitions from oldest to newest
+// Recording is enabled with log::set_trace_process_state(true)
+pub fn dump_state_history() {
+    println!("PROCESS STATE HISTORY");
+    // A copy, so nothing is printed with the lock held
+    let history = *STATE_HISTORY.lock();
+    for rec in history.iter() {
+        println!("[{:>12}] PID {:>5}: {:?} -> {:?}", rec.tick, rec.pid, rec.from, rec.to);
+    }
+}
+
 // A process struct in C-style ABI
 // A process includes the trap frame, it's stack, the program counter for execution, process id,
 // root page table, process state and it's private data
 #[repr(C)]
 pub struct Process {
     frame:              *mut TrapFrame,
-    stack:              *mut u8,
+    // Lowest mapped stack address, the stack spans stack_bottom..STACK_TOP
+    stack_bottom:       usize,
     program_counter:    usize,
     pid:                u16,
     root:               *mut Table,
     state:              ProcessState,
     data:               ProcessData,
-    sleep_until:        usize
+    // Pages holding argv and the argument strings, null if there are none
+    args:               *mut u8,
+    // End of the user heap, which spans HEAP_ADDR..heap_top
+    heap_top:           usize,
+    sleep_until:        usize,
+    // Pages mapped into the user address space, see page_limit
+    mapped_pages:       usize,
+    // Its own cap on mapped_pages, see set_mem_limit
+    mem_limit_pages:    usize,
+    // Page-aligned ranges holding the process' ELF segments
+    segments:           Vec<(usize, usize)>,
+    // What a syscall that was preempted and will run again has done so far
+    restart_done:       usize,
+    // Context-switch timer ticks that found the process running
+    cpu_ticks:          u64,
+    // Kept in the word at stack_bottom, under the usable stack, see
+    // canary_intact
+    canary:             u64,
+    // Dead and collected, to be freed by reap
+    reap_pending:       bool,
+    // What it passed to exit, KILLED until then
+    exit_status:        usize,
+    // The process it belongs to, 0 for one the kernel started
+    parent:             u16,
+    // (epc, tval) of its last page fault and how many times in a row it
+    // has happened, see fault_storm
+    last_fault:         (usize, usize),
+    fault_repeats:      usize,
+    // The hart this process is pinned to, None means any hart
+    affinity:           Option<usize>
 }
 
 impl Process {
@@ -150,60 +1418,448 @@ impl Process {
         &self.state
     }
 
+    // All state changes go through here so illegal ones are caught and
+    // legal ones can be traced. An illegal change leaves the state as it
+    // was, and panics in debug builds so the bug is found where it happens.
+    pub fn transition(&mut self, to: ProcessState) -> Result<(), StateError> {
+        if !self.state.can_become(to) {
+            let err = StateError { pid: self.pid, from: self.state, to };
+            if cfg!(debug_assertions) {
+                panic!("Illegal process state change: {:?}", err);
+            }
+            return Err(err);
+        }
+        if log::trace_process_state() {
+            record_state_transition(self.pid, self.state, to);
+        }
+        self.state = to;
+        Ok(())
+    }
+
     pub fn get_sleep_until(&self) -> usize {
         self.sleep_until as usize
     }
 
+    // Sleep until mtime reaches until, then carry on at pc
+    pub fn sleep(&mut self, until: usize, pc: usize) -> Result<(), StateError> {
+        self.transition(ProcessState::Sleeping)?;
+        self.sleep_until = until;
+        self.program_counter = pc;
+        Ok(())
+    }
+
+    // Make the process Waiting on key, with its entry in waiters, and have
+    // it resume at pc once it is woken
+    fn wait(&mut self, waiters: &mut Vec<(usize, u16)>, key: usize, pc: usize) -> Result<(), StateError> {
+        self.transition(ProcessState::Waiting)?;
+        self.program_counter = pc;
+        waiters.push((key, self.pid));
+        Ok(())
+    }
+
+    // Make a sleeping process runnable again if mtime has reached its
+    // wake up time. The process gets the milliseconds it still had left
+    // to sleep in a0, which is 0 unless it was woken early.
+    // Returns true if the process was woken.
+    pub fn wake_if_due(&mut self, now: usize) -> bool {
+        if self.state != ProcessState::Sleeping || now < self.sleep_until {
+            return false;
+        }
+        self.wake(now).is_ok()
+    }
+
+    fn wake(&mut self, now: usize) -> Result<(), StateError> {
+        self.transition(ProcessState::Running)?;
+        let left = self.sleep_until.saturating_sub(now) as u64;
+        unsafe { (*self.frame).regs[10] = (left / ms_to_ticks(1)) as usize; }
+        Ok(())
+    }
+
+    pub fn get_affinity(&self) -> Option<usize> {
+        self.affinity
+    }
+
+    pub fn set_affinity(&mut self, hart: Option<usize>) {
+        self.affinity = hart;
+    }
+
+    // Whether this process is allowed to be scheduled on hart
+    pub fn can_run_on(&self, hart: usize) -> bool {
+        match self.affinity {
+            Some(pinned) => pinned == hart,
+            None => true,
+        }
+    }
+
     // Create a new process with default conditions
     pub fn new_default(func: fn()) -> Self {
-        let func_addr = func as usize;
+        Self::new_at(func as usize)
+    }
+
+    // Create a new process with default conditions that starts at func_addr
+    // Everything a process can read that it didn't write itself has to
+    // start out zeroed, or it sees what the last owner of the memory left
+    // there: the trap frame (the slab zeroes reused slots), the page table
+    // root and the tables below it, and every stack, heap and argument
+    // page, which all come from zalloc. Only the kernel code and the vDSO,
+    // which are the same for every process, are mapped as they are.
+    pub fn new_at(func_addr: usize) -> Self {
         let func_vaddr = func_addr;
-        let ret_proc = Process {
-            frame:          zalloc(1) as *mut TrapFrame,
-            stack:          alloc(STACK_PAGES),
+        let mut ret_proc = Self::new_bare();
+        ret_proc.program_counter = func_vaddr;
+
+        // Map function pointer to it's own virtual address on the MMU
+        // The code lives in the kernel's text section, so the process
+        // gets to run it but never to write it.
+        for i in 0..=100 {
+            let modifier = i * 0x1000;
+            ret_proc.map_user(func_vaddr + modifier, func_addr + modifier, EntryBits::UserReadExecute.val());
+        }
+        // Return the newly created process structure
+        ret_proc
+    }
+
+    // A process that is on no list, for tests that need one of their own
+    #[cfg(test)]
+    pub fn new_unlisted() -> Self {
+        Self::new_bare()
+    }
+
+    // A process with its stack and the vDSO mapped but no code yet
+    fn new_bare() -> Self {
+        let mut ret_proc = Process {
+            frame:          TRAP_FRAMES.lock().alloc(),
+            stack_bottom:   STACK_TOP,
             program_counter:PROCESS_STARTING_ADDR,
-            pid:            unsafe { NEXT_PID },
+            pid:            alloc_pid(),
             root:           zalloc(1) as *mut Table,
             state:          ProcessState::Running,
             data:           ProcessData::zero(),
-            sleep_until:    0
+            args:           null_mut(),
+            heap_top:       HEAP_ADDR,
+            sleep_until:    0,
+            mapped_pages:   0,
+            mem_limit_pages:MEM_UNLIMITED,
+            segments:       Vec::new(),
+            restart_done:   0,
+            cpu_ticks:      0,
+            // Never 0, which is what a fresh stack page holds anyway
+            canary:         rng::next_u64().max(1),
+            reap_pending:   false,
+            exit_status:    KILLED,
+            parent:         0,
+            last_fault:     (0, 0),
+            fault_repeats:  0,
+            affinity:       None
         };
-        unsafe { NEXT_PID += 1; }
+        assert!(!ret_proc.frame.is_null(), "Out of memory for the trap frame");
+        assert!(!ret_proc.root.is_null(), "Out of memory for the page table");
         // Move stack pointer to the bottom
         // According to the register specs, x2 register (2) is the stack pointer
-        unsafe { (*ret_proc.frame).regs[2] = STACK_ADDR + (STACK_PAGES * PAGE_SIZE); }
-        // Map stack on the MMU
-        let pt;
-        unsafe {
-            pt = &mut *ret_proc.root;
+        unsafe { (*ret_proc.frame).regs[2] = STACK_TOP; }
+        // Map the top page of the stack, the rest is mapped as it is used.
+        // The canary goes in its lowest word, so fault in the word above
+        // it, faulting in the canary's own word would take a page more.
+        assert!(ret_proc.grow_stack(STACK_TOP - PAGE_SIZE + CANARY_SIZE).is_ok(), "Out of memory for the stack");
+
+        // The syscall trampoline and the time
+        assert!(ret_proc.map_vdso(), "Too many pages mapped for the vDSO");
+        ret_proc
+    }
+
+    // Map every PT_LOAD segment of elf into fresh zeroed pages, with the
+    // file's bytes copied in and the rest (the bss) left zero
+    fn load_elf(&mut self, elf: &Elf) -> Result<(), ElfError> {
+        for seg in elf.segments() {
+            if seg.memsz == 0 {
+                continue;
+            }
+            if seg.vaddr < PAGE_SIZE || seg.vaddr + seg.memsz > ELF_LOAD_END {
+                return Err(ElfError::BadSegment);
+            }
+            let start = seg.vaddr & !(PAGE_SIZE - 1);
+            let end = align_val(seg.vaddr + seg.memsz, 12);
+            self.segments.push((start, end));
+            let bytes = seg.file_bytes(elf);
+            let mut page_vaddr = start;
+            while page_vaddr < end {
+                let root = unsafe { &mut *self.root };
+                let (page, bits) = match lookup(root, VirtAddr(page_vaddr)) {
+                    // A page shared with an earlier segment keeps what is on
+                    // it already. Both segments have to want the same
+                    // permissions, giving the page those of both could make
+                    // code writable, as when .data starts on the page .text
+                    // ends on.
+                    Some((paddr, old)) => {
+                        let rwx = EntryBits::UserReadWriteExecute.val();
+                        if old & rwx != segment_bits(&seg) & rwx {
+                            return Err(ElfError::BadSegment);
+                        }
+                        unmap_page(root, VirtAddr(page_vaddr));
+                        self.mapped_pages -= 1;
+                        (paddr.0 as *mut u8, segment_bits(&seg))
+                    },
+                    None => {
+                        let page = zalloc(1);
+                        if page.is_null() {
+                            return Err(ElfError::OutOfMemory);
+                        }
+                        (page, segment_bits(&seg))
+                    }
+                };
+                // The part of the file that lands on this page
+                let lo = page_vaddr.max(seg.vaddr);
+                let hi = (page_vaddr + PAGE_SIZE).min(seg.vaddr + seg.filesz);
+                if lo < hi {
+                    unsafe {
+                        memcpy(page.add(lo - page_vaddr), bytes[lo - seg.vaddr..].as_ptr(), hi - lo);
+                    }
+                }
+                if !self.map_user(page_vaddr, page as usize, bits) {
+                    dealloc(page);
+                    return Err(ElfError::OutOfMemory);
+                }
+                page_vaddr += PAGE_SIZE;
+            }
         }
-        let saddr = ret_proc.stack as usize;
-        // Map stack onto the user process' virtual memory
-        for i in 0..STACK_PAGES {
-            let addr = i * PAGE_SIZE;
-            map(pt, STACK_ADDR + addr, saddr + addr, EntryBits::UserReadWrite.val(), 0);
-            println!("Set stack from 0x{:016x} -> 0x{:016x}", STACK_ADDR + addr, saddr + addr);
+        Ok(())
+    }
+
+    // Most pages the process may have mapped, its own limit or
+    // MAX_MAPPED_PAGES, whichever is lower
+    fn page_limit(&self) -> usize {
+        self.mem_limit_pages.min(MAX_MAPPED_PAGES)
+    }
+
+    // Map the shared vDSO page at VDSO_ADDR, read and execute only so no
+    // process can change what the others see. It counts against the page
+    // limit like any other page. Returns false if the limit is reached.
+    fn map_vdso(&mut self) -> bool {
+        self.map_user(VDSO_ADDR, vdso_page(), EntryBits::UserReadExecute.val())
+    }
+
+    // Map one user page and count it. Returns false, mapping nothing, if
+    // the process is at its page limit already.
+    fn map_user(&mut self, vaddr: usize, paddr: usize, bits: i64) -> bool {
+        if self.mapped_pages >= self.page_limit() {
+            return false;
         }
+        map(unsafe { &mut *self.root }, VirtAddr(vaddr), PhysAddr(paddr), bits, 0);
+        self.mapped_pages += 1;
+        true
+    }
 
-        // Map function pointer to it's own virtual address on the MMU
-        for i in 0..=100 {
-            let modifier = i * 0x1000;
-            map(pt, func_vaddr + modifier, func_addr + modifier, EntryBits::UserReadWriteExecute.val(), 0);
+    // Grow or shrink the heap by incr bytes, see process::sbrk
+    // Pages are mapped and unmapped as the heap top crosses page boundaries.
+    pub fn sbrk(&mut self, incr: isize) -> Option<usize> {
+        let old_top = self.heap_top;
+        let new_top = old_top.checked_add_signed(incr).filter(|&top| top >= HEAP_ADDR)?;
+        // Pages in use are the ones up to the heap top rounded up to a page
+        let old_end = align_val(old_top, 12);
+        let new_end = align_val(new_top, 12);
+        if new_end > old_end {
+            if self.mapped_pages + (new_end - old_end) / PAGE_SIZE > self.page_limit() {
+                return None;
+            }
+            let mut vaddr = old_end;
+            while vaddr < new_end {
+                let page = zalloc(1);
+                if page.is_null() {
+                    // Give back what we mapped so far and fail
+                    self.free_user_pages(old_end, vaddr);
+                    return None;
+                }
+                self.map_user(vaddr, page as usize, EntryBits::UserReadWrite.val());
+                vaddr += PAGE_SIZE;
+            }
+        } else {
+            self.free_user_pages(new_end, old_end);
         }
-        
-        // Map the make_syscall function on the MMU
-        map(pt, 0x8000_0000, 0x8000_0000, EntryBits::UserReadExecute.val(), 0);
-        // Return the newly created process structure
-        ret_proc
+        self.heap_top = new_top;
+        Some(old_top)
+    }
+
+    // Map zeroed pages from the page holding vaddr up to the current bottom
+    // of the stack. vaddr has to be inside the stack region and below what
+    // is already mapped. The canary goes in the lowest word of the new
+    // bottom page, which is not part of the usable stack. If vaddr is that
+    // very word, the page below is mapped as well, so the access that
+    // grew the stack never lands on the canary.
+    pub fn grow_stack(&mut self, vaddr: usize) -> Result<(), GrowError> {
+        if !(STACK_ADDR..self.stack_bottom).contains(&vaddr) {
+            return Err(GrowError::NotStack);
+        }
+        let mut new_bottom = vaddr & !(PAGE_SIZE - 1);
+        if vaddr - new_bottom < CANARY_SIZE {
+            if new_bottom == STACK_ADDR {
+                // No room left below for the canary
+                return Err(GrowError::OverLimit);
+            }
+            new_bottom -= PAGE_SIZE;
+        }
+        if self.mapped_pages + (self.stack_bottom - new_bottom) / PAGE_SIZE > self.page_limit() {
+            return Err(GrowError::OverLimit);
+        }
+        let mut bottom = self.stack_bottom;
+        let mut page = null_mut();
+        while bottom > new_bottom {
+            page = zalloc(1);
+            if page.is_null() {
+                // Give back what we mapped so far and fail
+                self.free_user_pages(bottom, self.stack_bottom);
+                return Err(GrowError::OutOfMemory);
+            }
+            bottom -= PAGE_SIZE;
+            self.map_user(bottom, page as usize, EntryBits::UserReadWrite.val());
+            tlb_shootdown(bottom, self.pid as usize);
+        }
+        self.stack_bottom = new_bottom;
+        // The canary moves down to the new bottom page. Where it was is
+        // ordinary stack now.
+        unsafe { (page as *mut u64).write_volatile(self.canary); }
+        Ok(())
+    }
+
+    // Whether the word at stack_bottom still holds the canary that
+    // grow_stack() put there. The usable stack is the part above it,
+    // usable_stack(), so a process that wrote over it went past the end
+    // of its stack or scribbled on memory it doesn't own.
+    pub fn canary_intact(&self) -> bool {
+        let root = unsafe { &*self.root };
+        match lookup(root, VirtAddr(self.stack_bottom)) {
+            Some((paddr, _)) => unsafe { (paddr.0 as *const u64).read_volatile() == self.canary },
+            None => false
+        }
+    }
+
+    // The stack the process may use, everything mapped above the canary
+    pub fn usable_stack(&self) -> core::ops::Range<usize> {
+        self.stack_bottom + CANARY_SIZE..STACK_TOP
+    }
+
+    // Whether the page at vaddr is one the process owns and frees when it
+    // is dropped: heap, stack, ELF segments and arguments
+    fn owns_page(&self, vaddr: usize) -> bool {
+        let args_end = ARGS_ADDR + allocation_pages(self.args as usize) * PAGE_SIZE;
+        (HEAP_ADDR..align_val(self.heap_top, 12)).contains(&vaddr)
+        || (self.stack_bottom..STACK_TOP).contains(&vaddr)
+        || (ARGS_ADDR..args_end).contains(&vaddr)
+        || self.segments.iter().any(|&(start, end)| (start..end).contains(&vaddr))
+    }
+
+    // See checkpoint
+    fn snapshot(&self) -> Result<ProcessSnapshot, KernelError> {
+        let mut segments = self.segments.clone();
+        if !self.args.is_null() {
+            segments.push((ARGS_ADDR, ARGS_ADDR + allocation_pages(self.args as usize) * PAGE_SIZE));
+        }
+        let (regs, fregs) = unsafe { ((*self.frame).regs, (*self.frame).fregs) };
+        let mut snap = ProcessSnapshot {
+            pid: self.pid,
+            regs,
+            fregs,
+            program_counter: self.program_counter,
+            restart_done: self.restart_done,
+            stack_bottom: self.stack_bottom,
+            heap_top: self.heap_top,
+            segments,
+            canary: self.canary,
+            mem_limit_pages: self.mem_limit_pages,
+            affinity: self.affinity,
+            pages: Vec::new()
+        };
+        let mut result = Ok(());
+        for_each_mapping(unsafe { &*self.root }, |vaddr, paddr, bits, level| {
+            if result.is_err() {
+                return;
+            }
+            // Read only pages the process owns are copied as well, the
+            // snapshot may outlive it. Anything bigger than a page was
+            // mapped by the kernel and isn't the process' own.
+            let data = if level == 0 && self.owns_page(vaddr.0) {
+                let copy = alloc(1);
+                if copy.is_null() {
+                    result = Err(KernelError::OutOfMemory);
+                    return;
+                }
+                unsafe { memcpy(copy, paddr.0 as *const u8, PAGE_SIZE); }
+                SnapshotData::Copied(copy)
+            } else {
+                SnapshotData::Shared(paddr.0)
+            };
+            snap.pages.push(SnapshotPage { vaddr: vaddr.0, bits, data });
+        });
+        // On failure dropping snap frees the copies made so far
+        result.map(|_| snap)
+    }
+
+    // Unmap and free the user pages (heap or stack) in start..end
+    fn free_user_pages(&mut self, start: usize, end: usize) {
+        let pt = unsafe { &mut *self.root };
+        let mut vaddr = start;
+        while vaddr < end {
+            if let Some(paddr) = unmap_page(pt, VirtAddr(vaddr)) {
+                dealloc(paddr.0 as *mut u8);
+                self.mapped_pages -= 1;
+                // The process may still have the old translation cached,
+                // on this hart or another
+                tlb_shootdown(vaddr, self.pid as usize);
+            }
+            vaddr += PAGE_SIZE;
+        }
+    }
+
+    // Copy args into pages mapped at ARGS_ADDR and point a0/a1 at them
+    pub fn set_args(&mut self, args: &[&str]) {
+        let ptr_size = size_of::<usize>();
+        // argv (plus its null terminator) followed by every string and its null byte
+        let argv_size = (args.len() + 1) * ptr_size;
+        let total = argv_size + args.iter().map(|a| a.len() + 1).sum::<usize>();
+        let pages = total.div_ceil(PAGE_SIZE);
+        let page = zalloc(pages);
+        assert!(!page.is_null(), "Out of memory for process arguments");
+
+        unsafe {
+            let argv = page as *mut usize;
+            let mut off = argv_size;
+            for (i, arg) in args.iter().enumerate() {
+                // argv holds the addresses the process will see
+                argv.add(i).write(ARGS_ADDR + off);
+                memcpy(page.add(off), arg.as_ptr(), arg.len());
+                // zalloc already zeroed the null terminator
+                off += arg.len() + 1;
+            }
+            // argv[argc] is already null for the same reason
+
+            for i in 0..pages {
+                let addr = i * PAGE_SIZE;
+                assert!(self.map_user(ARGS_ADDR + addr, page as usize + addr, EntryBits::UserReadWrite.val()),
+                        "Too many pages mapped for process arguments");
+            }
+            // a0 = argc, a1 = argv
+            (*self.frame).regs[10] = args.len();
+            (*self.frame).regs[11] = ARGS_ADDR;
+        }
+        self.args = page;
     }
 }
 
 // When the process structure is dropped, we need to deallocate the memory allocated to it as well
 impl Drop for Process {
     fn drop(&mut self) {
-        // Deallocate stack pages
-        dealloc(self.stack);
+        // Free the heap, stack and ELF pages, unmap() below only frees the tables
+        self.free_user_pages(HEAP_ADDR, align_val(self.heap_top, 12));
+        self.free_user_pages(self.stack_bottom, STACK_TOP);
+        for (start, end) in core::mem::take(&mut self.segments) {
+            self.free_user_pages(start, end);
+        }
+        if !self.args.is_null() {
+            dealloc(self.args);
+        }
+        // Give the trap frame back to its slab
+        TRAP_FRAMES.lock().free(self.frame);
         unsafe {
+            // The vDSO page is shared and stays, unmap() doesn't free leaves.
             // Unmap deallocate all page tables except root page table
             unmap(&mut *self.root);
         }
@@ -211,11 +1867,29 @@ impl Drop for Process {
     }
 }
 
+// The page table bits for an ELF segment. Write without Read is reserved
+// in RISC-V, so writable segments are readable too, and a segment with no
+// permissions at all is mapped read only.
+fn segment_bits(seg: &Segment) -> i64 {
+    let mut bits = EntryBits::User.val();
+    if seg.flags & (PF_R | PF_W) != 0 || seg.flags & (PF_R | PF_W | PF_X) == 0 {
+        bits |= EntryBits::Read.val();
+    }
+    if seg.flags & PF_W != 0 {
+        bits |= EntryBits::Write.val();
+    }
+    if seg.flags & PF_X != 0 {
+        bits |= EntryBits::Execute.val();
+    }
+    bits
+}
+
 // The private data in a process contains information
 // that is relevant to where we are, including the path
 // and open file descriptors.
 pub struct ProcessData {
 	cwd_path: [u8; 128],
+	fds: FdTable,
 }
 
 // This is private data that we can query with system calls.
@@ -223,6 +1897,558 @@ pub struct ProcessData {
 // is a per-process block queuing algorithm, we can put that here.
 impl ProcessData {
 	pub fn zero() -> Self {
-		ProcessData { cwd_path: [0; 128], }
+		ProcessData { cwd_path: [0; 128], fds: FdTable::new(), }
+	}
+
+	// The working directory as a string. It's stored NUL-terminated, an
+	// empty string means the root.
+	pub fn cwd(&self) -> &str {
+		let len = self.cwd_path.iter().position(|&c| c == 0).unwrap_or(self.cwd_path.len());
+		core::str::from_utf8(&self.cwd_path[..len]).unwrap_or("")
 	}
-}
\ No newline at end of file
+
+	// Resolve a path from this process against the RAM filesystem
+	pub fn resolve(&self, path: &str) -> Result<String, FsError> {
+		ramfs::resolve(self.cwd(), path)
+	}
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::syscall1;
+
+    // An executable with a PT_LOAD header, all bss, for every (flags,
+    // vaddr, memsz)
+    fn elf_with(segments: &[(u32, usize, usize)]) -> Vec<u8> {
+        let mut elf = vec![0u8; 64 + 56 * segments.len()];
+        elf[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1]);
+        // ET_EXEC, EM_RISCV, the entry, and the program headers right after
+        elf[16..20].copy_from_slice(&[2, 0, 243, 0]);
+        elf[24..32].copy_from_slice(&segments.first().map_or(0, |s| s.1 as u64).to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for (i, &(flags, vaddr, memsz)) in segments.iter().enumerate() {
+            let ph = &mut elf[64 + 56 * i..64 + 56 * (i + 1)];
+            ph[0..4].copy_from_slice(&1u32.to_le_bytes());
+            ph[4..8].copy_from_slice(&flags.to_le_bytes());
+            ph[16..24].copy_from_slice(&(vaddr as u64).to_le_bytes());
+            ph[40..48].copy_from_slice(&(memsz as u64).to_le_bytes());
+        }
+        elf
+    }
+
+    #[test_case]
+    fn load_elf_maps_segments_at_their_addresses() {
+        // A nop of .text, then 8 bytes of .data with a page of bss after
+        let mut elf = elf_with(&[(PF_R | PF_X, 0x1_0000, 4), (PF_R | PF_W, 0x2_0000, 8 + PAGE_SIZE)]);
+        for (i, bytes) in [&0x13u32.to_le_bytes()[..], b"abcdefgh"].into_iter().enumerate() {
+            let ph = 64 + 56 * i;
+            let offset = elf.len() as u64;
+            elf[ph + 8..ph + 16].copy_from_slice(&offset.to_le_bytes());
+            elf[ph + 32..ph + 40].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+            elf.extend_from_slice(bytes);
+        }
+        let pid = add_process_elf(&elf).expect("canned ELF not loaded");
+        assert_eq!(with_pid(pid, |p| p.program_counter), Some(0x1_0000));
+        let (_, root) = frame_of(pid).expect("test process went missing");
+        let root = unsafe { &*root };
+        let rwx = |vaddr| lookup(root, VirtAddr(vaddr)).map(|(_, bits)| bits & EntryBits::UserReadWriteExecute.val());
+        assert_eq!(rwx(0x1_0000), Some(EntryBits::UserReadExecute.val()));
+        assert_eq!(rwx(0x2_0000), Some(EntryBits::UserReadWrite.val()));
+        assert_eq!(rwx(0x2_1000), Some(EntryBits::UserReadWrite.val()));
+        assert_eq!(rwx(0x2_2000), None);
+        let mut code = [0u8; 4];
+        assert!(copy_from_user(root, &mut code, 0x1_0000).is_ok());
+        assert_eq!(u32::from_le_bytes(code), 0x13);
+        // The bss is zeroed right after the bytes from the file
+        let mut data = [0xffu8; 16];
+        assert!(copy_from_user(root, &mut data, 0x2_0000).is_ok());
+        assert_eq!(&data, b"abcdefgh\0\0\0\0\0\0\0\0");
+        let _ = kill(pid);
+        // Another machine, and a shared library rather than an executable
+        let mut x86 = elf.clone();
+        x86[18] = 62;
+        assert!(matches!(add_process_elf(&x86), Err(ElfError::NotRiscv)));
+        elf[16] = 3;
+        assert!(matches!(add_process_elf(&elf), Err(ElfError::NotExecutable)));
+    }
+
+    #[test_case]
+    fn load_elf_rejects_pages_shared_with_other_permissions() {
+        let text = (PF_R | PF_X, 0x1_0000, 0x800);
+        // .data starting on the page .text ends on
+        let data = (PF_R | PF_W, 0x1_0800, 0x800);
+        let more_text = (PF_R | PF_X, 0x1_0800, 0x800);
+        assert!(matches!(add_process_elf(&elf_with(&[text, data])), Err(ElfError::BadSegment)));
+        let pid = add_process_elf(&elf_with(&[text, more_text])).expect("same permissions can share a page");
+        let _ = kill(pid);
+    }
+
+    fn with_pid<R>(pid: u16, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+        unsafe {
+            let mut pl = PROCESS_LIST.take()?;
+            let ret = pl.iter_mut().find(|p| p.pid == pid).map(f);
+            PROCESS_LIST.replace(pl);
+            ret
+        }
+    }
+
+    #[test_case]
+    fn canary_sits_under_the_usable_stack() {
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        assert!(check_canary(pid));
+        with_pid(pid, |p| {
+            // A new process has one stack page
+            assert_eq!(p.stack_bottom, STACK_TOP - PAGE_SIZE);
+            assert_eq!(p.usable_stack(), STACK_TOP - PAGE_SIZE + CANARY_SIZE..STACK_TOP);
+            // A fault on the word the canary would go in takes the page
+            // below too
+            let page = STACK_TOP - 2 * PAGE_SIZE;
+            assert!(p.grow_stack(page).is_ok());
+            assert_eq!(p.usable_stack().start, page - PAGE_SIZE + CANARY_SIZE);
+            assert!(p.grow_stack(STACK_ADDR).is_err());
+        });
+        assert!(check_canary(pid));
+        with_pid(pid, |p| {
+            let (paddr, _) = lookup(unsafe { &*p.root }, VirtAddr(p.stack_bottom)).expect("stack bottom not mapped");
+            unsafe { (paddr.0 as *mut u64).write_volatile(!p.canary); }
+        });
+        assert!(!check_canary(pid));
+        let _ = kill(pid);
+    }
+
+    // Store to the page below the one a process starts with, then exit
+    extern "C" fn store_below_stack_entry(_argc: usize, _argv: usize) {
+        unsafe { ((STACK_TOP - PAGE_SIZE - 64) as *mut u64).write_volatile(1); }
+        loop {
+            syscall1(0, 0);
+        }
+    }
+
+    // Store to the guard page under the stack
+    extern "C" fn store_to_guard_entry(_argc: usize, _argv: usize) {
+        unsafe { ((STACK_ADDR - 8) as *mut u64).write_volatile(1); }
+        loop {
+            syscall1(0, 0);
+        }
+    }
+
+    #[test_case]
+    fn stores_below_the_stack_grow_it_until_the_guard() {
+        // What the store fault handler does for a store under the stack
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (frame, root) = frame_of(pid).expect("test process went missing");
+        let before = rss(pid).expect("test process went missing");
+        let vaddr = STACK_TOP - PAGE_SIZE - 64;
+        assert!(lookup(unsafe { &*root }, VirtAddr(vaddr)).is_none());
+        assert_eq!(grow_stack(frame as usize, vaddr), Ok(()));
+        assert!(lookup(unsafe { &*root }, VirtAddr(vaddr)).is_some());
+        assert_eq!(rss(pid), Some(before + 1));
+        assert_eq!(grow_stack(frame as usize, STACK_ADDR - 8), Err(GrowError::NotStack));
+        let _ = kill(pid);
+
+        // And through a real fault: the first process carries on, the
+        // one that hit the guard page is killed
+        let pid = add_process_with_args(store_below_stack_entry, &[]).expect("no room for the test process");
+        assert_eq!(run_to_exit(pid), Ok(0));
+        let pid = add_process_with_args(store_to_guard_entry, &[]).expect("no room for the test process");
+        assert_eq!(run_to_exit(pid), Ok(KILLED));
+    }
+
+    // Move tp up by one with set_tp, give up the hart and exit with
+    // whatever tp is when it comes back
+    extern "C" fn bump_tp_entry(_argc: usize, _argv: usize) {
+        let tp: usize;
+        unsafe { core::arch::asm!("mv {}, tp", out(reg) tp); }
+        syscall1(18, tp + 1);
+        syscall1(14, 0);
+        let tp: usize;
+        unsafe { core::arch::asm!("mv {}, tp", out(reg) tp); }
+        loop {
+            syscall1(0, tp);
+        }
+    }
+
+    #[test_case]
+    fn tp_survives_switching_away_and_back() {
+        let pid = add_process_with_args(bump_tp_entry, &[]).expect("no room for the test process");
+        assert!(set_thread_pointer(pid, 0x1000));
+        assert_eq!(run_to_exit(pid), Ok(0x1001));
+    }
+
+    // The word at user address vaddr of process pid, through its table
+    fn user_word(pid: u16, vaddr: usize) -> *mut u64 {
+        with_pid(pid, |p| lookup(unsafe { &*p.root }, VirtAddr(vaddr)))
+            .flatten()
+            .map(|(paddr, _)| paddr.0 as *mut u64)
+            .expect("address not mapped")
+    }
+
+    #[test_case]
+    fn restore_brings_back_memory_as_checkpointed() {
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let addr = STACK_TOP - 64;
+        let (frame, _) = frame_of(pid).expect("test process went missing");
+        unsafe {
+            user_word(pid, addr).write_volatile(0x1111);
+            (*frame).regs[10] = 0xaaaa;
+        }
+        let snap = checkpoint(pid).expect("checkpoint failed");
+        assert_eq!(snap.pid(), pid);
+        assert!(snap.copied_pages() >= 1);
+        unsafe {
+            user_word(pid, addr).write_volatile(0x2222);
+            (*frame).regs[10] = 0xbbbb;
+        }
+        let restored = restore(&snap).expect("restore failed");
+        assert_ne!(restored, pid);
+        let (restored_frame, _) = frame_of(restored).expect("restored process went missing");
+        assert_ne!(user_word(restored, addr), user_word(pid, addr));
+        unsafe {
+            assert_eq!(user_word(restored, addr).read_volatile(), 0x1111);
+            assert_eq!((*restored_frame).regs[10], 0xaaaa);
+            // The original carries on with its own memory
+            assert_eq!(user_word(pid, addr).read_volatile(), 0x2222);
+        }
+        assert_eq!(with_pid(restored, |p| p.program_counter), with_pid(pid, |p| p.program_counter));
+        assert!(check_canary(restored));
+        let _ = kill(restored);
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn args_are_passed_in_argv() {
+        let pid = add_process_with_args(spin_entry, &["ab", "cde"]).expect("no room for the test process");
+        let (frame, root) = frame_of(pid).expect("test process went missing");
+        let (argc, argv) = unsafe { ((*frame).regs[10], (*frame).regs[11]) };
+        assert_eq!((argc, argv), (2, ARGS_ADDR));
+        let mut page = [0u8; 32];
+        assert!(copy_from_user(unsafe { &*root }, &mut page, ARGS_ADDR).is_ok());
+        let ptr = |i: usize| usize::from_le_bytes(page[i * 8..i * 8 + 8].try_into().unwrap());
+        // Three pointers, the last one null, then the strings
+        assert_eq!((ptr(0), ptr(1), ptr(2)), (ARGS_ADDR + 24, ARGS_ADDR + 27, 0));
+        assert_eq!(&page[24..31], b"ab\0cde\0");
+        let _ = kill(pid);
+
+        // Too big for one page, the next one is mapped too
+        let long = String::from_utf8(vec![b'x'; PAGE_SIZE]).unwrap();
+        let pid = add_process_with_args(spin_entry, &[&long]).expect("no room for the test process");
+        let (_, root) = frame_of(pid).expect("test process went missing");
+        let mut tail = [0u8; 9];
+        assert!(copy_from_user(unsafe { &*root }, &mut tail, ARGS_ADDR + PAGE_SIZE).is_ok());
+        assert_eq!(&tail, b"xxxxxxxxx");
+        // The string starts after argv's two pointers, so its null byte
+        // is 16 bytes into the second page
+        let mut end = [1u8; 1];
+        assert!(copy_from_user(unsafe { &*root }, &mut end, ARGS_ADDR + 16 + PAGE_SIZE).is_ok());
+        assert_eq!(end[0], 0);
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn a_full_list_refuses_new_processes() {
+        let capacity = unsafe { PROCESS_LIST.as_ref().map(|pl| pl.capacity()) };
+        let mut added = Vec::new();
+        while let Ok(pid) = push_process(Process::new_unlisted()) {
+            added.push(pid);
+        }
+        let len = unsafe { PROCESS_LIST.as_ref().map(|pl| pl.len()) };
+        assert_eq!(len, Some(MAX_PROCESSES));
+        assert!(matches!(add_process_with_args(spin_entry, &[]), Err(ProcError::TooManyProcesses)));
+        assert!(matches!(push_process(Process::new_unlisted()), Err(ProcError::TooManyProcesses)));
+        let len = unsafe { PROCESS_LIST.as_ref().map(|pl| pl.len()) };
+        assert_eq!(len, Some(MAX_PROCESSES));
+        // Reserved up front, filling it up didn't reallocate
+        let now = unsafe { PROCESS_LIST.as_ref().map(|pl| pl.capacity()) };
+        assert_eq!(now, capacity);
+        // Dropping them frees their memory
+        unsafe {
+            let mut pl = PROCESS_LIST.take().expect("process list taken");
+            pl.retain(|p| !added.contains(&p.pid));
+            PROCESS_LIST.replace(pl);
+        }
+    }
+
+    #[test_case]
+    fn pids_are_unique_and_never_0() {
+        let old = NEXT_PID.load(Ordering::Relaxed);
+        let pids: [u16; 16] = core::array::from_fn(|_| alloc_pid());
+        for (i, pid) in pids.iter().enumerate() {
+            assert!(*pid != 0 && !pids[i + 1..].contains(pid));
+        }
+        // Wrapping around skips 0, the kernel's ASID
+        NEXT_PID.store(u16::MAX, Ordering::Relaxed);
+        assert_eq!(alloc_pid(), u16::MAX);
+        let wrapped = alloc_pid();
+        assert!(wrapped != 0 && !pid_in_use(wrapped));
+        // and any pid a live process still has
+        let live = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        NEXT_PID.store(live, Ordering::Relaxed);
+        assert_ne!(alloc_pid(), live);
+        let _ = kill(live);
+        // Carry on after every pid handed out here
+        NEXT_PID.store(old.max(live.wrapping_add(1)), Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn registers_of_a_stopped_process_can_be_changed() {
+        let pid = add_process_with_args(spin_entry, &["x"]).expect("no room for the test process");
+        // Not while it may be running
+        assert_eq!(read_reg(pid, 10), None);
+        assert!(!write_reg(pid, 10, 7));
+        assert_eq!(with_pid(pid, |p| p.transition(ProcessState::Sleeping).is_ok()), Some(true));
+        // argc and argv
+        assert_eq!(read_reg(pid, 10), Some(1));
+        assert_eq!(read_reg(pid, 11), Some(ARGS_ADDR));
+        assert!(write_reg(pid, 10, 7));
+        assert_eq!(read_reg(pid, 10), Some(7));
+        // f1
+        assert!(write_reg(pid, 33, 0x3ff0_0000_0000_0000));
+        assert_eq!(read_reg(pid, 33), Some(0x3ff0_0000_0000_0000));
+        assert!(!write_reg(pid, 0, 7));
+        assert_eq!(read_reg(pid, 0), Some(0));
+        assert_eq!(read_reg(pid, 64), None);
+        assert!(!write_reg(pid, 64, 7));
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn msleep_sets_the_wake_up_time() {
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (frame, _) = frame_of(pid).expect("test process went missing");
+        let before = scheduler::now() as usize;
+        assert!(msleep(frame as usize, 100, 0x1234));
+        let after = scheduler::now() as usize;
+        // 100 ms at 10 MHz
+        let until = with_pid(pid, |p| p.get_sleep_until()).expect("test process went missing");
+        assert!((before + 1_000_000..=after + 1_000_000).contains(&until));
+        assert_eq!(state_of(pid), Some(ProcessState::Sleeping));
+        assert_eq!(with_pid(pid, |p| p.program_counter), Some(0x1234));
+        let _ = kill(pid);
+
+        // 0 ms only yields, with nothing left to sleep
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (frame, _) = frame_of(pid).expect("test process went missing");
+        assert!(msleep(frame as usize, 0, 0x1234));
+        assert_eq!(state_of(pid), Some(ProcessState::Running));
+        assert_eq!(unsafe { (*frame).regs[10] }, 0);
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn mapping_past_the_page_limit_is_refused() {
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let rss_before = rss(pid);
+        with_pid(pid, |p| {
+            let real = p.mapped_pages;
+            let root = unsafe { &*p.root };
+            // As if almost everything had been used up
+            p.mapped_pages = MAX_MAPPED_PAGES - 1;
+            assert_eq!(p.sbrk(2 * PAGE_SIZE as isize), None);
+            assert_eq!(p.heap_top, HEAP_ADDR);
+            assert_eq!(p.grow_stack(STACK_TOP - 2 * PAGE_SIZE - 64), Err(GrowError::OverLimit));
+            p.mapped_pages = MAX_MAPPED_PAGES;
+            assert!(!p.map_user(HEAP_ADDR, p.args as usize, EntryBits::UserReadWrite.val()));
+            assert!(lookup(root, VirtAddr(HEAP_ADDR)).is_none());
+            p.mapped_pages = real;
+        });
+        assert_eq!(rss(pid), rss_before);
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn init_makes_the_first_process() {
+        // The one boot made is pid 1 and has somewhere to start
+        let pc = with_pid(1, |p| p.program_counter);
+        assert!(pc.is_some_and(|pc| pc != 0));
+        // Again on a list of its own, leaving the real one alone
+        let saved = unsafe { PROCESS_LIST.take() };
+        let ret = init();
+        let fresh = unsafe { PROCESS_LIST.take() };
+        unsafe { PROCESS_LIST = saved; }
+        let fresh = fresh.expect("init left no process list");
+        assert_eq!(fresh.len(), 1);
+        assert!(matches!(ret, Ok(pc) if pc != 0 && pc == fresh[0].program_counter));
+    }
+
+    #[test_case]
+    fn only_legal_state_changes_are_allowed() {
+        use ProcessState::*;
+        let states = [Running, Sleeping, Waiting, Dead];
+        let legal = [(Running, Sleeping), (Running, Waiting), (Running, Dead),
+                     (Sleeping, Running), (Sleeping, Dead),
+                     (Waiting, Running), (Waiting, Dead)];
+        for from in states {
+            for to in states {
+                assert_eq!(from.can_become(to), legal.contains(&(from, to)), "{:?} -> {:?}", from, to);
+            }
+        }
+        let mut p = Process::new_bare();
+        assert_eq!(p.state, Running);
+        assert!(p.transition(Sleeping).is_ok());
+        assert_eq!(p.state, Sleeping);
+        assert!(p.transition(Dead).is_ok());
+        // Debug builds panic on an illegal change rather than return
+        if !cfg!(debug_assertions) {
+            assert!(p.transition(Running).is_err());
+            assert_eq!(p.state, Dead);
+        }
+    }
+
+    // What the history ring holds for pid, oldest first
+    fn history_of(pid: u16) -> Vec<(ProcessState, ProcessState)> {
+        let history = *STATE_HISTORY.lock();
+        history.iter().filter(|rec| rec.pid == pid).map(|rec| (rec.from, rec.to)).collect()
+    }
+
+    #[test_case]
+    fn state_history_records_transitions_in_order() {
+        use ProcessState::*;
+        let tracing = log::trace_process_state();
+        log::set_trace_process_state(true);
+        let mut p = Process::new_unlisted();
+        let steps = [Sleeping, Running, Waiting, Running, Dead];
+        for to in steps {
+            assert!(p.transition(to).is_ok());
+        }
+        log::set_trace_process_state(tracing);
+        assert_eq!(history_of(p.pid),
+                   [(Running, Sleeping), (Sleeping, Running), (Running, Waiting), (Waiting, Running), (Running, Dead)]);
+        let history = *STATE_HISTORY.lock();
+        let ticks: Vec<u64> = history.iter().filter(|rec| rec.pid == p.pid).map(|rec| rec.tick).collect();
+        assert!(ticks.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test_case]
+    fn state_history_keeps_the_newest_records() {
+        // A pid no process gets
+        let pid = u16::MAX;
+        for _ in 0..STATE_HISTORY_LEN {
+            record_state_transition(pid, ProcessState::Running, ProcessState::Sleeping);
+        }
+        record_state_transition(pid, ProcessState::Sleeping, ProcessState::Dead);
+        let records = history_of(pid);
+        assert_eq!(records.len(), STATE_HISTORY_LEN);
+        assert_eq!(records.last(), Some(&(ProcessState::Sleeping, ProcessState::Dead)));
+    }
+
+    #[test_case]
+    fn wake_key_waits_for_the_process_list() {
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (frame, _) = frame_of(pid).expect("test process went missing");
+        let key = 0x1234_5678;
+        assert!(wait_on(frame as usize, key, 0));
+        // As if another hart had the list
+        let pl = unsafe { PROCESS_LIST.take() };
+        assert_eq!(wake_key(key, 1), 1);
+        unsafe { PROCESS_LIST = pl; }
+        assert_eq!(state_of(pid), Some(ProcessState::Waiting));
+        run_futex_wakes();
+        assert_eq!(state_of(pid), Some(ProcessState::Running));
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn every_process_shares_one_vdso_page() {
+        let first = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let second = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (_, first_root) = frame_of(first).expect("test process went missing");
+        let (_, second_root) = frame_of(second).expect("test process went missing");
+        let first_map = lookup(unsafe { &*first_root }, VirtAddr(VDSO_ADDR));
+        let second_map = lookup(unsafe { &*second_root }, VirtAddr(VDSO_ADDR));
+        let (paddr, bits) = first_map.expect("vDSO isn't mapped");
+        assert_eq!(paddr.0, vdso_page());
+        assert_eq!(second_map.map(|(paddr, _)| paddr.0), Some(vdso_page()));
+        // Shared, so nobody may write it
+        assert_eq!(bits & EntryBits::UserReadWriteExecute.val(), EntryBits::UserReadExecute.val());
+        let _ = kill(first);
+        let _ = kill(second);
+    }
+
+    #[test_case]
+    fn futex_wake_goes_by_the_physical_page() {
+        // Every process maps the same vDSO page, so this is one futex
+        // word seen from two address spaces
+        let waiter = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let waker = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (waiter_frame, root) = frame_of(waiter).expect("test process went missing");
+        let (waker_frame, _) = frame_of(waker).expect("test process went missing");
+        let mut word = [0u8; 4];
+        assert!(copy_from_user(unsafe { &*root }, &mut word, VDSO_ADDR).is_ok());
+        let word = u32::from_ne_bytes(word);
+        let (waiter_frame, waker_frame) = (waiter_frame as usize, waker_frame as usize);
+        assert_eq!(futex_wait(waiter_frame, VDSO_ADDR, !word, 0), Some(FutexWait::Changed));
+        assert_eq!(futex_wait(waiter_frame, VDSO_ADDR, word, 0), Some(FutexWait::Blocked));
+        assert_eq!(state_of(waiter), Some(ProcessState::Waiting));
+        // A word on any other page wakes nobody
+        assert_eq!(futex_wake(waker_frame, ARGS_ADDR, 1), Some(0));
+        assert_eq!(futex_wake(waker_frame, VDSO_ADDR, 1), Some(1));
+        assert_eq!(state_of(waiter), Some(ProcessState::Running));
+        let _ = kill(waiter);
+        let _ = kill(waker);
+    }
+
+    #[test_case]
+    fn the_same_fault_over_and_over_is_a_storm() {
+        let old = FAULT_LIMIT.swap(3, Ordering::Relaxed);
+        let pid = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let (frame, _) = frame_of(pid).expect("test process went missing");
+        let (frame, epc, tval) = (frame as usize, 0x2_0000, 0x3_0000);
+        for _ in 0..3 {
+            assert_eq!(fault_storm(frame, epc, tval), None);
+        }
+        // The same instruction on another address starts over
+        assert_eq!(fault_storm(frame, epc, tval + 8), None);
+        for _ in 0..2 {
+            assert_eq!(fault_storm(frame, epc, tval), None);
+        }
+        // And so does the timer finding it somewhere else
+        assert!(save_pc(frame, epc + 4));
+        for _ in 0..3 {
+            assert_eq!(fault_storm(frame, epc, tval), None);
+        }
+        assert_eq!(fault_storm(frame, epc, tval), Some(pid));
+        FAULT_LIMIT.store(old, Ordering::Relaxed);
+        let _ = kill(pid);
+    }
+
+    #[test_case]
+    fn children_of_an_ended_process_go_to_init() {
+        let parent = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let live = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let zombie = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        assert!(set_parent(live, parent) && set_parent(zombie, parent));
+        assert!(exit(zombie, 3).is_ok());
+        assert_eq!(with_pid(zombie, |p| p.reap_pending), Some(false));
+        assert!(exit(parent, 0).is_ok());
+        assert_eq!(with_pid(live, |p| p.parent), Some(INIT_PID));
+        assert_eq!(with_pid(zombie, |p| (p.parent, p.reap_pending)), Some((INIT_PID, true)));
+        // Nothing ran the zombie, so no hart is on its trap frame
+        let mut pl = unsafe { PROCESS_LIST.take() }.expect("process list taken");
+        assert!(reap(&mut pl) >= 1);
+        unsafe { PROCESS_LIST.replace(pl); }
+        assert_eq!(state_of(zombie), None);
+        assert_eq!(state_of(live), Some(ProcessState::Running));
+        let _ = kill(live);
+    }
+
+    #[test_case]
+    fn orphans_are_reaped_once_off_every_hart() {
+        let parent = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let child = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        assert!(set_parent(child, parent));
+        let (frame, _) = frame_of(child).expect("test process went missing");
+        assert!(kill(child).is_ok());
+        // As if the child had died on hart 1, which hasn't switched away
+        switched_to(1, frame as usize);
+        assert!(kill(parent).is_ok());
+        assert_eq!(with_pid(child, |p| p.reap_pending), Some(true));
+        let mut pl = unsafe { PROCESS_LIST.take() }.expect("process list taken");
+        assert_eq!(reap(&mut pl), 0);
+        switched_to(1, 0);
+        assert!(reap(&mut pl) >= 1);
+        unsafe { PROCESS_LIST.replace(pl); }
+        assert_eq!(state_of(child), None);
+    }
+}
@@ -0,0 +1,147 @@
+// RAM filesystem
+// A flat namespace of files kept in kernel heap memory. There are no
+// directories yet, every file lives directly under "/". Nothing here
+// survives a reboot.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use crate::lock::Mutex;
+
+// Longest file name we accept, not counting the leading '/'
+pub const NAME_MAX: usize = 64;
+// Largest a file may grow. Files live in the kernel heap, so a write far
+// past the end mustn't be able to ask it for everything it has.
+pub const MAX_FILE_SIZE: usize = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum FsError {
+    // The path is empty, too long or names a subdirectory
+    BadPath,
+    // create() of a file that is already there
+    Exists,
+    // The file doesn't exist (or was unlinked since it was opened)
+    NotFound,
+    // The write would take the file past MAX_FILE_SIZE
+    TooBig,
+    // The kernel heap has no room for the file to grow
+    NoSpace
+}
+
+static FILES: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+// What an open file refers to. This is what the file descriptor table
+// stores, reads and writes look the file up again by name each time.
+#[derive(Clone, Debug)]
+pub struct Handle {
+    name: String
+}
+
+impl Handle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// Turn a path into a file name. Relative paths are looked up from cwd,
+// which can only be the root (or empty, meaning the root) for now.
+pub fn resolve(cwd: &str, path: &str) -> Result<String, FsError> {
+    let name = match path.strip_prefix('/') {
+        Some(name) => name,
+        None if cwd.is_empty() || cwd == "/" => path,
+        None => return Err(FsError::BadPath)
+    };
+    if name.is_empty() || name.len() > NAME_MAX || name.contains('/') {
+        return Err(FsError::BadPath);
+    }
+    Ok(String::from(name))
+}
+
+// Make a new empty file
+pub fn create(path: &str) -> Result<Handle, FsError> {
+    let name = resolve("/", path)?;
+    let mut files = FILES.lock();
+    if files.contains_key(&name) {
+        return Err(FsError::Exists);
+    }
+    files.insert(name.clone(), Vec::new());
+    Ok(Handle { name })
+}
+
+pub fn open(path: &str) -> Result<Handle, FsError> {
+    let name = resolve("/", path)?;
+    if !FILES.lock().contains_key(&name) {
+        return Err(FsError::NotFound);
+    }
+    Ok(Handle { name })
+}
+
+// Copy bytes starting at offset into buf. Returns how many were copied,
+// which is 0 at or past the end of the file.
+pub fn read(handle: &Handle, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    let files = FILES.lock();
+    let data = files.get(&handle.name).ok_or(FsError::NotFound)?;
+    if offset >= data.len() {
+        return Ok(0);
+    }
+    let count = buf.len().min(data.len() - offset);
+    buf[..count].copy_from_slice(&data[offset..offset + count]);
+    Ok(count)
+}
+
+// Write buf at offset, growing the file if needed. Writing past the end
+// leaves a hole of zeroes. Returns how many bytes were written. A file
+// that would end past MAX_FILE_SIZE, or that the heap can't grow, is left
+// as it was.
+pub fn write(handle: &Handle, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+    let mut files = FILES.lock();
+    let data = files.get_mut(&handle.name).ok_or(FsError::NotFound)?;
+    let end = offset.checked_add(buf.len()).filter(|&end| end <= MAX_FILE_SIZE).ok_or(FsError::TooBig)?;
+    if end > data.len() {
+        data.try_reserve(end - data.len()).map_err(|_| FsError::NoSpace)?;
+        data.resize(end, 0);
+    }
+    data[offset..end].copy_from_slice(buf);
+    Ok(buf.len())
+}
+
+// Size of a file in bytes
+pub fn size(handle: &Handle) -> Result<usize, FsError> {
+    FILES.lock().get(&handle.name).map(|data| data.len()).ok_or(FsError::NotFound)
+}
+
+// Remove a file. Handles that still refer to it get NotFound from then on.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let name = resolve("/", path)?;
+    FILES.lock().remove(&name).map(|_| ()).ok_or(FsError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn written_bytes_read_back_after_reopening() {
+        let file = create("/ramfs-test").expect("create failed");
+        assert_eq!(create("/ramfs-test").err(), Some(FsError::Exists));
+        assert_eq!(write(&file, 0, b"hello"), Ok(5));
+        // Past the end, leaving a hole
+        assert_eq!(write(&file, 7, b"!"), Ok(1));
+        let again = open("ramfs-test").expect("open failed");
+        assert_eq!(size(&again), Ok(8));
+        let mut buf = [0xff; 16];
+        assert_eq!(read(&again, 0, &mut buf), Ok(8));
+        assert_eq!(&buf[..8], b"hello\0\0!");
+        assert_eq!(read(&again, 8, &mut buf), Ok(0));
+        assert_eq!(unlink("/ramfs-test"), Ok(()));
+        assert_eq!(read(&again, 0, &mut buf), Err(FsError::NotFound));
+        assert_eq!(open("/ramfs-test").err(), Some(FsError::NotFound));
+    }
+
+    #[test_case]
+    fn bad_paths_are_refused() {
+        assert_eq!(resolve("/", ""), Err(FsError::BadPath));
+        assert_eq!(resolve("/", "/dir/file"), Err(FsError::BadPath));
+        assert_eq!(resolve("/home", "file"), Err(FsError::BadPath));
+        assert_eq!(resolve("", "file"), Ok(String::from("file")));
+    }
+}
@@ -0,0 +1,28 @@
+// Kernel pseudo random numbers
+// A splitmix64 generator for values that shouldn't be easy to guess, like
+// stack canaries. There is no entropy source, so it starts from a fixed
+// seed and a boot gives the same numbers every time unless "seed=<n>" is
+// on the command line. That keeps runs reproducible while debugging.
+// Nothing here is fit for cryptography.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Used until seed() is called
+pub const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+// splitmix64 moves its state on by this every step
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+static STATE: AtomicU64 = AtomicU64::new(DEFAULT_SEED);
+
+// Start the sequence over from s
+pub fn seed(s: u64) {
+    STATE.store(s, Ordering::Relaxed);
+}
+
+// The next number. Each caller gets a different one, also across harts.
+pub fn next_u64() -> u64 {
+    let mut z = STATE.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -1,42 +1,238 @@
 // Scheduler for processes
 
-use crate::process::{ProcessState, PROCESS_LIST};
+use crate::{cpu::{ms_to_ticks, mtime_read, spin_hint}, process::{self, Process, ProcessState, PROCESS_LIST}, timer};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::collections::vec_deque::VecDeque;
 
-// Takes a process from the front of the process list
-// and returns it's trap frame, program counter and the satp(for the root page table)
-pub fn schedule() -> (usize, usize, usize) {
+// How long a process runs before the timer preempts it, unless the
+// quantum_ms boot option says otherwise
+pub const QUANTUM_MS: u64 = 10;
+static QUANTUM: AtomicU64 = AtomicU64::new(QUANTUM_MS);
+// With tickless on, a hart with nothing else to switch to isn't
+// interrupted every quantum, the next timer is pushed out this far.
+pub const TICKLESS: bool = true;
+pub const TICKLESS_MS: u64 = 1_000;
+
+// Number of context-switch timer interrupts taken so far
+pub static TICK: AtomicU64 = AtomicU64::new(0);
+// When set, sleeping and waking go by TICK instead of mtime. Each tick
+// counts as one quantum, so the same processes wake in the same order no
+// matter how fast the machine (or QEMU) runs.
+static LOGICAL_TIME: AtomicBool = AtomicBool::new(false);
+
+// What the trap handler needs to switch to a process. It is copied out
+// of the process so nothing has to hold on to the process list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SchedDecision {
+    pub frame_addr: usize,
+    pub pc:         usize,
+    pub satp:       usize
+}
+
+impl From<&Process> for SchedDecision {
+    fn from(process: &Process) -> Self {
+        let satp_root = process.get_table_address() >> 12;
+        let satp = if satp_root != 0 {
+            (8 << 60) | ((process.get_pid() as usize) << 44) | satp_root
+        } else {
+            0
+        };
+        SchedDecision { frame_addr: process.get_frame_address(),
+                        pc: process.get_program_counter(),
+                        satp }
+    }
+}
+
+// Picks the next process from the process list that is allowed to run on
+// hart, or None if there is nothing to run
+pub fn schedule(hart: usize) -> Option<SchedDecision> {
     unsafe {
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            pl.rotate_left(1);
-            let mut frame_addr = 0;
-            let mut mepc = 0;
-            let mut pid = 0;
-            let mut satp_root = 0;
-
-            if let Some(process) = pl.front() {
-                match process.get_state() {
-                    ProcessState::Running => {
-                        frame_addr = process.get_frame_address();
-                        mepc = process.get_program_counter();
-                        pid = process.get_pid() as usize;
-                        satp_root = process.get_table_address() >> 12;
-                    },
-                    ProcessState::Sleeping => {
-
-                    }
-                    _ => {},
-                }
+        let mut pl = PROCESS_LIST.take()?;
+        process::reap(&mut pl);
+        let decision = pick(&mut pl, hart);
+        PROCESS_LIST.replace(pl);
+        decision
+    }
+}
+
+// Rotate pl until a Running process that may run on hart is at the front.
+// Sleepers are woken by their timers, see timer::expire. Sleeping and dead
+// processes and ones pinned to other harts are skipped,
+// and we give up after going around the list once.
+fn pick(pl: &mut VecDeque<Process>, hart: usize) -> Option<SchedDecision> {
+    for _ in 0..pl.len() {
+        pl.rotate_left(1);
+        if let Some(process) = pl.front_mut() {
+            // run_to_exit() has one process to itself
+            let alone = process::running_alone().is_none_or(|pid| pid == process.get_pid());
+            if alone && process.can_run_on(hart) && *process.get_state() == ProcessState::Running {
+                println!("Scheduling {}", process.get_pid());
+                return Some(SchedDecision::from(&*process));
             }
-            println!("Scheduling {}", pid);
+        }
+    }
+    None
+}
+
+// Called on every context-switch timer interrupt
+pub fn tick() {
+    TICK.fetch_add(1, Ordering::Relaxed);
+}
+
+// Move the tick counter forward by n without waiting for the timer
+pub fn advance_ticks(n: u64) {
+    TICK.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn set_logical_time(on: bool) {
+    LOGICAL_TIME.store(on, Ordering::Relaxed);
+}
+
+// The time sleep and wakeup go by, in mtime ticks. With logical time on,
+// it is worked out from TICK.
+pub fn now() -> u64 {
+    if LOGICAL_TIME.load(Ordering::Relaxed) {
+        TICK.load(Ordering::Relaxed).saturating_mul(ms_to_ticks(quantum_ms()))
+    } else {
+        mtime_read()
+    }
+}
+
+// Wait until now() reaches at. With logical time nothing else would move
+// the clock, so the ticks are skipped forward instead.
+pub fn wait_until(at: u64) {
+    if LOGICAL_TIME.load(Ordering::Relaxed) {
+        let per_tick = ms_to_ticks(quantum_ms());
+        let behind = at.saturating_sub(now());
+        advance_ticks(behind.div_ceil(per_tick));
+    } else {
+        while mtime_read() < at {
+            spin_hint();
+        }
+    }
+}
+
+// Count the processes that are ready to run, on any hart
+pub fn runnable_count() -> usize {
+    count_runnable(|_| true)
+}
+
+// Count the processes that are ready to run and allowed on hart
+pub fn runnable_on(hart: usize) -> usize {
+    count_runnable(|p| p.can_run_on(hart))
+}
+
+fn count_runnable(f: impl Fn(&Process) -> bool) -> usize {
+    unsafe {
+        if let Some(pl) = PROCESS_LIST.take() {
+            let count = pl.iter().filter(|p| *p.get_state() == ProcessState::Running && f(p)).count();
             PROCESS_LIST.replace(pl);
-            if frame_addr != 0 {
-                if satp_root != 0 {
-                    return (frame_addr, mepc, (8 << 60) | (pid << 44) | (satp_root));
-                } else {
-                    return (frame_addr, mepc, 0);
-                }
-            }
+            return count;
+        }
+    }
+    0
+}
+
+// How many milliseconds from now hart's next context-switch timer should
+// fire. Preempting the only process the hart may run would just pick it
+// again. A sleeper or periodic timer due sooner than that brings the
+// timer forward so it isn't late.
+pub fn next_timer_ms(hart: usize) -> u64 {
+    let ms = if TICKLESS && runnable_on(hart) <= 1 {
+        TICKLESS_MS
+    } else {
+        quantum_ms()
+    };
+    let periodic = timer::next_periodic().map(|at| at as usize);
+    match next_wakeup().into_iter().chain(periodic).min() {
+        Some(at) => {
+            let left = (at as u64).saturating_sub(now()) / ms_to_ticks(1);
+            ms.min(left.max(1))
+        },
+        None => ms
+    }
+}
+
+// The earliest mtime a sleeping process wants to wake at, None if nothing
+// is asleep. This is the first pending timer, which may also be one for a
+// process that has died since, waking for it early is harmless.
+pub fn next_wakeup() -> Option<usize> {
+    timer::next_deadline().map(|at| at as usize)
+}
+
+pub fn quantum_ms() -> u64 {
+    QUANTUM.load(Ordering::Relaxed)
+}
+
+// A quantum of 0 would have the timer fire again immediately, so it's
+// rounded up to 1 ms.
+pub fn set_quantum_ms(ms: u64) {
+    QUANTUM.store(ms.max(1), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pids(pl: &VecDeque<Process>) -> [u16; 3] {
+        [pl[0].get_pid(), pl[1].get_pid(), pl[2].get_pid()]
+    }
+
+    #[test_case]
+    fn pick_skips_processes_that_cant_run() {
+        let mut pl: VecDeque<Process> = (0..4).map(|_| Process::new_unlisted()).collect();
+        // The list is rotated before the front is looked at, so pl[1]
+        // is looked at first and pl[0] last
+        assert!(pl[1].transition(ProcessState::Dead).is_ok());
+        assert!(pl[2].transition(ProcessState::Sleeping).is_ok());
+        let want = SchedDecision::from(&pl[3]);
+        let pid = pl[3].get_pid();
+        assert_eq!(pick(&mut pl, 0), Some(want));
+        assert_eq!(pl.front().map(|p| p.get_pid()), Some(pid));
+    }
+
+    #[test_case]
+    fn pick_gives_up_after_one_lap() {
+        let mut pl: VecDeque<Process> = (0..3).map(|_| Process::new_unlisted()).collect();
+        assert!(pl[0].transition(ProcessState::Dead).is_ok());
+        assert!(pl[1].transition(ProcessState::Sleeping).is_ok());
+        assert!(pl[2].transition(ProcessState::Waiting).is_ok());
+        let before = pids(&pl);
+        assert_eq!(pick(&mut pl, 0), None);
+        // Around once and back where it started
+        assert_eq!(pids(&pl), before);
+        assert_eq!(pick(&mut VecDeque::new(), 0), None);
+    }
+    #[test_case]
+    fn pick_leaves_processes_pinned_to_other_harts() {
+        let mut pl: VecDeque<Process> = (0..2).map(|_| Process::new_unlisted()).collect();
+        for p in pl.iter_mut() {
+            p.set_affinity(Some(1));
+        }
+        assert_eq!(pick(&mut pl, 0), None);
+        assert!(pick(&mut pl, 1).is_some());
+        // Unpinned runs anywhere
+        pl[0].set_affinity(None);
+        let want = SchedDecision::from(&pl[0]);
+        assert_eq!(pick(&mut pl, 0), Some(want));
+    }
+    #[test_case]
+    fn advancing_ticks_wakes_only_the_process_that_is_due() {
+        set_logical_time(true);
+        let quantum = quantum_ms();
+        let sleepers = [1, 3].map(|quanta| {
+            let pid = process::add_process_with_args(process::spin_entry, &[]).expect("no room for the test process");
+            let (frame, _) = process::frame_of(pid).expect("test process went missing");
+            assert!(process::msleep(frame as usize, quanta * quantum, 0));
+            pid
+        });
+        advance_ticks(1);
+        timer::expire(now());
+        assert_eq!(process::state_of(sleepers[0]), Some(ProcessState::Running));
+        assert_eq!(process::state_of(sleepers[1]), Some(ProcessState::Sleeping));
+        set_logical_time(false);
+        for pid in sleepers {
+            let _ = process::kill(pid);
         }
-        (0, 0, 0)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,144 @@
+// Slab allocator for fixed-size kernel objects
+// A Cache carves whole pages from the page allocator into equally sized
+// slots for one type, so small objects like trap frames don't each take
+// up a full page. Free slots are kept on a singly linked list threaded
+// through the slots themselves.
+
+use core::{marker::PhantomData, mem::{align_of, size_of}, ptr::null_mut};
+
+use crate::{mem::memset, page::{zalloc, PAGE_SIZE}};
+
+// A free slot stores the pointer to the next free slot in its first word
+struct FreeSlot {
+    next: *mut FreeSlot
+}
+
+pub struct Cache<T> {
+    free:       *mut FreeSlot,
+    // Number of pages this cache has taken from the page allocator
+    pages:      usize,
+    _marker:    PhantomData<T>
+}
+
+// A Cache only holds free slots, never a T, and the pages they are in
+// belong to it, so it can be handed to another hart whatever T is
+unsafe impl<T> Send for Cache<T> {}
+
+impl<T> Cache<T> {
+    // Each slot has to be big enough for a T and for the free list link,
+    // and a multiple of the alignment of both so every slot stays aligned.
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<FreeSlot>() {
+        align_of::<T>()
+    } else {
+        align_of::<FreeSlot>()
+    };
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<FreeSlot>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeSlot>()
+        };
+        (size + Self::SLOT_ALIGN - 1) & !(Self::SLOT_ALIGN - 1)
+    };
+    pub const SLOTS_PER_PAGE: usize = PAGE_SIZE / Self::SLOT_SIZE;
+
+    pub const fn new() -> Self {
+        Cache {
+            free:       null_mut(),
+            pages:      0,
+            _marker:    PhantomData
+        }
+    }
+
+    pub fn get_num_pages(&self) -> usize {
+        self.pages
+    }
+
+    // Allocate a zeroed slot, grabbing another page if the cache is full
+    // Returns null if the page allocator is out of memory.
+    pub fn alloc(&mut self) -> *mut T {
+        // Objects bigger than a page don't belong in a slab
+        assert!(Self::SLOTS_PER_PAGE > 0);
+        if self.free.is_null() && !self.grow() {
+            return null_mut();
+        }
+        unsafe {
+            let slot = self.free;
+            self.free = (*slot).next;
+            // A reused slot still holds whatever the last owner left there
+            memset(slot as *mut u8, 0, Self::SLOT_SIZE);
+            slot as *mut T
+        }
+    }
+
+    // Return a slot to the cache. The pointer must have come from alloc()
+    // on this same cache. Pages are kept by the cache for reuse.
+    pub fn free(&mut self, ptr: *mut T) {
+        if ptr.is_null() {
+            return;
+        }
+        let slot = ptr as *mut FreeSlot;
+        unsafe {
+            (*slot).next = self.free;
+        }
+        self.free = slot;
+    }
+
+    // Take a new page and put all of its slots on the free list
+    fn grow(&mut self) -> bool {
+        let page = zalloc(1);
+        if page.is_null() {
+            return false;
+        }
+        for i in (0..Self::SLOTS_PER_PAGE).rev() {
+            let slot = unsafe { page.add(i * Self::SLOT_SIZE) } as *mut FreeSlot;
+            unsafe {
+                (*slot).next = self.free;
+            }
+            self.free = slot;
+        }
+        self.pages += 1;
+        true
+    }
+}
+
+impl<T> Default for Cache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::dealloc;
+
+    type Obj = [u64; 64];
+
+    #[test_case]
+    fn a_full_cache_grabs_another_page() {
+        let mut cache: Cache<Obj> = Cache::new();
+        assert_eq!(Cache::<Obj>::SLOTS_PER_PAGE, PAGE_SIZE / size_of::<Obj>());
+        let mut objs = [null_mut(); PAGE_SIZE / size_of::<Obj>()];
+        for obj in objs.iter_mut() {
+            *obj = cache.alloc();
+            assert!(!obj.is_null());
+        }
+        assert_eq!(cache.get_num_pages(), 1);
+        let page = objs[0] as usize & !(PAGE_SIZE - 1);
+        assert!(objs.iter().all(|&obj| obj as usize & !(PAGE_SIZE - 1) == page));
+        let extra = cache.alloc();
+        assert_eq!(cache.get_num_pages(), 2);
+        assert_ne!(extra as usize & !(PAGE_SIZE - 1), page);
+        // A freed slot is handed out again, zeroed, without another page
+        unsafe { (*objs[3])[0] = 0xdead; }
+        cache.free(objs[3]);
+        let again = cache.alloc();
+        assert_eq!(again, objs[3]);
+        assert_eq!(unsafe { (*again)[0] }, 0);
+        assert_eq!(cache.get_num_pages(), 2);
+        // The cache never gives its pages back, so do it by hand
+        dealloc(page as *mut u8);
+        dealloc((extra as usize & !(PAGE_SIZE - 1)) as *mut u8);
+    }
+}
@@ -0,0 +1,134 @@
+// Wait queues
+// A WaitQueue is where processes wait for something the kernel will tell
+// them about, like input arriving or another process exiting. Features
+// keep one per thing to wait for instead of each handling the Waiting
+// state on their own. The queue only holds pids, the process list keeps
+// the processes.
+
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::{cpu::mhartid_read, lock::Mutex, process, trap::run_next};
+
+pub struct WaitQueue {
+    // Oldest waiter first
+    waiters: Mutex<VecDeque<u16>>
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(VecDeque::new()) }
+    }
+
+    // Block the process that owns frame on this queue and run something
+    // else. It resumes at pc once it is woken. This only returns, with
+    // false, if frame isn't a process' or the process can't wait.
+    pub fn wait(&self, frame: usize, pc: usize) -> bool {
+        self.wait_while(frame, pc, || true)
+    }
+
+    // Like wait, but only if blocked() still holds once the queue is
+    // locked. Whoever makes blocked() false and then wakes the queue
+    // can't slip in between the caller's own check and it getting on the
+    // queue, so that wake up isn't lost. Returns true straight away if
+    // blocked() is false, and false if the process can't wait.
+    pub fn wait_while(&self, frame: usize, pc: usize, blocked: impl FnOnce() -> bool) -> bool {
+        match self.join(frame, pc, blocked) {
+            Some(true) => run_next(mhartid_read()),
+            Some(false) => true,
+            None => false
+        }
+    }
+
+    // The part of wait_while before something else runs. Returns whether
+    // the process is now Waiting on the queue, None if it can't wait.
+    fn join(&self, frame: usize, pc: usize, blocked: impl FnOnce() -> bool) -> Option<bool> {
+        // Queued with the lock held, so a wake can't come between the
+        // process blocking and it being on the queue
+        let mut waiters = self.waiters.lock();
+        if !blocked() {
+            return Some(false);
+        }
+        waiters.push_back(process::block(frame, pc)?);
+        Some(true)
+    }
+
+    // Make the oldest waiter runnable. Returns false if nobody was waiting.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        // A waiter that was killed meanwhile doesn't use up the wake up
+        while let Some(pid) = waiters.pop_front() {
+            if process::unblock(pid) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Make every waiter runnable. Returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        waiters.drain(..).filter(|&pid| process::unblock(pid)).count()
+    }
+
+    // Number of processes on the queue, counting any killed since
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{add_process_with_args, frame_of, kill, spin_entry, state_of, ProcessState};
+
+    #[test_case]
+    fn wake_one_runs_exactly_one_waiter() {
+        let queue = WaitQueue::new();
+        let first = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let second = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let frame_of_pid = |pid| frame_of(pid).expect("test process went missing").0 as usize;
+        // Nothing to wait for
+        assert_eq!(queue.join(frame_of_pid(first), 0x1000, || false), Some(false));
+        assert_eq!(state_of(first), Some(ProcessState::Running));
+        assert_eq!(queue.join(frame_of_pid(first), 0x1000, || true), Some(true));
+        assert_eq!(queue.join(frame_of_pid(second), 0x1000, || true), Some(true));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(state_of(first), Some(ProcessState::Waiting));
+        assert_eq!(state_of(second), Some(ProcessState::Waiting));
+        // Oldest first
+        assert!(queue.wake_one());
+        assert_eq!(state_of(first), Some(ProcessState::Running));
+        assert_eq!(state_of(second), Some(ProcessState::Waiting));
+        assert_eq!(queue.wake_all(), 1);
+        assert_eq!(state_of(second), Some(ProcessState::Running));
+        assert!(!queue.wake_one());
+        assert!(queue.is_empty());
+        let _ = kill(first);
+        let _ = kill(second);
+    }
+
+    #[test_case]
+    fn killed_waiters_dont_use_up_a_wake() {
+        let queue = WaitQueue::new();
+        let dead = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        let live = add_process_with_args(spin_entry, &[]).expect("no room for the test process");
+        for pid in [dead, live] {
+            let (frame, _) = frame_of(pid).expect("test process went missing");
+            assert_eq!(queue.join(frame as usize, 0x1000, || true), Some(true));
+        }
+        assert!(kill(dead).is_ok());
+        assert!(queue.wake_one());
+        assert_eq!(state_of(live), Some(ProcessState::Running));
+        let _ = kill(live);
+    }
+}
@@ -1,25 +1,512 @@
 // System calls
+// The number goes in a7 and up to six arguments in a0-a5, the result comes
+// back in a0, usize::MAX for an error. This is the standard RISC-V
+// convention, and syscall0..syscall3 below make calls with it.
 
-use crate::cpu::TrapFrame;
+use core::arch::asm;
 
-pub fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
-    let syscall_no;
-    unsafe {
-        // x10 register is a0, we get syscall number in a0 register
-        syscall_no = (*frame).regs[10];
-    }
+use crate::{cpu::{mhartid_read, mip_read, TrapFrame, MIP_MTIP}, fd::{Blocker, FdError, Whence}, process::{self, FutexWait}, trap::{debug_break, run_next}, uart};
+
+// Handle a system call made with the registers in frame and return the pc
+// to resume at. Every syscall leaves its result in a0 of the frame, the
+// return value is only ever the pc: mepc + 4 to carry on after the ecall,
+// and syscalls that switch to another process don't return at all.
+// Taking the frame by reference means a syscall can be run against any
+// TrapFrame, not just the one the trap vector saved. Processes are found
+// by the address of their trap frame, so syscalls that act on the calling
+// process only work on a frame from a process.
+pub fn do_syscall(mepc: usize, frame: &mut TrapFrame) -> usize {
+    let frame_addr = frame as *mut TrapFrame as usize;
+    // x17 register is a7, we get syscall number in a7 register
+    let syscall_no = frame.regs[17];
     match syscall_no {
         0 => {
-            // Exit syscall
+            // exit, the calling process is done with the status in a0
+            // and something else runs
+            if let Some(pid) = process::pid_of_frame(frame_addr) {
+                let _ = process::exit(pid, frame.regs[10]);
+                run_next(mhartid_read());
+            }
+            // Not a process, there is nothing to end
+            frame.regs[10] = usize::MAX;
             mepc + 4
         },
         1 => {
             println!("Test sycall");
+            frame.regs[10] = 0;
+            mepc + 4
+        },
+        2 => {
+            // write, fd in a0, buffer in a1 and count in a2.
+            // Returns the bytes written in a0, or -1
+            let (fd, buf, count) = (frame.regs[10], frame.regs[11], frame.regs[12]);
+            match process::fd_write(frame_addr, fd, buf, count) {
+                Ok(Progress::Done(len)) => frame.regs[10] = len,
+                Ok(Progress::Preempted(len)) => {
+                    // Run the ecall again for the rest once the timer has
+                    // had its turn
+                    frame.regs[11] += len;
+                    frame.regs[12] -= len;
+                    return mepc;
+                },
+                Ok(Progress::Blocked(len)) => {
+                    // The same, once a full pipe has room again
+                    frame.regs[11] += len;
+                    frame.regs[12] -= len;
+                    if wait_for_fd(frame_addr, fd, mepc) {
+                        return mepc;
+                    }
+                    frame.regs[10] = usize::MAX
+                },
+                Err(_) => frame.regs[10] = usize::MAX
+            }
+            mepc + 4
+        },
+        3 => {
+            // open, path at a0 that is a1 bytes long. If bit 0 of a2 is set
+            // a missing file is created. Returns the fd in a0, or -1
+            let (path, len, create) = (frame.regs[10], frame.regs[11], frame.regs[12] & 1 != 0);
+            frame.regs[10] = process::fd_open(frame_addr, path, len, create).unwrap_or(usize::MAX);
+            mepc + 4
+        },
+        4 => {
+            // close the fd in a0, returns 0 in a0, or -1
+            let fd = frame.regs[10];
+            frame.regs[10] = process::fd_close(frame_addr, fd).map_or(usize::MAX, |_| 0);
+            mepc + 4
+        },
+        5 => {
+            // getpid, returns the pid of the caller in a0, or -1
+            frame.regs[10] = process::pid_of_frame(frame_addr).map_or(usize::MAX, usize::from);
+            mepc + 4
+        },
+        11 => {
+            // Debug break, report the caller and its registers, then carry on
+            debug_break(mepc, frame);
+            frame.regs[10] = 0;
+            mepc + 4
+        },
+        12 => {
+            // sbrk, grow (or with a negative increment, shrink) the heap
+            // by a0 bytes and return the previous heap top in a0, or -1
+            let incr = frame.regs[10] as isize;
+            frame.regs[10] = process::sbrk(frame_addr, incr).unwrap_or(usize::MAX);
+            mepc + 4
+        },
+        13 => {
+            // lseek, move the offset of fd a0 by a1 bytes from the start
+            // (a2 = 0), the current offset (1) or the end (2) of the file.
+            // Returns the new offset in a0, or -1
+            let (fd, offset) = (frame.regs[10], frame.regs[11] as isize);
+            frame.regs[10] = match Whence::from_usize(frame.regs[12]) {
+                Some(whence) => process::fd_seek(frame_addr, fd, offset, whence).unwrap_or(usize::MAX),
+                None => usize::MAX
+            };
+            mepc + 4
+        },
+        14 => {
+            // msleep, sleep for a0 milliseconds. 0 gives up the rest of the
+            // quantum. Returns the milliseconds left unslept in a0, which is
+            // 0 once the whole time has passed, or -1
+            let ms = frame.regs[10] as u64;
+            if process::msleep(frame_addr, ms, mepc + 4) {
+                run_next(mhartid_read());
+            }
+            frame.regs[10] = usize::MAX;
+            mepc + 4
+        },
+        15 => {
+            // futex_wait, block until a futex_wake on the address a0, if
+            // the 32-bit word there still holds a1. Returns 0 in a0 once
+            // woken, 1 if the word had already changed, or -1
+            let (uaddr, expected) = (frame.regs[10], frame.regs[11] as u32);
+            frame.regs[10] = match process::futex_wait(frame_addr, uaddr, expected, mepc + 4) {
+                Some(FutexWait::Blocked) => run_next(mhartid_read()),
+                Some(FutexWait::Changed) => 1,
+                None => usize::MAX
+            };
+            mepc + 4
+        },
+        16 => {
+            // futex_wake, wake up to a1 processes waiting on the address
+            // a0. Returns the number woken in a0, or -1
+            let (uaddr, count) = (frame.regs[10], frame.regs[11]);
+            frame.regs[10] = process::futex_wake(frame_addr, uaddr, count).unwrap_or(usize::MAX);
+            mepc + 4
+        },
+        17 => {
+            // read, fd in a0, buffer in a1 and count in a2. Returns the
+            // bytes read in a0, 0 at the end of a file, or -1. A read of
+            // the console or a pipe with no input waiting blocks until
+            // some arrives and then runs the syscall again.
+            let (fd, buf, count) = (frame.regs[10], frame.regs[11], frame.regs[12]);
+            frame.regs[10] = match process::fd_read(frame_addr, fd, buf, count) {
+                Ok(len) => len,
+                Err(FdError::WouldBlock) => {
+                    if wait_for_fd(frame_addr, fd, mepc) {
+                        return mepc;
+                    }
+                    usize::MAX
+                },
+                Err(_) => usize::MAX
+            };
+            mepc + 4
+        },
+        18 => {
+            // set_tp, make a0 the caller's thread pointer (tp), for a
+            // thread-local block or errno. Returns 0 in a0, or -1
+            frame.regs[10] = match process::pid_of_frame(frame_addr) {
+                // Set it in this frame, which is what gets restored on
+                // the way out. Going through the process list would be
+                // the same memory behind a second reference.
+                Some(_) => {
+                    frame.regs[4] = frame.regs[10];
+                    0
+                },
+                None => usize::MAX
+            };
+            mepc + 4
+        },
+        19 => {
+            // proc_stat, copy a ProcStat for process number a0, in pid
+            // order, to a1. Returns the number of processes in a0, or -1
+            // with state PROC_GONE copied if a0 is past the last one
+            let (index, buf) = (frame.regs[10], frame.regs[11]);
+            frame.regs[10] = process::proc_stat(frame_addr, index, buf).unwrap_or(usize::MAX);
+            mepc + 4
+        },
+        20 => {
+            // pipe, make a pipe and copy its read and write fds to the two
+            // 32-bit ints at a0. Returns 0 in a0, or -1
+            let fds = frame.regs[10];
+            frame.regs[10] = process::fd_pipe(frame_addr, fds).map_or(usize::MAX, |_| 0);
+            mepc + 4
+        },
+        21 => {
+            // meminfo, copy a page::MemInfo with the page allocator's
+            // counts to a0. Returns 0 in a0, or -1
+            let buf = frame.regs[10];
+            frame.regs[10] = process::meminfo(frame_addr, buf).map_or(usize::MAX, |_| 0);
             mepc + 4
         },
         _ => {
             println!("Unknown syscall number {}", syscall_no);
+            frame.regs[10] = usize::MAX;
             mepc + 4
         }
     }
-}
\ No newline at end of file
+}
+
+// How far a syscall that can be preempted got
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    // It finished, with this result
+    Done(usize),
+    // It stopped early for the timer after doing this much
+    Preempted(usize),
+    // It has to wait to go on, after doing this much
+    Blocked(usize)
+}
+
+// Wait for fd of the process that owns frame, which returned WouldBlock,
+// and run the ecall at pc again once it is ready. This doesn't return
+// while the process waits. It returns true if fd is ready already, so the
+// ecall should just run again now, and false if the process can't wait.
+fn wait_for_fd(frame: usize, fd: usize, pc: usize) -> bool {
+    match process::fd_blocker(frame, fd) {
+        Some(Blocker::Console) => {
+            if process::wait_on(frame, uart::rx_key(), pc) {
+                run_next(mhartid_read());
+            }
+            false
+        },
+        Some(Blocker::Pipe(waiter)) => waiter.wait(frame, pc),
+        None => false
+    }
+}
+
+// Whether a long syscall should stop for now and let the timer interrupt
+// in. Syscalls run in machine mode with interrupts off, so a tick that
+// comes due meanwhile just waits in mip. A syscall that stops has to
+// leave the frame so that making the same ecall again does the rest.
+// Returning mepc instead of mepc + 4 does that: the pending timer
+// interrupt is taken as soon as we are back in the process, and the
+// ecall runs again when it is next scheduled.
+pub fn check_preempt() -> bool {
+    mip_read() & MIP_MTIP != 0
+}
+
+// Make syscall num from the calling mode. These are inlined so a process
+// running kernel code doesn't need anything else mapped to use them.
+#[inline(always)]
+pub fn syscall0(num: usize) -> usize {
+    let ret;
+    unsafe { asm!("ecall", in("a7") num, lateout("a0") ret); }
+    ret
+}
+
+#[inline(always)]
+pub fn syscall1(num: usize, a0: usize) -> usize {
+    let ret;
+    unsafe { asm!("ecall", in("a7") num, inlateout("a0") a0 => ret); }
+    ret
+}
+
+#[inline(always)]
+pub fn syscall2(num: usize, a0: usize, a1: usize) -> usize {
+    let ret;
+    unsafe { asm!("ecall", in("a7") num, inlateout("a0") a0 => ret, in("a1") a1); }
+    ret
+}
+
+#[inline(always)]
+pub fn syscall3(num: usize, a0: usize, a1: usize, a2: usize) -> usize {
+    let ret;
+    unsafe { asm!("ecall", in("a7") num, inlateout("a0") a0 => ret, in("a1") a1, in("a2") a2); }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::mem::size_of;
+
+    use super::*;
+    use crate::{clint,
+                page::{lookup, MemInfo, Table, VirtAddr, PAGE_SIZE},
+                process::{ProcStat, ProcessState, ARGS_ADDR, HEAP_ADDR, PROCESS_LIST, PROC_GONE},
+                ramfs,
+                vm::{copy_from_user, copy_to_user}};
+
+    // Where the ecall being handled is, the pc a syscall returns past
+    const EPC: usize = 0x8000_1000;
+
+    // Run syscall num with the arguments in a0.. against a frame, a fresh
+    // one unless one is given with "in", and return a0 and the pc the
+    // syscall goes back to
+    macro_rules! syscall_test {
+        (in $frame:expr; $num:expr $(, $arg:expr)*) => {{
+            let frame: &mut TrapFrame = $frame;
+            let args: &[usize] = &[$($arg),*];
+            frame.regs[17] = $num;
+            frame.regs[10..10 + args.len()].copy_from_slice(args);
+            let pc = do_syscall(EPC, frame);
+            (frame.regs[10], pc)
+        }};
+        ($num:expr $(, $arg:expr)*) => {{
+            let mut frame = TrapFrame::zero();
+            syscall_test!(in &mut frame; $num $(, $arg)*)
+        }};
+    }
+
+    // A process with "hello" as its only argument. argv is two pointers,
+    // so the string is 16 bytes into the page at ARGS_ADDR.
+    const HELLO_ADDR: usize = ARGS_ADDR + 16;
+    // Past argv and "hello", still in the same page
+    const SCRATCH_ADDR: usize = ARGS_ADDR + 64;
+
+    extern "C" fn idle_entry(_argc: usize, _argv: usize) {
+        loop {
+            syscall0(5);
+        }
+    }
+
+    extern "C" fn exit_entry(_argc: usize, _argv: usize) {
+        // On the stack, the process can't read the kernel's data
+        let byte = b'!';
+        syscall3(2, 1, &byte as *const u8 as usize, 1);
+        loop {
+            syscall1(0, 42);
+        }
+    }
+
+    // Each wrapper once, with what came back passed on where the test
+    // can see it
+    extern "C" fn wrappers_entry(_argc: usize, argv: usize) {
+        let pid = syscall0(5);
+        // The word at argv isn't its complement, so this returns 1 at
+        // once, and only if a1 made it through
+        let word = unsafe { (argv as *const u32).read_volatile() };
+        let byte = b'0' + syscall2(15, argv, !word as usize) as u8;
+        syscall3(2, 1, &byte as *const u8 as usize, 1);
+        loop {
+            syscall1(0, pid);
+        }
+    }
+
+    fn with_process(f: impl FnOnce(u16, &mut TrapFrame, &Table)) {
+        let pid = process::add_process_with_args(idle_entry, &["hello"]).expect("no room for the test process");
+        let (frame, root) = process::frame_of(pid).expect("test process went missing");
+        f(pid, unsafe { &mut *frame }, unsafe { &*root });
+        let _ = process::kill(pid);
+    }
+
+    #[test_case]
+    fn exit_ends_the_process_with_its_status() {
+        let pid = process::add_process_with_args(exit_entry, &[]).expect("no room for the test process");
+        assert_eq!(process::run_to_exit(pid), Ok(42));
+        assert_eq!(process::take_alone_output(), b"!");
+    }
+
+    #[test_case]
+    fn wrappers_pass_arguments_and_results() {
+        let pid = process::add_process_with_args(wrappers_entry, &["x"]).expect("no room for the test process");
+        assert_eq!(process::run_to_exit(pid), Ok(usize::from(pid)));
+        assert_eq!(process::take_alone_output(), b"1");
+    }
+
+    #[test_case]
+    fn exit_without_a_process_fails() {
+        assert_eq!(syscall_test!(0, 42), (usize::MAX, EPC + 4));
+    }
+
+    #[test_case]
+    fn getpid_returns_the_callers_pid() {
+        with_process(|pid, frame, _| {
+            assert_eq!(syscall_test!(in frame; 5), (usize::from(pid), EPC + 4));
+        });
+        assert_eq!(syscall_test!(5), (usize::MAX, EPC + 4));
+    }
+
+    #[test_case]
+    fn write_copies_from_the_process() {
+        with_process(|_, frame, _| {
+            assert_eq!(syscall_test!(in frame; 2, 1, HELLO_ADDR, 5), (5, EPC + 4));
+            // Nothing is mapped at 0
+            assert_eq!(syscall_test!(in frame; 2, 1, 0, 5), (usize::MAX, EPC + 4));
+            assert_eq!(syscall_test!(in frame; 2, 99, HELLO_ADDR, 5), (usize::MAX, EPC + 4));
+        });
+        assert_eq!(syscall_test!(2, 1, HELLO_ADDR, 5), (usize::MAX, EPC + 4));
+    }
+
+    #[test_case]
+    fn meminfo_fills_in_the_page_counts() {
+        with_process(|_, frame, root| {
+            assert_eq!(syscall_test!(in frame; 21, SCRATCH_ADDR), (0, EPC + 4));
+            let mut bytes = [0u8; size_of::<MemInfo>()];
+            copy_from_user(root, &mut bytes, SCRATCH_ADDR).expect("meminfo buffer not readable");
+            let info = unsafe { (bytes.as_ptr() as *const MemInfo).read_unaligned() };
+            assert!(info.total_pages > 0);
+            assert!(info.free_pages + info.allocated_pages <= info.total_pages);
+            assert!(info.page_table_pages <= info.allocated_pages);
+            assert_eq!(syscall_test!(in frame; 21, 0), (usize::MAX, EPC + 4));
+        });
+        assert_eq!(syscall_test!(21, SCRATCH_ADDR), (usize::MAX, EPC + 4));
+    }
+
+    fn proc_stat_at(frame: &mut TrapFrame, root: &Table, index: usize) -> (usize, ProcStat) {
+        let (count, _) = syscall_test!(in frame; 19, index, SCRATCH_ADDR);
+        let mut bytes = [0u8; size_of::<ProcStat>()];
+        copy_from_user(root, &mut bytes, SCRATCH_ADDR).expect("proc_stat buffer not readable");
+        (count, unsafe { (bytes.as_ptr() as *const ProcStat).read_unaligned() })
+    }
+
+    #[test_case]
+    fn proc_stat_goes_through_processes_in_pid_order() {
+        with_process(|pid, frame, root| {
+            let (count, lowest, highest) = unsafe {
+                let pl = PROCESS_LIST.take().expect("process list taken");
+                let pids = pl.iter().map(|p| p.get_pid());
+                let ret = (pl.len(), pids.clone().min(), pids.max());
+                PROCESS_LIST.replace(pl);
+                ret
+            };
+            assert_eq!(highest, Some(pid));
+            let (total, first) = proc_stat_at(frame, root, 0);
+            assert_eq!(total, count);
+            // pid 1, the init process, if it has been made yet
+            assert_eq!(Some(first.pid as u16), lowest);
+            let (total, last) = proc_stat_at(frame, root, count - 1);
+            assert_eq!(total, count);
+            assert_eq!(last.pid, pid as u64);
+            assert_eq!(last.state, ProcessState::Running as u64);
+            assert!(last.rss > 0);
+            let (total, gone) = proc_stat_at(frame, root, count);
+            assert_eq!(total, usize::MAX);
+            assert_eq!(gone.state, PROC_GONE);
+        });
+    }
+
+    #[test_case]
+    fn sbrk_maps_and_unmaps_heap_pages() {
+        with_process(|_, frame, root| {
+            let mapped = |vaddr| lookup(root, VirtAddr(vaddr)).is_some();
+            assert_eq!(syscall_test!(in frame; 12, 2 * PAGE_SIZE), (HEAP_ADDR, EPC + 4));
+            assert!(mapped(HEAP_ADDR) && mapped(HEAP_ADDR + PAGE_SIZE));
+            let shrink = (-2 * PAGE_SIZE as isize) as usize;
+            assert_eq!(syscall_test!(in frame; 12, shrink), (HEAP_ADDR + 2 * PAGE_SIZE, EPC + 4));
+            assert!(!mapped(HEAP_ADDR) && !mapped(HEAP_ADDR + PAGE_SIZE));
+        });
+    }
+
+    #[test_case]
+    fn read_fills_a_buffer_across_pages() {
+        // Named like the argument so open can take its path from HELLO_ADDR
+        let handle = ramfs::create("/hello").expect("create failed");
+        let data: Vec<u8> = (0..2 * PAGE_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        assert_eq!(ramfs::write(&handle, 0, &data), Ok(data.len()));
+        with_process(|_, frame, root| {
+            let (fd, _) = syscall_test!(in frame; 3, HELLO_ADDR, 5, 0);
+            assert_ne!(fd, usize::MAX);
+            assert_eq!(syscall_test!(in frame; 12, 3 * PAGE_SIZE), (HEAP_ADDR, EPC + 4));
+            assert_eq!(syscall_test!(in frame; 13, fd, 10, 0), (10, EPC + 4));
+            // Starts just short of a page boundary and ends in the third page
+            let buf = HEAP_ADDR + PAGE_SIZE - 8;
+            let want = data.len() - 10;
+            assert_eq!(syscall_test!(in frame; 17, fd, buf, 2 * PAGE_SIZE), (want, EPC + 4));
+            let mut got = vec![0u8; want];
+            assert!(copy_from_user(root, &mut got, buf).is_ok());
+            assert!(got == data[10..]);
+            assert_eq!(syscall_test!(in frame; 13, fd, 0, 1), (data.len(), EPC + 4));
+            assert_eq!(syscall_test!(in frame; 17, fd, buf, 1), (0, EPC + 4));
+        });
+        assert!(ramfs::unlink("/hello").is_ok());
+    }
+
+    #[test_case]
+    fn a_pending_tick_stops_a_long_write_partway() {
+        let hart = mhartid_read();
+        let old = clint::read_mtimecmp(hart);
+        let data: Vec<u8> = (0..3 * PAGE_SIZE).map(|i| (i % 251) as u8).collect();
+        with_process(|_, frame, root| {
+            let (fd, _) = syscall_test!(in frame; 3, HELLO_ADDR, 5, 1);
+            assert_ne!(fd, usize::MAX);
+            assert_eq!(syscall_test!(in frame; 12, data.len()), (HEAP_ADDR, EPC + 4));
+            assert!(copy_to_user(root, HEAP_ADDR, &data).is_ok());
+            // Due at once, so a tick is pending from here on
+            clint::set_mtimecmp(hart, 0);
+            // Stops after the first page, with the ecall set up to write
+            // the rest when it is run again
+            assert_eq!(syscall_test!(in frame; 2, fd, HEAP_ADDR, data.len()), (fd, EPC));
+            assert_eq!((frame.regs[11], frame.regs[12]), (HEAP_ADDR + PAGE_SIZE, 2 * PAGE_SIZE));
+            clint::set_mtimecmp(hart, u64::MAX);
+            // The whole write is counted once it is done
+            assert_eq!(do_syscall(EPC, frame), EPC + 4);
+            assert_eq!(frame.regs[10], data.len());
+        });
+        clint::set_mtimecmp(hart, old);
+        let handle = ramfs::open("/hello").expect("written file went missing");
+        let mut got = vec![0u8; data.len() + 1];
+        assert_eq!(ramfs::read(&handle, 0, &mut got), Ok(data.len()));
+        assert!(got[..data.len()] == data[..]);
+        assert!(ramfs::unlink("/hello").is_ok());
+    }
+
+    #[test_case]
+    fn sbrk_fails_at_the_memory_limit() {
+        with_process(|pid, frame, _| {
+            let rss = process::rss(pid).expect("test process went missing");
+            assert!(process::set_mem_limit(pid, rss + 2));
+            assert_eq!(syscall_test!(in frame; 12, 2 * PAGE_SIZE), (HEAP_ADDR, EPC + 4));
+            assert_eq!(process::rss(pid), Some(rss + 2));
+            // Even a byte more needs a third page
+            assert_eq!(syscall_test!(in frame; 12, 1), (usize::MAX, EPC + 4));
+            assert_eq!(process::rss(pid), Some(rss + 2));
+            // Shrinking gives pages back, after which there is room again
+            assert_eq!(syscall_test!(in frame; 12, (-(PAGE_SIZE as isize)) as usize), (HEAP_ADDR + 2 * PAGE_SIZE, EPC + 4));
+            assert_eq!(process::rss(pid), Some(rss + 1));
+            assert_eq!(syscall_test!(in frame; 12, 1), (HEAP_ADDR + PAGE_SIZE, EPC + 4));
+            assert_eq!(process::rss(pid), Some(rss + 2));
+        });
+    }
+}
@@ -0,0 +1,29 @@
+// In-kernel tests
+// The kernel is built with custom_test_frameworks, so `cargo test` builds
+// a kernel whose kinit runs every #[test_case] after booting and then
+// powers QEMU off through the test finisher, passing or failing.
+// A failed test panics, and the panic handler fails the run.
+
+// Anything that can be a #[test_case], which prints its own name around
+// the run so a hang or panic shows which test it was in
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("{} ... ", core::any::type_name::<T>());
+        self();
+        println!("ok");
+    }
+}
+
+// The test runner custom_test_frameworks hands every #[test_case] to
+pub fn run_tests(tests: &[&dyn Testable]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("All {} tests passed", tests.len());
+    crate::test_finisher(crate::FINISHER_PASS);
+}
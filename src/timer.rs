@@ -0,0 +1,310 @@
+// Kernel timers
+// Things that need to happen at a later time, like waking a sleeping
+// process, are queued here with a deadline. The queue is a fixed array
+// kept sorted by deadline, so finding what is due only looks at the
+// front and nothing is allocated when a timer is added.
+// Drivers can also have a callback run every so many ticks, a tick being
+// one quantum of time. The timer interrupt only flags the callback as
+// due, it runs later from run_softirqs, so a slow one doesn't hold up the
+// interrupt.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{cpu::ms_to_ticks, lock::Mutex, process, scheduler};
+
+// Most timers that can be pending at once
+pub const MAX_TIMERS: usize = 64;
+// Most callbacks, and periodic timers, that can be registered
+pub const MAX_CALLBACKS: usize = 16;
+const _: () = assert!(MAX_CALLBACKS <= 32, "PENDING_CALLBACKS has a bit per callback");
+
+// What to do when a timer expires
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerId {
+    // Wake the process with this pid if it is still asleep
+    Wake(u16)
+}
+
+// There was no room left for another timer
+#[derive(Debug)]
+pub struct TimerFull;
+
+#[derive(Debug, PartialEq)]
+pub enum PeriodicError {
+    // Every periodic slot is in use
+    Full,
+    // No callback is registered with that id
+    NoCallback,
+    // An interval of 0 ticks would fire on every interrupt forever
+    ZeroInterval
+}
+
+struct Wheel {
+    // (deadline, id), sorted by deadline. Timers with the same deadline
+    // stay in the order they were added.
+    entries: [(u64, TimerId); MAX_TIMERS],
+    len:     usize
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Wheel { entries: [(0, TimerId::Wake(0)); MAX_TIMERS], len: 0 }
+    }
+
+    fn insert(&mut self, deadline: u64, id: TimerId) -> Result<(), TimerFull> {
+        if self.len == MAX_TIMERS {
+            return Err(TimerFull);
+        }
+        // After every timer due at or before deadline
+        let at = self.entries[..self.len].partition_point(|&(d, _)| d <= deadline);
+        self.entries.copy_within(at..self.len, at + 1);
+        self.entries[at] = (deadline, id);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, at: usize) -> (u64, TimerId) {
+        let entry = self.entries[at];
+        self.entries.copy_within(at + 1..self.len, at);
+        self.len -= 1;
+        entry
+    }
+
+    // Take the earliest timer if it is due at now
+    fn pop_due(&mut self, now: u64) -> Option<TimerId> {
+        if self.len > 0 && self.entries[0].0 <= now {
+            Some(self.remove(0).1)
+        } else {
+            None
+        }
+    }
+}
+
+static TIMERS: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+// Fire id once scheduler::now() has moved ticks past where it is now
+pub fn after(ticks: u64, id: TimerId) -> Result<(), TimerFull> {
+    at(scheduler::now().saturating_add(ticks), id)
+}
+
+// Fire id once scheduler::now() reaches deadline
+pub fn at(deadline: u64, id: TimerId) -> Result<(), TimerFull> {
+    TIMERS.lock().insert(deadline, id)
+}
+
+// Drop every pending timer for id. Returns how many there were.
+pub fn cancel(id: TimerId) -> usize {
+    let mut wheel = TIMERS.lock();
+    let mut removed = 0;
+    let mut i = 0;
+    while i < wheel.len {
+        if wheel.entries[i].1 == id {
+            wheel.remove(i);
+            removed += 1;
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+// The earliest deadline of a pending timer
+pub fn next_deadline() -> Option<u64> {
+    let wheel = TIMERS.lock();
+    if wheel.len > 0 {
+        Some(wheel.entries[0].0)
+    } else {
+        None
+    }
+}
+
+// Fire every timer due at now, earliest first. Returns how many fired.
+// The lock is dropped before each one fires, so firing may add timers.
+pub fn expire(now: u64) -> usize {
+    let mut fired = 0;
+    loop {
+        let id = TIMERS.lock().pop_due(now);
+        match id {
+            Some(id) => {
+                fire(id, now);
+                fired += 1;
+            },
+            None => return fired
+        }
+    }
+}
+
+fn fire(id: TimerId, now: u64) {
+    match id {
+        TimerId::Wake(pid) => {
+            process::wake_due(pid, now as usize);
+        }
+    }
+}
+
+// What a driver registers to be run periodically
+pub type Callback = fn();
+
+// Callbacks by id, see register_callback
+static CALLBACKS: Mutex<[Option<Callback>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+// Bit n is set while callback n is due and hasn't run yet
+static PENDING_CALLBACKS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+struct Periodic {
+    callback: usize,
+    // In scheduler::now() units
+    interval: u64,
+    // The scheduler::now() it is due at next
+    due:      u64
+}
+
+static PERIODIC: Mutex<[Option<Periodic>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+
+// Put f in the callback table and return the id it is called by
+pub fn register_callback(f: Callback) -> Result<usize, TimerFull> {
+    let mut callbacks = CALLBACKS.lock();
+    let id = callbacks.iter().position(|c| c.is_none()).ok_or(TimerFull)?;
+    callbacks[id] = Some(f);
+    Ok(id)
+}
+
+// Run callback callback_id once every interval_ticks ticks, starting
+// interval_ticks from now
+pub fn register_periodic(interval_ticks: u64, callback_id: usize) -> Result<(), PeriodicError> {
+    if interval_ticks == 0 {
+        return Err(PeriodicError::ZeroInterval);
+    }
+    if CALLBACKS.lock().get(callback_id).copied().flatten().is_none() {
+        return Err(PeriodicError::NoCallback);
+    }
+    let interval = interval_ticks.saturating_mul(ms_to_ticks(scheduler::quantum_ms()).max(1));
+    let mut periodic = PERIODIC.lock();
+    let slot = periodic.iter_mut().find(|p| p.is_none()).ok_or(PeriodicError::Full)?;
+    *slot = Some(Periodic { callback: callback_id, interval, due: scheduler::now().saturating_add(interval) });
+    Ok(())
+}
+
+// Flag the callbacks of the periodic timers that are due at now. Called
+// from the timer interrupt, which tickless mode may hold off for many
+// ticks, so it goes by when they are due and not by how often it is
+// called. If a callback holds the timers, they are left for the next
+// call. Returns how many came due.
+pub fn tick_periodic(now: u64) -> usize {
+    match PERIODIC.try_lock() {
+        Some(mut periodic) => flag_due(&mut periodic, now),
+        None => 0
+    }
+}
+
+fn flag_due(periodic: &mut [Option<Periodic>; MAX_CALLBACKS], now: u64) -> usize {
+    let mut due = 0;
+    for p in periodic.iter_mut().flatten() {
+        if now >= p.due {
+            // However many intervals went by, it is called once, and is
+            // due next where it would have been had none been missed
+            let late = (now - p.due) % p.interval;
+            p.due = now.saturating_add(p.interval - late);
+            PENDING_CALLBACKS.fetch_or(1 << p.callback, Ordering::AcqRel);
+            due += 1;
+        }
+    }
+    due
+}
+
+// The earliest scheduler::now() a periodic timer is due at, so that a
+// hart in tickless mode doesn't set its timer past it
+pub fn next_periodic() -> Option<u64> {
+    PERIODIC.try_lock()?.iter().flatten().map(|p| p.due).min()
+}
+
+// Run the callbacks tick_periodic() flagged, each once however many
+// times it came due meanwhile. The trap handler calls this with
+// interrupts on, see trap::run_softirqs. Returns how many ran.
+pub fn run_softirqs() -> usize {
+    let mut pending = PENDING_CALLBACKS.swap(0, Ordering::AcqRel);
+    let mut ran = 0;
+    while pending != 0 {
+        let id = pending.trailing_zeros() as usize;
+        pending &= pending - 1;
+        // Not called with the lock held, so it may register callbacks
+        let callback = CALLBACKS.lock()[id];
+        if let Some(f) = callback {
+            f();
+            ran += 1;
+        }
+    }
+    ran
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_call() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn tick_periodic_flags_callbacks_every_interval() {
+        let id = register_callback(count_call).expect("no room for a callback");
+        assert_eq!(register_periodic(0, id), Err(PeriodicError::ZeroInterval));
+        assert_eq!(register_periodic(2, MAX_CALLBACKS), Err(PeriodicError::NoCallback));
+        assert_eq!(register_periodic(2, id), Ok(()));
+        let (interval, due) = PERIODIC.lock().iter().flatten()
+                                      .find(|p| p.callback == id)
+                                      .map(|p| (p.interval, p.due))
+                                      .expect("periodic timer went missing");
+        let calls = CALLS.load(Ordering::Relaxed);
+        assert_eq!(tick_periodic(due - 1), 0);
+        assert_eq!(tick_periodic(due), 1);
+        // Due twice before the softirqs run is still one call
+        assert_eq!(tick_periodic(due + interval - 1), 0);
+        assert_eq!(tick_periodic(due + interval), 1);
+        run_softirqs();
+        assert_eq!(CALLS.load(Ordering::Relaxed), calls + 1);
+    }
+
+    #[test_case]
+    fn wheel_pops_by_deadline_then_insertion_order() {
+        let mut wheel = Wheel::new();
+        for (deadline, pid) in [(30, 1), (10, 2), (20, 3), (10, 4), (30, 5)] {
+            assert!(wheel.insert(deadline, TimerId::Wake(pid)).is_ok());
+        }
+        assert_eq!(wheel.pop_due(9), None);
+        // Equal deadlines come out in the order they went in
+        assert_eq!(wheel.pop_due(10), Some(TimerId::Wake(2)));
+        assert_eq!(wheel.pop_due(10), Some(TimerId::Wake(4)));
+        assert_eq!(wheel.pop_due(10), None);
+        assert_eq!(wheel.pop_due(100), Some(TimerId::Wake(3)));
+        assert_eq!(wheel.pop_due(100), Some(TimerId::Wake(1)));
+        assert_eq!(wheel.pop_due(100), Some(TimerId::Wake(5)));
+        assert_eq!(wheel.pop_due(100), None);
+    }
+
+    #[test_case]
+    fn wheel_refuses_timers_once_full() {
+        let mut wheel = Wheel::new();
+        for i in 0..MAX_TIMERS {
+            assert!(wheel.insert(i as u64, TimerId::Wake(i as u16)).is_ok());
+        }
+        assert!(wheel.insert(0, TimerId::Wake(0)).is_err());
+        assert_eq!(wheel.pop_due(0), Some(TimerId::Wake(0)));
+        assert!(wheel.insert(0, TimerId::Wake(0)).is_ok());
+    }
+
+    #[test_case]
+    fn tick_periodic_catches_up_on_skipped_ticks() {
+        let mut periodic = [None; MAX_CALLBACKS];
+        periodic[0] = Some(Periodic { callback: 0, interval: 3, due: 10 });
+        assert_eq!(flag_due(&mut periodic, 9), 0);
+        // As if tickless had held the timer off past two more intervals
+        assert_eq!(flag_due(&mut periodic, 17), 1);
+        assert_eq!(periodic[0].map(|p| p.due), Some(19));
+        PENDING_CALLBACKS.fetch_and(!1, Ordering::AcqRel);
+    }
+}
@@ -1,11 +1,19 @@
 // Trap handler
 
-use crate::{cpu::TrapFrame, plic, scheduler::schedule, syscall::do_syscall, uart};
+use crate::{cpu::{self, schedule_timer_in, Instruction, TrapFrame}, mmio, pipe, plic, process, scheduler::{self, next_timer_ms, next_wakeup, schedule, tick, wait_until}, syscall::do_syscall, timer, uart, vm::{classify_active_fault, copy_from_user, copy_to_user, current_root, fetch_instruction, is_kernel_stack_guard, is_kernel_write_violation, is_null_page, FaultKind}};
 
 extern "C" {
 	fn switch_to_user(frame: usize, mepc: usize, satp: usize) -> !;
+	fn m_trap_vector();
+	fn m_softirq_vector();
 }
 
+// What may interrupt the softirqs: the machine software interrupt (MSIE),
+// so a shootdown another hart is waiting on isn't held up, and the timer
+// (MTIE). External interrupts stay masked, their handlers take locks a
+// callback may be holding, and are taken once we're back in a process.
+const SOFTIRQ_MIE: usize = (1 << 3) | (1 << 7);
+
 #[no_mangle]
 extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status: usize, frame: *mut TrapFrame) -> usize {
     // Check if trap is asynchronous(1) or synchronous(0)
@@ -20,58 +28,47 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
     let cause_num = cause & 0xfff;
     let mut return_pc = epc;
     if is_async {
+        // The hart may have been waiting in wfi
+        cpu::idle_end(hart);
         match cause_num {
             3 => {
-                // Machine software interrupt
-                println!("Machine software interrupt CPU#{}", hart);
+                // Machine software interrupt, another hart wants us to
+                // drop some translations
+                if cpu::service_shootdowns(hart) == 0 {
+                    println!("Machine software interrupt CPU#{}", hart);
+                }
             },
             7 => unsafe {
-                // Context-switch timer, fires every 1 second to select a process and schedule it
-				let (frame, mepc, satp) = schedule();
-                let mtimecmp = 0x0200_4000 as *mut u64;
-				let mtime = 0x0200_bff8 as *const u64;
-				// The frequency given by QEMU is 10_000_000 Hz, so this sets
-				// the next interrupt to fire one second from now.
-				mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
-				switch_to_user(frame, mepc, satp);
+                // Context-switch timer, fires every quantum to select a process and schedule it
+				// The interrupted process resumes where it was when it is
+				// next scheduled
+				process::save_pc(frame as usize, epc);
+				process::charge_tick(frame as usize);
+				if let Some(pid) = process::stack_smashed(frame as usize) {
+					let _ = process::kill(pid);
+					run_next(hart);
+				}
+				tick();
+				process::set_vdso_time(scheduler::now());
+				timer::expire(scheduler::now());
+				timer::tick_periodic(scheduler::now());
+				plic::unmask_storms();
+				// The interrupt is dealt with, now for what it made due,
+				// which may make processes runnable
+				run_softirqs();
+				let next = schedule(hart);
+				schedule_timer_in(hart, next_timer_ms(hart));
+				// With nothing runnable, carry on with whatever was interrupted
+				if let Some(next) = next {
+					process::switched_to(hart, next.frame_addr);
+					switch_to_user(next.frame_addr, next.pc, next.satp);
+				}
             },
             11 => {
                 // Machine external interrupt
                 //println!("Machine external interrupt CPU#{}", hart);
-				// Check id of next interrupt in claim register
-				if let Some(interrupt) = plic::next() {
-					match interrupt {
-						10 => { 
-							// Interrupt 10 is the UART interrupt.
-							let mut my_uart = uart::Uart::new(0x1000_0000);
-							if let Some(c) = my_uart.get() {
-								match c {
-									8 => {
-										// This is a backspace, so we
-										// essentially have to write a space and
-										// backup again:
-										print!("{} {}", 8 as char, 8 as char);
-									},
-									10 | 13 => {
-										// Newline or carriage-return
-										println!();
-									},
-									_ => {
-										print!("{}", c as char);
-									},
-								}
-							}
-					
-						},
-						// Non-UART interrupts go here and do nothing.
-						_ => {
-							println!("Non-UART external interrupt: {}", interrupt);
-						}
-					}
-					// We've claimed it, so now say that we've handled it. This resets the interrupt pending
-					// and allows the UART to interrupt again.
-					plic::complete(interrupt);
-				}
+				// Handle everything the PLIC has pending, up to a limit
+				plic::drain(handle_external);
             },
             _ => {
                 println!("Unhandled async trap CPU#{} -> {}", hart, cause_num);
@@ -81,18 +78,50 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
         match cause_num {
 			2 => {
 				// Illegal instruction
+				// mtval holds the instruction bits on some harts and zero on
+				// others, so go and read the instruction ourselves if needed.
+				let insn = if tval != 0 {
+					Some(Instruction(tval as u32))
+				} else {
+					fetch_instruction(epc)
+				};
+				match insn {
+					Some(insn) => println!("Faulting instruction: {}", insn),
+					None => println!("Faulting instruction: <unmapped at 0x{:08x}>", epc),
+				}
 				panic!("Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}\n", hart, epc, tval);
 				while true {}
 			},
+			3 => unsafe {
+				// Breakpoint (ebreak)
+				// There is no debugger to hand the process to yet, so after
+				// reporting it we kill the process and run something else.
+				match debug_break(epc, &*frame) {
+					Some(pid) => {
+						println!("Killing PID {} at breakpoint", pid);
+						let _ = process::kill(pid);
+						run_next(hart);
+					},
+					None => panic!("Breakpoint in the kernel CPU#{} -> 0x{:08x}\n", hart, epc),
+				}
+			},
+			4 => {
+				// Load address misaligned
+				return_pc = misaligned("load", hart, epc, tval, frame);
+			},
+			6 => {
+				// Store address misaligned
+				return_pc = misaligned("store", hart, epc, tval, frame);
+			},
 			8 => {
 				// Environment (system) call from User mode
 				//println!("E-call from User mode! CPU#{} -> 0x{:08x}", hart, epc);
-				return_pc = do_syscall(return_pc, frame);
+				return_pc = do_syscall(return_pc, unsafe { &mut *frame });
 			},
 			9 => {
 				// Environment (system) call from Supervisor mode
 				println!("E-call from Supervisor mode! CPU#{} -> 0x{:08x}", hart, epc);
-				return_pc = do_syscall(return_pc, frame);
+				return_pc = do_syscall(return_pc, unsafe { &mut *frame });
 			},
 			11 => {
 				// Environment (system) call from Machine mode
@@ -101,21 +130,73 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 			// Page faults
 			12 => {
 				// Instruction page fault
-				println!("Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-				while true {}
-				return_pc += 4;
+				if is_null_page(tval) {
+					null_deref("fetch", hart, epc, tval, frame);
+				}
+				fault_storm(hart, epc, tval, frame);
+				println!("Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x} ({:?})", hart, epc, tval, classify_active_fault(tval));
+				bad_access("fetch", hart, epc, tval, frame);
 			},
 			13 => {
 				// Load page fault
-				println!("Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-				while true {}
-				return_pc += 4;
+				if is_null_page(tval) {
+					null_deref("load", hart, epc, tval, frame);
+				}
+				if is_kernel_stack_guard(tval) && process::pid_of_frame(frame as usize).is_none() {
+					panic!("Kernel stack overflow CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				}
+				fault_storm(hart, epc, tval, frame);
+				println!("Load page fault CPU#{} -> 0x{:08x}: 0x{:08x} ({:?})", hart, epc, tval, classify_active_fault(tval));
+				bad_access("load", hart, epc, tval, frame);
 			},
 			15 => {
 				// Store page fault
-				println!("Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-				while true {}
-				return_pc += 4;
+				if is_null_page(tval) {
+					null_deref("store", hart, epc, tval, frame);
+				}
+				if is_kernel_stack_guard(tval) && process::pid_of_frame(frame as usize).is_none() {
+					panic!("Kernel stack overflow CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				}
+				// The kernel wrote to its own read-only memory, which means
+				// something is corrupt. Don't carry on past it.
+				if current_root().is_some_and(|root| is_kernel_write_violation(root, tval)) {
+					match fetch_instruction(epc) {
+						Some(insn) => println!("Faulting instruction: {}", insn),
+						None => println!("Faulting instruction: <unmapped at 0x{:08x}>", epc),
+					}
+					panic!("write to read-only kernel page 0x{:08x} CPU#{} -> 0x{:08x}", tval, hart, epc);
+				}
+				fault_storm(hart, epc, tval, frame);
+				// A store just below the stack is the stack growing, map
+				// more of it and run the store again.
+				match process::grow_stack(frame as usize, tval) {
+					Ok(()) => return return_pc,
+					// It would take the process over its mapped page limit
+					Err(process::GrowError::OverLimit) => {
+						if let Some(pid) = process::pid_of_frame(frame as usize) {
+							println!("Killing PID {} over its mapped page limit", pid);
+							let _ = process::kill(pid);
+							run_next(hart);
+						}
+					},
+					Err(_) => {},
+				}
+				let kind = classify_active_fault(tval);
+				println!("Store page fault CPU#{} -> 0x{:08x}: 0x{:08x} ({:?})", hart, epc, tval, kind);
+				// A stray store may have hit the canary on its way here
+				if let Some(pid) = process::stack_smashed(frame as usize) {
+					let _ = process::kill(pid);
+					run_next(hart);
+				}
+				// A process that ran off the end of its stack can't go on
+				if kind == FaultKind::StackGuard {
+					if let Some(pid) = process::pid_of_frame(frame as usize) {
+						println!("Killing PID {} on stack overflow", pid);
+						let _ = process::kill(pid);
+						run_next(hart);
+					}
+				}
+				bad_access("store", hart, epc, tval, frame);
 			},
 			_ => {
 				panic!("Unhandled sync trap CPU#{} -> {}\n", hart, cause_num);
@@ -123,6 +204,262 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
         }
     }
 
+    // Whatever the trap did to pipes may have readers or writers to wake
+    pipe::run_wakes();
+    process::run_futex_wakes();
     // Return updated program counter after printing/panicking on trap
     return_pc
-}
\ No newline at end of file
+}
+
+// Report a fetch, load or store in the null page. That page is never
+// mapped, so this is a null pointer being followed rather than a page
+// that still has to be filled in. A process that does it is killed, the
+// kernel doing it stops here.
+fn null_deref(access: &str, hart: usize, epc: usize, tval: usize, frame: *mut TrapFrame) -> ! {
+    println!("Null pointer {} CPU#{} -> 0x{:08x}: 0x{:08x}", access, hart, epc, tval);
+    if let Some(pid) = process::pid_of_frame(frame as usize) {
+        println!("Killing PID {} on null pointer dereference", pid);
+        let _ = process::kill(pid);
+        run_next(hart);
+    }
+    panic!("Null pointer {} in the kernel at 0x{:08x}", access, epc);
+}
+
+// A page fault nothing could resolve. Running the access again would
+// only fault again, so the process that made it is killed. The kernel
+// making it stops here.
+fn bad_access(access: &str, hart: usize, epc: usize, tval: usize, frame: *mut TrapFrame) -> ! {
+    if let Some(pid) = process::pid_of_frame(frame as usize) {
+        println!("Killing PID {} on bad {} at 0x{:08x}", pid, access, tval);
+        let _ = process::kill(pid);
+        run_next(hart);
+    }
+    panic!("Bad {} in the kernel at 0x{:08x}: 0x{:08x}", access, epc, tval);
+}
+
+// Kill the process that owns frame if this page fault is one in a row
+// that isn't going away, see process::fault_storm. Returns otherwise.
+fn fault_storm(hart: usize, epc: usize, tval: usize, frame: *mut TrapFrame) {
+    if let Some(pid) = process::fault_storm(frame as usize, epc, tval) {
+        println!("Killing PID {} on page fault storm at 0x{:08x}: 0x{:08x}", pid, epc, tval);
+        let _ = process::kill(pid);
+        run_next(hart);
+    }
+}
+
+// Report a load or store of a misaligned address and do it a byte at a
+// time in the process' place, returning the pc after it. The hart traps
+// on these rather than doing them itself. An access we can't decode or
+// whose bytes aren't all mapped gets the process killed, one in the
+// kernel is a bug there.
+fn misaligned(access: &str, hart: usize, epc: usize, tval: usize, frame: *mut TrapFrame) -> usize {
+    println!("Misaligned {} CPU#{} -> 0x{:08x}: 0x{:08x}", access, hart, epc, tval);
+    let pid = match process::pid_of_frame(frame as usize) {
+        Some(pid) => pid,
+        None => panic!("Misaligned {} in the kernel at 0x{:08x}: 0x{:08x}", access, epc, tval),
+    };
+    let insn = fetch_instruction(epc);
+    if let (Some(insn), Some(root)) = (insn, current_root()) {
+        let regs = unsafe { &mut (*frame).regs };
+        match insn.mem_access() {
+            Some(acc) if acc.store => {
+                let bytes = regs[acc.reg].to_le_bytes();
+                if copy_to_user(root, tval, &bytes[..acc.width]).is_ok() {
+                    return epc + insn.size();
+                }
+            },
+            Some(acc) => {
+                let mut bytes = [0u8; 8];
+                if copy_from_user(root, &mut bytes[..acc.width], tval).is_ok() {
+                    let mut val = u64::from_le_bytes(bytes);
+                    if acc.signed {
+                        // Shift the top loaded bit up to bit 63 and back down
+                        let shift = 64 - 8 * acc.width as u32;
+                        val = ((val << shift) as i64 >> shift) as u64;
+                    }
+                    // x0 stays zero whatever is loaded into it
+                    if acc.reg != 0 {
+                        regs[acc.reg] = val as usize;
+                    }
+                    return epc + insn.size();
+                }
+            },
+            None => {},
+        }
+    }
+    println!("Killing PID {} on misaligned {}", pid, access);
+    let _ = process::kill(pid);
+    run_next(hart);
+}
+
+// Report a breakpoint: the process it came from, where it happened, and
+// its registers. Returns the pid, or None if the frame isn't a process'.
+pub fn debug_break(epc: usize, frame: &TrapFrame) -> Option<u16> {
+    let pid = process::pid_of_frame(frame as *const TrapFrame as usize);
+    match pid {
+        Some(pid) => println!("Breakpoint in PID {} at 0x{:08x}", pid, epc),
+        None => println!("Breakpoint in the kernel at 0x{:08x}", epc),
+    }
+    frame.dump();
+    pid
+}
+
+// Switch this hart to the next process. If everything is asleep we wait
+// here for the first one to wake, and with nothing asleep either we idle
+// until an interrupt may have made something runnable.
+pub fn run_next(hart: usize) -> ! {
+    loop {
+        // Like the end of a trap, a process that exited or was killed
+        // may have closed pipe ends
+        pipe::run_wakes();
+        process::run_futex_wakes();
+        // It may also have been the one process run_to_exit() runs
+        process::resume_if_alone_done();
+        run_softirqs();
+        if let Some(next) = schedule(hart) {
+            process::switched_to(hart, next.frame_addr);
+            unsafe { switch_to_user(next.frame_addr, next.pc, next.satp); }
+        }
+        match next_wakeup() {
+            Some(at) => {
+                // Nothing to run until then, which is idle time too
+                let before = cpu::mtime_read();
+                wait_until(at as u64);
+                cpu::add_idle(before, cpu::mtime_read());
+                timer::expire(scheduler::now());
+            },
+            None => idle(hart),
+        }
+    }
+}
+
+// Run the timer callbacks that are due. We're still in the trap, so
+// interrupts are turned on just while they run, with mtvec pointing at
+// m_softirq_vector, which can take a trap on top of this one. mstatus is
+// put back afterwards, as a nested trap changes MPP and MPIE.
+fn run_softirqs() -> usize {
+    let mstatus = cpu::mstatus_read();
+    let mie = cpu::mie_read();
+    cpu::mtvec_write(m_softirq_vector as *const () as usize);
+    cpu::mie_write(SOFTIRQ_MIE);
+    cpu::mstatus_write(mstatus | cpu::MSTATUS_MIE);
+    let ran = timer::run_softirqs();
+    cpu::mstatus_write(mstatus);
+    cpu::mie_write(mie);
+    cpu::mtvec_write(m_trap_vector as *const () as usize);
+    ran
+}
+
+// Traps taken while the softirqs run, see run_softirqs. Only what
+// SOFTIRQ_MIE lets through should get here, anything else is a bug in a
+// callback. Nothing here takes a lock a callback may hold.
+#[no_mangle]
+extern "C" fn m_softirq_trap(epc: usize, tval: usize, cause: usize, hart: usize) {
+    let is_async = (cause >> 63) & 1 == 1;
+    match (is_async, cause & 0xfff) {
+        (true, 3) => {
+            cpu::service_shootdowns(hart);
+        },
+        (true, 7) => {
+            // Count it like any context-switch timer interrupt, periodic
+            // timers that are busy catch up on the next one. Scheduling
+            // waits until the callbacks are done.
+            tick();
+            timer::tick_periodic(scheduler::now());
+            schedule_timer_in(hart, scheduler::quantum_ms());
+        },
+        (_, cause_num) => {
+            panic!("Trap {} in a softirq on CPU#{} at 0x{:08x}, mtval 0x{:08x}", cause_num, hart, epc, tval);
+        }
+    }
+}
+
+// Wait for an interrupt with nothing to run. We're still in the trap, so
+// interrupts aren't taken and what woke us is handled here instead. The
+// timer is set so that we look at the process list again every quantum,
+// for processes other harts made runnable.
+fn idle(hart: usize) {
+    schedule_timer_in(hart, scheduler::quantum_ms());
+    cpu::wait_for_interrupt();
+    let pending = cpu::mip_read();
+    if pending & cpu::MIP_MEIP != 0 {
+        plic::drain(handle_external);
+    }
+    if pending & cpu::MIP_MSIP != 0 {
+        cpu::service_shootdowns(hart);
+    }
+}
+
+// Handle one external interrupt claimed from the PLIC. plic::drain
+// completes it afterwards, which lets the device interrupt again.
+fn handle_external(interrupt: u32) {
+    match interrupt {
+        10 => {
+            // Interrupt 10 is the UART interrupt.
+            let mut my_uart = uart::Uart::new(mmio::UART_BASE);
+            if let Some(c) = my_uart.get() {
+                // Queue it for read and wake whoever is waiting for input
+                if uart::rx_push(c) {
+                    process::wake_key(uart::rx_key(), usize::MAX);
+                }
+                match c {
+                    8 => {
+                        // This is a backspace, so we
+                        // essentially have to write a space and
+                        // backup again:
+                        print!("{} {}", 8 as char, 8 as char);
+                    },
+                    10 | 13 => {
+                        // Newline or carriage-return
+                        println!();
+                    },
+                    _ => {
+                        print!("{}", c as char);
+                    },
+                }
+            }
+        },
+        // Non-UART interrupts go here and do nothing.
+        _ => {
+            println!("Non-UART external interrupt: {}", interrupt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::arch::asm;
+
+    use crate::syscall::syscall1;
+    use super::*;
+
+    extern "C" fn ebreak_entry(_argc: usize, _argv: usize) {
+        unsafe { asm!("ebreak"); }
+        loop {
+            syscall1(0, 0);
+        }
+    }
+
+    #[test_case]
+    fn ebreak_kills_the_process_not_the_kernel() {
+        let pid = process::add_process_with_args(ebreak_entry, &[]).expect("no room for the test process");
+        // Exiting would give 0, the breakpoint kills it before it gets there
+        assert_eq!(process::run_to_exit(pid), Ok(process::KILLED));
+    }
+    // lr.d has to be aligned even where plain loads don't, so this always
+    // traps with a misaligned load
+    extern "C" fn misaligned_lr_entry(_argc: usize, argv: usize) {
+        unsafe { asm!("lr.d {0}, ({1})", out(reg) _, in(reg) argv + 1); }
+        loop {
+            syscall1(0, 0);
+        }
+    }
+
+    #[test_case]
+    fn misaligned_loads_go_to_their_handler() {
+        // Which kills a process it can't emulate the access for, rather
+        // than panicking like the kernel does for unexpected causes
+        let pid = process::add_process_with_args(misaligned_lr_entry, &["x"]).expect("no room for the test process");
+        assert_eq!(process::run_to_exit(pid), Ok(process::KILLED));
+    }
+}
@@ -1,102 +1,355 @@
-use core::fmt::{Write, Result};
+use core::{fmt::{Write, Result}, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::{cpu::spin_hint, lock::Mutex, mmio::Mmio};
+
+// NS16550A register offsets from the UART's base address
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+// LSR bit 0, set when a received byte is waiting in RBR
+const LSR_DR: u8 = 1 << 0;
+// MCR bit 4 loops the transmitter back into the receiver
+const MCR_LOOP: u8 = 1 << 4;
+// Byte sent during the self-test. Alternating bits catch most wiring faults.
+const SELFTEST_BYTE: u8 = 0xa5;
+// How many times selftest polls for the looped back byte. QEMU doesn't
+// loop anything back, so this is how long it takes to skip the test there.
+const LOOPBACK_SPIN_LIMIT: usize = 100_000;
+
+// LCR bits 0 and 1, 8-bit words
+const LCR_WORD_8: u8 = 0b11;
+// FCR bit 0, turns the FIFOs on
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+// Whether the word length and FIFO are set up, by init() or by the first
+// write before it. There is only the one UART.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+// LSR bit 5, set when the transmit FIFO is empty
+const LSR_THRE: u8 = 1 << 5;
+// The NS16550A transmit FIFO holds 16 bytes
+const FIFO_DEPTH: usize = 16;
+// LSR bit 6, set when both the holding register and the shift register are empty
+const LSR_TEMT: u8 = 1 << 6;
+// How many times init polls for TEMT before giving up. On QEMU the
+// transmitter is always idle, so this is only reached on real hardware
+// with a stuck line.
+const INIT_SPIN_LIMIT: usize = 1_000_000;
+
+// Bytes the UART interrupt has received that nobody has read yet
+pub const RX_LEN: usize = 256;
+
+struct RxRing {
+    buf:  [u8; RX_LEN],
+    // Index of the oldest byte
+    head: usize,
+    len:  usize
+}
+
+static RX: Mutex<RxRing> = Mutex::new(RxRing { buf: [0; RX_LEN], head: 0, len: 0 });
+
+// Queue a byte that was received. Returns false if the ring is full, in
+// which case the byte is dropped.
+pub fn rx_push(c: u8) -> bool {
+    let mut rx = RX.lock();
+    if rx.len == RX_LEN {
+        return false;
+    }
+    let at = (rx.head + rx.len) % RX_LEN;
+    rx.buf[at] = c;
+    rx.len += 1;
+    true
+}
+
+// Take the oldest received byte
+pub fn rx_pop() -> Option<u8> {
+    let mut rx = RX.lock();
+    if rx.len == 0 {
+        return None;
+    }
+    let c = rx.buf[rx.head];
+    rx.head = (rx.head + 1) % RX_LEN;
+    rx.len -= 1;
+    Some(c)
+}
+
+// Processes waiting for input wait on this key, see process::wait_on
+pub fn rx_key() -> usize {
+    &RX as *const Mutex<RxRing> as usize
+}
+
+#[derive(Debug)]
+pub enum UartError {
+    // The transmitter never went idle
+    Timeout,
+    // In loopback mode we read back something other than what we sent
+    LoopbackMismatch { sent: u8, got: u8 }
+}
+
+// Outcome of a self-test that didn't find a fault
+#[derive(Debug, PartialEq)]
+pub enum SelfTest {
+    // The byte came back through loopback unchanged
+    Passed,
+    // Nothing came back, the UART doesn't implement loopback
+    Skipped
+}
 
 pub struct Uart {
-    base_addr: usize
+    regs: Mmio
 }
 
 // Implement write trait for Uart to use the write! macro with it
 impl Write for Uart {
     fn write_str(&mut self, s: &str) -> Result {
-        for c in s.bytes() {
-            self.put(c.clone());
-        }
-
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
 
 impl Uart {
-    pub fn new(base_addr: usize) -> Self {
+    pub const fn new(base_addr: usize) -> Self {
         Uart {
-            base_addr
+            regs: Mmio::new(base_addr)
         }
     }
 
-    pub fn init(&mut self) {
-        let ptr = self.base_addr as *mut u8;
-        unsafe {
-            // Set the 0th and 1st bit of LCR to 1 respectively
-            // LCR of the UART chip is at base_addr + 3 offset
-            // This will set the word length to be 8 bits
-            let lcr = (1 << 0) | (1 << 1);
-            ptr.add(3).write_volatile(lcr);
+    pub fn init(&mut self) -> core::result::Result<(), UartError> {
+        CONFIGURED.store(true, Ordering::Release);
+        // Set the 0th and 1st bit of LCR to 1 respectively
+        // LCR of the UART chip is at base_addr + 3 offset
+        // This will set the word length to be 8 bits
+        let lcr = (1 << 0) | (1 << 1);
+        self.regs.write8(LCR, lcr);
     
-            // Set 0th bit of FIFO register to 1
-            // FIFO control register is at base_addr + 2 offset
-            // This enables FIFO reads and writes of data to UART
-            let fcr = 1 << 0;
-            ptr.add(2).write_volatile(fcr);
+        // Set 0th bit of FIFO register to 1
+        // FIFO control register is at base_addr + 2 offset
+        // This enables FIFO reads and writes of data to UART
+        let fcr = 1 << 0;
+        self.regs.write8(FCR, fcr);
     
-            // Enable receiver buffer interrupts by setting 0th bit to 1
-            // IER is at base_addr + 1
-            // Raises CPU interrupt whenever data is added to the receiver
-            let ier = 1 << 0;
-            ptr.add(1).write_volatile(ier);
+        // Enable receiver buffer interrupts by setting 0th bit to 1
+        // IER is at base_addr + 1
+        // Raises CPU interrupt whenever data is added to the receiver
+        let ier = 1 << 0;
+        self.regs.write8(IER, ier);
     
-            // Calculate divisor to set the signaling rate(in baud)
-            // For QEMU, we do not need to calculate the divisor,
-            // but on real hardware it would be calculated as follows
-            // Based on UART NS16550A chipset spec
-            // divisor = ceil( (clock_hz) / baud_sps * 16)
-            // For a global clock rate of 22.729 MHz to a signaling rate of 2400 baud.
-            // divisor = ceil( 22_729_000 / 2400 * 16)
-            // divisor = ceil( 22_729_000 / 38_400)
-            // divisor = ceil( 591.01 ) = 592
+        // Calculate divisor to set the signaling rate(in baud)
+        // For QEMU, we do not need to calculate the divisor,
+        // but on real hardware it would be calculated as follows
+        // Based on UART NS16550A chipset spec
+        // divisor = ceil( (clock_hz) / baud_sps * 16)
+        // For a global clock rate of 22.729 MHz to a signaling rate of 2400 baud.
+        // divisor = ceil( 22_729_000 / 2400 * 16)
+        // divisor = ceil( 22_729_000 / 38_400)
+        // divisor = ceil( 591.01 ) = 592
     
-            // Split divisor into two parts of 8 bits
-            // giving divisor's most and least bits
-            let divisor: u16 = 592;
-            let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
-            let divisor_most: u8 = (divisor >> 8).try_into().unwrap();
+        // Split divisor into two parts of 8 bits
+        // giving divisor's most and least bits
+        let divisor: u16 = 592;
+        let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
+        let divisor_most: u8 = (divisor >> 8).try_into().unwrap();
     
-            // Set divisor latch access bit to 1 in LCR, which is the 7th bit
-            // This will signify base_addr + 0 and base_addr + 1 as DLL and DLM
-            // instead of transmitting and receiving registers
-            ptr.add(3).write_volatile(lcr | (1 << 7));
+        // Changing the divisor while a character is still being shifted out
+        // corrupts it, so wait for the transmitter to be idle first.
+        self.wait_idle()?;
+
+        // Set divisor latch access bit to 1 in LCR, which is the 7th bit
+        // This will signify base_addr + 0 and base_addr + 1 as DLL and DLM
+        // instead of transmitting and receiving registers
+        self.regs.write8(LCR, lcr | (1 << 7));
     
-            // Now write the divisor most into DLM and divisor least into DLL
-            // DLL is now at THR/RBR(base_addr + 0), DLM is at IER(base_addr + 1)
-            ptr.add(0).write_volatile(divisor_least);
-            ptr.add(1).write_volatile(divisor_most);
+        // Now write the divisor most into DLM and divisor least into DLL
+        // DLL is now at THR/RBR(base_addr + 0), DLM is at IER(base_addr + 1)
+        self.regs.write8(RBR_THR, divisor_least);
+        self.regs.write8(IER, divisor_most);
     
-            // Now that the divisor has been set, we can close the latch
-            // By setting the divisor latch access bit to 0
-            ptr.add(3).write_volatile(lcr);
+        // Now that the divisor has been set, we can close the latch
+        // By setting the divisor latch access bit to 0
+        self.regs.write8(LCR, lcr);
+        Ok(())
+    }
+
+    // Check that register access works by sending a byte to ourselves
+    // through loopback mode. Run this before interrupts are enabled, since
+    // the looped back byte would otherwise raise a receive interrupt.
+    pub fn selftest(&mut self) -> core::result::Result<SelfTest, UartError> {
+        self.wait_idle()?;
+        // Throw away anything already received so we only see our byte.
+        // The receive FIFO holds at most FIFO_DEPTH bytes, so a DR bit
+        // that is stuck on doesn't keep us here.
+        for _ in 0..FIFO_DEPTH {
+            if self.get().is_none() {
+                break;
+            }
+        }
+        let mcr = self.regs.read8(MCR);
+        self.regs.write8(MCR, mcr | MCR_LOOP);
+        self.put(SELFTEST_BYTE);
+        let mut got = None;
+        for _ in 0..LOOPBACK_SPIN_LIMIT {
+            got = self.get();
+            if got.is_some() {
+                break;
+            }
+            spin_hint();
+        }
+        self.regs.write8(MCR, mcr);
+        match got {
+            Some(c) if c == SELFTEST_BYTE => Ok(SelfTest::Passed),
+            Some(c) => Err(UartError::LoopbackMismatch { sent: SELFTEST_BYTE, got: c }),
+            None => Ok(SelfTest::Skipped)
+        }
+    }
+
+    // Wait until the transmitter is empty, giving up after INIT_SPIN_LIMIT polls
+    fn wait_idle(&self) -> core::result::Result<(), UartError> {
+        for _ in 0..INIT_SPIN_LIMIT {
+            if self.regs.read8(LSR) & LSR_TEMT != 0 {
+                return Ok(());
+            }
+            spin_hint();
         }
+        Err(UartError::Timeout)
     }
     
     pub fn get(&mut self) -> Option<u8> {
-        let ptr = self.base_addr as *mut u8;
-    
-        unsafe {
-            // Read the 0th bit from Line status register
-            // Which tells us if data is ready to be read or not
-            if ptr.add(5).read_volatile() & 1 == 0 {
-                // DR bit is not set, therefore return None
-                None
-            } else {
-                // DR bit is set, data is ready
-                Some(ptr.add(0).read_volatile())
-            }
+        // Read the 0th bit from Line status register
+        // Which tells us if data is ready to be read or not
+        if self.regs.read8(LSR) & LSR_DR == 0 {
+            // DR bit is not set, therefore return None
+            None
+        } else {
+            // DR bit is set, data is ready
+            Some(self.regs.read8(RBR_THR))
         }
     }
     
+    // The least that printing needs, 8-bit words and the FIFO, set up
+    // on the first write if init() hasn't run yet. A panic before init()
+    // then still prints something legible. init() does the rest, like
+    // the divisor and interrupts.
+    fn early_setup(&mut self) {
+        // Every write comes through here, so the common case is only a
+        // load. The swap makes sure just one caller does the setup.
+        if !CONFIGURED.load(Ordering::Acquire) && !CONFIGURED.swap(true, Ordering::AcqRel) {
+            self.regs.write8(LCR, LCR_WORD_8);
+            self.regs.write8(FCR, FCR_FIFO_ENABLE);
+        }
+    }
+
+    // Write a buffer, a FIFO's worth at a time
+    // Once THRE says the FIFO is empty, up to FIFO_DEPTH bytes can be queued
+    // back to back, so we only poll once per batch instead of once per byte.
+    pub fn write_bytes(&mut self, buf: &[u8]) {
+        self.early_setup();
+        let regs = &self.regs;
+        write_batched(buf, || regs.read8(LSR) & LSR_THRE != 0, |c| regs.write8(RBR_THR, c));
+    }
+
     pub fn put(&mut self, c: u8) {
-        let ptr = self.base_addr as *mut u8;
-    
-        unsafe {
-            // Ready to transmit/write to UART
-            ptr.add(0).write_volatile(c);
+        self.early_setup();
+        // Ready to transmit/write to UART
+        self.regs.write8(RBR_THR, c);
+    }
+}
+
+// Send buf with send, waiting for empty to say the FIFO is empty before
+// each FIFO_DEPTH bytes
+fn write_batched(buf: &[u8], mut empty: impl FnMut() -> bool, mut send: impl FnMut(u8)) {
+    for batch in buf.chunks(FIFO_DEPTH) {
+        while !empty() {
+            spin_hint();
         }
+        for &c in batch {
+            send(c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[test_case]
+    fn init_times_out_if_the_transmitter_never_goes_idle() {
+        // Plain memory in place of the registers, LSR never shows TEMT
+        let mut mem = [0u8; 8];
+        let mut uart = Uart::new(mem.as_mut_ptr() as usize);
+        assert!(matches!(uart.init(), Err(UartError::Timeout)));
+        // The divisor latch was never opened
+        assert_eq!(uart.regs.read8(LCR) & (1 << 7), 0);
+        uart.regs.write8(LSR, LSR_TEMT);
+        assert!(uart.init().is_ok());
+        assert_eq!(uart.regs.read8(LCR), LCR_WORD_8);
+        assert_eq!(uart.regs.read8(RBR_THR), (592 & 0xff) as u8);
+        assert_eq!(uart.regs.read8(IER), (592 >> 8) as u8);
+    }
+
+    #[test_case]
+    fn a_fifo_is_filled_between_polls() {
+        let buf: [u8; 40] = core::array::from_fn(|i| i as u8);
+        // Polls so far and bytes sent since the last one, shared by both
+        // closures
+        let counts = Cell::new((0, 0));
+        let mut at_poll = [0usize; 4];
+        let mut out = [0u8; 40];
+        let mut written = 0;
+        write_batched(&buf, || {
+            let (polls, since) = counts.get();
+            at_poll[polls] = since;
+            counts.set((polls + 1, 0));
+            true
+        }, |c| {
+            let (polls, since) = counts.get();
+            out[written] = c;
+            written += 1;
+            counts.set((polls, since + 1));
+        });
+        // 16, 16 and the last 8
+        assert_eq!(counts.get(), (3, 8));
+        assert_eq!(at_poll[..3], [0, FIFO_DEPTH, FIFO_DEPTH]);
+        assert_eq!(out, buf);
     }
-}
\ No newline at end of file
+
+    #[test_case]
+    fn selftest_passes_on_echo_and_skips_without() {
+        // Plain memory reads back what was written to THR as RBR, which
+        // is loopback as long as DR says there is a byte
+        let mut mem = [0u8; 8];
+        let mut uart = Uart::new(mem.as_mut_ptr() as usize);
+        uart.regs.write8(LSR, LSR_TEMT | LSR_DR);
+        uart.regs.write8(MCR, 0x3);
+        assert_eq!(uart.selftest().ok(), Some(SelfTest::Passed));
+        assert_eq!(uart.regs.read8(RBR_THR), SELFTEST_BYTE);
+        // Loopback is switched off again
+        assert_eq!(uart.regs.read8(MCR), 0x3);
+        // Nothing ever received
+        uart.regs.write8(LSR, LSR_TEMT);
+        assert_eq!(uart.selftest().ok(), Some(SelfTest::Skipped));
+        assert_eq!(uart.regs.read8(MCR), 0x3);
+    }
+    #[test_case]
+    fn the_first_write_before_init_sets_up_the_line() {
+        let mut mem = [0u8; 8];
+        let mut uart = Uart::new(mem.as_mut_ptr() as usize);
+        uart.regs.write8(LSR, LSR_THRE);
+        // As before init() ran. Nothing prints until it is put back.
+        let configured = CONFIGURED.swap(false, Ordering::AcqRel);
+        uart.write_bytes(b"x");
+        let early = (uart.regs.read8(LCR), uart.regs.read8(FCR));
+        // Only the first write does it
+        uart.regs.write8(LCR, 0);
+        uart.write_bytes(b"y");
+        let later = uart.regs.read8(LCR);
+        CONFIGURED.store(configured, Ordering::Release);
+        assert_eq!(early, (LCR_WORD_8, FCR_FIFO_ENABLE));
+        assert_eq!(later, 0);
+        assert_eq!(uart.regs.read8(RBR_THR), b'y');
+    }
+}
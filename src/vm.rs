@@ -0,0 +1,257 @@
+// Virtual memory diagnostics
+// Helpers for making sense of page faults by looking at the faulting
+// address (mtval) and the page table that was active at the time.
+
+use crate::{boot::kernel_stack_guard,
+            cpu::{satp_read, Instruction},
+            error::KernelError,
+            mem::memcpy,
+            page::{lookup, virt_to_phys, EntryBits, Table, VirtAddr, PAGE_SIZE},
+            process::{PROCESS_STARTING_ADDR, STACK_ADDR}};
+
+extern "C" {
+    static MEMORY_START: usize;
+    static MEMORY_END: usize;
+}
+
+// What a faulting address most likely means
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FaultKind {
+    // The address is in the first page, almost certainly a null dereference
+    NullPointer,
+    // The address is in the unmapped page just below a user stack
+    StackGuard,
+    // The address is in the unmapped page just below the kernel stack
+    KernelStackGuard,
+    // Nothing is mapped at this user address
+    UnmappedUser,
+    // Nothing is mapped at this kernel address
+    UnmappedKernel,
+    // The address is mapped, but not with the permissions the access needed
+    PermissionViolation
+}
+
+// The kernel is identity mapped, so kernel addresses are either in RAM
+// or in the MMIO window below where user processes start.
+pub fn is_kernel_addr(vaddr: usize) -> bool {
+    unsafe { vaddr < PROCESS_STARTING_ADDR || (MEMORY_START..MEMORY_END).contains(&vaddr) }
+}
+
+// The first page is never mapped in any address space, page::try_map()
+// refuses it, so that following a null pointer (plus a small offset for
+// a field) always faults.
+pub fn is_null_page(vaddr: usize) -> bool {
+    vaddr < PAGE_SIZE
+}
+
+// The user stack grows down from STACK_ADDR + its size. The page below
+// STACK_ADDR is never mapped so that running off the stack faults.
+pub fn is_stack_guard(vaddr: usize) -> bool {
+    (STACK_ADDR - PAGE_SIZE..STACK_ADDR).contains(&vaddr)
+}
+
+// The page below the kernel stack, see boot::kernel_stack_guard()
+pub fn is_kernel_stack_guard(vaddr: usize) -> bool {
+    let guard = kernel_stack_guard();
+    (guard..guard + PAGE_SIZE).contains(&vaddr)
+}
+
+// Classify a faulting address against the page table root
+pub fn classify_fault(root: &Table, tval: usize) -> FaultKind {
+    if !is_null_page(tval) && virt_to_phys(root, VirtAddr(tval)).is_some() {
+        // We only get here on a fault, so a translation means the
+        // access itself wasn't allowed.
+        FaultKind::PermissionViolation
+    } else {
+        classify_unmapped(tval)
+    }
+}
+
+// Whether a store to tval hit a kernel page that is mapped without Write,
+// such as .text or .rodata. That is never a page that just hasn't been
+// filled in yet, something in the kernel is writing where it must not.
+// Pages mapped User, like the kernel code a process runs, don't count.
+pub fn is_kernel_write_violation(root: &Table, tval: usize) -> bool {
+    if !is_kernel_addr(tval) || classify_fault(root, tval) != FaultKind::PermissionViolation {
+        return false;
+    }
+    match lookup(root, VirtAddr(tval)) {
+        Some((_, bits)) => bits & EntryBits::User.val() == 0 && bits & EntryBits::Write.val() == 0,
+        None => false
+    }
+}
+
+// Classify a faulting address in the address space that trapped
+pub fn classify_active_fault(tval: usize) -> FaultKind {
+    match current_root() {
+        Some(root) => classify_fault(root, tval),
+        None => classify_unmapped(tval)
+    }
+}
+
+// Classify an address that has no translation
+fn classify_unmapped(tval: usize) -> FaultKind {
+    if is_null_page(tval) {
+        FaultKind::NullPointer
+    } else if is_stack_guard(tval) {
+        FaultKind::StackGuard
+    } else if is_kernel_stack_guard(tval) {
+        FaultKind::KernelStackGuard
+    } else if is_kernel_addr(tval) {
+        FaultKind::UnmappedKernel
+    } else {
+        FaultKind::UnmappedUser
+    }
+}
+
+// Get the root page table of the address space that trapped.
+// Traps are handled in machine mode where satp isn't used for translation,
+// so satp still holds the interrupted hart's address space.
+// Returns None if translation was off.
+pub fn current_root() -> Option<&'static Table> {
+    let satp = satp_read();
+    if satp >> 60 == 0 {
+        return None;
+    }
+    let root = ((satp & 0xfff_ffff_ffff) << 12) as *const Table;
+    unsafe { root.as_ref() }
+}
+
+// Read the instruction at epc in the address space that trapped.
+// We run in machine mode without translation, so epc is walked through
+// the page table first. Returns None if any part of the instruction
+// isn't mapped or doesn't land in RAM.
+pub fn fetch_instruction(epc: usize) -> Option<Instruction> {
+    // Instructions are at least 2-byte aligned, read one half at a time
+    // because a 32-bit instruction can straddle two pages.
+    let low = read_text_half(epc)? as u32;
+    let insn = Instruction(low);
+    if insn.is_compressed() {
+        return Some(insn);
+    }
+    let high = read_text_half(epc.wrapping_add(2))? as u32;
+    Some(Instruction(low | (high << 16)))
+}
+
+// Read a halfword of code at a virtual address of the trapped context
+fn read_text_half(vaddr: usize) -> Option<u16> {
+    if vaddr & 1 != 0 {
+        return None;
+    }
+    let paddr = match current_root() {
+        Some(root) => usize::from(virt_to_phys(root, VirtAddr(vaddr))?),
+        None => vaddr
+    };
+    // Reading outside of RAM could hit a device register or nothing at all
+    if !unsafe { (MEMORY_START..MEMORY_END).contains(&paddr) } {
+        return None;
+    }
+    Some(unsafe { (paddr as *const u16).read_volatile() })
+}
+
+// Copy dst.len() bytes from the user address src in the address space
+// root into dst. Every page has to be mapped User and Read. Fails with
+// InvalidAddress if one isn't, in which case dst may be partly filled.
+pub fn copy_from_user(root: &Table, dst: &mut [u8], src: usize) -> Result<(), KernelError> {
+    let need = EntryBits::User.val() | EntryBits::Read.val();
+    let mut done = 0;
+    while done < dst.len() {
+        let paddr = match user_page(root, src, done, need) {
+            Some(paddr) => paddr,
+            None => return Err(KernelError::InvalidAddress)
+        };
+        let chunk = (PAGE_SIZE - (paddr & (PAGE_SIZE - 1))).min(dst.len() - done);
+        unsafe { memcpy(dst.as_mut_ptr().add(done), paddr as *const u8, chunk) };
+        done += chunk;
+    }
+    Ok(())
+}
+
+// Copy src to the user address dst in the address space root. Every page
+// has to be mapped User and Write, so a process can't get the kernel to
+// write over something it may only read, like its code. Fails with
+// InvalidAddress if a page isn't.
+pub fn copy_to_user(root: &Table, dst: usize, src: &[u8]) -> Result<(), KernelError> {
+    let need = EntryBits::User.val() | EntryBits::Write.val();
+    let mut done = 0;
+    while done < src.len() {
+        let paddr = match user_page(root, dst, done, need) {
+            Some(paddr) => paddr,
+            None => return Err(KernelError::InvalidAddress)
+        };
+        let chunk = (PAGE_SIZE - (paddr & (PAGE_SIZE - 1))).min(src.len() - done);
+        unsafe { memcpy(paddr as *mut u8, src.as_ptr().add(done), chunk) };
+        done += chunk;
+    }
+    Ok(())
+}
+
+// Translate base + done if it is mapped with all of the need bits
+fn user_page(root: &Table, base: usize, done: usize, need: i64) -> Option<usize> {
+    let vaddr = base.checked_add(done)?;
+    let (paddr, bits) = lookup(root, VirtAddr(vaddr))?;
+    if bits & need != need {
+        return None;
+    }
+    Some(paddr.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{dealloc, map, unmap, zalloc, PhysAddr};
+
+    #[test_case]
+    fn faults_are_classified_by_address() {
+        let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+        let page = zalloc(1);
+        map(root, VirtAddr(PROCESS_STARTING_ADDR), PhysAddr(page as usize),
+            EntryBits::User.val() | EntryBits::Read.val(), 0);
+        assert_eq!(classify_fault(root, 8), FaultKind::NullPointer);
+        assert_eq!(classify_fault(root, PROCESS_STARTING_ADDR + 8), FaultKind::PermissionViolation);
+        assert_eq!(classify_fault(root, PROCESS_STARTING_ADDR + PAGE_SIZE), FaultKind::UnmappedUser);
+        assert_eq!(classify_fault(root, STACK_ADDR - 8), FaultKind::StackGuard);
+        assert_eq!(classify_fault(root, kernel_stack_guard() + 8), FaultKind::KernelStackGuard);
+        // Below where processes start is the kernel's MMIO window
+        assert_eq!(classify_fault(root, 0x1000_0000), FaultKind::UnmappedKernel);
+        unmap(root);
+        dealloc(root as *mut Table as *mut u8);
+        dealloc(page);
+    }
+
+    #[test_case]
+    fn stores_to_read_only_kernel_pages_are_violations() {
+        let root = unsafe { (zalloc(1) as *mut Table).as_mut().unwrap() };
+        let page = PhysAddr(zalloc(1) as usize);
+        // Kernel addresses in the MMIO window, nothing real lives there
+        let (text, data, user_text) = (0x1000_0000, 0x1000_1000, 0x1000_2000);
+        map(root, VirtAddr(text), page, EntryBits::ReadExecute.val(), 0);
+        map(root, VirtAddr(data), page, EntryBits::ReadWrite.val(), 0);
+        map(root, VirtAddr(user_text), page, EntryBits::UserReadExecute.val(), 0);
+        map(root, VirtAddr(PROCESS_STARTING_ADDR), page, EntryBits::User.val() | EntryBits::Read.val(), 0);
+        assert!(is_kernel_write_violation(root, text + 8));
+        assert!(!is_kernel_write_violation(root, data + 8));
+        assert!(!is_kernel_write_violation(root, user_text + 8));
+        // Nothing mapped yet, so it may be a page still to be filled in
+        assert!(!is_kernel_write_violation(root, 0x1000_3000));
+        assert!(!is_kernel_write_violation(root, PROCESS_STARTING_ADDR + 8));
+        unmap(root);
+        dealloc(root as *mut Table as *mut u8);
+        dealloc(page.0 as *mut u8);
+    }
+
+    #[test_case]
+    fn instructions_are_fetched_through_the_active_table() {
+        // add a0, a0, a1 and then c.lw a0, 0(a1). On the kernel stack,
+        // which is mapped whether or not translation is on.
+        let code = [0x00b5_0533u32, 0x4188];
+        let epc = code.as_ptr() as usize;
+        let add = fetch_instruction(epc).expect("mapped instruction not fetched");
+        assert_eq!(add.0, 0x00b5_0533);
+        assert_eq!((add.opcode(), add.rd(), add.rs1(), add.rs2()), (0x33, 10, 10, 11));
+        // Only the half that is the instruction
+        assert_eq!(fetch_instruction(epc + 4).map(|insn| insn.0), Some(0x4188));
+        assert!(fetch_instruction(0).is_none());
+        assert!(fetch_instruction(epc + 1).is_none());
+    }
+}